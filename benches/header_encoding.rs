@@ -0,0 +1,114 @@
+//! Encoding of a full `BlockHeaders` response, sequentially and with headers encoded on a
+//! `rayon` pool, against appending headers that are already RLP, which is how the sentry
+//! serves them from the header cache.
+//!
+//! Responses are checked to be identical before they are measured. Criterion reports time
+//! per response for several response sizes up to the eth limit of 1024 headers.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ethereum_types::{Bloom, H160, H256, H64, U256};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rlp::RlpStream;
+use rlp_derive::{RlpDecodable, RlpEncodable};
+
+const HEADER_COUNTS: &[usize] = &[16, 128, 512, 1024];
+
+#[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+struct BlockHeader {
+    parent_hash: H256,
+    ommers_hash: H256,
+    beneficiary: H160,
+    state_root: H256,
+    transactions_root: H256,
+    receipts_root: H256,
+    logs_bloom: Bloom,
+    difficulty: U256,
+    number: u64,
+    gas_limit: u64,
+    gas_used: u64,
+    timestamp: u64,
+    extra_data: Vec<u8>,
+    mix_hash: H256,
+    nonce: H64,
+    base_fee_per_gas: U256,
+}
+
+fn block_headers(count: usize) -> Vec<BlockHeader> {
+    let mut logs_bloom = [0; 256];
+    for (i, byte) in logs_bloom.iter_mut().enumerate() {
+        *byte = (i * 37) as u8;
+    }
+    (0..count as u64)
+        .map(|i| BlockHeader {
+            parent_hash: H256::from_low_u64_be(13_000_000 + i),
+            ommers_hash: H256::repeat_byte(0x02),
+            beneficiary: H160::repeat_byte(0x03),
+            state_root: H256::repeat_byte(0x04),
+            transactions_root: H256::repeat_byte(0x05),
+            receipts_root: H256::repeat_byte(0x06),
+            logs_bloom: Bloom::from(logs_bloom),
+            difficulty: U256::from(8_500_000_000_000_000u64),
+            number: 13_000_001 + i,
+            gas_limit: 30_000_000,
+            gas_used: 15_000_000,
+            timestamp: 1_630_000_000 + i * 13,
+            extra_data: b"Geth/v1.10.8-stable/linux-amd64".to_vec(),
+            mix_hash: H256::repeat_byte(0x07),
+            nonce: H64::repeat_byte(0x08),
+            base_fee_per_gas: U256::from(30_000_000_000u64),
+        })
+        .collect()
+}
+
+fn encode_sequential(headers: &[BlockHeader]) -> Vec<u8> {
+    let mut s = RlpStream::new_list(headers.len());
+    for header in headers {
+        s.append(header);
+    }
+    s.out().to_vec()
+}
+
+/// Headers are encoded in parallel, the list is put together on the calling thread.
+fn encode_parallel(headers: &[BlockHeader]) -> Vec<u8> {
+    let encoded = headers.into_par_iter().map(rlp::encode).collect::<Vec<_>>();
+    append_encoded(&encoded)
+}
+
+fn append_encoded<T: AsRef<[u8]>>(encoded: &[T]) -> Vec<u8> {
+    let mut s = RlpStream::new_list(encoded.len());
+    for header in encoded {
+        s.append_raw(header.as_ref(), 1);
+    }
+    s.out().to_vec()
+}
+
+fn header_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BlockHeaders");
+    for &count in HEADER_COUNTS {
+        let headers = block_headers(count);
+        let cached = headers.iter().map(rlp::encode).collect::<Vec<_>>();
+
+        let expected = encode_sequential(&headers);
+        assert_eq!(encode_parallel(&headers), expected);
+        assert_eq!(append_encoded(&cached), expected);
+
+        group.throughput(Throughput::Bytes(expected.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("sequential", count),
+            &headers,
+            |b, headers| b.iter(|| encode_sequential(black_box(headers))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("parallel", count),
+            &headers,
+            |b, headers| b.iter(|| encode_parallel(black_box(headers))),
+        );
+        group.bench_with_input(BenchmarkId::new("cached", count), &cached, |b, cached| {
+            b.iter(|| append_encoded(black_box(cached)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, header_encoding);
+criterion_main!(benches);
@@ -0,0 +1,314 @@
+//! Encoding and decoding of eth messages with `rlp`, which the sentry uses, against `fastrlp`.
+//!
+//! Messages are mirrored as plain structs on each side, as the sentry is a binary crate.
+//! Values are typical for mainnet, and both sides are checked to produce the same bytes
+//! before they are measured. Criterion reports time per operation and MB/s of RLP.
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use ethereum_forkid::{ForkHash, ForkId};
+use ethereum_types::{Bloom, H160, H256, H64, U256};
+use hex_literal::hex;
+
+mod with_rlp {
+    use super::*;
+    use rlp_derive::{RlpDecodable, RlpEncodable};
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct BlockHeader {
+        pub parent_hash: H256,
+        pub ommers_hash: H256,
+        pub beneficiary: H160,
+        pub state_root: H256,
+        pub transactions_root: H256,
+        pub receipts_root: H256,
+        pub logs_bloom: Bloom,
+        pub difficulty: U256,
+        pub number: u64,
+        pub gas_limit: u64,
+        pub gas_used: u64,
+        pub timestamp: u64,
+        pub extra_data: Vec<u8>,
+        pub mix_hash: H256,
+        pub nonce: H64,
+        pub base_fee_per_gas: U256,
+    }
+
+    /// Legacy transaction.
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct Transaction {
+        pub nonce: u64,
+        pub gas_price: U256,
+        pub gas_limit: u64,
+        pub to: H160,
+        pub value: U256,
+        pub data: Vec<u8>,
+        pub v: u64,
+        pub r: U256,
+        pub s: U256,
+    }
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct StatusMessage {
+        pub protocol_version: usize,
+        pub network_id: u64,
+        pub total_difficulty: U256,
+        pub best_hash: H256,
+        pub genesis_hash: H256,
+        pub fork_id: ForkId,
+    }
+
+    /// Request by block number, as sent by syncing peers.
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct GetBlockHeaders {
+        pub block: u64,
+        pub max_headers: u64,
+        pub skip: u64,
+        pub reverse: bool,
+    }
+}
+
+/// Same messages with `fastrlp`. Numbers that fit are `u128`, and fixed-size byte
+/// arrays are used for hashes and for signature values that have no leading zeros.
+mod with_fastrlp {
+    use bytes::Bytes;
+    use fastrlp::{RlpDecodable, RlpEncodable};
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct BlockHeader {
+        pub parent_hash: [u8; 32],
+        pub ommers_hash: [u8; 32],
+        pub beneficiary: [u8; 20],
+        pub state_root: [u8; 32],
+        pub transactions_root: [u8; 32],
+        pub receipts_root: [u8; 32],
+        pub logs_bloom: [u8; 256],
+        pub difficulty: u128,
+        pub number: u64,
+        pub gas_limit: u64,
+        pub gas_used: u64,
+        pub timestamp: u64,
+        pub extra_data: Bytes,
+        pub mix_hash: [u8; 32],
+        pub nonce: [u8; 8],
+        pub base_fee_per_gas: u128,
+    }
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct Transaction {
+        pub nonce: u64,
+        pub gas_price: u128,
+        pub gas_limit: u64,
+        pub to: [u8; 20],
+        pub value: u128,
+        pub data: Bytes,
+        pub v: u64,
+        pub r: [u8; 32],
+        pub s: [u8; 32],
+    }
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct ForkId {
+        pub hash: [u8; 4],
+        pub next: u64,
+    }
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct StatusMessage {
+        pub protocol_version: u64,
+        pub network_id: u64,
+        pub total_difficulty: u128,
+        pub best_hash: [u8; 32],
+        pub genesis_hash: [u8; 32],
+        pub fork_id: ForkId,
+    }
+
+    #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+    pub struct GetBlockHeaders {
+        pub block: u64,
+        pub max_headers: u64,
+        pub skip: u64,
+        pub reverse: bool,
+    }
+}
+
+const MAINNET_GENESIS: [u8; 32] =
+    hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3");
+const MAINNET_TOTAL_DIFFICULTY: u128 = 58_750_003_716_598_352_816_469;
+const FORK_HASH: [u8; 4] = hex!("f0afd0e3");
+const BASE_FEE: u128 = 30_000_000_000;
+const SIGNATURE_R: [u8; 32] =
+    hex!("f2c6e4c9e2c5d1a47b3e8d51b46f0c9a3bd7f04e5e2a7b88d3c1e0f9a7b6c5d4");
+const SIGNATURE_S: [u8; 32] =
+    hex!("7a1c2e3d4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c");
+
+/// ERC-20 transfer call data.
+fn transfer_data() -> Vec<u8> {
+    let mut data = hex!("a9059cbb").to_vec();
+    data.extend_from_slice(&[0x11; 32]);
+    data.extend_from_slice(&[0x22; 32]);
+    data
+}
+
+fn block_header() -> (with_rlp::BlockHeader, with_fastrlp::BlockHeader) {
+    let extra_data = b"Geth/v1.10.8-stable/linux-amd64".to_vec();
+    let mut logs_bloom = [0; 256];
+    for (i, byte) in logs_bloom.iter_mut().enumerate() {
+        *byte = (i * 37) as u8;
+    }
+    (
+        with_rlp::BlockHeader {
+            parent_hash: H256::repeat_byte(0x01),
+            ommers_hash: H256::repeat_byte(0x02),
+            beneficiary: H160::repeat_byte(0x03),
+            state_root: H256::repeat_byte(0x04),
+            transactions_root: H256::repeat_byte(0x05),
+            receipts_root: H256::repeat_byte(0x06),
+            logs_bloom: Bloom::from(logs_bloom),
+            difficulty: U256::from(8_500_000_000_000_000u64),
+            number: 13_000_000,
+            gas_limit: 30_000_000,
+            gas_used: 15_000_000,
+            timestamp: 1_630_000_000,
+            extra_data: extra_data.clone(),
+            mix_hash: H256::repeat_byte(0x07),
+            nonce: H64::repeat_byte(0x08),
+            base_fee_per_gas: U256::from(BASE_FEE),
+        },
+        with_fastrlp::BlockHeader {
+            parent_hash: [0x01; 32],
+            ommers_hash: [0x02; 32],
+            beneficiary: [0x03; 20],
+            state_root: [0x04; 32],
+            transactions_root: [0x05; 32],
+            receipts_root: [0x06; 32],
+            logs_bloom,
+            difficulty: 8_500_000_000_000_000,
+            number: 13_000_000,
+            gas_limit: 30_000_000,
+            gas_used: 15_000_000,
+            timestamp: 1_630_000_000,
+            extra_data: extra_data.into(),
+            mix_hash: [0x07; 32],
+            nonce: [0x08; 8],
+            base_fee_per_gas: BASE_FEE,
+        },
+    )
+}
+
+fn transaction() -> (with_rlp::Transaction, with_fastrlp::Transaction) {
+    (
+        with_rlp::Transaction {
+            nonce: 42,
+            gas_price: U256::from(BASE_FEE + 2_000_000_000),
+            gas_limit: 65_000,
+            to: H160::repeat_byte(0x09),
+            value: U256::zero(),
+            data: transfer_data(),
+            v: 37,
+            r: U256::from_big_endian(&SIGNATURE_R),
+            s: U256::from_big_endian(&SIGNATURE_S),
+        },
+        with_fastrlp::Transaction {
+            nonce: 42,
+            gas_price: BASE_FEE + 2_000_000_000,
+            gas_limit: 65_000,
+            to: [0x09; 20],
+            value: 0,
+            data: transfer_data().into(),
+            v: 37,
+            r: SIGNATURE_R,
+            s: SIGNATURE_S,
+        },
+    )
+}
+
+fn status_message() -> (with_rlp::StatusMessage, with_fastrlp::StatusMessage) {
+    (
+        with_rlp::StatusMessage {
+            protocol_version: 66,
+            network_id: 1,
+            total_difficulty: U256::from(MAINNET_TOTAL_DIFFICULTY),
+            best_hash: H256::repeat_byte(0x0a),
+            genesis_hash: H256::from(MAINNET_GENESIS),
+            fork_id: ForkId {
+                hash: ForkHash(FORK_HASH),
+                next: 0,
+            },
+        },
+        with_fastrlp::StatusMessage {
+            protocol_version: 66,
+            network_id: 1,
+            total_difficulty: MAINNET_TOTAL_DIFFICULTY,
+            best_hash: [0x0a; 32],
+            genesis_hash: MAINNET_GENESIS,
+            fork_id: with_fastrlp::ForkId {
+                hash: FORK_HASH,
+                next: 0,
+            },
+        },
+    )
+}
+
+fn get_block_headers() -> (with_rlp::GetBlockHeaders, with_fastrlp::GetBlockHeaders) {
+    (
+        with_rlp::GetBlockHeaders {
+            block: 13_000_000,
+            max_headers: 192,
+            skip: 0,
+            reverse: false,
+        },
+        with_fastrlp::GetBlockHeaders {
+            block: 13_000_000,
+            max_headers: 192,
+            skip: 0,
+            reverse: false,
+        },
+    )
+}
+
+fn compare<R, F>(c: &mut Criterion, name: &str, (rlp_value, fastrlp_value): (R, F))
+where
+    R: rlp::Encodable + rlp::Decodable,
+    F: fastrlp::Encodable + fastrlp::Decodable,
+{
+    let encoded = rlp::encode(&rlp_value).freeze();
+    let mut fastrlp_encoded = BytesMut::new();
+    fastrlp_value.encode(&mut fastrlp_encoded);
+    assert_eq!(
+        &encoded[..],
+        &fastrlp_encoded[..],
+        "{} is encoded differently",
+        name
+    );
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("rlp/encode", |b| {
+        b.iter(|| rlp::encode(black_box(&rlp_value)))
+    });
+    group.bench_function("fastrlp/encode", |b| {
+        b.iter(|| {
+            let mut out = BytesMut::with_capacity(encoded.len());
+            black_box(&fastrlp_value).encode(&mut out);
+            out
+        })
+    });
+    group.bench_function("rlp/decode", |b| {
+        b.iter(|| rlp::decode::<R>(black_box(&encoded)).unwrap())
+    });
+    group.bench_function("fastrlp/decode", |b| {
+        b.iter(|| F::decode(&mut black_box(&encoded[..])).unwrap())
+    });
+    group.finish();
+}
+
+fn rlp_comparison(c: &mut Criterion) {
+    compare(c, "BlockHeader", block_header());
+    compare(c, "Transaction", transaction());
+    compare(c, "StatusMessage", status_message());
+    compare(c, "GetBlockHeaders", get_block_headers());
+}
+
+criterion_group!(benches, rlp_comparison);
+criterion_main!(benches);
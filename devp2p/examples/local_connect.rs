@@ -4,7 +4,7 @@ use devp2p::*;
 use maplit::btreemap;
 use rand::{seq::SliceRandom, thread_rng};
 use secp256k1::SecretKey;
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::time::sleep;
 use tracing::*;
 use tracing_subscriber::EnvFilter;
@@ -15,7 +15,13 @@ struct DummyServer;
 #[async_trait]
 impl CapabilityServer for DummyServer {
     #[instrument(skip(self, peer), fields(peer=&*peer.to_string()))]
-    fn on_peer_connect(&self, peer: PeerId, _: HashMap<CapabilityName, CapabilityVersion>) {
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        _: Option<SocketAddr>,
+        _: ConnectionDirection,
+        _: HashMap<CapabilityName, CapabilityVersion>,
+    ) {
         info!("Peer connected")
     }
 
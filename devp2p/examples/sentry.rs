@@ -13,6 +13,7 @@ use rlp_derive::{RlpDecodable, RlpEncodable};
 use secp256k1::SecretKey;
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -104,7 +105,13 @@ impl CapabilityServerImpl {
 #[async_trait]
 impl CapabilityServer for CapabilityServerImpl {
     #[instrument(skip(self, peer), fields(peer=&*peer.to_string()))]
-    fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>) {
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        _: Option<SocketAddr>,
+        _: ConnectionDirection,
+        caps: HashMap<CapabilityName, CapabilityVersion>,
+    ) {
         info!("Settting up peer state");
         let status_message = StatusMessage {
             protocol_version: *caps.get(&eth()).unwrap(),
@@ -0,0 +1,222 @@
+//! Accepting inbound connections without giving up on errors that only affect a single
+//! connection or pass once resources are freed.
+
+use crate::transport::Listener;
+use parking_lot::Mutex;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::*;
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(50);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcceptErrorClass {
+    /// Out of file descriptors or memory, accepting may succeed once some are freed.
+    ResourceExhausted,
+    /// Connection has failed before it could be accepted, the listener is fine.
+    Aborted,
+    /// Listener is unusable.
+    Fatal,
+}
+
+impl AcceptErrorClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ResourceExhausted => "resource_exhausted",
+            Self::Aborted => "aborted",
+            Self::Fatal => "fatal",
+        }
+    }
+}
+
+pub fn classify(e: &io::Error) -> AcceptErrorClass {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => return AcceptErrorClass::Aborted,
+        _ => {}
+    }
+
+    #[cfg(unix)]
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+            return AcceptErrorClass::ResourceExhausted;
+        }
+        // Network errors of the pending connection, see accept(2).
+        Some(libc::EPROTO)
+        | Some(libc::ENOPROTOOPT)
+        | Some(libc::ENETDOWN)
+        | Some(libc::ENETUNREACH)
+        | Some(libc::EHOSTDOWN)
+        | Some(libc::EHOSTUNREACH)
+        | Some(libc::EOPNOTSUPP) => {
+            return AcceptErrorClass::Aborted;
+        }
+        _ => {}
+    }
+
+    AcceptErrorClass::Fatal
+}
+
+/// Soft limit of open file descriptors of the process.
+#[cfg(unix)]
+fn open_files_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid `rlimit` to write to.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn open_files_limit() -> Option<u64> {
+    None
+}
+
+/// Accept errors seen so far, by class.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AcceptErrorStats {
+    pub resource_exhausted: u64,
+    pub aborted: u64,
+    /// Error the listener has stopped on.
+    pub fatal: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AcceptErrors {
+    resource_exhausted: AtomicU64,
+    aborted: AtomicU64,
+    fatal: Mutex<Option<String>>,
+}
+
+impl AcceptErrors {
+    fn on_error(&self, e: &io::Error) -> AcceptErrorClass {
+        let class = classify(e);
+        match class {
+            AcceptErrorClass::ResourceExhausted => {
+                self.resource_exhausted.fetch_add(1, Ordering::Relaxed);
+            }
+            AcceptErrorClass::Aborted => {
+                self.aborted.fetch_add(1, Ordering::Relaxed);
+            }
+            AcceptErrorClass::Fatal => {
+                *self.fatal.lock() = Some(e.to_string());
+            }
+        }
+        class
+    }
+
+    pub fn stats(&self) -> AcceptErrorStats {
+        AcceptErrorStats {
+            resource_exhausted: self.resource_exhausted.load(Ordering::Relaxed),
+            aborted: self.aborted.load(Ordering::Relaxed),
+            fatal: self.fatal.lock().clone(),
+        }
+    }
+}
+
+/// Accept the next connection, riding out transient errors. Fails only if the listener
+/// is unusable.
+pub(crate) async fn accept<L: Listener>(
+    listener: &mut L,
+    errors: &AcceptErrors,
+) -> io::Result<(L::Transport, SocketAddr)> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let e = match listener.accept().await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+
+        match errors.on_error(&e) {
+            AcceptErrorClass::Aborted => {
+                debug!("Inbound connection failed before accept: {}", e);
+            }
+            AcceptErrorClass::ResourceExhausted => {
+                warn!(
+                    "Cannot accept inbound connections: {} (open files limit: {}), retrying in {:?}",
+                    e,
+                    open_files_limit().map_or_else(|| "unknown".to_string(), |v| v.to_string()),
+                    backoff
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+            AcceptErrorClass::Fatal => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::collections::VecDeque;
+    use tokio::net::TcpStream;
+
+    struct MockListener {
+        errors: VecDeque<io::Error>,
+    }
+
+    #[async_trait]
+    impl Listener for MockListener {
+        type Transport = TcpStream;
+
+        async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+            match self.errors.pop_front() {
+                Some(e) => Err(e),
+                None => futures::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_accept_errors_are_survived() {
+        let mut errors = VecDeque::new();
+        errors.push_back(io::Error::from(io::ErrorKind::ConnectionAborted));
+        #[cfg(unix)]
+        {
+            errors.push_back(io::Error::from_raw_os_error(libc::EMFILE));
+            errors.push_back(io::Error::from_raw_os_error(libc::EPROTO));
+        }
+        errors.push_back(io::Error::new(io::ErrorKind::Other, "listener closed"));
+        errors.push_back(io::Error::from(io::ErrorKind::ConnectionReset));
+        let mut listener = MockListener { errors };
+        let errors = AcceptErrors::default();
+
+        let e = accept(&mut listener, &errors).await.unwrap_err();
+        assert_eq!(e.to_string(), "listener closed");
+        // Stopped at the fatal error.
+        assert_eq!(listener.errors.len(), 1);
+        #[cfg(unix)]
+        assert_eq!(
+            errors.stats(),
+            AcceptErrorStats {
+                resource_exhausted: 1,
+                aborted: 2,
+                fatal: Some("listener closed".into()),
+            }
+        );
+
+        // Transient errors never end the loop.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), accept(&mut listener, &errors))
+                .await
+                .is_err()
+        );
+        assert!(listener.errors.is_empty());
+    }
+}
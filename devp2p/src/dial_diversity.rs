@@ -0,0 +1,179 @@
+//! Choice of the next peer to dial that spreads outbound connections across networks and
+//! discovery sources, so that the most prolific source does not pick all of our peers.
+
+use crate::types::{NodeRecord, PeerId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::IpAddr,
+};
+
+/// Discovered peers kept to choose from, the oldest one is dropped once full.
+pub const DIAL_CANDIDATES: usize = 32;
+
+/// Network of a peer address: /16 for IPv4, /32 for IPv6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NetworkPrefix {
+    V4([u8; 2]),
+    V6([u16; 2]),
+}
+
+impl NetworkPrefix {
+    pub fn new(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                Self::V4([octets[0], octets[1]])
+            }
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                Self::V6([segments[0], segments[1]])
+            }
+        }
+    }
+}
+
+impl fmt::Display for NetworkPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4([a, b]) => write!(f, "{}.{}.0.0/16", a, b),
+            Self::V6([a, b]) => write!(f, "{:x}:{:x}::/32", a, b),
+        }
+    }
+}
+
+/// Discovered peer waiting to be dialed.
+#[derive(Clone, Debug)]
+pub struct DialCandidate {
+    pub record: NodeRecord,
+    /// Name of the discovery that found the peer.
+    pub source: String,
+}
+
+/// Number of outbound connections by network and discovery source.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DialDistributionStats {
+    pub by_prefix: BTreeMap<NetworkPrefix, usize>,
+    pub by_source: BTreeMap<String, usize>,
+}
+
+/// Network and discovery source of peers we have dialed.
+#[derive(Debug, Default)]
+pub struct DialDistribution {
+    peers: HashMap<PeerId, (NetworkPrefix, String)>,
+}
+
+impl DialDistribution {
+    pub fn insert(&mut self, candidate: &DialCandidate) {
+        self.peers.insert(
+            candidate.record.id,
+            (
+                NetworkPrefix::new(candidate.record.addr.ip()),
+                candidate.source.clone(),
+            ),
+        );
+    }
+
+    /// Forget peers that are no longer connected or being dialed.
+    pub fn retain(&mut self, mut f: impl FnMut(PeerId) -> bool) {
+        self.peers.retain(|&peer, _| f(peer));
+    }
+
+    pub fn stats(&self) -> DialDistributionStats {
+        let mut stats = DialDistributionStats::default();
+        for (prefix, source) in self.peers.values() {
+            *stats.by_prefix.entry(*prefix).or_default() += 1;
+            *stats.by_source.entry(source.clone()).or_default() += 1;
+        }
+        stats
+    }
+}
+
+/// Higher for candidates in networks and from sources we have fewer connections to.
+pub fn diversity_score(candidate: &DialCandidate, distribution: &DialDistributionStats) -> f64 {
+    let prefix = distribution
+        .by_prefix
+        .get(&NetworkPrefix::new(candidate.record.addr.ip()))
+        .copied()
+        .unwrap_or(0);
+    let source = distribution
+        .by_source
+        .get(&candidate.source)
+        .copied()
+        .unwrap_or(0);
+    1.0 / (1 + prefix) as f64 + 1.0 / (1 + source) as f64
+}
+
+/// Index of the candidate to dial next, the oldest one among the best scored.
+pub fn select_candidate<'a>(
+    candidates: impl IntoIterator<Item = &'a DialCandidate>,
+    distribution: &DialDistributionStats,
+) -> Option<usize> {
+    let mut best = None;
+    for (i, candidate) in candidates.into_iter().enumerate() {
+        let score = diversity_score(candidate, distribution);
+        if best.map_or(true, |(_, best_score)| score > best_score) {
+            best = Some((i, score));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: u64, addr: &str, source: &str) -> DialCandidate {
+        DialCandidate {
+            record: NodeRecord {
+                id: PeerId::from_low_u64_be(id),
+                addr: addr.parse().unwrap(),
+            },
+            source: source.to_string(),
+        }
+    }
+
+    #[test]
+    fn prefers_unseen_networks_and_sources() {
+        let mut distribution = DialDistribution::default();
+        for c in &[
+            candidate(1, "10.0.0.1:30303", "discv4"),
+            candidate(2, "10.0.1.1:30303", "discv4"),
+            candidate(3, "[2001:db8::1]:30303", "dnsdisc"),
+        ] {
+            distribution.insert(c);
+        }
+        let stats = distribution.stats();
+        assert_eq!(
+            stats.by_prefix[&NetworkPrefix::new([10, 0, 9, 9].into())],
+            2
+        );
+        assert_eq!(stats.by_source["discv4"], 2);
+        assert_eq!(
+            NetworkPrefix::new("2001:db8:1::1".parse().unwrap()).to_string(),
+            "2001:db8::/32"
+        );
+
+        // FIFO order would dial the crowded provider again.
+        let pool = vec![
+            candidate(4, "10.0.200.1:30303", "discv4"),
+            candidate(5, "[2001:db8:ff::1]:30303", "discv4"),
+            candidate(6, "192.168.0.1:30303", "discv4"),
+            candidate(7, "192.168.0.2:30303", "bootnodes"),
+        ];
+        assert_eq!(select_candidate(&pool, &stats), Some(3));
+        assert_eq!(select_candidate(&pool[..3], &stats), Some(2));
+        assert_eq!(select_candidate(&pool[..2], &stats), Some(1));
+        assert_eq!(select_candidate(&pool[..0], &stats), None);
+
+        // Ties are broken in discovery order.
+        assert_eq!(
+            select_candidate(&pool[2..3], &DialDistributionStats::default()),
+            Some(0)
+        );
+        assert_eq!(select_candidate(&pool, &Default::default()), Some(0));
+
+        distribution.retain(|peer| peer != PeerId::from_low_u64_be(1));
+        assert_eq!(distribution.stats().by_source["discv4"], 1);
+    }
+}
@@ -1,12 +1,13 @@
 use super::algorithm::ECIES;
-use crate::{errors::ECIESError, transport::Transport, types::PeerId};
+use crate::{errors::ECIESError, transport::Transport, types::PeerId, util::pk2id};
 use anyhow::{bail, Context as _};
 use bytes::{Bytes, BytesMut};
 use futures::{ready, Sink, SinkExt};
-use secp256k1::SecretKey;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
     fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -165,6 +166,7 @@ impl Encoder<EgressECIESValue> for ECIESCodec {
 #[derive(Debug)]
 pub struct ECIESStream<Io> {
     stream: Framed<Io, ECIESCodec>,
+    local_id: PeerId,
     remote_id: PeerId,
 }
 
@@ -179,6 +181,7 @@ where
         secret_key: SecretKey,
         remote_id: PeerId,
     ) -> anyhow::Result<Self> {
+        let local_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
         let ecies = ECIESCodec::new_client(secret_key, remote_id)
             .map_err(|_| io::Error::new(io::ErrorKind::Other, "invalid handshake"))?;
 
@@ -194,6 +197,7 @@ where
         if matches!(ack, Some(IngressECIESValue::Ack)) {
             Ok(Self {
                 stream: transport,
+                local_id,
                 remote_id,
             })
         } else {
@@ -204,6 +208,7 @@ where
     /// Listen on a just connected ECIES client
     #[instrument(skip(transport, secret_key), fields(peer=&*format!("{:?}", transport.remote_addr())))]
     pub async fn incoming(transport: Io, secret_key: SecretKey) -> anyhow::Result<Self> {
+        let local_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
         let ecies = ECIESCodec::new_server(secret_key).context("handshake error")?;
 
         debug!("incoming ecies stream ...");
@@ -227,14 +232,25 @@ where
 
         Ok(Self {
             stream: transport,
+            local_id,
             remote_id,
         })
     }
 
+    /// Get the id of the key this end has authenticated with
+    pub fn local_id(&self) -> PeerId {
+        self.local_id
+    }
+
     /// Get the remote id
     pub fn remote_id(&self) -> PeerId {
         self.remote_id
     }
+
+    /// Get the address of the underlying transport's remote end
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.stream.get_ref().remote_addr()
+    }
 }
 
 impl<Io> Stream for ECIESStream<Io>
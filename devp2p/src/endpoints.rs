@@ -0,0 +1,120 @@
+//! Where a node accepts connections. Inbound peers connect from an ephemeral port, which
+//! must never be dialed back.
+
+use crate::types::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+/// Nodes remembered by each source.
+const ENDPOINT_CAPACITY: usize = 4096;
+
+/// Bounded map that forgets the least recently inserted keys.
+#[derive(Debug)]
+struct RecentMap<V> {
+    values: HashMap<PeerId, (u64, V)>,
+    order: VecDeque<(u64, PeerId)>,
+    next_seq: u64,
+}
+
+impl<V> Default for RecentMap<V> {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+            order: Default::default(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<V> RecentMap<V> {
+    fn insert(&mut self, peer: PeerId, value: V) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.values.insert(peer, (seq, value));
+        self.order.push_back((seq, peer));
+
+        while self.values.len() > ENDPOINT_CAPACITY {
+            if let Some((seq, peer)) = self.order.pop_front() {
+                // Entry may have been replaced since.
+                if self.values.get(&peer).map_or(false, |&(s, _)| s == seq) {
+                    self.values.remove(&peer);
+                }
+            }
+        }
+        // Drop stale order entries so that the queue stays bounded too.
+        if self.order.len() > 2 * ENDPOINT_CAPACITY {
+            let values = &self.values;
+            self.order
+                .retain(|(seq, peer)| values.get(peer).map_or(false, |&(s, _)| s == *seq));
+        }
+    }
+
+    fn get(&self, peer: &PeerId) -> Option<&V> {
+        self.values.get(peer).map(|(_, v)| v)
+    }
+}
+
+/// Reconciles endpoints of a node learned from discovery and from its Hello.
+#[derive(Debug, Default)]
+pub struct EndpointBook {
+    discovered: RecentMap<SocketAddr>,
+    /// Source IP with the port announced in Hello, `None` if it announced port 0.
+    advertised: RecentMap<Option<SocketAddr>>,
+}
+
+impl EndpointBook {
+    /// Endpoint from discovery, or one we have successfully dialed.
+    pub fn on_discovered(&mut self, peer: PeerId, addr: SocketAddr) {
+        self.discovered.insert(peer, addr);
+    }
+
+    /// Inbound peer has connected, `addr` is its source IP with the port it announced.
+    pub fn on_inbound(&mut self, peer: PeerId, addr: Option<SocketAddr>) {
+        self.advertised.insert(peer, addr);
+    }
+
+    /// Where to dial the node: endpoint from discovery if known, otherwise source IP with
+    /// the announced port. `None` if it announced no port, as behind NAT.
+    pub fn resolve(&self, peer: PeerId) -> Option<SocketAddr> {
+        if let Some(&addr) = self.discovered.get(&peer) {
+            return Some(addr);
+        }
+        self.advertised.get(&peer).copied().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_endpoint() {
+        let mut book = EndpointBook::default();
+        let peer = PeerId::from_low_u64_be;
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        // Source IP with the announced port.
+        book.on_inbound(peer(1), Some(addr("10.0.0.1:30303")));
+        assert_eq!(book.resolve(peer(1)), Some(addr("10.0.0.1:30303")));
+
+        // Discovery knows better, e.g. when the node is behind port forwarding.
+        book.on_discovered(peer(1), addr("1.2.3.4:30305"));
+        assert_eq!(book.resolve(peer(1)), Some(addr("1.2.3.4:30305")));
+        book.on_inbound(peer(1), Some(addr("10.0.0.1:30303")));
+        assert_eq!(book.resolve(peer(1)), Some(addr("1.2.3.4:30305")));
+
+        // Announced port 0 is not dialable.
+        book.on_inbound(peer(2), None);
+        assert_eq!(book.resolve(peer(2)), None);
+        assert_eq!(book.resolve(peer(3)), None);
+
+        // Oldest nodes are forgotten.
+        for i in 10..10 + ENDPOINT_CAPACITY as u64 {
+            book.on_discovered(peer(i), addr("1.1.1.1:30303"));
+        }
+        assert_eq!(book.resolve(peer(1)), Some(addr("10.0.0.1:30303")));
+        assert_eq!(book.discovered.values.len(), ENDPOINT_CAPACITY);
+    }
+}
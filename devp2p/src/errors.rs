@@ -1,3 +1,8 @@
+use crate::{
+    peer::{MAX_CLIENT_VERSION_LEN, MAX_HELLO_CAPABILITIES},
+    types::PeerId,
+};
+use rlp::DecoderError;
 use std::io;
 use thiserror::Error;
 
@@ -15,6 +20,28 @@ pub enum ECIESError {
     Other(#[from] anyhow::Error),
 }
 
+/// Hello that is rejected.
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error(
+        "hello lists {0} capabilities, at most {} are accepted",
+        MAX_HELLO_CAPABILITIES
+    )]
+    TooManyCapabilities(usize),
+    #[error(
+        "hello client version is {0} bytes, at most {} are accepted",
+        MAX_CLIENT_VERSION_LEN
+    )]
+    ClientVersionTooLong(usize),
+    #[error("invalid hello: {0}")]
+    Rlp(#[from] DecoderError),
+    #[error("hello from {hello:02x} while the handshake has authenticated {authenticated:02x}")]
+    IdentityMismatch {
+        authenticated: PeerId,
+        hello: PeerId,
+    },
+}
+
 impl From<ECIESError> for io::Error {
     fn from(error: ECIESError) -> Self {
         Self::new(io::ErrorKind::Other, format!("ECIES error: {:?}", error))
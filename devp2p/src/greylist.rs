@@ -0,0 +1,213 @@
+//! Greylisting of addresses whose inbound handshakes keep failing, so that scanners
+//! sending garbage do not cost us key agreement on every connection.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// When addresses are greylisted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GreylistPolicy {
+    /// Failed handshakes within `window` after which the address is greylisted,
+    /// 0 disables greylisting.
+    pub max_failures: usize,
+    pub window: Duration,
+    /// How long connections from a greylisted address are dropped.
+    pub period: Duration,
+    /// Number of addresses tracked, the least recently seen are forgotten.
+    pub capacity: usize,
+}
+
+impl Default for GreylistPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            window: Duration::from_secs(60),
+            period: Duration::from_secs(5 * 60),
+            capacity: 4096,
+        }
+    }
+}
+
+/// Greylisted address with the time it has left and the connections dropped so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GreylistedAddr {
+    pub ip: IpAddr,
+    pub remaining: Duration,
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+struct AddrState {
+    failures: VecDeque<Instant>,
+    greylisted_until: Option<Instant>,
+    hits: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+pub struct Greylist {
+    policy: GreylistPolicy,
+    exempt: HashSet<IpAddr>,
+    addrs: HashMap<IpAddr, AddrState>,
+}
+
+impl Greylist {
+    pub fn new(policy: GreylistPolicy) -> Self {
+        Self {
+            policy,
+            exempt: Default::default(),
+            addrs: Default::default(),
+        }
+    }
+
+    /// Never greylist the address, e.g. of a trusted peer.
+    pub fn exempt(&mut self, ip: IpAddr) {
+        self.exempt.insert(ip);
+        self.addrs.remove(&ip);
+    }
+
+    /// Whether a new connection from the address should be dropped, counted as a hit if so.
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let state = match self.addrs.get_mut(&ip) {
+            Some(v) => v,
+            None => return false,
+        };
+        match state.greylisted_until {
+            Some(until) if until > now => {
+                state.hits += 1;
+                state.last_seen = now;
+                true
+            }
+            Some(_) => {
+                self.addrs.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record failed handshake. Returns whether the address has been greylisted by it.
+    pub fn on_failure(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.policy.max_failures == 0 || self.exempt.contains(&ip) {
+            return false;
+        }
+
+        if !self.addrs.contains_key(&ip) && self.addrs.len() >= self.policy.capacity.max(1) {
+            if let Some(oldest) = self
+                .addrs
+                .iter()
+                .min_by_key(|(_, state)| state.last_seen)
+                .map(|(&ip, _)| ip)
+            {
+                self.addrs.remove(&oldest);
+            }
+        }
+
+        let policy = self.policy;
+        let state = self.addrs.entry(ip).or_insert_with(|| AddrState {
+            failures: VecDeque::with_capacity(policy.max_failures),
+            greylisted_until: None,
+            hits: 0,
+            last_seen: now,
+        });
+        state.last_seen = now;
+        if state.greylisted_until.map_or(false, |until| until > now) {
+            return false;
+        }
+
+        while let Some(&first) = state.failures.front() {
+            if now.saturating_duration_since(first) < policy.window {
+                break;
+            }
+            state.failures.pop_front();
+        }
+        state.failures.push_back(now);
+        if state.failures.len() < policy.max_failures {
+            return false;
+        }
+
+        state.failures.clear();
+        state.greylisted_until = Some(now + policy.period);
+        state.hits = 0;
+        true
+    }
+
+    /// Successful handshake clears failures of the address.
+    pub fn on_success(&mut self, ip: IpAddr) {
+        self.addrs.remove(&ip);
+    }
+
+    pub fn greylisted(&self, now: Instant) -> Vec<GreylistedAddr> {
+        self.addrs
+            .iter()
+            .filter_map(|(&ip, state)| {
+                let until = state.greylisted_until.filter(|&until| until > now)?;
+                Some(GreylistedAddr {
+                    ip,
+                    remaining: until - now,
+                    hits: state.hits,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn greylist_after_failures() {
+        let mut greylist = Greylist::new(GreylistPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            period: Duration::from_secs(300),
+            capacity: 2,
+        });
+        let scanner = IpAddr::from([203, 0, 113, 1]);
+        let trusted = IpAddr::from([203, 0, 113, 2]);
+        let now = Instant::now();
+        let secs = |secs| now + Duration::from_secs(secs);
+
+        greylist.exempt(trusted);
+        for i in 0..5 {
+            assert!(!greylist.on_failure(trusted, secs(i)));
+        }
+        assert!(!greylist.check(trusted, secs(5)));
+
+        // First failure falls out of the window.
+        assert!(!greylist.on_failure(scanner, secs(0)));
+        assert!(!greylist.on_failure(scanner, secs(50)));
+        assert!(!greylist.on_failure(scanner, secs(70)));
+        assert!(greylist.on_failure(scanner, secs(80)));
+        assert!(greylist.check(scanner, secs(81)));
+        assert!(greylist.check(scanner, secs(82)));
+        assert_eq!(
+            greylist.greylisted(secs(100)),
+            vec![GreylistedAddr {
+                ip: scanner,
+                remaining: Duration::from_secs(280),
+                hits: 2,
+            }]
+        );
+        assert!(!greylist.check(scanner, secs(380)));
+        assert!(greylist.greylisted(secs(380)).is_empty());
+
+        // Success clears the failures.
+        assert!(!greylist.on_failure(scanner, secs(400)));
+        assert!(!greylist.on_failure(scanner, secs(401)));
+        greylist.on_success(scanner);
+        assert!(!greylist.on_failure(scanner, secs(402)));
+
+        // Least recently seen address is forgotten.
+        for i in 3..5 {
+            greylist.on_failure(IpAddr::from([203, 0, 113, i]), secs(403));
+        }
+        assert!(!greylist.on_failure(scanner, secs(404)));
+        assert!(!greylist.on_failure(scanner, secs(405)));
+        assert!(greylist.on_failure(scanner, secs(406)));
+    }
+}
@@ -0,0 +1,176 @@
+//! Execution of RLPx handshakes away from the message processing workers.
+
+use crate::errors::HandshakeError;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::{Builder, Handle},
+    sync::oneshot::{channel as oneshot, Sender as OneshotSender},
+    task::JoinHandle,
+};
+
+const DURATION_SAMPLES: usize = 1024;
+
+/// Handshake statistics at a point in time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HandshakeStats {
+    /// Number of handshakes that are queued or in progress.
+    pub queue_depth: usize,
+    /// Number of samples the percentiles are calculated over.
+    pub samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// Handshakes failed because Hello carried another id than ECIES has authenticated.
+    pub identity_mismatches: u64,
+}
+
+#[derive(Debug, Default)]
+struct HandshakeMetrics {
+    queue_depth: AtomicUsize,
+    durations: Mutex<VecDeque<Duration>>,
+    identity_mismatches: AtomicU64,
+}
+
+impl HandshakeMetrics {
+    fn record(&self, duration: Duration) {
+        let mut durations = self.durations.lock();
+        if durations.len() >= DURATION_SAMPLES {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    fn snapshot(&self) -> HandshakeStats {
+        let mut durations = self.durations.lock().iter().copied().collect::<Vec<_>>();
+        durations.sort_unstable();
+
+        let percentile = |p: usize| {
+            if durations.is_empty() {
+                Duration::default()
+            } else {
+                durations[(durations.len() - 1) * p / 100]
+            }
+        };
+
+        HandshakeStats {
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            samples: durations.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            identity_mismatches: self.identity_mismatches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl<'a> QueueGuard<'a> {
+    fn new(queue_depth: &'a AtomicUsize) -> Self {
+        queue_depth.fetch_add(1, Ordering::Relaxed);
+        Self(queue_depth)
+    }
+}
+
+impl<'a> Drop for QueueGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs handshakes either inline or on a dedicated runtime.
+///
+/// The dedicated runtime is owned by a separate OS thread so that it can be shut down
+/// without blocking inside of the async context that drops the executor.
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeExecutor {
+    handle: Option<Handle>,
+    _shutdown: Option<OneshotSender<()>>,
+    metrics: HandshakeMetrics,
+}
+
+impl HandshakeExecutor {
+    /// Create executor with dedicated runtime of `threads` workers. Zero threads means inline execution.
+    pub(crate) fn new(threads: usize) -> io::Result<Self> {
+        if threads == 0 {
+            return Ok(Self::default());
+        }
+
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("devp2p-handshake")
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+
+        let (shutdown_tx, shutdown_rx) = oneshot::<()>();
+        std::thread::Builder::new()
+            .name("devp2p-handshake-runtime".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let _ = shutdown_rx.await;
+                });
+            })?;
+
+        Ok(Self {
+            handle: Some(handle),
+            _shutdown: Some(shutdown_tx),
+            metrics: Default::default(),
+        })
+    }
+
+    pub(crate) async fn run<F, T>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _guard = QueueGuard::new(&self.metrics.queue_depth);
+        let started = Instant::now();
+
+        let res = if let Some(handle) = &self.handle {
+            let mut task = AbortOnDrop(handle.spawn(f));
+            (&mut task.0)
+                .await
+                .map_err(|e| anyhow!("handshake task failed: {}", e))
+                .and_then(|res| res)
+        } else {
+            f.await
+        };
+
+        match &res {
+            Ok(_) => self.metrics.record(started.elapsed()),
+            Err(e) => {
+                if let Some(HandshakeError::IdentityMismatch { .. }) = e.downcast_ref() {
+                    self.metrics
+                        .identity_mismatches
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        res
+    }
+
+    pub(crate) fn stats(&self) -> HandshakeStats {
+        self.metrics.snapshot()
+    }
+}
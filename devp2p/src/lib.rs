@@ -8,21 +8,43 @@
 
 #![allow(clippy::large_enum_variant, clippy::upper_case_acronyms)]
 
+mod accept;
+mod dial_diversity;
 mod disc;
 pub mod ecies;
+mod endpoints;
 mod errors;
+mod greylist;
+mod handshake;
+mod log_limiter;
 mod mac;
 mod node_filter;
 mod peer;
+#[cfg(feature = "quic")]
+pub mod quic;
+mod redial;
 mod rlpx;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod transport;
 mod types;
 pub mod util;
 
+pub use accept::{AcceptErrorClass, AcceptErrorStats};
+pub use dial_diversity::{DialDistributionStats, NetworkPrefix};
 pub use disc::*;
-pub use peer::{DisconnectReason, PeerStream};
-pub use rlpx::{ListenOptions, Swarm, SwarmBuilder};
+pub use errors::HandshakeError;
+pub use greylist::{GreylistPolicy, GreylistedAddr};
+pub use handshake::HandshakeStats;
+pub use log_limiter::{LogLimiter, Suppressed};
+pub use peer::{
+    DisconnectReason, PeerStream, ProtocolVersion, MAX_CLIENT_VERSION_LEN, MAX_HELLO_CAPABILITIES,
+};
+pub use redial::{
+    RedialExemptions, RedialPolicy, RedialReasonStats, RedialRule, RedialStats, REDIAL_BASE_DELAY,
+};
+pub use rlpx::{CapabilityRegistry, ListenOptions, Swarm, SwarmBuilder, DIAL_INTERVAL};
 pub use types::{
     CapabilityId, CapabilityInfo, CapabilityName, CapabilityServer, CapabilityVersion,
-    InboundEvent, Message, NodeRecord, OutboundEvent, PeerId,
+    ConnectionDirection, InboundEvent, Message, NodeRecord, OutboundEvent, PeerId,
 };
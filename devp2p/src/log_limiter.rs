@@ -0,0 +1,174 @@
+//! Keyed rate limiter for repetitive log messages.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// Number of identical events suppressed since the last emission.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Suppressed(pub usize);
+
+impl Display for Suppressed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0 > 0 {
+            write!(f, " ({} similar suppressed)", self.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct LimiterEntry {
+    last_emitted: Instant,
+    suppressed: usize,
+}
+
+/// Allows at most one log emission per key per interval.
+/// Suppressed count is reported with the next allowed emission.
+#[derive(Debug)]
+pub struct LogLimiter<K> {
+    interval: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<K, LimiterEntry>>,
+}
+
+impl<K: Clone + Hash + Eq> LogLimiter<K> {
+    /// Create limiter tracking at most `capacity` keys.
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity: capacity.max(1),
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns `Some` if event should be logged now.
+    pub fn check(&self, key: K) -> Option<Suppressed> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn check_at(&self, key: K, now: Instant) -> Option<Suppressed> {
+        let mut entries = self.entries.lock();
+
+        if let Some(entry) = entries.get_mut(&key) {
+            if now.saturating_duration_since(entry.last_emitted) < self.interval {
+                entry.suppressed += 1;
+                return None;
+            }
+
+            entry.last_emitted = now;
+            return Some(Suppressed(std::mem::take(&mut entry.suppressed)));
+        }
+
+        if entries.len() >= self.capacity {
+            let interval = self.interval;
+            entries.retain(|_, entry| now.saturating_duration_since(entry.last_emitted) < interval);
+        }
+
+        if entries.len() >= self.capacity {
+            // Still full: evict the oldest key, its suppressed count is lost.
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_emitted)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key,
+            LimiterEntry {
+                last_emitted: now,
+                suppressed: 0,
+            },
+        );
+
+        Some(Suppressed(0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppression_and_summary() {
+        let limiter = LogLimiter::new(Duration::from_secs(10), 16);
+        let start = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(("peer1", "status"), start),
+            Some(Suppressed(0))
+        );
+        for i in 1..=5 {
+            assert_eq!(
+                limiter.check_at(("peer1", "status"), start + Duration::from_secs(i)),
+                None
+            );
+        }
+
+        // Different error class and different peer are independent.
+        assert_eq!(
+            limiter.check_at(("peer1", "handshake"), start),
+            Some(Suppressed(0))
+        );
+        assert_eq!(
+            limiter.check_at(("peer2", "status"), start),
+            Some(Suppressed(0))
+        );
+
+        // Next emission after interval carries the summary.
+        let next = start + Duration::from_secs(10);
+        assert_eq!(
+            limiter.check_at(("peer1", "status"), next),
+            Some(Suppressed(5))
+        );
+        assert_eq!(
+            limiter.check_at(("peer1", "status"), next + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            limiter.check_at(("peer1", "status"), next + Duration::from_secs(10)),
+            Some(Suppressed(1))
+        );
+    }
+
+    #[test]
+    fn bounded() {
+        let limiter = LogLimiter::new(Duration::from_secs(10), 4);
+        let start = Instant::now();
+
+        for i in 0..100 {
+            assert!(limiter
+                .check_at(i, start + Duration::from_millis(i))
+                .is_some());
+            assert!(limiter.len() <= 4);
+        }
+
+        // Most recent keys are still limited.
+        assert_eq!(
+            limiter.check_at(99, start + Duration::from_millis(100)),
+            None
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Suppressed(0).to_string(), "");
+        assert_eq!(Suppressed(3).to_string(), " (3 similar suppressed)");
+    }
+}
@@ -1,5 +1,7 @@
-use crate::{ecies::ECIESStream, transport::Transport, types::*, util::pk2id};
-use anyhow::{anyhow, bail, Context as _};
+use crate::{
+    ecies::ECIESStream, errors::HandshakeError, transport::Transport, types::*, util::pk2id,
+};
+use anyhow::{anyhow, bail, ensure, Context as _};
 use bytes::{Bytes, BytesMut};
 use derive_more::Display;
 use enum_primitive_derive::Primitive;
@@ -8,8 +10,10 @@ use num_traits::*;
 use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
+    collections::{btree_map::Entry, BTreeMap, HashSet},
     fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -17,9 +21,13 @@ use tokio_stream::{Stream, StreamExt};
 use tracing::*;
 
 const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+/// Hello listing more capabilities than this is rejected before they are decoded.
+pub const MAX_HELLO_CAPABILITIES: usize = 64;
+/// Hello with a longer client version is rejected before it is decoded.
+pub const MAX_CLIENT_VERSION_LEN: usize = 256;
 
 /// RLPx disconnect reason.
-#[derive(Clone, Copy, Debug, Display, Primitive)]
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, Primitive)]
 pub enum DisconnectReason {
     #[display(fmt = "disconnect requested")]
     DisconnectRequested = 0x00,
@@ -50,11 +58,19 @@ pub enum DisconnectReason {
 }
 
 /// RLPx protocol version.
-#[derive(Copy, Clone, Debug, Primitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Primitive)]
 pub enum ProtocolVersion {
+    V4 = 4,
+    /// Adds Snappy compression of message payloads.
     V5 = 5,
 }
 
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::V5
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CapabilityMessage {
     pub name: CapabilityName,
@@ -110,6 +126,51 @@ impl Decodable for HelloMessage {
     }
 }
 
+impl HelloMessage {
+    /// Decode Hello of a remote peer, checking sizes before anything is allocated.
+    pub fn decode_bounded(rlp: &Rlp) -> Result<Self, HandshakeError> {
+        let capabilities = rlp.at(2)?.item_count()?;
+        if capabilities > MAX_HELLO_CAPABILITIES {
+            return Err(HandshakeError::TooManyCapabilities(capabilities));
+        }
+        let client_version = rlp.at(1)?.data()?.len();
+        if client_version > MAX_CLIENT_VERSION_LEN {
+            return Err(HandshakeError::ClientVersionTooLong(client_version));
+        }
+
+        Ok(Self::decode(rlp)?)
+    }
+}
+
+/// Capabilities both sides support, the highest shared version of each, sorted by name.
+fn shared_capabilities(
+    local: Vec<CapabilityInfo>,
+    remote: &[CapabilityMessage],
+) -> Vec<CapabilityInfo> {
+    let remote = remote
+        .iter()
+        .map(|cap| (cap.name, cap.version))
+        .collect::<HashSet<_>>();
+
+    let mut shared = BTreeMap::new();
+    for cap in local {
+        if !remote.contains(&(cap.name, cap.version)) {
+            continue;
+        }
+        match shared.entry(cap.name) {
+            Entry::Vacant(entry) => {
+                entry.insert(cap);
+            }
+            Entry::Occupied(mut entry) => {
+                if entry.get().version < cap.version {
+                    entry.insert(cap);
+                }
+            }
+        }
+    }
+    shared.into_iter().map(|(_, cap)| cap).collect()
+}
+
 #[derive(Debug)]
 struct Snappy {
     encoder: snap::raw::Encoder,
@@ -135,8 +196,14 @@ pub struct PeerStream<Io> {
     port: u16,
     id: PeerId,
     remote_id: PeerId,
+    remote_addr: Option<SocketAddr>,
+    /// Port the remote has announced in Hello
+    remote_port: u16,
+    /// Client version the remote has announced in Hello
+    remote_client_version: String,
 
-    snappy: Snappy,
+    /// Set if both sides support compression.
+    snappy: Option<Snappy>,
 
     disconnected: bool,
 }
@@ -155,6 +222,23 @@ where
         &self.shared_capabilities
     }
 
+    /// Client version the remote has announced in Hello
+    pub fn remote_client_version(&self) -> &str {
+        &self.remote_client_version
+    }
+
+    /// Address the remote accepts connections on, if known: remote IP with the
+    /// listening port announced in Hello. `None` if it has announced port 0, as the
+    /// source port of an inbound connection is not one to dial.
+    pub fn remote_listen_addr(&self) -> Option<SocketAddr> {
+        if self.remote_port == 0 {
+            return None;
+        }
+        let mut addr = self.remote_addr?;
+        addr.set_port(self.remote_port);
+        Some(addr)
+    }
+
     /// Connect to a peer over TCP
     #[instrument(
         skip(transport, secret_key, client_version, capabilities, port, remote_id),
@@ -164,6 +248,7 @@ where
         transport: Io,
         secret_key: SecretKey,
         remote_id: PeerId,
+        protocol_version: ProtocolVersion,
         client_version: String,
         capabilities: Vec<CapabilityInfo>,
         port: u16,
@@ -171,6 +256,7 @@ where
         Ok(Self::new(
             ECIESStream::connect(transport, secret_key, remote_id).await?,
             secret_key,
+            protocol_version,
             client_version,
             capabilities,
             port,
@@ -186,6 +272,7 @@ where
     pub async fn incoming(
         transport: Io,
         secret_key: SecretKey,
+        protocol_version: ProtocolVersion,
         client_version: String,
         capabilities: Vec<CapabilityInfo>,
         port: u16,
@@ -193,6 +280,7 @@ where
         Ok(Self::new(
             ECIESStream::incoming(transport, secret_key).await?,
             secret_key,
+            protocol_version,
             client_version,
             capabilities,
             port,
@@ -200,17 +288,26 @@ where
         .await?)
     }
 
-    /// Create a new peer stream
+    /// Create a new peer stream. Frames the remote sends right after its Hello stay
+    /// buffered in the stream and are the first items it yields.
+    ///
+    /// Fails if the remote Hello carries another id than the ECIES handshake has
+    /// authenticated, or if `secret_key` is not the key of the ECIES session.
     #[instrument(skip(transport, secret_key, client_version, capabilities, port), fields(id=&*transport.remote_id().to_string()))]
     pub async fn new(
         mut transport: ECIESStream<Io>,
         secret_key: SecretKey,
+        protocol_version: ProtocolVersion,
         client_version: String,
         capabilities: Vec<CapabilityInfo>,
         port: u16,
     ) -> anyhow::Result<Self> {
         let public_key = PublicKey::from_secret_key(SECP256K1, &secret_key);
-        let id = pk2id(&public_key);
+        let id = transport.local_id();
+        ensure!(
+            pk2id(&public_key) == id,
+            "hello key is not the key of the ECIES session"
+        );
         let nonhello_capabilities = capabilities.clone();
         let nonhello_client_version = client_version.clone();
 
@@ -219,7 +316,7 @@ where
         let hello = HelloMessage {
             port,
             id,
-            protocol_version: ProtocolVersion::V5.to_usize().unwrap(),
+            protocol_version: protocol_version.to_usize().unwrap(),
             client_version,
             capabilities: {
                 let mut caps = Vec::new();
@@ -284,45 +381,70 @@ where
             }
         }
 
-        let val = Rlp::new(payload)
-            .as_val::<HelloMessage>()
-            .context("hello failed (rlp)")?;
+        let val = HelloMessage::decode_bounded(&Rlp::new(payload)).context("hello failed")?;
         debug!("hello message: {:?}", val);
-        let mut shared_capabilities: Vec<CapabilityInfo> = Vec::new();
-
-        for cap_info in nonhello_capabilities {
-            let cap_match = val
-                .capabilities
-                .iter()
-                .any(|v| v.name == cap_info.name && v.version == cap_info.version);
-
-            if cap_match {
-                shared_capabilities.push(cap_info);
-            }
-        }
-
-        let shared_caps_original = shared_capabilities.clone();
-
-        for cap_info in shared_caps_original {
-            shared_capabilities
-                .retain(|v| v.name != cap_info.name || v.version >= cap_info.version);
-        }
-
-        shared_capabilities.sort_by_key(|v| v.name);
+        let remote_protocol_version = ProtocolVersion::from_usize(val.protocol_version);
+        // Snappy is only used if both sides speak v5 or later.
+        let compression = remote_protocol_version.map_or(false, |version| {
+            version.min(protocol_version) >= ProtocolVersion::V5
+        });
+        let shared_capabilities = shared_capabilities(nonhello_capabilities, &val.capabilities);
 
         let no_shared_caps = shared_capabilities.is_empty();
 
         let mut this = Self {
             remote_id: transport.remote_id(),
+            remote_addr: transport.remote_addr(),
+            remote_port: val.port,
+            remote_client_version: val.client_version,
             stream: transport,
             client_version: nonhello_client_version,
             port,
             id,
             shared_capabilities,
-            snappy: Snappy::default(),
+            snappy: if compression {
+                Some(Snappy::default())
+            } else {
+                None
+            },
             disconnected: false,
         };
 
+        if val.id != this.remote_id {
+            debug!(
+                "Hello id {:02x} differs from authenticated id, disconnecting.",
+                val.id
+            );
+            let _ = this
+                .send(PeerMessage::Disconnect(
+                    DisconnectReason::UnexpectedHandshakeIdentity,
+                ))
+                .await;
+
+            return Err(HandshakeError::IdentityMismatch {
+                authenticated: this.remote_id,
+                hello: val.id,
+            }
+            .into());
+        }
+
+        if remote_protocol_version.is_none() {
+            debug!(
+                "Unsupported p2p protocol version {}, disconnecting.",
+                val.protocol_version
+            );
+            let _ = this
+                .send(PeerMessage::Disconnect(
+                    DisconnectReason::IncompatibleP2PProtocolVersion,
+                ))
+                .await;
+
+            bail!(
+                "handshake failed - unsupported p2p protocol version {}",
+                val.protocol_version
+            );
+        }
+
         if no_shared_caps {
             debug!("No shared capabilities, disconnecting.");
             let _ = this
@@ -373,7 +495,10 @@ where
                 let (cap, id, data) = match message_id {
                     Ok(message_id) => {
                         let input = &val[1..];
-                        let payload_len = snap::raw::decompress_len(input)?;
+                        let payload_len = match &s.snappy {
+                            Some(_) => snap::raw::decompress_len(input)?,
+                            None => input.len(),
+                        };
                         if payload_len > MAX_PAYLOAD_SIZE {
                             return Poll::Ready(Some(Err(io::Error::new(
                                 io::ErrorKind::InvalidInput,
@@ -383,7 +508,10 @@ where
                                 ),
                             ))));
                         }
-                        let data = Bytes::from(s.snappy.decoder.decompress_vec(input)?);
+                        let data = match &mut s.snappy {
+                            Some(snappy) => Bytes::from(snappy.decoder.decompress_vec(input)?),
+                            None => val.slice(1..),
+                        };
                         trace!("Decompressed raw message data: {}", hex::encode(&data));
 
                         if message_id < 0x10 {
@@ -548,13 +676,17 @@ where
         s.append(&message_id);
         let mut msg = s.out();
 
-        let mut buf = msg.split_off(msg.len());
-        buf.resize(snap::raw::max_compress_len(payload.len()), 0);
+        if let Some(snappy) = &mut this.snappy {
+            let mut buf = msg.split_off(msg.len());
+            buf.resize(snap::raw::max_compress_len(payload.len()), 0);
 
-        let compressed_len = this.snappy.encoder.compress(&*payload, &mut buf).unwrap();
-        buf.truncate(compressed_len);
+            let compressed_len = snappy.encoder.compress(&*payload, &mut buf).unwrap();
+            buf.truncate(compressed_len);
 
-        msg.unsplit(buf);
+            msg.unsplit(buf);
+        } else {
+            msg.extend_from_slice(&payload);
+        }
 
         Pin::new(&mut this.stream).start_send(msg.freeze())?;
 
@@ -569,3 +701,89 @@ where
         Pin::new(&mut self.get_mut().stream).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+
+    fn name(s: &str) -> CapabilityName {
+        CapabilityName(ArrayString::from(s).unwrap())
+    }
+
+    fn hello(client_version: String, capabilities: Vec<CapabilityMessage>) -> Bytes {
+        rlp::encode(&HelloMessage {
+            protocol_version: 5,
+            client_version,
+            capabilities,
+            port: 30303,
+            id: PeerId::from_low_u64_be(1),
+        })
+        .freeze()
+    }
+
+    #[test]
+    fn oversized_hello_is_rejected() {
+        let eth = |version| CapabilityMessage {
+            name: name("eth"),
+            version,
+        };
+
+        let valid = hello("client".into(), (60..68).map(eth).collect());
+        assert_eq!(
+            HelloMessage::decode_bounded(&Rlp::new(&valid))
+                .unwrap()
+                .capabilities
+                .len(),
+            8
+        );
+        // Truncated and padded hellos are errors, never panics.
+        for len in 0..valid.len() {
+            assert!(HelloMessage::decode_bounded(&Rlp::new(&valid[..len])).is_err());
+        }
+
+        for &count in &[MAX_HELLO_CAPABILITIES + 1, 100_000] {
+            let data = hello("client".into(), (0..count).map(eth).collect());
+            assert!(matches!(
+                HelloMessage::decode_bounded(&Rlp::new(&data)),
+                Err(HandshakeError::TooManyCapabilities(n)) if n == count
+            ));
+        }
+        let data = hello("x".repeat(MAX_CLIENT_VERSION_LEN + 1), vec![eth(68)]);
+        assert!(matches!(
+            HelloMessage::decode_bounded(&Rlp::new(&data)),
+            Err(HandshakeError::ClientVersionTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn highest_shared_capability_versions() {
+        let local = |n, version| CapabilityInfo {
+            name: name(n),
+            version,
+            length: 17,
+        };
+        let remote = |n, version| CapabilityMessage {
+            name: name(n),
+            version,
+        };
+
+        assert_eq!(
+            shared_capabilities(
+                vec![
+                    local("snap", 1),
+                    local("eth", 66),
+                    local("eth", 68),
+                    local("eth", 67)
+                ],
+                &[
+                    remote("eth", 66),
+                    remote("eth", 67),
+                    remote("les", 4),
+                    remote("snap", 1)
+                ],
+            ),
+            vec![local("eth", 67), local("snap", 1)]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Experimental QUIC transport. Standard Ethereum clients only speak RLPx over TCP,
+//! so this only connects nodes that all enable it.
+//!
+//! Every connection carries RLPx over a single bidirectional stream, with the same
+//! ECIES handshake as over TCP. QUIC requires TLS, for which each endpoint uses a
+//! throwaway self-signed certificate that is not verified: peers are authenticated
+//! by the ECIES handshake instead.
+
+use crate::transport::{Listener, Transport};
+use anyhow::{anyhow, Context as _};
+use async_trait::async_trait;
+use educe::Educe;
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use quinn::{
+    Certificate, CertificateChain, ClientConfigBuilder, Connecting, Connection, Endpoint, Incoming,
+    NewConnection, PrivateKey, RecvStream, SendStream, ServerConfigBuilder,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::*;
+
+const ALPN: &[u8] = b"devp2p";
+const SERVER_NAME: &str = "devp2p";
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts any server certificate, the remote is authenticated by RLPx.
+struct SkipCertificateVerification;
+
+impl rustls::ServerCertVerifier for SkipCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::RootCertStore,
+        _: &[rustls::Certificate],
+        _: webpki::DNSNameRef<'_>,
+        _: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// RLPx connection over a QUIC stream.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct QuicTransport {
+    remote_addr: SocketAddr,
+    #[educe(Debug(ignore))]
+    _connection: Connection,
+    #[educe(Debug(ignore))]
+    send: SendStream,
+    #[educe(Debug(ignore))]
+    recv: RecvStream,
+}
+
+impl QuicTransport {
+    fn new(connection: Connection, (send, recv): (SendStream, RecvStream)) -> Self {
+        Self {
+            remote_addr: connection.remote_address(),
+            _connection: connection,
+            send,
+            recv,
+        }
+    }
+}
+
+impl AsyncRead for QuicTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl Transport for QuicTransport {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+}
+
+/// QUIC socket used both for dialing and accepting peers.
+#[derive(Clone, Educe)]
+#[educe(Debug)]
+pub struct QuicEndpoint {
+    #[educe(Debug(ignore))]
+    endpoint: Endpoint,
+}
+
+impl QuicEndpoint {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<QuicTransport> {
+        let NewConnection { connection, .. } = self.endpoint.connect(&addr, SERVER_NAME)?.await?;
+        let streams = connection.open_bi().await?;
+        Ok(QuicTransport::new(connection, streams))
+    }
+}
+
+async fn accept_connection(connecting: Connecting) -> anyhow::Result<QuicTransport> {
+    tokio::time::timeout(ACCEPT_TIMEOUT, async move {
+        let NewConnection {
+            connection,
+            mut bi_streams,
+            ..
+        } = connecting.await?;
+        let streams = bi_streams
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("connection closed before opening a stream"))??;
+        Ok(QuicTransport::new(connection, streams))
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))?
+}
+
+/// Inbound QUIC connections, yielded once the peer has opened its stream.
+pub struct QuicListener {
+    incoming: Incoming,
+    pending: FuturesUnordered<BoxFuture<'static, anyhow::Result<QuicTransport>>>,
+}
+
+#[async_trait]
+impl Listener for QuicListener {
+    type Transport = QuicTransport;
+
+    async fn accept(&mut self) -> io::Result<(QuicTransport, SocketAddr)> {
+        loop {
+            tokio::select! {
+                connecting = self.incoming.next() => match connecting {
+                    Some(connecting) => self.pending.push(Box::pin(accept_connection(connecting))),
+                    None => return Err(io::Error::new(io::ErrorKind::Other, "QUIC endpoint closed")),
+                },
+                Some(res) = self.pending.next(), if !self.pending.is_empty() => match res {
+                    Ok(transport) => {
+                        let remote_addr = transport.remote_addr;
+                        return Ok((transport, remote_addr));
+                    }
+                    Err(e) => debug!("Failed to accept QUIC connection: {}", e),
+                },
+            }
+        }
+    }
+}
+
+/// Bind a QUIC endpoint at `addr`.
+pub fn bind(addr: SocketAddr) -> anyhow::Result<(QuicEndpoint, QuicListener)> {
+    let certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    let key = PrivateKey::from_der(&certificate.serialize_private_key_der())?;
+    let certificate = Certificate::from_der(&certificate.serialize_der()?)?;
+
+    let mut server_config = ServerConfigBuilder::default();
+    server_config.protocols(&[ALPN]);
+    server_config.certificate(CertificateChain::from_certs(vec![certificate]), key)?;
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.protocols(&[ALPN]);
+    let mut client_config = client_config.build();
+    Arc::get_mut(&mut client_config.crypto)
+        .ok_or_else(|| anyhow!("TLS config is shared"))?
+        .dangerous()
+        .set_certificate_verifier(Arc::new(SkipCertificateVerification));
+
+    let mut builder = Endpoint::builder();
+    builder.listen(server_config.build());
+    builder.default_client_config(client_config);
+    let (endpoint, incoming) = builder
+        .bind(&addr)
+        .with_context(|| format!("Failed to bind QUIC endpoint to {}", addr))?;
+
+    Ok((
+        QuicEndpoint { endpoint },
+        QuicListener {
+            incoming,
+            pending: Default::default(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn stream_over_quic() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let (dialer, _) = bind(localhost).unwrap();
+        let (listener_endpoint, mut listener) = bind(localhost).unwrap();
+        let addr = listener_endpoint.local_addr().unwrap();
+
+        let (mut outbound, inbound) = tokio::join!(
+            async {
+                let mut transport = dialer.connect(addr).await.unwrap();
+                // Stream is only visible to the listener once data is sent.
+                transport.write_all(b"ping").await.unwrap();
+                transport
+            },
+            listener.accept()
+        );
+        let (mut inbound, remote_addr) = inbound.unwrap();
+        assert_eq!(Some(remote_addr), inbound.remote_addr());
+
+        let mut buf = [0; 4];
+        inbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        inbound.write_all(b"pong").await.unwrap();
+        outbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
@@ -0,0 +1,430 @@
+//! Redial policy driven by the disconnect reason given by the remote.
+
+use crate::{peer::DisconnectReason, types::PeerId};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+/// Delay before redialing a peer that disconnected us, before the reason multiplier.
+pub const REDIAL_BASE_DELAY: Duration = Duration::from_secs(30);
+/// Peer we gave up on is not dialed again for this long.
+pub const GIVE_UP_PERIOD: Duration = Duration::from_secs(60 * 60);
+const MAX_TRACKED_PEERS: usize = 4096;
+
+/// How to treat redials after the remote disconnected us with a given reason.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RedialRule {
+    /// Multiplier for `REDIAL_BASE_DELAY`, applied per consecutive disconnect.
+    pub delay_multiplier: f64,
+    /// Consecutive disconnects with this reason before we give up on the peer.
+    pub max_attempts: usize,
+    /// Whether this reason likely points at a problem on our side.
+    pub alert: bool,
+}
+
+impl RedialRule {
+    pub const fn new(delay_multiplier: f64, max_attempts: usize, alert: bool) -> Self {
+        Self {
+            delay_multiplier,
+            max_attempts,
+            alert,
+        }
+    }
+}
+
+/// Per-reason redial rules.
+#[derive(Clone, Debug)]
+pub struct RedialPolicy {
+    rules: HashMap<DisconnectReason, RedialRule>,
+    fallback: RedialRule,
+}
+
+impl Default for RedialPolicy {
+    fn default() -> Self {
+        let rules = vec![
+            // Remote is full, it will likely have a free slot soon.
+            (
+                DisconnectReason::TooManyPeers,
+                RedialRule::new(1.0, 10, false),
+            ),
+            (
+                DisconnectReason::ClientQuitting,
+                RedialRule::new(10.0, 3, false),
+            ),
+            (
+                DisconnectReason::AlreadyConnected,
+                RedialRule::new(2.0, 5, false),
+            ),
+            // Remote thinks we are broken, redialing quickly only gets us banned.
+            (
+                DisconnectReason::UselessPeer,
+                RedialRule::new(60.0, 1, true),
+            ),
+            (
+                DisconnectReason::ProtocolBreach,
+                RedialRule::new(60.0, 1, true),
+            ),
+            (
+                DisconnectReason::IncompatibleP2PProtocolVersion,
+                RedialRule::new(60.0, 1, true),
+            ),
+        ];
+
+        Self {
+            rules: rules.into_iter().collect(),
+            fallback: RedialRule::new(2.0, 5, false),
+        }
+    }
+}
+
+impl RedialPolicy {
+    /// Override rule for a reason.
+    pub fn with_rule(mut self, reason: DisconnectReason, rule: RedialRule) -> Self {
+        self.rules.insert(reason, rule);
+        self
+    }
+
+    pub fn rule(&self, reason: DisconnectReason) -> RedialRule {
+        self.rules.get(&reason).copied().unwrap_or(self.fallback)
+    }
+
+    /// Delay before the next dial after `attempts` consecutive disconnects with `reason`,
+    /// `None` if we should give up. Never longer than `GIVE_UP_PERIOD`.
+    pub fn delay(&self, reason: DisconnectReason, attempts: usize) -> Option<Duration> {
+        let rule = self.rule(reason);
+        if attempts > rule.max_attempts {
+            return None;
+        }
+
+        let secs = REDIAL_BASE_DELAY.as_secs_f64() * rule.delay_multiplier * attempts as f64;
+        Some(if secs.is_nan() {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(secs.clamp(0.0, GIVE_UP_PERIOD.as_secs_f64()))
+        })
+    }
+}
+
+/// Redial statistics for one disconnect reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RedialReasonStats {
+    /// Redials scheduled after remote disconnects.
+    pub scheduled: usize,
+    /// Dials skipped because the peer is still backed off.
+    pub deferred: usize,
+    /// Peers we gave up on.
+    pub given_up: usize,
+}
+
+pub type RedialStats = HashMap<DisconnectReason, RedialReasonStats>;
+
+#[derive(Debug)]
+struct RedialEntry {
+    reason: DisconnectReason,
+    attempts: usize,
+    not_before: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct RedialTracker {
+    policy: RedialPolicy,
+    peers: HashMap<PeerId, RedialEntry>,
+    stats: RedialStats,
+}
+
+impl RedialTracker {
+    pub fn new(policy: RedialPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Remote has disconnected us with `reason`.
+    pub fn on_remote_disconnect(&mut self, peer: PeerId, reason: DisconnectReason, now: Instant) {
+        if self.peers.len() >= MAX_TRACKED_PEERS && !self.peers.contains_key(&peer) {
+            self.peers.retain(|_, entry| entry.not_before > now);
+        }
+
+        let attempts = match self.peers.get(&peer) {
+            Some(entry) if entry.reason == reason => entry.attempts + 1,
+            _ => 1,
+        };
+
+        let stats = self.stats.entry(reason).or_default();
+        let not_before = match self.policy.delay(reason, attempts) {
+            Some(delay) => {
+                stats.scheduled += 1;
+                now + delay
+            }
+            None => {
+                stats.given_up += 1;
+                if self.policy.rule(reason).alert {
+                    warn!(
+                        "Giving up on peer {} after it disconnected us {} time(s) with reason: {}. This may indicate a problem with this node.",
+                        peer, attempts, reason
+                    );
+                } else {
+                    debug!(
+                        "Giving up on peer {} after {} disconnect(s) with reason: {}",
+                        peer, attempts, reason
+                    );
+                }
+                now + GIVE_UP_PERIOD
+            }
+        };
+
+        self.peers.insert(
+            peer,
+            RedialEntry {
+                reason,
+                attempts,
+                not_before,
+            },
+        );
+    }
+
+    /// Whether peer can be dialed now.
+    pub fn may_dial(&mut self, peer: PeerId, now: Instant) -> bool {
+        match self.peers.get(&peer) {
+            Some(entry) if entry.not_before > now => {
+                self.stats.entry(entry.reason).or_default().deferred += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Peer has completed a session without being disconnected by remote.
+    pub fn on_success(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    pub fn stats(&self) -> RedialStats {
+        self.stats.clone()
+    }
+}
+
+/// Peers dialed regardless of redial delays and the dial cooldown for a while, e.g. ones
+/// disconnected on purpose so that they reconnect at once. Handle shared with the swarm.
+#[derive(Clone, Debug, Default)]
+pub struct RedialExemptions(Arc<Mutex<HashMap<PeerId, Instant>>>);
+
+impl RedialExemptions {
+    /// Exempt the peer until `until`.
+    pub fn exempt(&self, peer: PeerId, until: Instant) {
+        let mut exemptions = self.0.lock();
+        if exemptions.len() >= MAX_TRACKED_PEERS && !exemptions.contains_key(&peer) {
+            let now = Instant::now();
+            exemptions.retain(|_, until| *until > now);
+        }
+        exemptions.insert(peer, until);
+    }
+
+    pub fn is_exempt(&self, peer: PeerId, now: Instant) -> bool {
+        let mut exemptions = self.0.lock();
+        match exemptions.get(&peer) {
+            Some(&until) if until > now => true,
+            Some(_) => {
+                exemptions.remove(&peer);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Peers we have dialed recently, which are not dialed again until the cooldown passes
+/// however the session ended. Disabled with zero cooldown.
+#[derive(Debug, Default)]
+pub struct DialCooldown {
+    cooldown: Duration,
+    dialed: HashMap<PeerId, Instant>,
+    order: VecDeque<(Instant, PeerId)>,
+}
+
+impl DialCooldown {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            ..Default::default()
+        }
+    }
+
+    pub fn on_dial(&mut self, peer: PeerId, now: Instant) {
+        if self.cooldown.is_zero() {
+            return;
+        }
+        self.dialed.insert(peer, now);
+        self.order.push_back((now, peer));
+    }
+
+    pub fn may_dial(&mut self, peer: PeerId, now: Instant) -> bool {
+        while let Some(&(at, expired)) = self.order.front() {
+            if now.saturating_duration_since(at) < self.cooldown {
+                break;
+            }
+            self.order.pop_front();
+            // Peer may have been dialed again since.
+            if self.dialed.get(&expired) == Some(&at) {
+                self.dialed.remove(&expired);
+            }
+        }
+        !self.dialed.contains_key(&peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_REASONS: &[DisconnectReason] = &[
+        DisconnectReason::DisconnectRequested,
+        DisconnectReason::TcpSubsystemError,
+        DisconnectReason::ProtocolBreach,
+        DisconnectReason::UselessPeer,
+        DisconnectReason::TooManyPeers,
+        DisconnectReason::AlreadyConnected,
+        DisconnectReason::IncompatibleP2PProtocolVersion,
+        DisconnectReason::NullNodeIdentity,
+        DisconnectReason::ClientQuitting,
+        DisconnectReason::UnexpectedHandshakeIdentity,
+        DisconnectReason::ConnectedToSelf,
+        DisconnectReason::PingTimeout,
+        DisconnectReason::SubprotocolSpecific,
+    ];
+
+    #[test]
+    fn scheduled_delays() {
+        let policy = RedialPolicy::default();
+        let secs = |reason, attempts| policy.delay(reason, attempts).map(|d| d.as_secs());
+
+        for &reason in ALL_REASONS {
+            let expected = match reason {
+                DisconnectReason::TooManyPeers => vec![Some(30), Some(60)],
+                DisconnectReason::ClientQuitting => vec![Some(300), Some(600)],
+                DisconnectReason::UselessPeer
+                | DisconnectReason::ProtocolBreach
+                | DisconnectReason::IncompatibleP2PProtocolVersion => vec![Some(1800), None],
+                _ => vec![Some(60), Some(120)],
+            };
+
+            assert_eq!(
+                vec![secs(reason, 1), secs(reason, 2)],
+                expected,
+                "{:?}",
+                reason
+            );
+        }
+
+        assert_eq!(secs(DisconnectReason::TooManyPeers, 11), None);
+        assert_eq!(secs(DisconnectReason::ClientQuitting, 4), None);
+    }
+
+    #[test]
+    fn overridden_rule() {
+        let policy = RedialPolicy::default().with_rule(
+            DisconnectReason::UselessPeer,
+            RedialRule::new(0.5, 100, false),
+        );
+
+        assert_eq!(
+            policy.delay(DisconnectReason::UselessPeer, 2),
+            Some(Duration::from_secs(30))
+        );
+        assert!(!policy.rule(DisconnectReason::UselessPeer).alert);
+    }
+
+    #[test]
+    fn delay_of_odd_multiplier_is_bounded() {
+        for &(multiplier, expected) in &[
+            (-1.0, Duration::ZERO),
+            (f64::NAN, Duration::ZERO),
+            (f64::INFINITY, GIVE_UP_PERIOD),
+            (f64::MAX, GIVE_UP_PERIOD),
+        ] {
+            let policy = RedialPolicy::default().with_rule(
+                DisconnectReason::TooManyPeers,
+                RedialRule::new(multiplier, 10, false),
+            );
+            assert_eq!(
+                policy.delay(DisconnectReason::TooManyPeers, 2),
+                Some(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn tracker_backs_off_and_gives_up() {
+        let mut tracker = RedialTracker::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        tracker.on_remote_disconnect(peer, DisconnectReason::UselessPeer, now);
+        assert!(!tracker.may_dial(peer, now));
+        assert!(tracker.may_dial(peer, now + Duration::from_secs(1800)));
+
+        tracker.on_remote_disconnect(peer, DisconnectReason::UselessPeer, now);
+        assert!(!tracker.may_dial(peer, now + Duration::from_secs(1800)));
+        assert!(tracker.may_dial(peer, now + GIVE_UP_PERIOD));
+
+        assert_eq!(
+            tracker.stats()[&DisconnectReason::UselessPeer],
+            RedialReasonStats {
+                scheduled: 1,
+                deferred: 2,
+                given_up: 1,
+            }
+        );
+
+        // Different reason starts counting from scratch.
+        tracker.on_remote_disconnect(peer, DisconnectReason::TooManyPeers, now);
+        assert!(tracker.may_dial(peer, now + Duration::from_secs(30)));
+
+        tracker.on_success(peer);
+        assert!(tracker.may_dial(peer, now));
+    }
+
+    #[test]
+    fn dial_cooldown() {
+        let mut cooldown = DialCooldown::new(Duration::from_secs(60));
+        let a = PeerId::from_low_u64_be(1);
+        let b = PeerId::from_low_u64_be(2);
+        let now = Instant::now();
+        let secs = |secs| now + Duration::from_secs(secs);
+
+        cooldown.on_dial(a, now);
+        cooldown.on_dial(b, secs(30));
+        assert!(!cooldown.may_dial(a, secs(59)));
+        assert!(cooldown.may_dial(a, secs(60)));
+        assert!(!cooldown.may_dial(b, secs(60)));
+
+        // Redial restarts the cooldown.
+        cooldown.on_dial(a, secs(60));
+        assert!(!cooldown.may_dial(a, secs(100)));
+        assert!(cooldown.may_dial(b, secs(100)));
+
+        let mut disabled = DialCooldown::default();
+        disabled.on_dial(a, now);
+        assert!(disabled.may_dial(a, now));
+    }
+
+    #[test]
+    fn redial_exemptions_expire() {
+        let exemptions = RedialExemptions::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        assert!(!exemptions.is_exempt(peer, now));
+        exemptions
+            .clone()
+            .exempt(peer, now + Duration::from_secs(10));
+        assert!(exemptions.is_exempt(peer, now + Duration::from_secs(9)));
+        assert!(!exemptions.is_exempt(peer, now + Duration::from_secs(10)));
+        assert!(exemptions.0.lock().is_empty());
+    }
+}
@@ -1,23 +1,38 @@
 //! RLPx protocol implementation in Rust
 
-use crate::{disc::Discovery, node_filter::*, peer::*, transport::Transport, types::*};
+#[cfg(feature = "quic")]
+use crate::quic::{self, QuicEndpoint};
+use crate::{
+    accept::{accept, AcceptErrorStats, AcceptErrors},
+    dial_diversity::*,
+    disc::Discovery,
+    endpoints::EndpointBook,
+    greylist::{Greylist, GreylistPolicy, GreylistedAddr},
+    handshake::{HandshakeExecutor, HandshakeStats},
+    log_limiter::{LogLimiter, Suppressed},
+    node_filter::*,
+    peer::*,
+    redial::{DialCooldown, RedialExemptions, RedialPolicy, RedialStats, RedialTracker},
+    transport::{Listener, Transport},
+    types::*,
+};
 use anyhow::{anyhow, bail, Context};
 use cidr::{Cidr, IpCidr};
 use educe::Educe;
-use futures::sink::SinkExt;
+use futures::{sink::SinkExt, FutureExt};
 use parking_lot::Mutex;
 use secp256k1::SecretKey;
 use std::{
-    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     fmt::Debug,
     future::Future,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::Deref,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Weak,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use task_group::TaskGroup;
 use tokio::{
@@ -37,7 +52,12 @@ const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
 const PING_TIMEOUT: Duration = Duration::from_secs(60);
 const DISCOVERY_TIMEOUT_SECS: u64 = 90;
 const DISCOVERY_CONNECT_TIMEOUT_SECS: u64 = 5;
-const DIAL_INTERVAL: Duration = Duration::from_millis(100);
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+const ERROR_LOG_CAPACITY: usize = 1024;
+
+/// Limiter for per-address connection errors, keyed by remote IP and error class.
+type ErrorLogLimiter = LogLimiter<(IpAddr, &'static str)>;
+pub const DIAL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone, Copy)]
 enum DisconnectInitiator {
@@ -72,6 +92,9 @@ impl PeerState {
 struct PeerStreams {
     /// Mapping of remote IDs to streams in `StreamMap`
     mapping: HashMap<PeerId, PeerState>,
+    redial: RedialTracker,
+    dial_cooldown: DialCooldown,
+    endpoints: EndpointBook,
 }
 
 impl PeerStreams {
@@ -82,39 +105,58 @@ impl PeerStreams {
     }
 }
 
-impl Default for PeerStreams {
-    fn default() -> Self {
+impl PeerStreams {
+    fn new(redial_policy: RedialPolicy, dial_cooldown: Duration) -> Self {
         Self {
             mapping: HashMap::new(),
+            redial: RedialTracker::new(redial_policy),
+            dial_cooldown: DialCooldown::new(dial_cooldown),
+            endpoints: Default::default(),
         }
     }
 }
 
+impl Default for PeerStreams {
+    fn default() -> Self {
+        Self::new(Default::default(), Default::default())
+    }
+}
+
 #[derive(Educe)]
 #[educe(Clone)]
 struct PeerStreamHandshakeData<C> {
     port: u16,
     secret_key: SecretKey,
+    protocol_version: ProtocolVersion,
     client_version: String,
-    capabilities: Arc<CapabilitySet>,
+    capabilities: CapabilityRegistry,
     capability_server: Arc<C>,
+    handshake_executor: Arc<HandshakeExecutor>,
+    error_log_limiter: Arc<ErrorLogLimiter>,
+    greylist: Arc<Mutex<Greylist>>,
 }
 
-async fn handle_incoming<C>(
+async fn handle_incoming<C, L>(
     task_group: Weak<TaskGroup>,
     streams: Arc<Mutex<PeerStreams>>,
     node_filter: Arc<Mutex<dyn NodeFilter>>,
-    tcp_incoming: TcpListener,
+    mut incoming: L,
     cidr: Option<IpCidr>,
     handshake_data: PeerStreamHandshakeData<C>,
+    accept_errors: Arc<AcceptErrors>,
 ) where
     C: CapabilityServer,
+    L: Listener,
 {
     let _: anyhow::Result<()> = async {
         loop {
-            match tcp_incoming.accept().await {
+            match accept(&mut incoming, &accept_errors).await {
                 Err(e) => {
-                    bail!("failed to accept peer: {:?}, shutting down", e);
+                    error!(
+                        "Failed to accept peer: {}, no longer accepting connections",
+                        e
+                    );
+                    bail!(e);
                 }
                 Ok((stream, remote_addr)) => {
                     let tasks = task_group
@@ -123,15 +165,39 @@ async fn handle_incoming<C>(
 
                     if let Some(cidr) = &cidr {
                         if !cidr.contains(&remote_addr.ip()) {
-                            debug!(
-                                "Ignoring connection request: {} is not in range {}",
-                                remote_addr, cidr
-                            );
+                            if let Some(suppressed) = handshake_data
+                                .error_log_limiter
+                                .check((remote_addr.ip(), "cidr"))
+                            {
+                                debug!(
+                                    "Ignoring connection request: {} is not in range {}{}",
+                                    remote_addr, cidr, suppressed
+                                );
+                            }
 
                             continue;
                         }
                     }
 
+                    // Dropped before any crypto, as its handshakes keep failing.
+                    if handshake_data
+                        .greylist
+                        .lock()
+                        .check(remote_addr.ip(), Instant::now())
+                    {
+                        if let Some(suppressed) = handshake_data
+                            .error_log_limiter
+                            .check((remote_addr.ip(), "greylist"))
+                        {
+                            debug!(
+                                "Ignoring connection request: {} is greylisted{}",
+                                remote_addr, suppressed
+                            );
+                        }
+
+                        continue;
+                    }
+
                     let f = handle_incoming_request(
                         streams.clone(),
                         node_filter.clone(),
@@ -146,11 +212,14 @@ async fn handle_incoming<C>(
     .await;
 }
 
-/// Set up newly connected peer's state, start its tasks
+/// Set up newly connected peer's state, start its tasks. `remote_addr` is where the peer
+/// accepts connections, if known.
 fn setup_peer_state<C, Io>(
     streams: Weak<Mutex<PeerStreams>>,
     capability_server: Arc<C>,
     remote_id: PeerId,
+    remote_addr: Option<SocketAddr>,
+    direction: ConnectionDirection,
     peer: PeerStream<Io>,
 ) -> ConnectedPeerState
 where
@@ -163,11 +232,14 @@ where
         .copied()
         .map(|cap_info| (cap_info.name, cap_info.version))
         .collect::<HashMap<_, _>>();
+    capability_server.on_peer_client_version(remote_id, peer.remote_client_version());
     let (mut sink, mut stream) = futures::StreamExt::split(peer);
     let (peer_disconnect_tx, mut peer_disconnect_rx) = unbounded_channel();
     let tasks = TaskGroup::default();
 
-    capability_server.on_peer_connect(remote_id, capability_set);
+    // Must precede the ingress router, the peer may have sent its first messages along
+    // with Hello and they are yielded as soon as the stream is polled.
+    capability_server.on_peer_connect(remote_id, remote_addr, direction, capability_set);
 
     let pinged = Arc::new(AtomicBool::default());
     let (pings_tx, mut pings) = channel(1);
@@ -235,6 +307,7 @@ where
         format!("peer {} egress router & disconnector", remote_id),
         async move {
             let mut event_fut = capability_server.next(remote_id);
+            let mut remote_reason = None;
             loop {
                 let mut disconnecting = None;
                 let mut egress = None;
@@ -300,6 +373,9 @@ where
                         // We have sent disconnect message, wait for grace period.
                         sleep(Duration::from_secs(GRACE_PERIOD_SECS)).await;
                     }
+                    if let DisconnectInitiator::Remote = initiator {
+                        remote_reason = Some(reason);
+                    }
                     capability_server
                         .on_peer_event(
                             remote_id,
@@ -316,7 +392,16 @@ where
             if let Some(streams) = streams.upgrade() {
                 // This is the last line that is guaranteed to be executed.
                 // After this the peer's task group is dropped and any alive tasks are forcibly cancelled.
-                streams.lock().disconnect_peer(remote_id);
+                let mut streams = streams.lock();
+                match remote_reason {
+                    Some(reason) => {
+                        streams
+                            .redial
+                            .on_remote_disconnect(remote_id, reason, Instant::now())
+                    }
+                    None => streams.redial.on_success(remote_id),
+                }
+                streams.disconnect_peer(remote_id);
             }
         }
         .instrument(span!(
@@ -365,32 +450,49 @@ async fn handle_incoming_request<C, Io>(
 {
     let PeerStreamHandshakeData {
         secret_key,
+        protocol_version,
         client_version,
         capabilities,
         capability_server,
         port,
+        handshake_executor,
+        error_log_limiter,
+        greylist,
     } = handshake_data;
+    let remote_addr = stream.remote_addr();
+    let capabilities = capabilities.snapshot();
     // Do handshake and convert incoming connection into stream.
-    let peer_res = tokio::time::timeout(
-        Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
-        PeerStream::incoming(
-            stream,
-            secret_key,
-            client_version,
-            capabilities.get_capabilities().to_vec(),
-            port,
-        ),
-    )
-    .await
-    .unwrap_or_else(|_| Err(anyhow!("incoming connection timeout")));
+    let peer_res = handshake_executor
+        .run(async move {
+            tokio::time::timeout(
+                Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+                PeerStream::incoming(
+                    stream,
+                    secret_key,
+                    protocol_version,
+                    client_version,
+                    capabilities,
+                    port,
+                ),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("incoming connection timeout")))
+        })
+        .await;
 
     match peer_res {
         Ok(peer) => {
+            if let Some(addr) = remote_addr {
+                greylist.lock().on_success(addr.ip());
+            }
+
             let remote_id = peer.remote_id();
             let s = streams.clone();
             let mut s = s.lock();
             let node_filter = node_filter.clone();
-            let PeerStreams { mapping } = &mut *s;
+            let PeerStreams {
+                mapping, endpoints, ..
+            } = &mut *s;
             let total_connections = mapping.len();
 
             match mapping.entry(remote_id) {
@@ -408,10 +510,13 @@ async fn handle_incoming_request<C, Io>(
                 Entry::Vacant(entry) => {
                     if node_filter.lock().allow(total_connections, remote_id) {
                         debug!("New incoming peer connected: {}", remote_id);
+                        endpoints.on_inbound(remote_id, peer.remote_listen_addr());
                         entry.insert(PeerState::Connected(setup_peer_state(
                             Arc::downgrade(&streams),
                             capability_server,
                             remote_id,
+                            endpoints.resolve(remote_id),
+                            ConnectionDirection::Inbound,
                             peer,
                         )));
                     } else {
@@ -421,7 +526,17 @@ async fn handle_incoming_request<C, Io>(
             }
         }
         Err(e) => {
-            debug!("Peer disconnected with error {}", e);
+            if let Some(suppressed) = match remote_addr {
+                Some(addr) => error_log_limiter.check((addr.ip(), "handshake")),
+                None => Some(Suppressed::default()),
+            } {
+                debug!("Peer disconnected with error {}{}", e, suppressed);
+            }
+            if let Some(addr) = remote_addr {
+                if greylist.lock().on_failure(addr.ip(), Instant::now()) {
+                    info!("Greylisted {} after repeated failed handshakes", addr.ip());
+                }
+            }
         }
     }
 }
@@ -437,6 +552,52 @@ impl CapabilitySet {
     fn get_capabilities(&self) -> &[CapabilityInfo] {
         &self.capability_cache
     }
+
+    fn insert(&mut self, id: CapabilityId, length: CapabilityLength) {
+        self.inner.insert(id, length);
+        *self = std::mem::take(&mut self.inner).into();
+    }
+
+    fn remove(&mut self, id: CapabilityId) -> bool {
+        let removed = self.inner.remove(&id).is_some();
+        *self = std::mem::take(&mut self.inner).into();
+        removed
+    }
+}
+
+/// Shared handle to capabilities advertised by the swarm.
+///
+/// Changes apply to handshakes started afterwards, existing peers are not affected.
+#[derive(Clone, Debug, Default)]
+pub struct CapabilityRegistry {
+    inner: Arc<Mutex<CapabilitySet>>,
+}
+
+impl CapabilityRegistry {
+    fn new(capabilities: CapabilitySet) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(capabilities)),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<CapabilityInfo> {
+        self.inner.lock().get_capabilities().to_vec()
+    }
+
+    /// Start advertising the capability.
+    pub fn register(&self, id: CapabilityId, length: CapabilityLength) {
+        self.inner.lock().insert(id, length);
+    }
+
+    /// Stop advertising the capability. Returns `false` if it was not advertised.
+    pub fn unregister(&self, id: CapabilityId) -> bool {
+        self.inner.lock().remove(id)
+    }
+
+    /// Whether capability is currently advertised.
+    pub fn contains(&self, id: CapabilityId) -> bool {
+        self.inner.lock().inner.contains_key(&id)
+    }
 }
 
 impl From<BTreeMap<CapabilityId, CapabilityLength>> for CapabilitySet {
@@ -475,19 +636,35 @@ pub struct Swarm<C: CapabilityServer> {
     tasks: Arc<TaskGroup>,
 
     streams: Arc<Mutex<PeerStreams>>,
+    redial_exemptions: RedialExemptions,
 
     currently_connecting: Arc<AtomicUsize>,
+    dial_interval_ms: AtomicU64,
+    dial_distribution: Mutex<DialDistribution>,
+    /// Dial regardless of `max_peers`.
+    unlimited_dials: bool,
+    /// Peers the dialer is connecting to.
+    dialing: Arc<Mutex<HashSet<PeerId>>>,
 
     node_filter: Arc<Mutex<dyn NodeFilter>>,
 
-    capabilities: Arc<CapabilitySet>,
+    capabilities: CapabilityRegistry,
     #[educe(Debug(ignore))]
     capability_server: Arc<C>,
 
+    handshake_executor: Arc<HandshakeExecutor>,
+    error_log_limiter: Arc<ErrorLogLimiter>,
+    greylist: Arc<Mutex<Greylist>>,
+    accept_errors: Arc<AcceptErrors>,
+
     #[educe(Debug(ignore))]
     secret_key: SecretKey,
+    protocol_version: ProtocolVersion,
     client_version: String,
     port: u16,
+
+    #[cfg(feature = "quic")]
+    quic: Option<QuicEndpoint>,
 }
 
 /// Builder for ergonomically creating a new `Server`.
@@ -496,6 +673,13 @@ pub struct SwarmBuilder {
     task_group: Option<Arc<TaskGroup>>,
     listen_options: Option<ListenOptions>,
     client_version: String,
+    handshake_threads: usize,
+    redial_policy: RedialPolicy,
+    greylist_policy: GreylistPolicy,
+    dial_cooldown: Duration,
+    unlimited_dials: bool,
+    quic_addr: Option<SocketAddr>,
+    p2p_protocol_version: ProtocolVersion,
 }
 
 impl SwarmBuilder {
@@ -514,6 +698,52 @@ impl SwarmBuilder {
         self
     }
 
+    /// Run handshakes on a dedicated runtime with the given number of threads.
+    /// Zero (default) runs them on the caller's runtime.
+    pub fn with_handshake_threads(mut self, threads: usize) -> Self {
+        self.handshake_threads = threads;
+        self
+    }
+
+    /// How to redial peers that disconnected us, depending on their disconnect reason.
+    pub fn with_redial_policy(mut self, policy: RedialPolicy) -> Self {
+        self.redial_policy = policy;
+        self
+    }
+
+    /// When to drop inbound connections from addresses whose handshakes keep failing.
+    pub fn with_greylist_policy(mut self, policy: GreylistPolicy) -> Self {
+        self.greylist_policy = policy;
+        self
+    }
+
+    /// Do not dial a discovered peer again for this long after dialing it, e.g. when
+    /// crawling. Disabled (default) with zero.
+    pub fn with_dial_cooldown(mut self, cooldown: Duration) -> Self {
+        self.dial_cooldown = cooldown;
+        self
+    }
+
+    /// Keep dialing discovered peers regardless of `max_peers`, e.g. when crawling. Inbound
+    /// connections are still limited.
+    pub fn with_unlimited_dials(mut self) -> Self {
+        self.unlimited_dials = true;
+        self
+    }
+
+    /// RLPx protocol version announced in Hello, V5 (default) or V4 for very old peers.
+    pub fn with_p2p_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.p2p_protocol_version = version;
+        self
+    }
+
+    /// Also accept and dial peers over QUIC at the given address, see [`quic`](crate::quic).
+    #[cfg(feature = "quic")]
+    pub fn with_quic_listener(mut self, addr: SocketAddr) -> Self {
+        self.quic_addr = Some(addr);
+        self
+    }
+
     /// Create a new RLPx node
     pub async fn build<C: CapabilityServer>(
         self,
@@ -528,6 +758,13 @@ impl SwarmBuilder {
             capability_mask.into(),
             capability_server,
             self.listen_options,
+            self.handshake_threads,
+            self.redial_policy,
+            self.greylist_policy,
+            self.dial_cooldown,
+            self.unlimited_dials,
+            self.quic_addr,
+            self.p2p_protocol_version,
         )
         .await
     }
@@ -549,6 +786,13 @@ impl Swarm<()> {
             task_group: None,
             listen_options: None,
             client_version: format!("rust-devp2p/{}", env!("CARGO_PKG_VERSION")),
+            handshake_threads: 0,
+            redial_policy: Default::default(),
+            greylist_policy: Default::default(),
+            dial_cooldown: Default::default(),
+            unlimited_dials: false,
+            quic_addr: None,
+            p2p_protocol_version: Default::default(),
         }
     }
 }
@@ -571,27 +815,57 @@ impl<C: CapabilityServer> Swarm<C> {
         capabilities: CapabilitySet,
         capability_server: Arc<C>,
         listen_options: Option<ListenOptions>,
+        handshake_threads: usize,
+        redial_policy: RedialPolicy,
+        greylist_policy: GreylistPolicy,
+        dial_cooldown: Duration,
+        unlimited_dials: bool,
+        quic_addr: Option<SocketAddr>,
+        protocol_version: ProtocolVersion,
     ) -> anyhow::Result<Arc<Self>> {
         let tasks = task_group.unwrap_or_default();
 
+        let handshake_executor = Arc::new(
+            HandshakeExecutor::new(handshake_threads)
+                .context("Failed to start handshake runtime")?,
+        );
+        let error_log_limiter =
+            Arc::new(ErrorLogLimiter::new(ERROR_LOG_INTERVAL, ERROR_LOG_CAPACITY));
+        let greylist = Arc::new(Mutex::new(Greylist::new(greylist_policy)));
+        let accept_errors = Arc::new(AcceptErrors::default());
+
         let port = listen_options
             .as_ref()
             .map_or(0, |options| options.addr.port());
 
-        let streams = Arc::new(Mutex::new(PeerStreams::default()));
+        let streams = Arc::new(Mutex::new(PeerStreams::new(redial_policy, dial_cooldown)));
         let node_filter = Arc::new(Mutex::new(MemoryNodeFilter::new(Arc::new(
             listen_options
                 .as_ref()
                 .map_or(0.into(), |options| options.max_peers.into()),
         ))));
 
-        let capabilities = Arc::new(capabilities);
+        let capabilities = CapabilityRegistry::new(capabilities);
+
+        let cidr = listen_options
+            .as_ref()
+            .and_then(|options| options.cidr.clone());
+        let handshake_data = PeerStreamHandshakeData {
+            port,
+            secret_key,
+            protocol_version,
+            client_version: client_version.clone(),
+            capabilities: capabilities.clone(),
+            capability_server: capability_server.clone(),
+            handshake_executor: handshake_executor.clone(),
+            error_log_limiter: error_log_limiter.clone(),
+            greylist: greylist.clone(),
+        };
 
         if let Some(options) = &listen_options {
             let tcp_incoming = TcpListener::bind(options.addr)
                 .await
                 .context("Failed to bind RLPx node to socket")?;
-            let cidr = options.cidr.clone();
             tasks.spawn_with_name(
                 "incoming handler",
                 handle_incoming(
@@ -599,80 +873,145 @@ impl<C: CapabilityServer> Swarm<C> {
                     streams.clone(),
                     node_filter.clone(),
                     tcp_incoming,
-                    cidr,
-                    PeerStreamHandshakeData {
-                        port,
-                        secret_key,
-                        client_version: client_version.clone(),
-                        capabilities: capabilities.clone(),
-                        capability_server: capability_server.clone(),
-                    },
+                    cidr.clone(),
+                    handshake_data.clone(),
+                    accept_errors.clone(),
                 ),
             );
         }
 
+        #[cfg(feature = "quic")]
+        let quic = match quic_addr {
+            Some(addr) => {
+                let (endpoint, quic_incoming) = quic::bind(addr)?;
+                tasks.spawn_with_name(
+                    "QUIC incoming handler",
+                    handle_incoming(
+                        Arc::downgrade(&tasks),
+                        streams.clone(),
+                        node_filter.clone(),
+                        quic_incoming,
+                        cidr,
+                        handshake_data,
+                        accept_errors.clone(),
+                    ),
+                );
+                Some(endpoint)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "quic"))]
+        if quic_addr.is_some() {
+            bail!("QUIC support is not compiled in");
+        }
+
         let server = Arc::new(Self {
             tasks: tasks.clone(),
             streams,
+            redial_exemptions: Default::default(),
             currently_connecting: Default::default(),
+            dial_interval_ms: AtomicU64::new(DIAL_INTERVAL.as_millis() as u64),
+            dial_distribution: Default::default(),
+            unlimited_dials,
+            dialing: Default::default(),
             node_filter,
             capabilities,
             capability_server,
+            handshake_executor,
+            error_log_limiter,
+            greylist,
+            accept_errors,
             secret_key,
+            protocol_version,
             client_version,
             port,
+            #[cfg(feature = "quic")]
+            quic,
         });
 
         if let Some(mut options) = listen_options {
             tasks.spawn_with_name("dialer", {
+                let current_peers = server.dialing.clone();
                 let server = Arc::downgrade(&server);
                 let tasks = Arc::downgrade(&tasks);
                 async move {
-                    let current_peers = Arc::new(Mutex::new(HashSet::new()));
+                    let mut candidates = VecDeque::with_capacity(DIAL_CANDIDATES);
                     loop {
                         if let Some(server) = server.upgrade() {
                             let streams_len = server.streams.lock().mapping.len();
-                            let max_peers = server.node_filter.lock().max_peers();
+                            let max_peers = if server.unlimited_dials {
+                                usize::MAX
+                            } else {
+                                server.node_filter.lock().max_peers()
+                            };
 
                             if streams_len < max_peers {
                                 trace!("Discovering peers as our peer count is too low: {} < {}", streams_len, max_peers);
-                                match tokio::time::timeout(
-                                    Duration::from_secs(DISCOVERY_TIMEOUT_SECS),
-                                    options.discovery_tasks.next(),
-                                )
-                                .await {
-                                    Err(_) => {
-                                        debug!("Failed to get new peer: timed out");
+                                // Wait for discoveries only if there is nobody left to dial.
+                                if candidates.is_empty() {
+                                    match tokio::time::timeout(
+                                        Duration::from_secs(DISCOVERY_TIMEOUT_SECS),
+                                        options.discovery_tasks.next(),
+                                    )
+                                    .await {
+                                        Err(_) => {
+                                            debug!("Failed to get new peer: timed out");
+                                        }
+                                        Ok(None) => {
+                                            debug!("Discoveries ended, dialer quitting");
+                                            return;
+                                        }
+                                        Ok(Some((source, res))) => server.add_dial_candidate(&mut candidates, &current_peers, source, res),
                                     }
-                                    Ok(None) => {
-                                        debug!("Discoveries ended, dialer quitting");
-                                        return;
+                                }
+                                // Then take whatever else the discoveries have ready to choose from.
+                                for _ in 0..DIAL_CANDIDATES {
+                                    match options.discovery_tasks.next().now_or_never() {
+                                        Some(Some((source, res))) => server.add_dial_candidate(&mut candidates, &current_peers, source, res),
+                                        _ => break,
                                     }
-                                    Ok(Some((disc_id, Ok(NodeRecord { addr, id: remote_id })))) => {
-                                        if let Some(tasks) = tasks.upgrade() {
-                                            if current_peers.lock().insert(remote_id) {
-                                                debug!("Discovered peer: {:?} ({})", remote_id, disc_id);
-                                                tasks.spawn_with_name(format!("add peer {} at {}", remote_id, addr), {
-                                                    let current_peers = current_peers.clone();
-                                                    async move {
-                                                        if tokio::time::timeout(
-                                                            Duration::from_secs(DISCOVERY_CONNECT_TIMEOUT_SECS),
-                                                            server.add_peer_inner(addr, remote_id, true)
-                                                        ).await.is_err() {
-                                                            debug!("Timed out adding peer {}", remote_id);
-                                                        }
-                                                        current_peers.lock().remove(&remote_id)
-                                                    }
-                                                });
+                                }
+
+                                let distribution = server.dial_distribution();
+                                {
+                                    let current_peers = current_peers.lock();
+                                    candidates.retain(|candidate| !current_peers.contains(&candidate.record.id));
+                                }
+                                if let Some(candidate) = select_candidate(&candidates, &distribution).and_then(|i| candidates.remove(i)) {
+                                    if let Some(tasks) = tasks.upgrade() {
+                                        let NodeRecord { addr, id: remote_id } = candidate.record;
+                                        // Discovery may have reported a newer endpoint since the candidate was queued.
+                                        let addr = server.dial_endpoint(remote_id).unwrap_or(addr);
+                                        current_peers.lock().insert(remote_id);
+                                        server.streams.lock().dial_cooldown.on_dial(remote_id, Instant::now());
+                                        server.dial_distribution.lock().insert(&candidate);
+                                        debug!("Dialing peer: {:?} ({})", remote_id, candidate.source);
+                                        tasks.spawn_with_name(format!("add peer {} at {}", remote_id, addr), {
+                                            let server = server.clone();
+                                            let current_peers = current_peers.clone();
+                                            async move {
+                                                if tokio::time::timeout(
+                                                    Duration::from_secs(DISCOVERY_CONNECT_TIMEOUT_SECS),
+                                                    server.add_peer_inner(addr, remote_id, true, TcpStream::connect(addr))
+                                                ).await.is_err() {
+                                                    debug!("Timed out adding peer {}", remote_id);
+                                                }
+                                                current_peers.lock().remove(&remote_id)
                                             }
-                                        }
+                                        });
                                     }
-                                    Ok(Some((disc_id, Err(e)))) => warn!("Failed to get new peer: {} ({})", e, disc_id)
                                 }
 
-                                sleep(DIAL_INTERVAL).await;
+                                sleep(server.dial_interval()).await;
                             } else {
                                 trace!("Skipping discovery as current number of peers is too high: {} >= {}", streams_len, max_peers);
+                                // Still learn endpoints for redials, and keep the freshest candidates.
+                                for _ in 0..DIAL_CANDIDATES {
+                                    match options.discovery_tasks.next().now_or_never() {
+                                        Some(Some((source, res))) => server.add_dial_candidate(&mut candidates, &current_peers, source, res),
+                                        _ => break,
+                                    }
+                                }
                                 sleep(Duration::from_secs(2)).await;
                             }
                         } else {
@@ -691,23 +1030,53 @@ impl<C: CapabilityServer> Swarm<C> {
         &self,
         node_record: NodeRecord,
     ) -> impl Future<Output = anyhow::Result<bool>> + Send + 'static {
-        self.add_peer_inner(node_record.addr, node_record.id, false)
+        self.add_peer_inner(
+            node_record.addr,
+            node_record.id,
+            false,
+            TcpStream::connect(node_record.addr),
+        )
+    }
+
+    /// Add a new peer over QUIC, which needs a QUIC listener and is not supported by
+    /// standard Ethereum clients.
+    #[cfg(feature = "quic")]
+    pub fn add_quic_peer(
+        &self,
+        node_record: NodeRecord,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send + 'static {
+        let endpoint = self.quic.clone();
+        self.add_peer_inner(node_record.addr, node_record.id, false, async move {
+            endpoint
+                .ok_or_else(|| anyhow!("QUIC is not enabled"))?
+                .connect(node_record.addr)
+                .await
+        })
     }
 
-    fn add_peer_inner(
+    fn add_peer_inner<Io, E>(
         &self,
         addr: SocketAddr,
         remote_id: PeerId,
         check_peer: bool,
-    ) -> impl Future<Output = anyhow::Result<bool>> + Send + 'static {
+        connect: impl Future<Output = Result<Io, E>> + Send + 'static,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send + 'static
+    where
+        Io: Transport,
+        E: Into<anyhow::Error>,
+    {
         let tasks = self.tasks.clone();
         let streams = self.streams.clone();
         let node_filter = self.node_filter.clone();
+        let unlimited_dials = self.unlimited_dials;
 
-        let capability_set = self.capabilities.get_capabilities().to_vec();
+        let capability_set = self.capabilities.snapshot();
         let capability_server = self.capability_server.clone();
+        let handshake_executor = self.handshake_executor.clone();
+        let error_log_limiter = self.error_log_limiter.clone();
 
         let secret_key = self.secret_key;
+        let protocol_version = self.protocol_version;
         let client_version = self.client_version.clone();
         let port = self.port;
 
@@ -763,7 +1132,12 @@ impl<C: CapabilityServer> Swarm<C> {
                         );
                     }
                     Entry::Vacant(vacant) => {
-                        if check_peer && !node_filter.allow(connection_num, remote_id) {
+                        let allowed = if unlimited_dials {
+                            !node_filter.is_banned(remote_id)
+                        } else {
+                            node_filter.allow(connection_num, remote_id)
+                        };
+                        if check_peer && !allowed {
                             trace!("rejecting peer {}", remote_id);
                         } else {
                             debug!("connecting to peer {} at {}", remote_id, addr);
@@ -781,22 +1155,26 @@ impl<C: CapabilityServer> Swarm<C> {
 
             // Connecting to peer is a long running operation so we have to break the mutex lock.
             let peer_res = async {
-                let transport = TcpStream::connect(addr).await?;
-                PeerStream::connect(
-                    transport,
-                    secret_key,
-                    remote_id,
-                    client_version,
-                    capability_set,
-                    port,
-                )
-                .await
+                let transport = connect.await.map_err(Into::into)?;
+                handshake_executor
+                    .run(PeerStream::connect(
+                        transport,
+                        secret_key,
+                        remote_id,
+                        protocol_version,
+                        client_version,
+                        capability_set,
+                        port,
+                    ))
+                    .await
             }
             .await;
 
             let s = streams.clone();
             let mut s = s.lock();
-            let PeerStreams { mapping } = &mut *s;
+            let PeerStreams {
+                mapping, endpoints, ..
+            } = &mut *s;
 
             // Adopt the new connection if the peer has not been dropped or superseded by incoming connection.
             if let Entry::Occupied(mut peer_state) = mapping.entry(remote_id) {
@@ -805,11 +1183,14 @@ impl<C: CapabilityServer> Swarm<C> {
                         Ok(peer) => {
                             assert_eq!(peer.remote_id(), remote_id);
                             debug!("New peer connected: {}", remote_id);
+                            endpoints.on_discovered(remote_id, addr);
 
                             *peer_state.get_mut() = PeerState::Connected(setup_peer_state(
                                 Arc::downgrade(&streams),
                                 capability_server,
                                 remote_id,
+                                Some(addr),
+                                ConnectionDirection::Outbound,
                                 peer,
                             ));
 
@@ -817,7 +1198,11 @@ impl<C: CapabilityServer> Swarm<C> {
                             return Ok(true);
                         }
                         Err(e) => {
-                            debug!("peer disconnected with error {}", e);
+                            if let Some(suppressed) =
+                                error_log_limiter.check((addr.ip(), "handshake"))
+                            {
+                                debug!("peer disconnected with error {}{}", e, suppressed);
+                            }
                             peer_state.remove();
                             return Err(e);
                         }
@@ -830,10 +1215,140 @@ impl<C: CapabilityServer> Swarm<C> {
         .instrument(span!(Level::DEBUG, "add peer",))
     }
 
+    /// Drop the connection without sending Disconnect or waiting for the peer's queue,
+    /// e.g. if the peer stopped reading it. The capability server gets the disconnect
+    /// event as after any other disconnect. Returns `false` if the peer is not connected.
+    pub async fn disconnect(&self, remote_id: PeerId, reason: DisconnectReason) -> bool {
+        let state = match self.streams.lock().mapping.entry(remote_id) {
+            Entry::Occupied(entry) if entry.get().is_connected() => entry.remove(),
+            _ => return false,
+        };
+        // Cancels the peer's tasks, which may be stuck writing to the socket.
+        drop(state);
+
+        self.capability_server
+            .on_peer_event(
+                remote_id,
+                InboundEvent::Disconnect {
+                    reason: Some(reason),
+                },
+            )
+            .await;
+        true
+    }
+
     /// Returns the number of peers we're currently dialing
     pub fn dialing(&self) -> usize {
         self.currently_connecting.load(Ordering::Relaxed)
     }
+
+    /// Returns handle to capabilities advertised to new peers
+    pub fn capability_registry(&self) -> CapabilityRegistry {
+        self.capabilities.clone()
+    }
+
+    /// Returns redial statistics by the remote's disconnect reason
+    pub fn redial_stats(&self) -> RedialStats {
+        self.streams.lock().redial.stats()
+    }
+
+    /// Returns handle to peers dialed regardless of redial delays and the dial cooldown
+    pub fn redial_exemptions(&self) -> RedialExemptions {
+        self.redial_exemptions.clone()
+    }
+
+    /// Returns the interval between dials of discovered peers
+    pub fn dial_interval(&self) -> Duration {
+        Duration::from_millis(self.dial_interval_ms.load(Ordering::Relaxed))
+    }
+
+    /// Set the interval between dials of discovered peers
+    pub fn set_dial_interval(&self, interval: Duration) {
+        self.dial_interval_ms
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns handshake queue depth and duration percentiles
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        self.handshake_executor.stats()
+    }
+
+    /// Errors accepting inbound connections by class, and the one the listener has stopped on.
+    pub fn accept_errors(&self) -> AcceptErrorStats {
+        self.accept_errors.stats()
+    }
+
+    /// Where to dial the peer back: its endpoint from discovery if known, otherwise the
+    /// address of its inbound connection with the port it announced. Never the source port
+    /// of an inbound connection.
+    pub fn dial_endpoint(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.streams.lock().endpoints.resolve(peer)
+    }
+
+    /// Addresses whose inbound connections are currently dropped.
+    pub fn greylisted(&self) -> Vec<GreylistedAddr> {
+        self.greylist.lock().greylisted(Instant::now())
+    }
+
+    /// Never greylist the address, e.g. of a static peer.
+    pub fn exempt_from_greylist(&self, ip: IpAddr) {
+        self.greylist.lock().exempt(ip);
+    }
+
+    /// Returns the number of dialed peers by network and discovery source
+    pub fn dial_distribution(&self) -> DialDistributionStats {
+        let streams = self.streams.lock();
+        let mut distribution = self.dial_distribution.lock();
+        let dialing = self.dialing.lock();
+        distribution.retain(|peer| streams.mapping.contains_key(&peer) || dialing.contains(&peer));
+        distribution.stats()
+    }
+
+    fn add_dial_candidate(
+        &self,
+        candidates: &mut VecDeque<DialCandidate>,
+        current_peers: &Mutex<HashSet<PeerId>>,
+        source: String,
+        res: anyhow::Result<NodeRecord>,
+    ) {
+        let record = match res {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Failed to get new peer: {} ({})", e, source);
+                return;
+            }
+        };
+        self.streams
+            .lock()
+            .endpoints
+            .on_discovered(record.id, record.addr);
+        if current_peers.lock().contains(&record.id)
+            || candidates
+                .iter()
+                .any(|candidate| candidate.record.id == record.id)
+        {
+            return;
+        }
+        {
+            let mut streams = self.streams.lock();
+            let now = Instant::now();
+            if self.redial_exemptions.is_exempt(record.id, now) {
+                trace!("Peer {} is exempt from redial delays", record.id);
+            } else if !streams.redial.may_dial(record.id, now) {
+                trace!("Not redialing peer {} yet", record.id);
+                return;
+            } else if !streams.dial_cooldown.may_dial(record.id, now) {
+                trace!("Peer {} has been dialed recently", record.id);
+                return;
+            }
+        }
+
+        debug!("Discovered peer: {:?} ({})", record.id, source);
+        if candidates.len() >= DIAL_CANDIDATES {
+            candidates.pop_front();
+        }
+        candidates.push_back(DialCandidate { record, source });
+    }
 }
 
 impl<C: CapabilityServer> Deref for Swarm<C> {
@@ -0,0 +1,617 @@
+//! In-memory transport that misbehaves on purpose, for testing timeouts and
+//! keepalive against a slow, stalling or failing network.
+
+use crate::transport::Transport;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{
+        duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+        ReadHalf, WriteHalf,
+    },
+    sync::{mpsc, watch},
+    time::{sleep, sleep_until, Instant},
+};
+
+/// Bytes buffered between an endpoint and the link.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Largest piece of data delayed and delivered at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks on the wire, written but not yet delivered.
+const CHUNKS_IN_FLIGHT: usize = 64;
+
+/// Behaviour of one direction of the link.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConditions {
+    /// Delay of every chunk of data.
+    pub latency: Duration,
+    /// Random extra delay of every chunk, up to this value. Chunks are still
+    /// delivered in order, as they would be over TCP.
+    pub jitter: Duration,
+    /// Bytes per second, unlimited if not set. Nothing is delivered at zero.
+    pub bandwidth: Option<u64>,
+    /// Chance of the connection being dropped on every chunk written.
+    pub drop_probability: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Up,
+    /// Nothing is delivered in either direction until resumed.
+    Stalled,
+    /// Connection is closed, both endpoints see it as reset.
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    AToB,
+    BToA,
+}
+
+/// Controls the link between two transports from the test.
+#[derive(Clone, Debug)]
+pub struct LinkHandle {
+    state: Arc<watch::Sender<LinkState>>,
+    state_rx: watch::Receiver<LinkState>,
+    a_to_b: Arc<Mutex<LinkConditions>>,
+    b_to_a: Arc<Mutex<LinkConditions>>,
+}
+
+impl LinkHandle {
+    pub fn state(&self) -> LinkState {
+        *self.state_rx.borrow()
+    }
+
+    pub fn stall(&self) {
+        if self.state() == LinkState::Up {
+            let _ = self.state.send(LinkState::Stalled);
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.state() == LinkState::Stalled {
+            let _ = self.state.send(LinkState::Up);
+        }
+    }
+
+    pub fn drop_connection(&self) {
+        let _ = self.state.send(LinkState::Down);
+    }
+
+    /// Change behaviour of the link, applied from the next chunk of data.
+    pub fn set_conditions(&self, direction: Direction, conditions: LinkConditions) {
+        *match direction {
+            Direction::AToB => &self.a_to_b,
+            Direction::BToA => &self.b_to_a,
+        }
+        .lock() = conditions;
+    }
+}
+
+/// One end of an in-memory connection.
+#[derive(Debug)]
+pub struct FaultyTransport {
+    inner: DuplexStream,
+    remote_addr: SocketAddr,
+}
+
+impl AsyncRead for FaultyTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultyTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+}
+
+/// Wait until the link state matches, `Down` is returned once the link is gone.
+async fn wait_for(
+    state: &mut watch::Receiver<LinkState>,
+    f: impl Fn(LinkState) -> bool,
+) -> LinkState {
+    loop {
+        let current = *state.borrow();
+        if f(current) {
+            return current;
+        }
+        if state.changed().await.is_err() {
+            return LinkState::Down;
+        }
+    }
+}
+
+/// Take chunks written by one endpoint and put them on the wire.
+async fn send_side(
+    mut from: ReadHalf<DuplexStream>,
+    wire: mpsc::Sender<(Instant, Vec<u8>)>,
+    conditions: Arc<Mutex<LinkConditions>>,
+    state_tx: Arc<watch::Sender<LinkState>>,
+    mut state: watch::Receiver<LinkState>,
+) {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut last_delivery = Instant::now();
+    loop {
+        let n = tokio::select! {
+            res = from.read(&mut buf) => match res {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            },
+            _ = wait_for(&mut state, |s| s == LinkState::Down) => return,
+        };
+
+        let conditions = conditions.lock().clone();
+        if conditions.drop_probability > 0.0 && thread_rng().gen_bool(conditions.drop_probability) {
+            let _ = state_tx.send(LinkState::Down);
+            return;
+        }
+
+        let jitter = thread_rng().gen_range(0..=conditions.jitter.as_micros() as u64);
+        let delay = conditions.latency + Duration::from_micros(jitter);
+        last_delivery = last_delivery.max(Instant::now() + delay);
+
+        if wire.send((last_delivery, buf[..n].to_vec())).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Deliver chunks from the wire to the other endpoint.
+async fn receive_side(
+    mut wire: mpsc::Receiver<(Instant, Vec<u8>)>,
+    mut to: WriteHalf<DuplexStream>,
+    conditions: Arc<Mutex<LinkConditions>>,
+    mut state: watch::Receiver<LinkState>,
+) {
+    while let Some((deliver_at, chunk)) = wire.recv().await {
+        tokio::select! {
+            _ = sleep_until(deliver_at) => {}
+            _ = wait_for(&mut state, |s| s == LinkState::Down) => return,
+        }
+
+        if wait_for(&mut state, |s| s != LinkState::Stalled).await == LinkState::Down {
+            return;
+        }
+
+        let bandwidth = conditions.lock().bandwidth;
+        match bandwidth {
+            Some(0) => {
+                wait_for(&mut state, |s| s == LinkState::Down).await;
+                return;
+            }
+            Some(bandwidth) => {
+                let transfer = Duration::from_secs_f64(chunk.len() as f64 / bandwidth as f64);
+                tokio::select! {
+                    _ = sleep(transfer) => {}
+                    _ = wait_for(&mut state, |s| s == LinkState::Down) => return,
+                }
+            }
+            None => {}
+        }
+
+        tokio::select! {
+            res = to.write_all(&chunk) => if res.is_err() {
+                return;
+            },
+            _ = wait_for(&mut state, |s| s == LinkState::Down) => return,
+        }
+    }
+
+    // Sending endpoint has closed its side, let the other one see EOF.
+    let _ = to.shutdown().await;
+}
+
+/// Carry data in one direction, from what one endpoint writes to what the other reads.
+fn spawn_link(
+    from: ReadHalf<DuplexStream>,
+    to: WriteHalf<DuplexStream>,
+    conditions: Arc<Mutex<LinkConditions>>,
+    state_tx: Arc<watch::Sender<LinkState>>,
+    state: watch::Receiver<LinkState>,
+) {
+    let (wire_tx, wire_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+
+    tokio::spawn(send_side(
+        from,
+        wire_tx,
+        conditions.clone(),
+        state_tx,
+        state.clone(),
+    ));
+    tokio::spawn(receive_side(wire_rx, to, conditions, state));
+}
+
+/// Connected pair of transports with a link that behaves as configured.
+/// Must be called from within Tokio runtime.
+pub fn pair(
+    a_to_b: LinkConditions,
+    b_to_a: LinkConditions,
+) -> (FaultyTransport, FaultyTransport, LinkHandle) {
+    let (a, a_link) = duplex(BUFFER_SIZE);
+    let (b, b_link) = duplex(BUFFER_SIZE);
+    let (a_link_read, a_link_write) = split(a_link);
+    let (b_link_read, b_link_write) = split(b_link);
+
+    let (state_tx, state_rx) = watch::channel(LinkState::Up);
+    let state_tx = Arc::new(state_tx);
+    let handle = LinkHandle {
+        state: state_tx.clone(),
+        state_rx: state_rx.clone(),
+        a_to_b: Arc::new(Mutex::new(a_to_b)),
+        b_to_a: Arc::new(Mutex::new(b_to_a)),
+    };
+
+    spawn_link(
+        a_link_read,
+        b_link_write,
+        handle.a_to_b.clone(),
+        state_tx.clone(),
+        state_rx.clone(),
+    );
+    spawn_link(
+        b_link_read,
+        a_link_write,
+        handle.b_to_a.clone(),
+        state_tx,
+        state_rx,
+    );
+
+    let addr = |last| SocketAddr::from((Ipv4Addr::new(127, 0, 0, last), 30303));
+    (
+        FaultyTransport {
+            inner: a,
+            remote_addr: addr(2),
+        },
+        FaultyTransport {
+            inner: b,
+            remote_addr: addr(1),
+        },
+        handle,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ecies::ECIESStream, errors::HandshakeError, peer::*, types::*, util::pk2id};
+    use arrayvec::ArrayString;
+    use bytes::Bytes;
+    use futures::SinkExt;
+    use secp256k1::{PublicKey, SecretKey, SECP256K1};
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+
+    type Peer = PeerStream<FaultyTransport>;
+
+    fn capabilities() -> Vec<CapabilityInfo> {
+        vec![CapabilityInfo::new(
+            CapabilityId {
+                name: CapabilityName(ArrayString::from("eth").unwrap()),
+                version: 66,
+            },
+            17,
+        )]
+    }
+
+    async fn connect(a_to_b: LinkConditions, b_to_a: LinkConditions) -> (Peer, Peer, LinkHandle) {
+        connect_with_versions(a_to_b, b_to_a, ProtocolVersion::V5, ProtocolVersion::V5).await
+    }
+
+    async fn connect_with_versions(
+        a_to_b: LinkConditions,
+        b_to_a: LinkConditions,
+        a_version: ProtocolVersion,
+        b_version: ProtocolVersion,
+    ) -> (Peer, Peer, LinkHandle) {
+        let (a, b, handle) = pair(a_to_b, b_to_a);
+        let a_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &b_key));
+
+        let (a, b) = tokio::join!(
+            Peer::connect(a, a_key, b_id, a_version, "a".into(), capabilities(), 30303),
+            Peer::incoming(b, b_key, b_version, "b".into(), capabilities(), 30303),
+        );
+        (a.unwrap(), b.unwrap(), handle)
+    }
+
+    fn message(data: Vec<u8>) -> PeerMessage {
+        PeerMessage::Subprotocol(SubprotocolMessage {
+            cap_name: CapabilityName(ArrayString::from("eth").unwrap()),
+            message: Message {
+                id: 0x04,
+                data: Bytes::from(data),
+            },
+        })
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|_| thread_rng().gen()).collect()
+    }
+
+    #[tokio::test]
+    async fn large_body_under_constrained_bandwidth() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            bandwidth: Some(4 * 1024 * 1024),
+            drop_probability: 0.0,
+        };
+        let (mut a, mut b, handle) = connect(conditions.clone(), conditions).await;
+
+        // Incompressible, so that it takes about half a second on the wire.
+        let data = random_bytes(2 * 1024 * 1024);
+        let (sent, received) = tokio::join!(a.send(message(data.clone())), b.next());
+        sent.unwrap();
+
+        match received {
+            Some(Ok(PeerMessage::Subprotocol(SubprotocolMessage { message, .. }))) => {
+                assert_eq!(message.data, data)
+            }
+            other => panic!("unexpected message: {:?}", other.map(|m| m.map(|_| ()))),
+        }
+        assert_eq!(handle.state(), LinkState::Up);
+    }
+
+    #[tokio::test]
+    async fn v4_peer_talks_to_v5_peer() {
+        let (mut a, mut b, _handle) = connect_with_versions(
+            Default::default(),
+            Default::default(),
+            ProtocolVersion::V4,
+            ProtocolVersion::V5,
+        )
+        .await;
+
+        // Without compression on either side, both ends agree on the payload.
+        let data = vec![0; 1024];
+        for (sender, receiver) in [(&mut a, &mut b), (&mut b, &mut a)] {
+            sender.send(message(data.clone())).await.unwrap();
+            match receiver.next().await {
+                Some(Ok(PeerMessage::Subprotocol(SubprotocolMessage { message, .. }))) => {
+                    assert_eq!(message.data, data)
+                }
+                other => panic!("unexpected message: {:?}", other.map(|m| m.map(|_| ()))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn status_sent_with_hello_is_not_lost() {
+        let (a, b, _handle) = pair(Default::default(), Default::default());
+        let a_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let a_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &a_key));
+        let b_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &b_key));
+
+        // Scripted peer sends Hello and Status in one write, without waiting for our Hello.
+        let remote = async move {
+            let mut stream = ECIESStream::connect(a, a_key, b_id).await.unwrap();
+            let mut hello = rlp::encode(&0_usize);
+            hello.extend_from_slice(&rlp::encode(&HelloMessage {
+                protocol_version: 5,
+                client_version: "fast".into(),
+                capabilities: vec![CapabilityMessage {
+                    name: CapabilityName(ArrayString::from("eth").unwrap()),
+                    version: 66,
+                }],
+                port: 30303,
+                id: a_id,
+            }));
+            let mut status = rlp::encode(&0x10_usize);
+            status.extend_from_slice(&snap::raw::Encoder::new().compress_vec(b"status").unwrap());
+            stream.feed(hello.freeze()).await.unwrap();
+            stream.feed(status.freeze()).await.unwrap();
+            stream.flush().await.unwrap();
+            stream
+        };
+        let (_remote, peer) = tokio::join!(
+            remote,
+            Peer::incoming(
+                b,
+                b_key,
+                ProtocolVersion::V5,
+                "b".into(),
+                capabilities(),
+                30303
+            )
+        );
+        let mut peer = peer.unwrap();
+
+        let received = timeout(Duration::from_secs(1), peer.next()).await.unwrap();
+        match received {
+            Some(Ok(PeerMessage::Subprotocol(SubprotocolMessage { cap_name, message }))) => {
+                assert_eq!(cap_name.0.as_str(), "eth");
+                assert_eq!(message.id, 0);
+                assert_eq!(&message.data[..], b"status");
+            }
+            other => panic!("unexpected message: {:?}", other.map(|m| m.map(|_| ()))),
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_with_other_id_fails_handshake() {
+        let (a, b, _handle) = pair(Default::default(), Default::default());
+        let a_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let a_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &a_key));
+        let b_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &b_key));
+        let other_id = PeerId::from_low_u64_be(7);
+
+        // Scripted peer authenticates as `a_id`, but claims `other_id` in Hello.
+        let remote = async move {
+            let mut stream = ECIESStream::connect(a, a_key, b_id).await.unwrap();
+            let mut hello = rlp::encode(&0_usize);
+            hello.extend_from_slice(&rlp::encode(&HelloMessage {
+                protocol_version: 4,
+                client_version: "relay".into(),
+                capabilities: vec![CapabilityMessage {
+                    name: CapabilityName(ArrayString::from("eth").unwrap()),
+                    version: 66,
+                }],
+                port: 30303,
+                id: other_id,
+            }));
+            stream.send(hello.freeze()).await.unwrap();
+
+            // Our Hello, then the disconnect.
+            let hello = stream.next().await.unwrap().unwrap();
+            assert_eq!(hello[0], 0x00);
+            stream.next().await.unwrap().unwrap()
+        };
+        let (disconnect, peer) = tokio::join!(
+            remote,
+            Peer::incoming(
+                b,
+                b_key,
+                ProtocolVersion::V5,
+                "b".into(),
+                capabilities(),
+                30303
+            )
+        );
+
+        assert_eq!(
+            &disconnect[..],
+            &[0x01, DisconnectReason::UnexpectedHandshakeIdentity as u8][..]
+        );
+        match peer.unwrap_err().downcast::<HandshakeError>() {
+            Ok(HandshakeError::IdentityMismatch {
+                authenticated,
+                hello,
+            }) => {
+                assert_eq!(authenticated, a_id);
+                assert_eq!(hello, other_id);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_is_signed_by_session_key() {
+        let (a, b, _handle) = pair(Default::default(), Default::default());
+        let a_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let b_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &b_key));
+
+        let (a, b) = tokio::join!(
+            ECIESStream::connect(a, a_key, b_id),
+            ECIESStream::incoming(b, b_key),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.local_id(), b.remote_id());
+
+        // Hello of another key than the session's is never sent.
+        let other_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        assert!(Peer::new(
+            a,
+            other_key,
+            ProtocolVersion::V5,
+            "a".into(),
+            capabilities(),
+            30303
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn stall_delays_ping_until_resumed() {
+        let (mut a, mut b, handle) = connect(Default::default(), Default::default()).await;
+
+        handle.stall();
+        a.send(PeerMessage::Ping).await.unwrap();
+        // Peer that stops answering is seen as a ping timeout by the swarm.
+        assert!(timeout(Duration::from_millis(200), b.next()).await.is_err());
+
+        handle.resume();
+        let received = timeout(Duration::from_secs(1), b.next()).await.unwrap();
+        assert!(matches!(received, Some(Ok(PeerMessage::Ping))));
+    }
+
+    #[tokio::test]
+    async fn drop_resets_both_ends() {
+        let (mut a, mut b, handle) = connect(Default::default(), Default::default()).await;
+
+        handle.drop_connection();
+        let received = timeout(Duration::from_secs(1), b.next()).await.unwrap();
+        assert!(!matches!(received, Some(Ok(_))));
+
+        let mut failed = false;
+        for _ in 0..16 {
+            if a.send(message(random_bytes(1024))).await.is_err() {
+                failed = true;
+                break;
+            }
+        }
+        assert!(failed);
+    }
+
+    #[tokio::test]
+    async fn random_drop_resets_connection() {
+        let (mut a, mut b, handle) = connect(Default::default(), Default::default()).await;
+
+        handle.set_conditions(
+            Direction::AToB,
+            LinkConditions {
+                drop_probability: 1.0,
+                ..Default::default()
+            },
+        );
+        let _ = a.send(PeerMessage::Ping).await;
+        let received = timeout(Duration::from_secs(1), b.next()).await.unwrap();
+        assert!(!matches!(received, Some(Ok(_))));
+        assert_eq!(handle.state(), LinkState::Down);
+    }
+
+    #[tokio::test]
+    async fn zero_bandwidth_blocks_writer() {
+        let (mut a, _b, handle) = connect(Default::default(), Default::default()).await;
+
+        handle.set_conditions(
+            Direction::AToB,
+            LinkConditions {
+                bandwidth: Some(0),
+                ..Default::default()
+            },
+        );
+        // More than the link can buffer, so the write never completes.
+        let data = random_bytes(4 * CHUNK_SIZE * CHUNKS_IN_FLIGHT);
+        assert!(timeout(Duration::from_millis(500), a.send(message(data)))
+            .await
+            .is_err());
+    }
+}
@@ -1,7 +1,8 @@
-use std::{fmt::Debug, net::SocketAddr};
+use async_trait::async_trait;
+use std::{fmt::Debug, io, net::SocketAddr};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
+    net::{TcpListener, TcpStream},
 };
 
 pub trait Transport: AsyncRead + AsyncWrite + Debug + Send + Unpin + 'static {
@@ -13,3 +14,20 @@ impl Transport for TcpStream {
         self.peer_addr().ok()
     }
 }
+
+/// Source of inbound connections.
+#[async_trait]
+pub trait Listener: Send + 'static {
+    type Transport: Transport;
+
+    async fn accept(&mut self) -> io::Result<(Self::Transport, SocketAddr)>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    type Transport = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
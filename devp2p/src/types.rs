@@ -37,6 +37,17 @@ impl FromStr for NodeRecord {
     }
 }
 
+impl std::fmt::Display for NodeRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "enode://{}@{}",
+            hex::encode(self.id.as_bytes()),
+            self.addr
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CapabilityName(pub ArrayString<[u8; 4]>);
 
@@ -92,6 +103,26 @@ impl From<CapabilityInfo> for CapabilityId {
     }
 }
 
+/// Which side opened the connection.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ConnectionDirection {
+    /// Remote peer connected to us.
+    #[display(fmt = "inbound")]
+    Inbound,
+    /// We dialed the remote peer.
+    #[display(fmt = "outbound")]
+    Outbound,
+}
+
+impl ConnectionDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Inbound => "inbound",
+            Self::Outbound => "outbound",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Display)]
 pub enum InboundEvent {
     #[display(
@@ -121,7 +152,17 @@ pub enum OutboundEvent {
 #[auto_impl(&, Box, Arc)]
 pub trait CapabilityServer: Send + Sync + 'static {
     /// Should be used to set up relevant state for the peer.
-    fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>);
+    /// `addr` is the address the peer accepts connections on, if known.
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        addr: Option<SocketAddr>,
+        direction: ConnectionDirection,
+        caps: HashMap<CapabilityName, CapabilityVersion>,
+    );
+    /// Called with the client version the peer has announced in Hello, right before
+    /// `on_peer_connect`.
+    fn on_peer_client_version(&self, _peer: PeerId, _client_version: &str) {}
     /// Called on the next event for peer.
     async fn on_peer_event(&self, peer: PeerId, event: InboundEvent);
     /// Get the next event for peer.
@@ -130,7 +171,14 @@ pub trait CapabilityServer: Send + Sync + 'static {
 
 #[async_trait]
 impl CapabilityServer for () {
-    fn on_peer_connect(&self, _: PeerId, _: HashMap<CapabilityName, CapabilityVersion>) {}
+    fn on_peer_connect(
+        &self,
+        _: PeerId,
+        _: Option<SocketAddr>,
+        _: ConnectionDirection,
+        _: HashMap<CapabilityName, CapabilityVersion>,
+    ) {
+    }
 
     async fn on_peer_event(&self, _: PeerId, _: InboundEvent) {}
 
@@ -3,8 +3,9 @@ use array_init::array_init;
 use arrayvec::ArrayVec;
 use primitive_types::H256;
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     convert::TryFrom,
+    time::Instant,
 };
 use tracing::*;
 
@@ -20,10 +21,21 @@ pub fn distance(n1: NodeId, n2: NodeId) -> H256 {
 
 pub type NodeBucket = ArrayVec<[NodeRecord; BUCKET_SIZE]>;
 
+/// Routing table entry, for diagnostics.
+#[derive(Clone, Copy, Debug)]
+pub struct TableEntry {
+    pub record: NodeRecord,
+    /// When the node was last added or verified.
+    pub last_seen: Option<Instant>,
+    /// Log distance from our own ID, which is also the bucket index.
+    pub distance_from_self: usize,
+}
+
 #[derive(Debug, Default)]
 pub struct KBucket {
     bucket: VecDeque<NodeRecord>,
     replacements: VecDeque<NodeRecord>,
+    last_seen: HashMap<NodeId, Instant>,
 }
 
 impl KBucket {
@@ -39,6 +51,7 @@ impl KBucket {
 
     pub fn push_replacement(&mut self, peer: NodeRecord) {
         if self.replacements.len() < REPLACEMENTS_SIZE {
+            self.last_seen.insert(peer.id, Instant::now());
             self.replacements.push_back(peer)
         }
     }
@@ -139,6 +152,7 @@ impl Table {
 
             // Push to front of bucket if we have less than BUCKET_SIZE peers, or we are shuffling existing peer...
             if bucket.bucket.len() < BUCKET_SIZE {
+                bucket.last_seen.insert(node.id, Instant::now());
                 bucket.bucket.push_front(node);
             } else {
                 // ...add to replacements otherwise
@@ -163,6 +177,7 @@ impl Table {
 
             // Push to back of bucket if we have less than BUCKET_SIZE peers...
             if bucket.bucket.len() < BUCKET_SIZE {
+                bucket.last_seen.insert(node.id, Instant::now());
                 bucket.bucket.push_back(node);
             } else {
                 // ...add to replacements otherwise
@@ -188,6 +203,7 @@ impl Table {
                         .expect("already returned if no replacement");
                     trace!("Replacing with {:?}", replacement);
                     bucket.bucket.remove(i);
+                    bucket.last_seen.remove(&node);
                     bucket.bucket.push_back(replacement);
 
                     return;
@@ -221,6 +237,21 @@ impl Table {
             .collect()
     }
 
+    /// All nodes in the routing table, excluding replacements.
+    pub fn entries(&self) -> Vec<TableEntry> {
+        self.kbuckets
+            .iter()
+            .enumerate()
+            .flat_map(|(distance_from_self, kbucket)| {
+                kbucket.bucket.iter().map(move |record| TableEntry {
+                    record: *record,
+                    last_seen: kbucket.last_seen.get(&record.id).copied(),
+                    distance_from_self,
+                })
+            })
+            .collect()
+    }
+
     pub fn len(&self) -> usize {
         self.kbuckets
             .iter()
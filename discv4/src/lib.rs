@@ -11,4 +11,7 @@ mod util;
 use primitive_types::H512;
 
 pub type NodeId = H512;
-pub use crate::node::{Node, NodeRecord};
+pub use crate::{
+    kad::TableEntry,
+    node::{Node, NodeRecord},
+};
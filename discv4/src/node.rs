@@ -47,9 +47,10 @@ pub const FIND_NODE_TIMEOUT: Duration = Duration::from_secs(10);
 pub const QUERY_AWAIT_PING_TIME: Duration = Duration::from_secs(2);
 pub const NEIGHBOURS_WAIT_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// Packet expiration is wall clock time by the protocol. A clock stepped before the epoch
+/// only makes peers drop our packets, so it is clamped instead of taking the node down.
 fn expiry(timeout: Duration) -> u64 {
-    u64::try_from(Utc::now().timestamp()).expect("this would predate the protocol inception")
-        + timeout.as_secs()
+    u64::try_from(Utc::now().timestamp()).unwrap_or(0) + timeout.as_secs()
 }
 
 fn ping_expiry() -> u64 {
@@ -880,4 +881,9 @@ impl Node {
     pub fn num_nodes(&self) -> usize {
         self.connected.lock().len()
     }
+
+    /// Snapshot of the routing table.
+    pub fn table_entries(&self) -> Vec<TableEntry> {
+        self.connected.lock().entries()
+    }
 }
@@ -0,0 +1,162 @@
+use crate::{header_cache::MAX_HEADERS_SERVE, response_quality::DIRECTED_REQUEST_TIMEOUT};
+use devp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Weight of the latest sample in the moving average.
+const EMA_ALPHA: f64 = 0.1;
+/// Requests of a peer awaiting the control's response that are tracked.
+const MAX_PENDING_REQUESTS: usize = 64;
+
+#[derive(Debug, Default)]
+struct PeerLatency {
+    pending: VecDeque<Instant>,
+    /// Moving average of response time in seconds.
+    average: Option<f64>,
+}
+
+impl PeerLatency {
+    /// Forget requests the control has not answered in time. Their responses, if any,
+    /// are not sampled.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&sent) = self.pending.front() {
+            if now.saturating_duration_since(sent) < DIRECTED_REQUEST_TIMEOUT {
+                break;
+            }
+            self.pending.pop_front();
+        }
+    }
+}
+
+/// Tracks how long the control takes to answer GetBlockHeaders of each peer
+/// and limits the number of requested headers while it is slow.
+#[derive(Debug)]
+pub struct AdaptiveHeaders {
+    threshold: Duration,
+    peers: HashMap<PeerId, PeerLatency>,
+}
+
+impl AdaptiveHeaders {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            peers: Default::default(),
+        }
+    }
+
+    /// GetBlockHeaders of the peer has been forwarded to the control.
+    pub fn on_request(&mut self, peer: PeerId, now: Instant) {
+        let latency = self.peers.entry(peer).or_default();
+        latency.expire(now);
+        if latency.pending.len() >= MAX_PENDING_REQUESTS {
+            latency.pending.pop_front();
+        }
+        latency.pending.push_back(now);
+    }
+
+    /// Control has answered the oldest GetBlockHeaders of the peer that has not expired.
+    pub fn on_response(&mut self, peer: PeerId, now: Instant) {
+        if let Some(latency) = self.peers.get_mut(&peer) {
+            latency.expire(now);
+            if let Some(sent) = latency.pending.pop_front() {
+                let sample = now.saturating_duration_since(sent).as_secs_f64();
+                latency.average = Some(match latency.average {
+                    Some(average) => average + EMA_ALPHA * (sample - average),
+                    None => sample,
+                });
+            }
+        }
+    }
+
+    pub fn average_latency(&self, peer: PeerId) -> Option<Duration> {
+        self.peers.get(&peer)?.average.map(Duration::from_secs_f64)
+    }
+
+    /// Maximum number of headers the control is asked for on behalf of the peer.
+    pub fn max_headers(&self, peer: PeerId) -> u64 {
+        match self.average_latency(peer) {
+            Some(average) if average > self.threshold => MAX_HEADERS_SERVE / 2,
+            _ => MAX_HEADERS_SERVE,
+        }
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_follows_latency() {
+        let threshold = Duration::from_millis(500);
+        let mut headers = AdaptiveHeaders::new(threshold);
+        let peer = PeerId::from_low_u64_be(1);
+        let mut now = Instant::now();
+
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE);
+
+        let mut request = |headers: &mut AdaptiveHeaders, latency: Duration| {
+            headers.on_request(peer, now);
+            now += latency;
+            headers.on_response(peer, now);
+        };
+
+        request(&mut headers, Duration::from_millis(100));
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE);
+
+        // Single slow response moves the average only slightly.
+        request(&mut headers, Duration::from_secs(2));
+        let average = headers.average_latency(peer).unwrap();
+        assert!(average > Duration::from_millis(289) && average < Duration::from_millis(291));
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE);
+
+        while headers.average_latency(peer).unwrap() <= threshold {
+            request(&mut headers, Duration::from_secs(2));
+        }
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE / 2);
+
+        while headers.average_latency(peer).unwrap() > threshold {
+            request(&mut headers, Duration::from_millis(100));
+        }
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE);
+
+        headers.on_disconnect(peer);
+        assert_eq!(headers.average_latency(peer), None);
+    }
+
+    #[test]
+    fn unanswered_requests_expire() {
+        let threshold = Duration::from_millis(500);
+        let mut headers = AdaptiveHeaders::new(threshold);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        // The control never answers the first request.
+        headers.on_request(peer, now);
+        let later = now + DIRECTED_REQUEST_TIMEOUT;
+        for i in 0..10 {
+            let sent = later + Duration::from_secs(i);
+            headers.on_request(peer, sent);
+            headers.on_response(peer, sent + Duration::from_millis(100));
+        }
+        assert_eq!(
+            headers.average_latency(peer),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(headers.max_headers(peer), MAX_HEADERS_SERVE);
+
+        // Response to a request that has expired is not sampled.
+        let sent = later + Duration::from_secs(20);
+        headers.on_request(peer, sent);
+        headers.on_response(peer, sent + DIRECTED_REQUEST_TIMEOUT);
+        assert_eq!(
+            headers.average_latency(peer),
+            Some(Duration::from_millis(100))
+        );
+    }
+}
@@ -0,0 +1,820 @@
+use crate::{
+    api_auth::ApiToken,
+    breaches::BreachRecord,
+    decode,
+    effective_config::EffectiveConfig,
+    eth::{EthMessageId, FullStatusData},
+    header_cache,
+    message_log::{Direction, PeerMessageLogEntry},
+    metrics::Metrics,
+    peer_watch::{self, PeerRecord, PeerRecordChange, WatchUpdate},
+    self_test::{self, RuntimeChecks},
+    served::{ServedKind, ServedSource},
+    tasks::TaskInfo,
+    CapabilityServerImpl, TOP_CONSUMERS,
+};
+use anyhow::Context;
+use arrayvec::ArrayString;
+use devp2p::{CapabilityId, CapabilityName, ConnectionDirection, DisconnectReason, PeerId};
+use futures::{future::poll_fn, Stream, StreamExt};
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn, Service},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    fmt::Display,
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tonic::body::BoxBody;
+use tracing::*;
+
+/// How long `POST /peers/:id/refresh` waits for the peer to connect again.
+const REFRESH_DEADLINE: Duration = Duration::from_secs(30);
+
+fn peer_json(record: &PeerRecord) -> Value {
+    json!({
+        "id": hex::encode(record.id.as_bytes()),
+        "protocol_version": record.protocol_version,
+        "valid": record.valid,
+        "syncing": record.syncing,
+        "passive": record.passive,
+        "min_block": record.min_block,
+        "labels": record.labels,
+        "connection_id": record.connection_id.map(|id| id.to_string()),
+    })
+}
+
+fn change_json(id: PeerId, change: &PeerRecordChange) -> Value {
+    let mut v = serde_json::Map::new();
+    v.insert("id".into(), hex::encode(id.as_bytes()).into());
+    if let Some(protocol_version) = change.protocol_version {
+        v.insert("protocol_version".into(), protocol_version.into());
+    }
+    if let Some(valid) = change.valid {
+        v.insert("valid".into(), valid.into());
+    }
+    if let Some(syncing) = change.syncing {
+        v.insert("syncing".into(), syncing.into());
+    }
+    if let Some(passive) = change.passive {
+        v.insert("passive".into(), passive.into());
+    }
+    if let Some(min_block) = change.min_block {
+        v.insert("min_block".into(), min_block.into());
+    }
+    if let Some(labels) = &change.labels {
+        v.insert("labels".into(), json!(labels));
+    }
+    if let Some(connection_id) = change.connection_id {
+        v.insert(
+            "connection_id".into(),
+            connection_id.map(|id| id.to_string()).into(),
+        );
+    }
+    v.into()
+}
+
+fn watch_json(update: WatchUpdate) -> Value {
+    match update {
+        WatchUpdate::Snapshot(records) => {
+            json!({ "snapshot": records.iter().map(peer_json).collect::<Vec<_>>() })
+        }
+        WatchUpdate::Diff(events) => json!({
+            "diff": events
+                .iter()
+                .map(|event| match event {
+                    peer_watch::PeerEvent::Added(record) => json!({ "added": peer_json(record) }),
+                    peer_watch::PeerEvent::Removed(id) => {
+                        json!({ "removed": hex::encode(id.as_bytes()) })
+                    }
+                    peer_watch::PeerEvent::Changed { id, change } => {
+                        json!({ "changed": change_json(*id, change) })
+                    }
+                })
+                .collect::<Vec<_>>(),
+        }),
+    }
+}
+
+fn breach_json(record: &BreachRecord) -> Value {
+    json!({
+        "id": hex::encode(record.peer.as_bytes()),
+        "reason": record.reason.to_string(),
+        "timestamp": record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        "messages": record
+            .messages
+            .iter()
+            .map(|sample| json!({
+                "id": sample.message_id,
+                "size": sample.size,
+                "prefix": hex::encode(&sample.prefix),
+            }))
+            .collect::<Vec<_>>(),
+        "dump": record.dump.as_ref().map(|path| path.display().to_string()),
+    })
+}
+
+fn message_log_json(entry: &PeerMessageLogEntry, now: Instant) -> Value {
+    json!({
+        "direction": match entry.direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        },
+        "id": entry.message_id,
+        "size": entry.size,
+        "age_secs": now.saturating_duration_since(entry.timestamp).as_secs_f64(),
+    })
+}
+
+fn task_json(task: &TaskInfo, now: Instant) -> Value {
+    json!({
+        "name": task.name,
+        "owner": task.owner.to_string(),
+        "age_secs": now.saturating_duration_since(task.started).as_secs_f64(),
+    })
+}
+
+fn table_entry_json(entry: &discv4::TableEntry, now: Instant) -> Value {
+    json!({
+        "id": hex::encode(entry.record.id.as_bytes()),
+        "address": entry.record.address.to_string(),
+        "tcp_port": entry.record.tcp_port,
+        "udp_port": entry.record.udp_port,
+        "distance": entry.distance_from_self,
+        "last_seen_age_secs": entry
+            .last_seen
+            .map(|last_seen| now.saturating_duration_since(last_seen).as_secs_f64()),
+    })
+}
+
+fn status_json(status: &FullStatusData) -> Value {
+    let fork_id = status.fork_filter.current();
+    json!({
+        "network_id": status.status.network_id,
+        "total_difficulty": status.status.total_difficulty.to_string(),
+        "best_hash": hex::encode(status.status.best_hash.as_bytes()),
+        "best_block": status.status.best_block,
+        "genesis_hash": hex::encode(status.status.fork_data.genesis.as_bytes()),
+        "fork_id": {
+            "hash": hex::encode(fork_id.hash.0),
+            "next": fork_id.next,
+        },
+    })
+}
+
+fn served_json(capability_server: &CapabilityServerImpl) -> Value {
+    let mut total = serde_json::Map::new();
+    for &kind in &[ServedKind::Headers, ServedKind::Bodies] {
+        let mut by_source = serde_json::Map::new();
+        for &source in &[ServedSource::Cache, ServedSource::Control] {
+            by_source.insert(
+                source.as_str().into(),
+                json!(capability_server.served_total(kind, source)),
+            );
+        }
+        total.insert(kind.as_str().into(), by_source.into());
+    }
+
+    json!({
+        "total": total,
+        "top_consumers": capability_server
+            .top_consumers(TOP_CONSUMERS)
+            .into_iter()
+            .map(|(peer, served)| json!({
+                "id": hex::encode(peer.as_bytes()),
+                "items": served.items,
+                "bytes": served.bytes,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[derive(Deserialize)]
+struct DecodeRequest {
+    /// Only `eth` is supported.
+    capability: Option<String>,
+    version: u8,
+    /// Wire ID of the message at `version`.
+    message_id: usize,
+    /// Hex, optionally prefixed with `0x`.
+    payload: String,
+}
+
+async fn decode_response(body: Body) -> Response<Body> {
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let req = match serde_json::from_slice::<DecodeRequest>(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return error_response(StatusCode::BAD_REQUEST, format!("invalid request: {}", e))
+        }
+    };
+    if let Some(capability) = req.capability.filter(|c| c != "eth") {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            format!("unsupported capability {}", capability),
+        );
+    }
+    let id = match EthMessageId::from_wire(req.version.into(), req.message_id) {
+        Some(v) => v,
+        None => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("no message {} in eth/{}", req.message_id, req.version),
+            )
+        }
+    };
+    let payload = match hex::decode(req.payload.trim_start_matches("0x")) {
+        Ok(v) => v,
+        Err(e) => {
+            return error_response(StatusCode::BAD_REQUEST, format!("invalid payload: {}", e))
+        }
+    };
+
+    match decode::decode_message(id, req.version, &payload) {
+        Ok(mut v) => {
+            v["message_id"] = format!("{:?}", id).into();
+            json_response(StatusCode::OK, v)
+        }
+        Err(e) => json_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            json!({ "error": e.error, "offset": e.offset }),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct PushHeadersRequest {
+    /// RLP encoded headers in hex, optionally prefixed with `0x`.
+    headers: Vec<String>,
+}
+
+async fn push_headers_response(
+    capability_server: &CapabilityServerImpl,
+    body: Body,
+) -> Response<Body> {
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let req = match serde_json::from_slice::<PushHeadersRequest>(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return error_response(StatusCode::BAD_REQUEST, format!("invalid request: {}", e))
+        }
+    };
+    let mut headers = Vec::with_capacity(req.headers.len());
+    for (i, header) in req.headers.iter().enumerate() {
+        let rlp = match hex::decode(header.trim_start_matches("0x")) {
+            Ok(v) => v,
+            Err(e) => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid header {}: {}", i, e),
+                )
+            }
+        };
+        match header_cache::parse_header(rlp.into()) {
+            Ok(v) => headers.push(v),
+            Err(e) => {
+                return error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("invalid header {}: {}", i, e),
+                )
+            }
+        }
+    }
+
+    let pushed = headers.len();
+    if !capability_server.push_headers(headers) {
+        return error_response(StatusCode::CONFLICT, "header cache is disabled");
+    }
+    json_response(StatusCode::OK, json!({ "pushed": pushed }))
+}
+
+#[derive(Deserialize)]
+struct SetLabelRequest {
+    value: String,
+    #[serde(default)]
+    persistent: bool,
+}
+
+async fn set_label_response(
+    capability_server: &CapabilityServerImpl,
+    peer: PeerId,
+    key: &str,
+    body: Option<Body>,
+) -> Response<Body> {
+    let req = match body {
+        Some(body) => {
+            let body = match hyper::body::to_bytes(body).await {
+                Ok(v) => v,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+            };
+            match serde_json::from_slice::<SetLabelRequest>(&body) {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid request: {}", e),
+                    )
+                }
+            }
+        }
+        // Empty value removes the label.
+        None => SetLabelRequest {
+            value: String::new(),
+            persistent: false,
+        },
+    };
+
+    match capability_server.set_peer_label(peer, key.to_string(), req.value, req.persistent) {
+        Ok(()) => json_response(
+            StatusCode::OK,
+            json!({ "labels": capability_server.peer_labels(peer) }),
+        ),
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, error: impl ToString) -> Response<Body> {
+    json_response(status, json!({ "error": error.to_string() }))
+}
+
+/// Newline delimited JSON, a line per item, until the stream ends or the client goes away.
+fn ndjson_response(stream: impl Stream<Item = Value> + Send + 'static) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(
+            stream.map(|v| Ok::<_, Infallible>(format!("{}\n", v))),
+        ))
+        .unwrap()
+}
+
+async fn handle(
+    capability_server: &CapabilityServerImpl,
+    metrics: &Metrics,
+    effective_config: &EffectiveConfig,
+    runtime_checks: &RuntimeChecks,
+    req: Request<Body>,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let path = parts.uri.path().trim_end_matches('/');
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    match (&parts.method, segments.as_slice()) {
+        (&Method::GET, ["peers"]) => json_response(
+            StatusCode::OK,
+            Value::Array(
+                capability_server
+                    .peer_snapshot()
+                    .values()
+                    .map(peer_json)
+                    .collect(),
+            ),
+        ),
+        (&Method::POST, ["peers", "disconnect-all"]) => {
+            let disconnected = capability_server
+                .disconnect_all_peers(DisconnectReason::DisconnectRequested)
+                .await;
+            json_response(StatusCode::OK, json!({ "disconnected": disconnected }))
+        }
+        (&Method::GET, ["peers", "watch"]) => {
+            ndjson_response(capability_server.watch_peers().map(watch_json))
+        }
+        (&Method::GET, ["peers", "enode"]) => json_response(
+            StatusCode::OK,
+            capability_server.connected_enode_urls().into(),
+        ),
+        (&Method::GET, ["peers", id, "messages"]) => {
+            let peer = match id.parse::<PeerId>() {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid peer id: {}", e),
+                    )
+                }
+            };
+            let last_n = match parts
+                .uri
+                .query()
+                .and_then(|query| query.split('&').find_map(|p| p.strip_prefix("last=")))
+            {
+                Some(last_n) => match last_n.parse() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return error_response(
+                            StatusCode::BAD_REQUEST,
+                            format!("invalid last: {}", e),
+                        )
+                    }
+                },
+                None => usize::MAX,
+            };
+
+            let now = Instant::now();
+            json_response(
+                StatusCode::OK,
+                capability_server
+                    .peer_message_log(peer, last_n)
+                    .iter()
+                    .map(|entry| message_log_json(entry, now))
+                    .collect(),
+            )
+        }
+        (&Method::POST, ["peers", id, "refresh"]) => match id.parse::<PeerId>() {
+            Ok(peer) => match capability_server.refresh_peer(peer, REFRESH_DEADLINE).await {
+                Ok(reconnected) => {
+                    json_response(StatusCode::OK, json!({ "reconnected": reconnected }))
+                }
+                Err(e) => error_response(StatusCode::CONFLICT, e),
+            },
+            Err(e) => error_response(StatusCode::BAD_REQUEST, format!("invalid peer id: {}", e)),
+        },
+        (method, ["peers", id, "labels", key]) => {
+            let peer = match id.parse::<PeerId>() {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid peer id: {}", e),
+                    )
+                }
+            };
+
+            match *method {
+                Method::PUT => set_label_response(capability_server, peer, key, Some(body)).await,
+                Method::DELETE => set_label_response(capability_server, peer, key, None).await,
+                _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (method, ["peers", id]) => {
+            let peer = match id.parse::<PeerId>() {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid peer id: {}", e),
+                    )
+                }
+            };
+
+            match *method {
+                Method::GET => match capability_server.peer_snapshot().get(&peer) {
+                    Some(record) => json_response(StatusCode::OK, peer_json(record)),
+                    None => error_response(StatusCode::NOT_FOUND, "peer is not connected"),
+                },
+                Method::DELETE => {
+                    if capability_server
+                        .disconnect_peer(peer, DisconnectReason::DisconnectRequested)
+                        .await
+                    {
+                        Response::builder()
+                            .status(StatusCode::NO_CONTENT)
+                            .body(Body::empty())
+                            .unwrap()
+                    } else {
+                        error_response(StatusCode::NOT_FOUND, "peer is not connected")
+                    }
+                }
+                _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (&Method::GET, ["whitelist"]) => json_response(
+            StatusCode::OK,
+            Value::Array(
+                capability_server
+                    .whitelisted_peers()
+                    .into_iter()
+                    .map(|peer| hex::encode(peer.as_bytes()).into())
+                    .collect(),
+            ),
+        ),
+        (method, ["whitelist", id]) => {
+            let peer = match id.parse::<PeerId>() {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid peer id: {}", e),
+                    )
+                }
+            };
+
+            let changed = match *method {
+                Method::PUT => capability_server.whitelist_peer(peer),
+                Method::DELETE => capability_server.unwhitelist_peer(peer).await,
+                _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            };
+            json_response(StatusCode::OK, json!({ "changed": changed }))
+        }
+        (&Method::GET, ["bans"]) => json_response(
+            StatusCode::OK,
+            capability_server
+                .banned_peers()
+                .into_iter()
+                .map(|(peer, left, provenance)| {
+                    json!({
+                        "id": hex::encode(peer.as_bytes()),
+                        "expires_in_secs": left.as_secs(),
+                        "provenance": provenance.as_str(),
+                    })
+                })
+                .collect(),
+        ),
+        (&Method::DELETE, ["bans", id]) => match id.parse::<PeerId>() {
+            Ok(peer) => json_response(
+                StatusCode::OK,
+                json!({ "changed": capability_server.unban_peer(peer) }),
+            ),
+            Err(e) => error_response(StatusCode::BAD_REQUEST, format!("invalid peer id: {}", e)),
+        },
+        (&Method::GET, ["status"]) => match capability_server.status() {
+            Some(status) => {
+                let mut v = status_json(&status);
+                v["fork_mismatch_suspected"] = capability_server.fork_mismatch_suspected().into();
+                json_response(StatusCode::OK, v)
+            }
+            None => error_response(StatusCode::NOT_FOUND, "status has not been set yet"),
+        },
+        (&Method::GET, ["served"]) => json_response(StatusCode::OK, served_json(capability_server)),
+        (&Method::GET, ["lifetimes"]) => json_response(
+            StatusCode::OK,
+            json!({
+                "inbound": capability_server.connection_lifetimes(ConnectionDirection::Inbound),
+                "outbound": capability_server.connection_lifetimes(ConnectionDirection::Outbound),
+            }),
+        ),
+        (&Method::GET, ["forwarding"]) => json_response(
+            StatusCode::OK,
+            capability_server
+                .forward_queue_stats(Instant::now())
+                .into_iter()
+                .map(|(queue, stats)| {
+                    (
+                        queue.to_string(),
+                        json!({
+                            "depth": stats.depth,
+                            "oldest_age_secs": stats.oldest_age.map(|age| age.as_secs_f64()),
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        ),
+        (&Method::GET, ["routing-table"]) => {
+            let now = Instant::now();
+            json_response(
+                StatusCode::OK,
+                capability_server
+                    .dump_routing_table()
+                    .iter()
+                    .map(|entry| table_entry_json(entry, now))
+                    .collect(),
+            )
+        }
+        (&Method::GET, ["tasks"]) => {
+            let now = Instant::now();
+            json_response(
+                StatusCode::OK,
+                capability_server
+                    .debug_tasks()
+                    .iter()
+                    .map(|task| task_json(task, now))
+                    .collect(),
+            )
+        }
+        (&Method::DELETE, ["capabilities", name, version]) => {
+            let name = match ArrayString::from(name) {
+                Ok(v) => CapabilityName(v),
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid capability name: {}", e),
+                    )
+                }
+            };
+            let version = match version.parse() {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid capability version: {}", e),
+                    )
+                }
+            };
+
+            match capability_server
+                .unregister_capability(CapabilityId { name, version })
+                .await
+            {
+                Ok(()) => Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+                Err(e) => error_response(StatusCode::NOT_FOUND, e),
+            }
+        }
+        (&Method::GET, ["crawl"]) => match capability_server.crawler() {
+            Some(crawler) => match serde_json::to_value(crawler.results()) {
+                Ok(v) => json_response(StatusCode::OK, v),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            },
+            None => error_response(StatusCode::NOT_FOUND, "not crawling"),
+        },
+        (&Method::GET, ["breaches"]) => json_response(
+            StatusCode::OK,
+            capability_server
+                .breach_records()
+                .iter()
+                .map(breach_json)
+                .collect(),
+        ),
+        (&Method::GET, ["config"]) => match serde_json::to_value(effective_config) {
+            Ok(v) => json_response(StatusCode::OK, v),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        },
+        (&Method::GET, ["self-test"]) => {
+            let reports = self_test::run_checks(runtime_checks()).await;
+            let status = if self_test::passed(&reports) {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            match serde_json::to_value(reports) {
+                Ok(v) => json_response(status, v),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        }
+        (&Method::POST, ["decode"]) => decode_response(body).await,
+        (&Method::POST, ["header-cache"]) => push_headers_response(capability_server, body).await,
+        (&Method::GET, ["metrics"]) => match metrics.encode() {
+            Ok(buf) => Response::builder()
+                .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                .body(Body::from(buf))
+                .unwrap(),
+            Err(e) => {
+                warn!("Failed to encode metrics: {}", e);
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+            }
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// State the admin API is served from.
+#[derive(Clone)]
+pub struct AdminApi {
+    pub capability_server: Arc<CapabilityServerImpl>,
+    pub metrics: Arc<Metrics>,
+    pub effective_config: Arc<EffectiveConfig>,
+    pub runtime_checks: RuntimeChecks,
+}
+
+impl AdminApi {
+    async fn handle(self, req: Request<Body>) -> Response<Body> {
+        handle(
+            &self.capability_server,
+            &self.metrics,
+            &self.effective_config,
+            &self.runtime_checks,
+            req,
+        )
+        .await
+    }
+}
+
+/// Serve REST admin API, an alternative to the gRPC interface for operators.
+pub async fn serve(addr: SocketAddr, api: AdminApi) -> anyhow::Result<()> {
+    let make_svc = make_service_fn(move |_: &AddrStream| {
+        let api = api.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let api = api.clone();
+                async move { Ok::<_, Infallible>(api.handle(req).await) }
+            }))
+        }
+    });
+
+    info!("Admin REST server starting on {}", addr);
+
+    Server::try_bind(&addr)
+        .context("Failed to bind admin REST server")?
+        .serve(make_svc)
+        .await
+        .context("Admin REST server failed")
+}
+
+/// Print peers of the sentry serving the admin API on `addr` to stdout, one JSON object
+/// per line.
+pub async fn dump_peers(addr: &str, api_token: Option<&ApiToken>) -> anyhow::Result<()> {
+    let mut request = reqwest::Client::new().get(format!("http://{}/peers", addr));
+    if let Some(api_token) = api_token {
+        request = request.header(AUTHORIZATION, api_token.bearer());
+    }
+    let peers = request
+        .send()
+        .await
+        .context("Failed to reach admin REST server")?
+        .error_for_status()?
+        .json::<Vec<Value>>()
+        .await
+        .context("Failed to decode peers")?;
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for peer in &peers {
+        serde_json::to_writer(&mut stdout, peer)?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn is_grpc(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/grpc"))
+}
+
+/// Serve the gRPC API and the REST admin API on a single address. Requests with gRPC
+/// content type go to `grpc`, all others to the admin API. If `api_token` is set, admin
+/// requests must carry it too, as `grpc` is expected to check it for gRPC calls.
+pub async fn serve_unified<G>(
+    addr: SocketAddr,
+    grpc: G,
+    api: AdminApi,
+    api_token: Option<ApiToken>,
+) -> anyhow::Result<()>
+where
+    G: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    G::Future: Send,
+    G::Error: Display,
+{
+    let make_svc = make_service_fn(move |_: &AddrStream| {
+        let grpc = grpc.clone();
+        let api = api.clone();
+        let api_token = api_token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let mut grpc = grpc.clone();
+                let api = api.clone();
+                let api_token = api_token.clone();
+                async move {
+                    if !is_grpc(&req) {
+                        if let Some(Err(status)) = api_token.map(|token| token.check_http(&req)) {
+                            debug!("Rejecting admin request: {}", status.message());
+                            api.metrics.rejected_api_calls.inc();
+                            return Ok::<_, Infallible>(
+                                error_response(StatusCode::UNAUTHORIZED, status.message())
+                                    .map(BoxBody::map_from),
+                            );
+                        }
+                        return Ok::<_, Infallible>(api.handle(req).await.map(BoxBody::map_from));
+                    }
+
+                    let res = match poll_fn(|cx| grpc.poll_ready(cx)).await {
+                        Ok(()) => grpc.call(req).await,
+                        Err(e) => Err(e),
+                    };
+                    Ok(res.unwrap_or_else(|e| {
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR, e).map(BoxBody::map_from)
+                    }))
+                }
+            }))
+        }
+    });
+
+    info!("Unified gRPC and admin REST server starting on {}", addr);
+
+    Server::try_bind(&addr)
+        .context("Failed to bind unified server")?
+        .serve(make_svc)
+        .await
+        .context("Unified server failed")
+}
@@ -0,0 +1,118 @@
+use ethereum_types::H256;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the control to announce a new head before doing it ourselves.
+pub const ANNOUNCE_SUPPRESSION_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PendingHead {
+    number: u64,
+    hash: H256,
+    observed: Instant,
+}
+
+/// Decides when the sentry should announce our new best block itself.
+#[derive(Debug, Default)]
+pub struct HeadAnnouncer {
+    best: u64,
+    control_announced: u64,
+    pending: Option<PendingHead>,
+}
+
+impl HeadAnnouncer {
+    /// Record best block from the status. Only increases of the best block are announced.
+    pub fn on_new_head(&mut self, number: u64, hash: H256, now: Instant) {
+        if number > self.best {
+            self.best = number;
+            self.pending = if number > self.control_announced {
+                Some(PendingHead {
+                    number,
+                    hash,
+                    observed: now,
+                })
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Record that the control has broadcast block at `number` itself.
+    pub fn on_control_announce(&mut self, number: u64) {
+        self.control_announced = self.control_announced.max(number);
+        if let Some(pending) = &self.pending {
+            if pending.number <= self.control_announced {
+                self.pending = None;
+            }
+        }
+    }
+
+    /// Head to announce, if the suppression window has passed without the control announcing it.
+    pub fn poll(&mut self, now: Instant) -> Option<(u64, H256)> {
+        let pending = self.pending?;
+        if now.saturating_duration_since(pending.observed) < ANNOUNCE_SUPPRESSION_WINDOW {
+            return None;
+        }
+
+        self.pending = None;
+        Some((pending.number, pending.hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_after_window() {
+        let mut announcer = HeadAnnouncer::default();
+        let now = Instant::now();
+        let hash = H256::repeat_byte(1);
+
+        announcer.on_new_head(100, hash, now);
+        assert_eq!(announcer.poll(now), None);
+        assert_eq!(
+            announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW),
+            Some((100, hash))
+        );
+        // Announced only once.
+        assert_eq!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW * 2), None);
+    }
+
+    #[test]
+    fn suppressed_by_control() {
+        let mut announcer = HeadAnnouncer::default();
+        let now = Instant::now();
+
+        announcer.on_new_head(100, H256::repeat_byte(1), now);
+        announcer.on_control_announce(100);
+        assert_eq!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW), None);
+
+        // Control announcing ahead of our status suppresses it as well.
+        announcer.on_control_announce(105);
+        announcer.on_new_head(104, H256::repeat_byte(2), now);
+        assert_eq!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW), None);
+    }
+
+    #[test]
+    fn only_increases_are_announced() {
+        let mut announcer = HeadAnnouncer::default();
+        let now = Instant::now();
+
+        announcer.on_new_head(100, H256::repeat_byte(1), now);
+        assert!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW).is_some());
+
+        announcer.on_new_head(100, H256::repeat_byte(1), now);
+        announcer.on_new_head(99, H256::repeat_byte(2), now);
+        assert_eq!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW), None);
+
+        // Newer head replaces the pending one and restarts the window.
+        let later = now + Duration::from_secs(1);
+        announcer.on_new_head(101, H256::repeat_byte(3), now);
+        announcer.on_new_head(102, H256::repeat_byte(4), later);
+        assert_eq!(announcer.poll(now + ANNOUNCE_SUPPRESSION_WINDOW), None);
+        assert_eq!(
+            announcer.poll(later + ANNOUNCE_SUPPRESSION_WINDOW),
+            Some((102, H256::repeat_byte(4)))
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use crate::metrics::Metrics;
+use anyhow::{bail, Context};
+use std::{fmt, path::Path, sync::Arc};
+use tonic::{Request, Status};
+use tracing::*;
+
+const AUTHORIZATION: &str = "authorization";
+const BEARER: &[u8] = b"Bearer ";
+
+/// Shared secret that clients of the sentry API present as a bearer token.
+#[derive(Clone)]
+pub struct ApiToken(Vec<u8>);
+
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiToken(..)")
+    }
+}
+
+/// Compare in time that does not depend on where the inputs differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl ApiToken {
+    pub fn new(token: &str) -> anyhow::Result<Self> {
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("API token is empty");
+        }
+        Ok(Self(token.as_bytes().to_vec()))
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        Self::new(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read API token from {}", path.display()))?,
+        )
+    }
+
+    pub fn check<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        self.check_value(request.metadata().get(AUTHORIZATION).map(|v| v.as_bytes()))
+    }
+
+    /// Check admin REST request served next to the gRPC API.
+    pub fn check_http<B>(&self, request: &hyper::Request<B>) -> Result<(), Status> {
+        self.check_value(request.headers().get(AUTHORIZATION).map(|v| v.as_bytes()))
+    }
+
+    fn check_value(&self, value: Option<&[u8]>) -> Result<(), Status> {
+        let value = value.ok_or_else(|| Status::unauthenticated("Missing API token"))?;
+        if value.len() < BEARER.len() || !value[..BEARER.len()].eq_ignore_ascii_case(BEARER) {
+            return Err(Status::unauthenticated("API token must be a bearer token"));
+        }
+        if !constant_time_eq(&value[BEARER.len()..], &self.0) {
+            return Err(Status::unauthenticated("Invalid API token"));
+        }
+        Ok(())
+    }
+
+    /// Value of the authorization header that carries the token.
+    pub fn bearer(&self) -> String {
+        format!("Bearer {}", String::from_utf8_lossy(&self.0))
+    }
+
+    /// Present the token on a call to another sentry that shares it.
+    pub fn authorize<T>(&self, request: &mut Request<T>) {
+        match self.bearer().parse() {
+            Ok(value) => {
+                request.metadata_mut().insert(AUTHORIZATION, value);
+            }
+            Err(_) => warn!("API token cannot be sent in gRPC metadata"),
+        }
+    }
+
+    /// Interceptor rejecting calls and streams that do not carry the token.
+    pub fn interceptor(
+        self,
+        metrics: Arc<Metrics>,
+    ) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static {
+        move |request| match self.check(&request) {
+            Ok(()) => Ok(request),
+            Err(status) => {
+                debug!("Rejecting API call: {}", status.message());
+                metrics.rejected_api_calls.inc();
+                Err(status)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        grpc::sentry::{
+            sentry_client::SentryClient, sentry_server::SentryServer, PeerMinBlockRequest,
+        },
+        services::SentryService,
+        CapabilityServerImpl,
+    };
+    use devp2p::PeerId;
+    use std::time::Duration;
+    use tonic::{transport::Server, Code};
+
+    #[test]
+    fn bearer_token_is_required() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let intercept = ApiToken::new("secret\n")
+            .unwrap()
+            .interceptor(metrics.clone());
+        let request = |value: Option<&str>| {
+            let mut request = Request::new(());
+            if let Some(value) = value {
+                request
+                    .metadata_mut()
+                    .insert(AUTHORIZATION, value.parse().unwrap());
+            }
+            request
+        };
+
+        assert!(intercept(request(Some("Bearer secret"))).is_ok());
+        assert!(intercept(request(Some("bearer secret"))).is_ok());
+        for value in [
+            None,
+            Some("Bearer secre"),
+            Some("Bearer secret2"),
+            Some("secret"),
+        ] {
+            assert_eq!(
+                intercept(request(value)).unwrap_err().code(),
+                Code::Unauthenticated
+            );
+        }
+        assert_eq!(metrics.rejected_api_calls.get(), 4);
+
+        assert!(ApiToken::new(" ").is_err());
+    }
+
+    #[tokio::test]
+    async fn server_checks_calls_and_streams() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(SentryServer::with_interceptor(
+                    SentryService::new(capability_server),
+                    ApiToken::new("secret")
+                        .unwrap()
+                        .interceptor(metrics.clone()),
+                ))
+                .serve(addr),
+        );
+        let mut client = loop {
+            match SentryClient::connect(format!("http://{}", addr)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let accepted = ApiToken::new("secret").unwrap();
+        let wrong = ApiToken::new("other").unwrap();
+        for &(token, expected) in &[
+            (Some(&accepted), None),
+            (None, Some(Code::Unauthenticated)),
+            (Some(&wrong), Some(Code::Unauthenticated)),
+        ] {
+            fn request<T>(message: T, token: Option<&ApiToken>) -> Request<T> {
+                let mut request = Request::new(message);
+                if let Some(token) = token {
+                    token.authorize(&mut request);
+                }
+                request
+            }
+
+            let unary = client
+                .peer_min_block(request(
+                    PeerMinBlockRequest {
+                        peer_id: Some(PeerId::from_low_u64_be(1).into()),
+                        min_block: 1,
+                    },
+                    token,
+                ))
+                .await;
+            assert_eq!(unary.err().map(|status| status.code()), expected);
+
+            let stream = client.receive_messages(request((), token)).await;
+            assert_eq!(stream.err().map(|status| status.code()), expected);
+        }
+        assert_eq!(metrics.rejected_api_calls.get(), 4);
+    }
+
+    #[test]
+    fn admin_requests_carry_bearer_token() {
+        let token = ApiToken::new("secret").unwrap();
+        let request = |value: Option<&str>| {
+            let mut request = hyper::Request::builder();
+            if let Some(value) = value {
+                request = request.header(AUTHORIZATION, value);
+            }
+            request.body(()).unwrap()
+        };
+
+        assert!(token.check_http(&request(Some(&token.bearer()))).is_ok());
+        assert!(token.check_http(&request(None)).is_err());
+        assert!(token.check_http(&request(Some("Bearer other"))).is_err());
+    }
+}
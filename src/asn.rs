@@ -0,0 +1,149 @@
+//! Limit of inbound peers per autonomous system, so that a single network cannot take
+//! most of our peer slots.
+
+use anyhow::Context;
+use devp2p::{ConnectionDirection, PeerId};
+use educe::Educe;
+use maxminddb::{geoip2, Reader};
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+pub const DEFAULT_MAX_PEERS_PER_ASN: usize = 5;
+
+/// ASN of an address, if known.
+pub type AsnLookup = Box<dyn Fn(IpAddr) -> Option<u32> + Send + Sync>;
+
+/// Look up ASNs in a MaxMind ASN database, e.g. `GeoLite2-ASN.mmdb`.
+pub fn open_database(path: &Path) -> anyhow::Result<AsnLookup> {
+    let reader = Reader::open_readfile(path)
+        .with_context(|| format!("Failed to open GeoIP database {}", path.display()))?;
+    Ok(Box::new(move |ip| {
+        reader
+            .lookup::<geoip2::Asn>(ip)
+            .ok()?
+            .autonomous_system_number
+    }))
+}
+
+/// Counts connected peers by ASN. All peers are counted, only inbound ones are rejected,
+/// as outbound peers have been chosen by us.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct AsnLimiter {
+    #[educe(Debug(ignore))]
+    lookup: AsnLookup,
+    max_per_asn: usize,
+    peers: HashMap<PeerId, u32>,
+    counts: HashMap<u32, usize>,
+}
+
+impl AsnLimiter {
+    pub fn new(lookup: AsnLookup, max_per_asn: usize) -> Self {
+        Self {
+            lookup,
+            max_per_asn,
+            peers: Default::default(),
+            counts: Default::default(),
+        }
+    }
+
+    /// Record connected peer. Returns ASN of an inbound peer that would exceed the limit,
+    /// the peer is not recorded then.
+    pub fn on_connect(
+        &mut self,
+        peer: PeerId,
+        ip: IpAddr,
+        direction: ConnectionDirection,
+    ) -> Result<(), u32> {
+        // New connection of the peer replaces the old one.
+        self.on_disconnect(peer);
+
+        let asn = match (self.lookup)(ip) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let count = self.counts.entry(asn).or_default();
+        if direction == ConnectionDirection::Inbound && *count >= self.max_per_asn {
+            if *count == 0 {
+                self.counts.remove(&asn);
+            }
+            return Err(asn);
+        }
+        *count += 1;
+        self.peers.insert(peer, asn);
+        Ok(())
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        if let Some(asn) = self.peers.remove(&peer) {
+            if let Some(count) = self.counts.get_mut(&asn) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&asn);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_inbound_peers_per_asn() {
+        let mut limiter = AsnLimiter::new(
+            Box::new(|ip| match ip {
+                IpAddr::V4(ip) if ip.octets()[0] == 10 => None,
+                IpAddr::V4(ip) => Some(ip.octets()[0].into()),
+                IpAddr::V6(_) => None,
+            }),
+            2,
+        );
+        let peer = PeerId::from_low_u64_be;
+        let ip = |a: u8, b: u8| IpAddr::from([a, 0, 0, b]);
+
+        assert_eq!(
+            limiter.on_connect(peer(1), ip(1, 1), ConnectionDirection::Inbound),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.on_connect(peer(2), ip(1, 2), ConnectionDirection::Outbound),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.on_connect(peer(3), ip(1, 3), ConnectionDirection::Inbound),
+            Err(1)
+        );
+        // Outbound peers are counted but not rejected.
+        assert_eq!(
+            limiter.on_connect(peer(4), ip(1, 4), ConnectionDirection::Outbound),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.on_connect(peer(5), ip(2, 1), ConnectionDirection::Inbound),
+            Ok(())
+        );
+        // Unknown ASN is not limited.
+        for i in 6..10 {
+            assert_eq!(
+                limiter.on_connect(peer(i), ip(10, i as u8), ConnectionDirection::Inbound),
+                Ok(())
+            );
+        }
+
+        limiter.on_disconnect(peer(1));
+        limiter.on_disconnect(peer(2));
+        assert_eq!(
+            limiter.on_connect(peer(3), ip(1, 3), ConnectionDirection::Inbound),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.on_connect(peer(3), ip(1, 3), ConnectionDirection::Inbound),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.on_connect(peer(1), ip(1, 1), ConnectionDirection::Inbound),
+            Err(1)
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BudgetState {
+    Normal,
+    /// Bulk data is no longer served and gossip is reduced.
+    NearlyExhausted,
+    Exhausted,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Counter {
+    limit: Option<u64>,
+    used: u64,
+}
+
+impl Counter {
+    fn state(&self, nearly_exhausted_percent: u64) -> BudgetState {
+        match self.limit {
+            Some(limit) if self.used >= limit => BudgetState::Exhausted,
+            Some(limit)
+                if u128::from(self.used) * 100
+                    >= u128::from(limit) * u128::from(nearly_exhausted_percent) =>
+            {
+                BudgetState::NearlyExhausted
+            }
+            _ => BudgetState::Normal,
+        }
+    }
+}
+
+/// Global ingress and egress byte budgets, reset at the end of every period.
+#[derive(Debug)]
+pub struct BandwidthBudget {
+    period: Duration,
+    nearly_exhausted_percent: u64,
+    period_start: Instant,
+    ingress: Counter,
+    egress: Counter,
+    state: BudgetState,
+}
+
+impl BandwidthBudget {
+    pub fn new(
+        period: Duration,
+        ingress_limit: Option<u64>,
+        egress_limit: Option<u64>,
+        nearly_exhausted_percent: u64,
+        now: Instant,
+    ) -> Self {
+        Self {
+            period: period.max(Duration::from_secs(1)),
+            nearly_exhausted_percent: nearly_exhausted_percent.min(100),
+            period_start: now,
+            ingress: Counter {
+                limit: ingress_limit,
+                used: 0,
+            },
+            egress: Counter {
+                limit: egress_limit,
+                used: 0,
+            },
+            state: BudgetState::Normal,
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.ingress.limit.is_some() || self.egress.limit.is_some()
+    }
+
+    /// Returns the new state if it has changed.
+    fn update(&mut self, now: Instant) -> Option<BudgetState> {
+        let elapsed = now.saturating_duration_since(self.period_start);
+        if elapsed >= self.period {
+            let periods = (elapsed.as_secs_f64() / self.period.as_secs_f64()).floor();
+            self.period_start += self.period.mul_f64(periods);
+            self.ingress.used = 0;
+            self.egress.used = 0;
+        }
+
+        let state = self
+            .ingress
+            .state(self.nearly_exhausted_percent)
+            .max(self.egress.state(self.nearly_exhausted_percent));
+        if state != self.state {
+            self.state = state;
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the new state if it has changed.
+    pub fn on_ingress(&mut self, bytes: usize, now: Instant) -> Option<BudgetState> {
+        let reset = self.update(now);
+        self.ingress.used = self.ingress.used.saturating_add(bytes as u64);
+        self.update(now).or(reset)
+    }
+
+    /// Returns the new state if it has changed.
+    pub fn on_egress(&mut self, bytes: usize, now: Instant) -> Option<BudgetState> {
+        let reset = self.update(now);
+        self.egress.used = self.egress.used.saturating_add(bytes as u64);
+        self.update(now).or(reset)
+    }
+
+    pub fn state(&mut self, now: Instant) -> BudgetState {
+        self.update(now);
+        self.state
+    }
+
+    /// Bytes received and sent in the current period.
+    pub fn used(&self) -> (u64, u64) {
+        (self.ingress.used, self.egress.used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn crosses_boundaries_and_resets() {
+        let start = Instant::now();
+        let mut budget = BandwidthBudget::new(HOUR, None, Some(1000), 90, start);
+        assert!(budget.is_limited());
+
+        assert_eq!(budget.on_egress(899, start), None);
+        assert_eq!(budget.on_ingress(1_000_000, start), None);
+        assert_eq!(
+            budget.on_egress(1, start),
+            Some(BudgetState::NearlyExhausted)
+        );
+        assert_eq!(budget.on_egress(100, start), Some(BudgetState::Exhausted));
+        assert_eq!(budget.state(start + HOUR / 2), BudgetState::Exhausted);
+
+        // Next period starts from scratch.
+        assert_eq!(budget.state(start + HOUR), BudgetState::Normal);
+        assert_eq!(budget.used(), (0, 0));
+
+        // Periods are aligned to the start even if nothing happens for a while.
+        budget.on_egress(950, start + HOUR * 3 + HOUR / 2);
+        assert_eq!(
+            budget.state(start + HOUR * 4 - Duration::from_secs(1)),
+            BudgetState::NearlyExhausted
+        );
+        assert_eq!(budget.state(start + HOUR * 4), BudgetState::Normal);
+    }
+
+    #[test]
+    fn ingress_budget() {
+        let start = Instant::now();
+        let mut budget = BandwidthBudget::new(HOUR, Some(100), None, 50, start);
+
+        assert_eq!(budget.on_egress(1_000_000, start), None);
+        assert_eq!(
+            budget.on_ingress(50, start),
+            Some(BudgetState::NearlyExhausted)
+        );
+        assert_eq!(budget.on_ingress(50, start), Some(BudgetState::Exhausted));
+
+        let unlimited = BandwidthBudget::new(HOUR, None, None, 90, start);
+        assert!(!unlimited.is_limited());
+    }
+}
@@ -0,0 +1,133 @@
+use devp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Where a ban comes from. Only local bans are shared with sibling sentries,
+/// so that bans do not bounce between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BanProvenance {
+    Local,
+    /// Received from a sibling sentry.
+    Remote,
+}
+
+impl BanProvenance {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Remote => "remote",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub until: Instant,
+    pub provenance: BanProvenance,
+}
+
+/// Peers refused until their ban expires.
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: HashMap<PeerId, Ban>,
+}
+
+impl BanList {
+    /// Ban the peer unless it is already banned for longer, a local ban wins a tie.
+    /// Returns whether the ban has changed.
+    pub fn insert(
+        &mut self,
+        peer: PeerId,
+        ttl: Duration,
+        provenance: BanProvenance,
+        now: Instant,
+    ) -> bool {
+        let ban = Ban {
+            until: now + ttl,
+            provenance,
+        };
+        match self.bans.get(&peer) {
+            Some(existing)
+                if existing.until > now
+                    && (existing.until > ban.until
+                        || (existing.until == ban.until
+                            && existing.provenance == BanProvenance::Local)) =>
+            {
+                false
+            }
+            _ => {
+                self.bans.insert(peer, ban);
+                true
+            }
+        }
+    }
+
+    /// Lift the ban. Remote removal does not lift a local ban.
+    /// Returns whether the peer has been banned.
+    pub fn remove(&mut self, peer: PeerId, provenance: BanProvenance) -> bool {
+        match self.bans.get(&peer) {
+            Some(ban) if provenance == BanProvenance::Local || ban.provenance == provenance => {
+                self.bans.remove(&peer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_banned(&self, peer: PeerId, now: Instant) -> bool {
+        self.bans.get(&peer).map_or(false, |ban| ban.until > now)
+    }
+
+    /// Drop the ban of the peer if it has run out. Returns whether it has been dropped.
+    pub fn expire(&mut self, peer: PeerId, now: Instant) -> bool {
+        match self.bans.get(&peer) {
+            Some(ban) if ban.until <= now => {
+                self.bans.remove(&peer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn bans(&self, now: Instant) -> Vec<(PeerId, Ban)> {
+        self.bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(&peer, &ban)| (peer, ban))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stricter_ban_wins() {
+        let mut bans = BanList::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+        let hour = Duration::from_secs(3600);
+
+        assert!(bans.insert(peer, hour / 6, BanProvenance::Remote, now));
+        assert!(bans.insert(peer, hour, BanProvenance::Local, now));
+        // Shorter remote ban does not shorten the local one.
+        assert!(!bans.insert(peer, hour / 6, BanProvenance::Remote, now));
+        assert!(!bans.remove(peer, BanProvenance::Remote));
+        assert!(bans.is_banned(peer, now + hour / 2));
+        assert!(!bans.is_banned(peer, now + hour));
+
+        // Expired ban is replaced by any new one.
+        assert!(bans.insert(peer, hour / 6, BanProvenance::Remote, now + hour));
+        assert_eq!(bans.bans(now + hour)[0].1.provenance, BanProvenance::Remote);
+        assert!(bans.remove(peer, BanProvenance::Local));
+        assert!(!bans.is_banned(peer, now + hour));
+
+        assert!(bans.insert(peer, hour, BanProvenance::Local, now));
+        assert!(!bans.expire(peer, now + hour / 2));
+        assert!(bans.expire(peer, now + hour));
+        assert!(bans.bans(now).is_empty());
+    }
+}
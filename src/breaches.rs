@@ -0,0 +1,240 @@
+use crate::{
+    capture::{message_file_name, unix_millis, FileWriter},
+    config::BreachLogConfig,
+};
+use anyhow::Context;
+use bytes::Bytes;
+use devp2p::{DisconnectReason, PeerId};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::*;
+
+/// Inbound message as kept for diagnostics, with only the start of its payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InboundSample {
+    pub message_id: usize,
+    pub size: usize,
+    pub prefix: Bytes,
+}
+
+/// Peer that we disconnected for breaching the protocol, with its last messages.
+#[derive(Clone, Debug)]
+pub struct BreachRecord {
+    pub peer: PeerId,
+    pub reason: DisconnectReason,
+    pub timestamp: SystemTime,
+    /// Oldest first, the last one is usually the offending message.
+    pub messages: Vec<InboundSample>,
+    /// Full payload of the offending message, if dumps are enabled.
+    pub dump: Option<PathBuf>,
+}
+
+/// Recent inbound messages of each peer and records of protocol breaches.
+#[derive(Debug)]
+pub struct BreachLog {
+    messages: usize,
+    prefix_bytes: usize,
+    max_records: usize,
+    samples: HashMap<PeerId, VecDeque<InboundSample>>,
+    records: VecDeque<BreachRecord>,
+}
+
+impl BreachLog {
+    pub fn new(config: &BreachLogConfig) -> Self {
+        Self {
+            messages: config.messages,
+            prefix_bytes: config.prefix_bytes,
+            max_records: config.records,
+            samples: Default::default(),
+            records: Default::default(),
+        }
+    }
+
+    pub fn on_connect(&mut self, peer: PeerId) {
+        if self.messages > 0 {
+            self.samples
+                .insert(peer, VecDeque::with_capacity(self.messages));
+        }
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.samples.remove(&peer);
+    }
+
+    /// Keep the start of the message. Messages of peers that are not connected are ignored.
+    pub fn record(&mut self, peer: PeerId, message_id: usize, data: &[u8]) {
+        if let Some(samples) = self.samples.get_mut(&peer) {
+            if samples.len() >= self.messages {
+                samples.pop_front();
+            }
+            // Copy, so that the full payload is not kept alive.
+            samples.push_back(InboundSample {
+                message_id,
+                size: data.len(),
+                prefix: Bytes::copy_from_slice(&data[..data.len().min(self.prefix_bytes)]),
+            });
+        }
+    }
+
+    pub fn on_breach(
+        &mut self,
+        peer: PeerId,
+        reason: DisconnectReason,
+        dump: Option<PathBuf>,
+        now: SystemTime,
+    ) {
+        if self.max_records == 0 {
+            return;
+        }
+        if self.records.len() >= self.max_records {
+            self.records.pop_front();
+        }
+        self.records.push_back(BreachRecord {
+            peer,
+            reason,
+            timestamp: now,
+            messages: self
+                .samples
+                .get(&peer)
+                .map(|samples| samples.iter().cloned().collect())
+                .unwrap_or_default(),
+            dump,
+        });
+    }
+
+    /// Newest first.
+    pub fn records(&self) -> Vec<BreachRecord> {
+        self.records.iter().rev().cloned().collect()
+    }
+}
+
+/// Full payloads of offending messages, written in the background into a directory that
+/// holds at most `max_dumps` files.
+#[derive(Debug)]
+pub struct BreachDumps {
+    dir: PathBuf,
+    max_dumps: usize,
+    writer: FileWriter,
+    /// Files in the directory, counting those still being written.
+    dumps: Mutex<usize>,
+}
+
+impl BreachDumps {
+    pub fn new(dir: &Path, max_dumps: usize) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create dump directory {}", dir.display()))?;
+        let dumps = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read dump directory {}", dir.display()))?
+            .count();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_dumps,
+            writer: FileWriter::spawn("breach dumps")?,
+            dumps: Mutex::new(dumps),
+        })
+    }
+
+    /// Queue the payload to be written. Returns path of the dump unless the directory is
+    /// full or the writer is behind.
+    pub fn dump(
+        &self,
+        peer: PeerId,
+        message_id: usize,
+        data: Bytes,
+        now: SystemTime,
+    ) -> Option<PathBuf> {
+        let mut dumps = self.dumps.lock();
+        if *dumps >= self.max_dumps {
+            return None;
+        }
+
+        let path = self
+            .dir
+            .join(message_file_name(unix_millis(now), peer, message_id));
+        if !self.writer.write(path.clone(), data) {
+            debug!(
+                "Breach dumps are behind, dropping message {} of {}",
+                message_id, peer
+            );
+            return None;
+        }
+        *dumps += 1;
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_samples_and_dumps() {
+        let mut log = BreachLog::new(&BreachLogConfig {
+            messages: 2,
+            prefix_bytes: 4,
+            records: 1,
+            ..Default::default()
+        });
+        let peer = PeerId::from_low_u64_be(1);
+        let now = SystemTime::now();
+
+        log.record(peer, 1, b"ignored");
+        log.on_connect(peer);
+        for id in 2..5 {
+            log.record(peer, id, &[id as u8; 10]);
+        }
+        log.on_breach(peer, DisconnectReason::ProtocolBreach, None, now);
+        log.on_breach(peer, DisconnectReason::UselessPeer, None, now);
+
+        let records = log.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].reason, DisconnectReason::UselessPeer);
+        assert_eq!(
+            records[0].messages,
+            vec![
+                InboundSample {
+                    message_id: 3,
+                    size: 10,
+                    prefix: Bytes::from_static(&[3; 4]),
+                },
+                InboundSample {
+                    message_id: 4,
+                    size: 10,
+                    prefix: Bytes::from_static(&[4; 4]),
+                },
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("sentry-dumps-{}", std::process::id()));
+        let dumps = BreachDumps::new(&dir, 1).unwrap();
+        let path = dumps
+            .dump(peer, 4, Bytes::from_static(b"payload"), now)
+            .unwrap();
+        assert_eq!(
+            dumps.dump(peer, 4, Bytes::from_static(b"payload"), now),
+            None
+        );
+        drop(dumps);
+        for _ in 0..100 {
+            if fs::read(&path).ok().as_deref() == Some(&b"payload"[..]) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"payload");
+
+        // Files left from before count towards the limit.
+        assert_eq!(
+            BreachDumps::new(&dir, 1)
+                .unwrap()
+                .dump(peer, 4, Bytes::from_static(b"payload"), now),
+            None
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
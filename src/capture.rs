@@ -0,0 +1,100 @@
+use anyhow::Context;
+use bytes::Bytes;
+use devp2p::PeerId;
+use parking_lot::Mutex;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, SyncSender},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::*;
+
+/// Files waiting to be written, beyond which further files are dropped.
+const WRITE_QUEUE: usize = 1024;
+
+/// Name of the file holding the payload of one message of the peer, as read by
+/// `ReplayDataProvider`.
+pub fn message_file_name(millis: u128, peer: PeerId, message_id: usize) -> String {
+    format!(
+        "{}-{}-{}.rlp",
+        millis,
+        hex::encode(&peer.as_bytes()[..8]),
+        message_id
+    )
+}
+
+pub fn unix_millis(now: SystemTime) -> u128 {
+    now.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Writes files on a thread of its own, so that message handling does not wait for the
+/// disk. Files are dropped while the thread is behind.
+#[derive(Clone, Debug)]
+pub struct FileWriter {
+    sender: SyncSender<(PathBuf, Bytes)>,
+}
+
+impl FileWriter {
+    pub fn spawn(name: &str) -> anyhow::Result<Self> {
+        let (sender, receiver) = sync_channel::<(PathBuf, Bytes)>(WRITE_QUEUE);
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                for (path, data) in receiver {
+                    if let Err(e) = fs::write(&path, &data) {
+                        warn!("Failed to write {}: {}", path.display(), e);
+                    }
+                }
+            })
+            .with_context(|| format!("Failed to start {} thread", name))?;
+        Ok(Self { sender })
+    }
+
+    /// Queue the file to be written. Returns `false` if it has been dropped.
+    pub fn write(&self, path: PathBuf, data: Bytes) -> bool {
+        self.sender.try_send((path, data)).is_ok()
+    }
+}
+
+/// Records GetBlockHeaders of peers and our BlockHeaders replies, to be served by
+/// `ReplayDataProvider` later.
+#[derive(Debug)]
+pub struct PeerCapture {
+    dir: PathBuf,
+    writer: FileWriter,
+    /// Timestamp of the last file. Files get distinct timestamps, so that their names
+    /// do not collide and keep the order of messages.
+    last_millis: Mutex<u128>,
+}
+
+impl PeerCapture {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create capture directory {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            writer: FileWriter::spawn("capture")?,
+            last_millis: Default::default(),
+        })
+    }
+
+    /// Record the payload of a message as it is on the wire.
+    pub fn record(&self, peer: PeerId, message_id: usize, data: Bytes, now: SystemTime) {
+        let millis = {
+            let mut last_millis = self.last_millis.lock();
+            *last_millis = unix_millis(now).max(*last_millis + 1);
+            *last_millis
+        };
+        let path = self.dir.join(message_file_name(millis, peer, message_id));
+        if !self.writer.write(path, data) {
+            debug!(
+                "Capture is behind, dropping message {} of {}",
+                message_id, peer
+            );
+        }
+    }
+}
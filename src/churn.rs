@@ -0,0 +1,63 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+pub const CHURN_WINDOW: Duration = Duration::from_secs(60);
+const MAX_TRACKED_EVENTS: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChurnEvent {
+    Connect,
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChurnRate {
+    pub connects: usize,
+    pub disconnects: usize,
+}
+
+impl ChurnRate {
+    pub fn total(&self) -> usize {
+        self.connects + self.disconnects
+    }
+}
+
+/// Counts peer connects and disconnects over a sliding one-minute window.
+#[derive(Debug, Default)]
+pub struct PeerChurnTracker {
+    events: VecDeque<(Instant, ChurnEvent)>,
+}
+
+impl PeerChurnTracker {
+    pub fn record(&mut self, event: ChurnEvent, now: Instant) {
+        self.prune(now);
+        if self.events.len() >= MAX_TRACKED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, event));
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.events.front() {
+            if now.saturating_duration_since(at) <= CHURN_WINDOW {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+
+    pub fn rate_per_minute(&mut self, now: Instant) -> ChurnRate {
+        self.prune(now);
+
+        let mut rate = ChurnRate::default();
+        for &(_, event) in &self.events {
+            match event {
+                ChurnEvent::Connect => rate.connects += 1,
+                ChurnEvent::Disconnect => rate.disconnects += 1,
+            }
+        }
+        rate
+    }
+}
@@ -0,0 +1,376 @@
+use crate::eth::{unwrap_request_id, wrap_request_id, EthMessageId};
+use bytes::Bytes;
+use devp2p::PeerId;
+use ethereum_types::H256;
+use rlp::{Rlp, RlpStream};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Requests older than this are no longer coalesced with.
+pub const PENDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_WAITERS: usize = 64;
+
+#[derive(Debug)]
+struct Waiter {
+    peer: PeerId,
+    /// ID of the waiter's own request, put back into the reply for it.
+    request_id: Option<u64>,
+    /// Requested block hashes if they are in a different order than the forwarded ones.
+    hashes: Option<Vec<H256>>,
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    origin: PeerId,
+    /// Whether the forwarded request, and so the reply to it, carries a request ID.
+    with_request_id: bool,
+    /// Block hashes of the forwarded `GetBlockBodies`, in its order.
+    hashes: Option<Vec<H256>>,
+    waiters: Vec<Waiter>,
+    created: Instant,
+}
+
+/// Coalesces identical requests from different peers into a single request to the control.
+///
+/// The first requester's request is forwarded, the rest wait for the control's reply to it.
+/// Request IDs of eth/66+ are not part of the comparison, each waiter gets the reply with
+/// its own request ID.
+/// `GetBlockBodies` for the same set of hashes in any order are identical, bodies in the
+/// reply are reordered for each waiter.
+#[derive(Debug, Default)]
+pub struct RequestCoalescer {
+    pending: HashMap<(EthMessageId, Bytes), PendingRequest>,
+}
+
+fn request_for(response: EthMessageId) -> Option<EthMessageId> {
+    match response {
+        EthMessageId::BlockHeaders => Some(EthMessageId::GetBlockHeaders),
+        EthMessageId::BlockBodies => Some(EthMessageId::GetBlockBodies),
+        _ => None,
+    }
+}
+
+/// Key under which identical requests are found: sorted hashes for `GetBlockBodies`, along
+/// with the hashes in request order, and the raw request otherwise.
+/// `data` is the request without its request ID.
+fn canonicalize(id: EthMessageId, data: Bytes) -> (Bytes, Option<Vec<H256>>) {
+    if id != EthMessageId::GetBlockBodies {
+        return (data, None);
+    }
+
+    let hashes = match Rlp::new(&data).as_list::<H256>() {
+        Ok(v) => v,
+        Err(_) => return (data, None),
+    };
+    let mut sorted = hashes.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    (rlp::encode_list(&sorted).freeze(), Some(hashes))
+}
+
+/// Bodies of `reply` to a request for `forwarded` hashes, rearranged in `requested` order.
+/// Stops at the first hash the reply has no body for, as peers do.
+fn reorder_bodies(reply: &Bytes, forwarded: &[H256], requested: &[H256]) -> Option<Bytes> {
+    let reply = Rlp::new(reply);
+    if !reply.is_list() {
+        return None;
+    }
+    let by_hash = forwarded
+        .iter()
+        .zip(reply.iter().map(|body| body.as_raw()))
+        .collect::<HashMap<_, _>>();
+
+    let bodies = requested
+        .iter()
+        .take_while(|hash| by_hash.contains_key(hash))
+        .map(|hash| by_hash[hash])
+        .collect::<Vec<_>>();
+    let mut s = RlpStream::new_list(bodies.len());
+    for body in bodies {
+        s.append_raw(body, 1);
+    }
+    Some(s.out().freeze())
+}
+
+impl RequestCoalescer {
+    /// Whether this request can be coalesced at all.
+    pub fn is_coalescable(id: EthMessageId) -> bool {
+        matches!(
+            id,
+            EthMessageId::GetBlockHeaders | EthMessageId::GetBlockBodies
+        )
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.pending
+            .retain(|_, req| now.saturating_duration_since(req.created) < PENDING_REQUEST_TIMEOUT);
+    }
+
+    /// Register request. Returns `true` if it should be forwarded to the control,
+    /// `false` if peer will get the reply to an identical pending request.
+    pub fn on_request(
+        &mut self,
+        peer: PeerId,
+        id: EthMessageId,
+        data: Bytes,
+        with_request_id: bool,
+        now: Instant,
+    ) -> bool {
+        self.prune(now);
+
+        let (request_id, data) = if with_request_id {
+            match unwrap_request_id(&data) {
+                Ok((request_id, payload)) => (Some(request_id), payload),
+                // Malformed request is up to the control to deal with.
+                Err(_) => return true,
+            }
+        } else {
+            (None, data)
+        };
+
+        let (key, hashes) = canonicalize(id, data);
+        let req = self
+            .pending
+            .entry((id, key))
+            .or_insert_with(|| PendingRequest {
+                origin: peer,
+                with_request_id,
+                hashes: hashes.clone(),
+                waiters: vec![],
+                created: now,
+            });
+
+        if req.origin == peer || req.waiters.len() >= MAX_WAITERS {
+            return true;
+        }
+
+        if !req.waiters.iter().any(|waiter| waiter.peer == peer) {
+            let hashes = hashes.filter(|hashes| Some(hashes) != req.hashes.as_ref());
+            req.waiters.push(Waiter {
+                peer,
+                request_id,
+                hashes,
+            });
+        }
+
+        false
+    }
+
+    /// Control has replied to `peer` with `data`. Returns peers waiting for the same reply,
+    /// along with the reply for each of them.
+    pub fn on_response(
+        &mut self,
+        peer: PeerId,
+        response: EthMessageId,
+        data: &Bytes,
+    ) -> Vec<(PeerId, Bytes)> {
+        let request = match request_for(response) {
+            Some(v) => v,
+            None => return vec![],
+        };
+
+        // Replies come in request order, so the oldest pending request is answered.
+        let key = self
+            .pending
+            .iter()
+            .filter(|((id, _), req)| *id == request && req.origin == peer)
+            .min_by_key(|(_, req)| req.created)
+            .map(|(key, _)| key.clone());
+
+        let req = match key.and_then(|key| self.pending.remove(&key)) {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let data = if req.with_request_id {
+            match unwrap_request_id(data) {
+                Ok((_, payload)) => payload,
+                Err(_) => return vec![],
+            }
+        } else {
+            data.clone()
+        };
+        req.waiters
+            .into_iter()
+            .filter_map(|waiter| {
+                let reply = match (&waiter.hashes, &req.hashes) {
+                    (Some(requested), Some(forwarded)) => {
+                        reorder_bodies(&data, forwarded, requested)?
+                    }
+                    _ => data.clone(),
+                };
+                let reply = match waiter.request_id {
+                    Some(request_id) => wrap_request_id(request_id, &reply),
+                    None => reply,
+                };
+                Some((waiter.peer, reply))
+            })
+            .collect()
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.pending.retain(|_, req| {
+            req.waiters.retain(|waiter| waiter.peer != peer);
+            req.origin != peer
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_bodies_coalesced_in_any_order() {
+        let mut coalescer = RequestCoalescer::default();
+        let now = Instant::now();
+        let peer = PeerId::from_low_u64_be;
+        let hash = H256::from_low_u64_be;
+        let request = |hashes: &[u64]| {
+            rlp::encode_list(&hashes.iter().copied().map(hash).collect::<Vec<_>>()).freeze()
+        };
+
+        assert!(coalescer.on_request(
+            peer(1),
+            EthMessageId::GetBlockBodies,
+            request(&[1, 2, 3]),
+            false,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(2),
+            EthMessageId::GetBlockBodies,
+            request(&[1, 2, 3]),
+            false,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(3),
+            EthMessageId::GetBlockBodies,
+            request(&[3, 1, 2]),
+            false,
+            now
+        ));
+        assert!(coalescer.on_request(
+            peer(4),
+            EthMessageId::GetBlockBodies,
+            request(&[1, 2]),
+            false,
+            now
+        ));
+
+        // Bodies are stood in for by numbers.
+        let reply = rlp::encode_list(&[10_u64, 20, 30]).freeze();
+        let waiters = coalescer.on_response(peer(1), EthMessageId::BlockBodies, &reply);
+        assert_eq!(
+            waiters,
+            vec![
+                (peer(2), reply),
+                (peer(3), rlp::encode_list(&[30_u64, 10, 20]).freeze()),
+            ]
+        );
+
+        // Reply without the body of the first hash a waiter wants leaves it with nothing.
+        assert!(coalescer.on_request(
+            peer(1),
+            EthMessageId::GetBlockBodies,
+            request(&[1, 2, 3]),
+            false,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(3),
+            EthMessageId::GetBlockBodies,
+            request(&[3, 1, 2]),
+            false,
+            now
+        ));
+        let reply = rlp::encode_list(&[10_u64, 20]).freeze();
+        assert_eq!(
+            coalescer.on_response(peer(1), EthMessageId::BlockBodies, &reply),
+            vec![(peer(3), rlp::encode_list::<u64, u64>(&[]).freeze())]
+        );
+    }
+
+    #[test]
+    fn block_bodies_with_request_ids_coalesced_in_any_order() {
+        let mut coalescer = RequestCoalescer::default();
+        let now = Instant::now();
+        let peer = PeerId::from_low_u64_be;
+        let hash = H256::from_low_u64_be;
+        let request = |request_id: u64, hashes: &[u64]| {
+            wrap_request_id(
+                request_id,
+                &rlp::encode_list(&hashes.iter().copied().map(hash).collect::<Vec<_>>()),
+            )
+        };
+
+        assert!(coalescer.on_request(
+            peer(1),
+            EthMessageId::GetBlockBodies,
+            request(7, &[1, 2, 3]),
+            true,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(2),
+            EthMessageId::GetBlockBodies,
+            request(8, &[2, 3, 1]),
+            true,
+            now
+        ));
+
+        let reply = wrap_request_id(7, &rlp::encode_list(&[10_u64, 20, 30]));
+        assert_eq!(
+            coalescer.on_response(peer(1), EthMessageId::BlockBodies, &reply),
+            vec![(
+                peer(2),
+                wrap_request_id(8, &rlp::encode_list(&[20_u64, 30, 10]))
+            )]
+        );
+    }
+
+    #[test]
+    fn request_ids_are_not_compared_and_restored() {
+        let mut coalescer = RequestCoalescer::default();
+        let now = Instant::now();
+        let peer = PeerId::from_low_u64_be;
+        let request = rlp::encode(&H256::from_low_u64_be(1));
+
+        assert!(coalescer.on_request(
+            peer(1),
+            EthMessageId::GetBlockHeaders,
+            wrap_request_id(1, &request),
+            true,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(2),
+            EthMessageId::GetBlockHeaders,
+            wrap_request_id(2, &request),
+            true,
+            now
+        ));
+        assert!(!coalescer.on_request(
+            peer(3),
+            EthMessageId::GetBlockHeaders,
+            request.clone().freeze(),
+            false,
+            now
+        ));
+
+        let headers = rlp::encode_list(&[10_u64, 20]);
+        assert_eq!(
+            coalescer.on_response(
+                peer(1),
+                EthMessageId::BlockHeaders,
+                &wrap_request_id(1, &headers)
+            ),
+            vec![
+                (peer(2), wrap_request_id(2, &headers)),
+                (peer(3), headers.freeze()),
+            ]
+        );
+    }
+}
@@ -1,11 +1,16 @@
+use crate::{
+    disconnect_policy::DisconnectAction, static_peers::StaticPeer, whitelist::PeerWhitelistMode,
+};
 use cidr::IpCidr;
 use clap::Clap;
 use derive_more::FromStr;
-use devp2p::NodeRecord;
+use devp2p::PeerId;
 use educe::Educe;
-use serde::Deserialize;
+use ethereum_types::H256;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_with::DeserializeFromStr;
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
+use url::Url;
 
 #[derive(Educe, Clap)]
 #[clap(
@@ -14,24 +19,157 @@ use std::path::PathBuf;
 )]
 #[educe(Debug)]
 pub struct Opts {
-    #[clap(long, env)]
+    /// TOML or YAML (`.yaml`, `.yml`) config file. `${VAR}` in a YAML file is replaced
+    /// with the environment variable, `$$` with `$`.
+    #[clap(long, env, alias = "config")]
     pub config_path: PathBuf,
+    /// Lowest eth protocol version to advertise.
+    #[clap(long, env)]
+    pub min_eth_version: Option<usize>,
+    /// Highest eth protocol version to advertise.
+    #[clap(long, env)]
+    pub max_eth_version: Option<usize>,
+    /// Also advertise eth/63 for old peers that support nothing newer. Their Status has no
+    /// fork ID, so they are only checked to be on the same genesis.
+    #[clap(long)]
+    pub eth63_compat: bool,
+    /// Check that the sentry can bind its ports, load its node key and reach its discovery
+    /// sources, print the results and exit, with failure if a required check has failed.
+    #[clap(long)]
+    pub self_test: bool,
+    /// Overrides `max_parallel_peer_events` of the config file.
+    #[clap(long, env)]
+    pub max_parallel_peer_events: Option<usize>,
+    /// Overrides `adaptive_headers_latency_threshold_ms` of the config file.
+    #[clap(long, env)]
+    pub adaptive_headers_latency_threshold: Option<u64>,
+    /// Overrides `min_messages_per_window` of the config file.
+    #[clap(long, env)]
+    pub min_messages_per_window: Option<usize>,
+    /// Overrides `stall_depth_threshold` of the config file.
+    #[clap(long, env)]
+    pub stall_depth_threshold: Option<usize>,
+    /// Overrides `stall_timeout_secs` of the config file.
+    #[clap(long, env)]
+    pub stall_timeout: Option<u64>,
+    /// MaxMind ASN database (e.g. `GeoLite2-ASN.mmdb`) to limit inbound peers per
+    /// autonomous system with. No limit if not set.
+    #[clap(long, env)]
+    pub geoip_db: Option<PathBuf>,
+    /// Peers from one autonomous system above which inbound peers from it are rejected,
+    /// defaults to 5. Needs `--geoip-db`.
+    #[clap(long, env)]
+    pub max_peers_per_asn: Option<usize>,
+    /// Crawl the network instead of serving the control: dial discovered nodes regardless
+    /// of `max_peers`, record their Hello and Status and disconnect. Inbound connections
+    /// are still limited by `max_peers`. Peers never become valid, so nothing is forwarded
+    /// to the control.
+    #[clap(long)]
+    pub crawl: bool,
+    /// Nodes dialed per second in crawl mode, defaults to 10.
+    #[clap(long, env)]
+    pub crawl_rate: Option<f64>,
+    /// Seconds before a crawled node is dialed again, defaults to 3600.
+    #[clap(long, env)]
+    pub crawl_cooldown: Option<u64>,
+    /// File crawl results are appended to as JSON lines, also served at `/crawl` of the
+    /// admin API.
+    #[clap(long, env)]
+    pub crawl_output: Option<PathBuf>,
+    /// JSON-RPC endpoint of a node to take our status from until the control sets it, so
+    /// that peers are found without a control.
+    #[clap(long, env)]
+    pub web3_url: Option<Url>,
+    /// WebSocket endpoint to take our status from, instead of `--web3-url`.
+    #[clap(long, env)]
+    pub web3_ws_url: Option<Url>,
+    /// Connections kept open to the web3 endpoint, defaults to 1.
+    #[clap(long, env)]
+    pub web3_pool_size: Option<usize>,
+    /// Network ID of the status taken from `--web3-url`, defaults to 1.
+    #[clap(long, env)]
+    pub chain_id: Option<u64>,
+    /// Genesis hash of the status taken from `--web3-url`, defaults to mainnet's.
+    #[clap(long, env)]
+    pub genesis_hash: Option<H256>,
+    /// Comma separated fork blocks of the status taken from `--web3-url`, defaults to
+    /// mainnet's.
+    #[clap(long, env, use_delimiter = true)]
+    pub fork_blocks: Vec<u64>,
+    /// Token that sentry API clients must send as `authorization: Bearer <token>`.
+    #[clap(long, env)]
+    #[educe(Debug(ignore))]
+    pub api_token: Option<String>,
+    /// File to read the API token from, instead of `--api-token`.
+    #[clap(long, env)]
+    pub api_token_file: Option<PathBuf>,
+    /// Address to serve REST admin API on, disabled if not set.
+    #[clap(long, env)]
+    pub admin_rest_addr: Option<String>,
+    /// Address to serve both the sentry gRPC API and the REST admin API on, told apart
+    /// by content type. Supersedes `sentry_addr` and `--admin-rest-addr` if set.
+    #[clap(long, env)]
+    pub unified_addr: Option<String>,
+    /// OTLP collector to export a span for every inbound eth message to, e.g.
+    /// `http://localhost:4317`. Disabled if not set.
+    #[clap(long, env)]
+    pub otlp_endpoint: Option<String>,
+    /// Print effective configuration with the source of every value and exit.
+    #[clap(long)]
+    pub dump_config: bool,
+    /// Print peers of the running sentry as newline-delimited JSON and exit. They are
+    /// fetched from its admin API at `--unified-addr` or `--admin-rest-addr`.
+    #[clap(long)]
+    pub dump_peers_json: bool,
+    /// Print enode URLs of connected peers to stdout on SIGUSR2.
+    #[clap(long)]
+    pub export_peers_on_signal: bool,
+    /// Experimental: also accept RLPx over QUIC on this UDP port and dial `quic_peers`.
+    /// Not supported by other Ethereum clients, only other sentries with QUIC enabled.
+    /// Needs the sentry built with the `quic` feature.
+    #[clap(long, env)]
+    pub p2p_quic_port: Option<u16>,
+    /// Sign every outbound eth message with the node key and drop inbound messages
+    /// without a valid signature of their peer. All peers must have this enabled.
+    #[clap(long)]
+    pub sign_messages: bool,
+    /// Overrides `serve_data` of the config file, `--serve-data=false` runs the sentry
+    /// read-only.
+    #[clap(long, env)]
+    pub serve_data: Option<bool>,
+    /// Comma separated peer IDs, overrides `peer_whitelist` of the config file.
+    #[clap(long, env, use_delimiter = true)]
+    pub peer_whitelist: Vec<PeerId>,
+    /// `strict` or `permissive`, overrides `peer_whitelist_mode` of the config file.
+    #[clap(long, env)]
+    pub peer_whitelist_mode: Option<PeerWhitelistMode>,
+    /// Overrides `tx_pool_ttl_secs` of the config file.
+    #[clap(long, env)]
+    pub tx_pool_ttl: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Educe)]
+#[derive(Debug, Deserialize, Serialize, Educe)]
 #[educe(Default)]
 pub struct DnsDiscConfig {
     #[educe(Default("all.mainnet.ethdisco.net"))]
     pub address: String,
 }
 
-#[derive(Debug, DeserializeFromStr, FromStr)]
-pub struct NR(pub NodeRecord);
-
 #[derive(Debug, DeserializeFromStr, FromStr)]
 pub struct Dicv4NR(pub discv4::NodeRecord);
 
-#[derive(Debug, Deserialize, Educe)]
+impl Serialize for Dicv4NR {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!(
+            "enode://{}@{}?discport={}",
+            hex::encode(self.0.id.as_bytes()),
+            self.0.tcp_addr(),
+            self.0.udp_port
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
 #[educe(Default)]
 #[serde(default)]
 pub struct Discv4Config {
@@ -44,7 +182,7 @@ pub struct Discv4Config {
     pub concurrent_lookups: usize,
 }
 
-#[derive(Debug, Deserialize, Educe)]
+#[derive(Debug, Deserialize, Serialize, Educe)]
 #[educe(Default)]
 #[serde(default)]
 pub struct Discv5Config {
@@ -54,7 +192,179 @@ pub struct Discv5Config {
     pub bootnodes: Vec<discv5::Enr>,
 }
 
-#[derive(Educe, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct ForkHealthConfig {
+    /// Number of recent Status exchanges and disconnects to consider.
+    #[educe(Default(100))]
+    pub window: usize,
+    #[educe(Default(20))]
+    pub min_samples: usize,
+    /// Fraction of failures in the window above which fork mismatch is suspected.
+    #[educe(Default(0.5))]
+    pub threshold: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct ResponseQualityConfig {
+    /// Number of recent directed requests per peer to consider.
+    #[educe(Default(20))]
+    pub window: usize,
+    #[educe(Default(10))]
+    pub min_samples: usize,
+    /// Fraction of empty or timed out responses above which the peer is demoted.
+    #[educe(Default(0.5))]
+    pub threshold: f64,
+    /// How long demoted peer is excluded from peer selection.
+    #[educe(Default(300))]
+    pub cooldown_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct BandwidthBudgetConfig {
+    /// Budget period, byte counters are reset at its end.
+    #[educe(Default(86400))]
+    pub period_secs: u64,
+    /// Bytes received from peers per period, unlimited if not set.
+    pub ingress_bytes: Option<u64>,
+    /// Bytes sent to peers per period, unlimited if not set.
+    pub egress_bytes: Option<u64>,
+    /// Percentage of a budget after which bulk data is no longer served and gossip is reduced.
+    #[educe(Default(90))]
+    pub nearly_exhausted_percent: u64,
+    /// Stop accepting new peers once a budget is exhausted.
+    pub reject_peers_when_exhausted: bool,
+}
+
+/// What happens to passive peers when a dialed peer needs a slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PassivePeerEviction {
+    #[educe(Default)]
+    Off,
+    /// Only log the peer that would be disconnected.
+    LogOnly,
+    Enforce,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct PassivePeersConfig {
+    /// Valid peers that have sent no eth message besides Status for this long are passive.
+    #[educe(Default(1800))]
+    pub window_secs: u64,
+    pub eviction: PassivePeerEviction,
+    /// Passive peers are only evicted while more peers than this are connected.
+    pub evict_above_peers: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct DuplicateFilterConfig {
+    /// Block announcements seen within this many seconds are dropped as duplicates, 0
+    /// disables the filter.
+    #[educe(Default(5))]
+    pub window_secs: u64,
+    /// Chance that an announcement seen for the first time is taken for a duplicate.
+    #[educe(Default(0.01))]
+    pub false_positive_rate: f64,
+    /// Copies of the same `NewBlockHashes` let through within the window.
+    #[educe(Default(1))]
+    pub new_block_hashes_copies: usize,
+    /// Copies of the same block in `NewBlock` let through within the window. More than one
+    /// guards against a peer that announces a block and withholds its body.
+    #[educe(Default(2))]
+    pub new_block_copies: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct BreachLogConfig {
+    /// Last inbound messages per peer attached to records of protocol breaches.
+    #[educe(Default(8))]
+    pub messages: usize,
+    /// Bytes kept from the start of each of those messages.
+    #[educe(Default(64))]
+    pub prefix_bytes: usize,
+    /// Protocol breach records kept.
+    #[educe(Default(32))]
+    pub records: usize,
+    /// Write full payloads of offending messages to this directory.
+    pub dump_dir: Option<PathBuf>,
+    /// Payloads are no longer dumped once the directory holds this many files.
+    #[educe(Default(100))]
+    pub max_dumps: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct GreylistConfig {
+    /// Failed inbound handshakes from an address within `window_secs` after which its
+    /// connections are dropped before any crypto, 0 disables greylisting.
+    #[educe(Default(10))]
+    pub max_failures: usize,
+    #[educe(Default(60))]
+    pub window_secs: u64,
+    /// How long connections from a greylisted address are dropped.
+    #[educe(Default(300))]
+    pub period_secs: u64,
+    /// Addresses tracked at most, the least recently seen are forgotten.
+    #[educe(Default(4096))]
+    pub capacity: usize,
+    /// Addresses that are never greylisted, in addition to those of reserved and QUIC peers.
+    pub exempt: Vec<IpAddr>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct SiblingsConfig {
+    /// gRPC addresses of other sentries in front of the same control, e.g.
+    /// `http://10.0.0.2:8000`. Peers penalized by the control or breaching the protocol
+    /// are banned and the bans are shared with them. Empty disables bans, penalized
+    /// peers are only disconnected then.
+    pub addrs: Vec<String>,
+    /// How long a peer banned by this sentry is refused.
+    #[educe(Default(3600))]
+    pub ban_ttl_secs: u64,
+    /// How long a peer banned by a sibling is refused, at most.
+    #[educe(Default(600))]
+    pub remote_ban_ttl_secs: u64,
+}
+
+/// Override of the redial rule for one disconnect reason.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RedialRuleConfig {
+    /// Disconnect reason code as sent on the wire.
+    pub reason: u8,
+    pub delay_multiplier: f64,
+    pub max_attempts: usize,
+    #[serde(default)]
+    pub alert: bool,
+}
+
+/// Override of the disconnect policy for one message ID, unset actions keep their default.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DisconnectPolicyConfig {
+    /// Message ID as sent on the wire, may be unknown to the sentry.
+    pub message_id: usize,
+    #[serde(default)]
+    pub on_bad_rlp: Option<DisconnectAction>,
+    #[serde(default)]
+    pub on_unknown_message: Option<DisconnectAction>,
+}
+
+#[derive(Educe, Deserialize, Serialize)]
 #[educe(Default, Debug)]
 #[serde(default)]
 pub struct Config {
@@ -65,11 +375,111 @@ pub struct Config {
     pub cidr: Option<IpCidr>,
     #[educe(Default("0.0.0.0:8000"))]
     pub sentry_addr: String,
+    pub metrics_addr: Option<String>,
+    /// Selected counters are kept in this file across restarts, see `Metrics::snapshot`.
+    pub metrics_file: Option<PathBuf>,
+    #[educe(Default(60))]
+    pub metrics_file_interval_secs: u64,
     pub dnsdisc: Option<DnsDiscConfig>,
     pub discv4: Option<Discv4Config>,
     pub discv5: Option<Discv5Config>,
-    pub reserved_peers: Vec<NR>,
+    /// Peers to keep dialing, host may be a DNS name that is resolved on every attempt.
+    pub reserved_peers: Vec<StaticPeer>,
+    /// Peers to keep dialing over QUIC, with their QUIC port. Needs `--p2p-quic-port`.
+    pub quic_peers: Vec<StaticPeer>,
     #[educe(Default(50))]
     pub max_peers: usize,
     pub peers_file: Option<PathBuf>,
+    /// Local bans are kept in this file across restarts, see `siblings`.
+    pub bans_file: Option<PathBuf>,
+    /// Record GetBlockHeaders of peers and our replies to them in this directory.
+    pub capture_dir: Option<PathBuf>,
+    /// Answer GetBlockHeaders with replies recorded in this directory by `capture_dir`
+    /// instead of asking the control. For regression testing without a node.
+    pub replay_dir: Option<PathBuf>,
+    #[educe(Default(2))]
+    pub handshake_threads: usize,
+    /// RLPx protocol version announced to peers, 4 disables compression for very old nodes.
+    #[educe(Default(5))]
+    pub p2p_protocol_version: usize,
+    pub fork_health: ForkHealthConfig,
+    pub response_quality: ResponseQualityConfig,
+    pub bandwidth_budget: BandwidthBudgetConfig,
+    pub passive_peers: PassivePeersConfig,
+    pub duplicate_filter: DuplicateFilterConfig,
+    pub breach_log: BreachLogConfig,
+    pub greylist: GreylistConfig,
+    pub siblings: SiblingsConfig,
+    /// Peer whose Status total difficulty is below this percentage of ours is considered syncing.
+    #[educe(Default(90))]
+    pub syncing_td_percent: u64,
+    /// Syncing peer is promoted once it is at most this many blocks behind our head.
+    #[educe(Default(128))]
+    pub syncing_block_tolerance: u64,
+    pub redial_policy: Vec<RedialRuleConfig>,
+    /// Whether malformed or unexpected messages from peers cost them their connection.
+    pub disconnect_policy: Vec<DisconnectPolicyConfig>,
+    /// Number of blocks behind the highest pushed header to keep in header cache.
+    #[educe(Default(1024))]
+    pub header_cache_window: u64,
+    /// Connects and disconnects per minute above which dialing is slowed down.
+    #[educe(Default(100))]
+    pub max_churn_rate: usize,
+    /// Announce our new best block to peers if the control does not do it in time.
+    pub announce_head: bool,
+    /// Number of recent messages to keep per peer for debugging, 0 disables the log.
+    #[educe(Default(100))]
+    pub peer_message_log_size: usize,
+    /// Peer that has not sent a valid Status this many seconds after connecting is
+    /// disconnected, 0 disables the timeout.
+    #[educe(Default(10))]
+    pub status_timeout_secs: u64,
+    /// Peer reconnecting within this many seconds resumes its previous session.
+    #[educe(Default(10))]
+    pub reconnect_window_secs: u64,
+    /// Rapid reconnects of a peer per minute above which it is rejected, 0 disables the limit.
+    #[educe(Default(5))]
+    pub max_reconnects_per_minute: usize,
+    /// Maximum number of peer events handled concurrently, defaults to 4 * number of CPUs.
+    pub max_parallel_peer_events: Option<usize>,
+    /// Average time in milliseconds the control may take to answer GetBlockHeaders of a peer
+    /// before fewer headers are requested on its behalf.
+    #[educe(Default(500))]
+    pub adaptive_headers_latency_threshold_ms: u64,
+    /// Messages a valid peer has to send every 10 minutes to stay connected. Peers are never
+    /// disconnected for being idle if 0.
+    #[educe(Default(1))]
+    pub min_messages_per_window: usize,
+    /// Sends waiting for room in a peer's outbound queue above which the peer is considered
+    /// stalled.
+    #[educe(Default(32))]
+    pub stall_depth_threshold: usize,
+    /// Seconds a send may wait for room in a peer's outbound queue, or the peer may stay
+    /// stalled, before it is disconnected.
+    #[educe(Default(5))]
+    pub stall_timeout_secs: u64,
+    /// Reject messages from the control that are not structurally valid for the target peer.
+    /// Disable to send raw payloads when testing.
+    #[educe(Default(true))]
+    pub validate_outbound: bool,
+    /// Answer data requests of peers. If disabled, the sentry only collects: requests
+    /// get empty responses right away and are never forwarded to the control.
+    #[educe(Default(true))]
+    pub serve_data: bool,
+    /// Peers that are always allowed to connect and are kept over others.
+    pub peer_whitelist: Vec<PeerId>,
+    /// In strict mode, only whitelisted peers may connect.
+    pub peer_whitelist_mode: PeerWhitelistMode,
+    /// Seconds to keep transactions sent to peers, to answer GetPooledTransactions
+    /// for them without the control. 0 disables answering from the sentry.
+    #[educe(Default(300))]
+    pub tx_pool_ttl_secs: u64,
+    /// Strip eth/66 request IDs from messages forwarded to the control and add them to
+    /// messages from the control, so that the control does not depend on peer versions.
+    /// eth/66 and later are only advertised if set.
+    pub normalize_request_ids: bool,
+    /// Messages forwarded to the control above this many bytes are counted and logged,
+    /// as they may exceed the control's gRPC message size limit (4 MiB by default).
+    #[educe(Default(4 * 1024 * 1024))]
+    pub large_message_threshold: usize,
 }
@@ -0,0 +1,128 @@
+use crate::config::Config;
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::{env, fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn new(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+}
+
+/// Replace `${VAR}` with the value of the variable, `$$` with `$`.
+fn interpolate(text: &str, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => bail!("Unclosed ${{ in config file"),
+            };
+            let name = &after[..end];
+            match var(name) {
+                Some(value) => out.push_str(&value),
+                None => bail!("Environment variable {} is not set", name),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Interpolate environment variables into a YAML config file. TOML files are taken as is.
+fn expand(
+    format: Format,
+    text: String,
+    var: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    match format {
+        Format::Toml => Ok(text),
+        Format::Yaml => interpolate(&text, var),
+    }
+}
+
+/// Parse the config file, TOML or YAML by its extension, with environment variables
+/// interpolated into YAML. Also returns the file as written, to tell which values were
+/// set there.
+pub fn load_config(path: &Path) -> anyhow::Result<(Config, Value)> {
+    let format = Format::new(path);
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let text = expand(format, text, |name| env::var(name).ok())
+        .with_context(|| format!("Failed to interpolate config file {}", path.display()))?;
+    parse(format, &text).with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+fn parse(format: Format, text: &str) -> anyhow::Result<(Config, Value)> {
+    Ok(match format {
+        Format::Toml => (
+            toml::from_str(text)?,
+            serde_json::to_value(toml::from_str::<toml::Value>(text)?)?,
+        ),
+        Format::Yaml => (
+            serde_yaml::from_str(text)?,
+            serde_json::to_value(serde_yaml::from_str::<serde_yaml::Value>(text)?)?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_with_interpolation() {
+        let var = |name: &str| match name {
+            "NODE_KEY" => Some("deadbeef".to_string()),
+            _ => None,
+        };
+        let text = interpolate(
+            "node_key: ${NODE_KEY}\nmax_peers: 10\ndiscv4:\n  port: 30305\nnote: $$5 $x\n",
+            var,
+        )
+        .unwrap();
+        assert_eq!(
+            text,
+            "node_key: deadbeef\nmax_peers: 10\ndiscv4:\n  port: 30305\nnote: $5 $x\n"
+        );
+        assert!(interpolate("${MISSING}", var).is_err());
+        assert!(interpolate("${NODE_KEY", var).is_err());
+
+        assert_eq!(Format::new(Path::new("sentry.yml")), Format::Yaml);
+        assert_eq!(Format::new(Path::new("sentry.toml")), Format::Toml);
+
+        let (config, file) = parse(Format::Yaml, &text).unwrap();
+        assert_eq!(config.node_key.as_deref(), Some("deadbeef"));
+        assert_eq!(config.max_peers, 10);
+        assert_eq!(config.discv4.unwrap().port, 30305);
+        assert_eq!(file["discv4"]["port"], 30305);
+    }
+
+    #[test]
+    fn toml_is_not_interpolated() {
+        let text = "# costs $$5, see ${DOCS}\nnode_key = \"${NODE_KEY}\"\n".to_string();
+        let expanded = expand(Format::Toml, text.clone(), |_| None).unwrap();
+        assert_eq!(expanded, text);
+
+        let (config, _) = parse(Format::Toml, &expanded).unwrap();
+        assert_eq!(config.node_key.as_deref(), Some("${NODE_KEY}"));
+    }
+}
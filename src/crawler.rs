@@ -0,0 +1,199 @@
+//! Crawl mode: dial as many discovered nodes as possible, record what they tell about
+//! themselves in Hello and Status, and disconnect right after.
+
+use crate::eth::Eth63StatusMessage;
+use anyhow::Context;
+use devp2p::{CapabilityName, CapabilityVersion, PeerId};
+use ethereum_forkid::ForkId;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::*;
+
+/// Nodes dialed per second.
+pub const DEFAULT_CRAWL_RATE: f64 = 10.0;
+/// Node is not dialed again for this long after a visit.
+pub const DEFAULT_CRAWL_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// Latest results kept in memory for the admin API, all of them are in the output file.
+const CRAWL_RESULTS_CAPACITY: usize = 10_000;
+/// Results waiting to be written, beyond which further results are left out of the file.
+const OUTPUT_QUEUE: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CrawlForkId {
+    pub hash: String,
+    pub next: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CrawlResult {
+    pub id: String,
+    pub addr: Option<SocketAddr>,
+    pub client_version: Option<String>,
+    /// Capabilities shared with us, e.g. `eth/66`.
+    pub capabilities: Vec<String>,
+    pub protocol_version: usize,
+    pub network_id: u64,
+    pub total_difficulty: String,
+    pub best_hash: String,
+    pub genesis_hash: String,
+    /// Not sent at eth/63.
+    pub fork_id: Option<CrawlForkId>,
+    /// Time from the end of the RLPx handshake to the node's Status.
+    pub latency_ms: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    addr: Option<SocketAddr>,
+    client_version: Option<String>,
+    capabilities: Vec<String>,
+    connected: Option<Instant>,
+}
+
+/// Append results to the file, flushing whenever the queue is empty.
+fn write_output(file: File, results: Receiver<String>) {
+    let mut file = BufWriter::new(file);
+    while let Ok(line) = results.recv() {
+        let res = std::iter::once(line)
+            .chain(results.try_iter())
+            .try_for_each(|line| writeln!(file, "{}", line))
+            .and_then(|_| file.flush());
+        if let Err(e) = res {
+            warn!("Failed to write crawl results: {}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Crawler {
+    /// Written on a thread of its own, so that crawled peers are not held up by the disk.
+    output: Option<SyncSender<String>>,
+    sessions: Mutex<HashMap<PeerId, Session>>,
+    results: Mutex<VecDeque<CrawlResult>>,
+}
+
+impl Crawler {
+    /// Results are appended to `output` as JSON lines, if set.
+    pub fn new(output: Option<&Path>) -> anyhow::Result<Self> {
+        let output = output
+            .map(|path| {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open crawl output {}", path.display()))?;
+                let (sender, receiver) = sync_channel(OUTPUT_QUEUE);
+                thread::Builder::new()
+                    .name("crawl output".into())
+                    .spawn(move || write_output(file, receiver))
+                    .context("Failed to start crawl output thread")?;
+                Ok::<_, anyhow::Error>(sender)
+            })
+            .transpose()?;
+        Ok(Self {
+            output,
+            sessions: Default::default(),
+            results: Default::default(),
+        })
+    }
+
+    pub fn on_client_version(&self, peer: PeerId, client_version: &str) {
+        self.sessions.lock().entry(peer).or_default().client_version =
+            Some(client_version.to_string());
+    }
+
+    pub fn on_connect(
+        &self,
+        peer: PeerId,
+        addr: Option<SocketAddr>,
+        caps: &HashMap<CapabilityName, CapabilityVersion>,
+        now: Instant,
+    ) {
+        let mut capabilities = caps
+            .iter()
+            .map(|(name, version)| format!("{}/{}", name.0, version))
+            .collect::<Vec<_>>();
+        capabilities.sort();
+
+        let mut sessions = self.sessions.lock();
+        let session = sessions.entry(peer).or_default();
+        session.addr = addr;
+        session.capabilities = capabilities;
+        session.connected = Some(now);
+    }
+
+    /// Record Status of the node, which ends its visit.
+    pub fn on_status(
+        &self,
+        peer: PeerId,
+        status: &Eth63StatusMessage,
+        fork_id: Option<ForkId>,
+        now: Instant,
+        timestamp: SystemTime,
+    ) -> CrawlResult {
+        let session = self.sessions.lock().remove(&peer).unwrap_or_default();
+        let result = CrawlResult {
+            id: hex::encode(peer.as_bytes()),
+            addr: session.addr,
+            client_version: session.client_version,
+            capabilities: session.capabilities,
+            protocol_version: status.protocol_version,
+            network_id: status.network_id,
+            total_difficulty: status.total_difficulty.to_string(),
+            best_hash: format!("{:?}", status.best_hash),
+            genesis_hash: format!("{:?}", status.genesis_hash),
+            fork_id: fork_id.map(|fork_id| CrawlForkId {
+                hash: hex::encode(fork_id.hash.0),
+                next: fork_id.next,
+            }),
+            latency_ms: session.connected.map_or(0, |connected| {
+                now.saturating_duration_since(connected).as_millis()
+            }) as u64,
+            timestamp: timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+
+        if let Some(output) = &self.output {
+            match serde_json::to_string(&result) {
+                Ok(line) => {
+                    if output.try_send(line).is_err() {
+                        warn!("Crawl output is behind, leaving out result of {}", peer);
+                    }
+                }
+                Err(e) => warn!("Failed to write crawl result of {}: {}", peer, e),
+            }
+        }
+
+        let mut results = self.results.lock();
+        if results.len() >= CRAWL_RESULTS_CAPACITY {
+            results.pop_front();
+        }
+        results.push_back(result.clone());
+
+        result
+    }
+
+    /// Node disconnected before its Status, nothing is recorded.
+    pub fn on_disconnect(&self, peer: PeerId) {
+        self.sessions.lock().remove(&peer);
+    }
+
+    /// Latest results, oldest first.
+    pub fn results(&self) -> Vec<CrawlResult> {
+        self.results.lock().iter().cloned().collect()
+    }
+}
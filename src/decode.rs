@@ -0,0 +1,379 @@
+//! Rendering of eth payloads with the sentry's own codecs, for inspecting bytes that a
+//! peer or the control could not make sense of.
+
+use crate::eth::{
+    decode_status, BlockHashNumber, BlockId, EthMessageId, GetBlockHeaders,
+    NewPooledTransactionHashes68, Withdrawal, ETH_66, ETH_68, MAX_ETH_MESSAGE_SIZE,
+};
+use devp2p::util::keccak256;
+use ethereum_types::{H256, U256};
+use rlp::{Decodable, DecoderError, Rlp};
+use serde_json::{json, Value};
+use std::fmt::{self, Display};
+
+/// Why a payload failed to decode and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    /// Offset into the payload of the item that failed to decode.
+    pub offset: usize,
+    pub error: String,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.error, self.offset)
+    }
+}
+
+struct Payload<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    fn fail(&self, rlp: &Rlp<'a>, error: impl ToString) -> DecodeError {
+        DecodeError {
+            offset: rlp.as_raw().as_ptr() as usize - self.data.as_ptr() as usize,
+            error: error.to_string(),
+        }
+    }
+
+    fn val<T: Decodable>(&self, rlp: &Rlp<'a>) -> Result<T, DecodeError> {
+        rlp.as_val().map_err(|e| self.fail(rlp, e))
+    }
+
+    /// Items of a list, failing at the first malformed one.
+    fn items(&self, rlp: &Rlp<'a>) -> Result<Vec<Rlp<'a>>, DecodeError> {
+        if !rlp.is_list() {
+            return Err(self.fail(rlp, DecoderError::RlpExpectedToBeList));
+        }
+        let count = rlp.item_count().map_err(|e| self.fail(rlp, e))?;
+        (0..count)
+            .map(|i| rlp.at(i).map_err(|e| self.fail(rlp, e)))
+            .collect()
+    }
+
+    fn hashes(&self, rlp: &Rlp<'a>) -> Result<Value, DecodeError> {
+        self.items(rlp)?
+            .iter()
+            .map(|item| Ok(hex::encode(self.val::<H256>(item)?.as_bytes()).into()))
+            .collect()
+    }
+
+    fn status(&self, rlp: &Rlp<'a>, version: u8) -> Result<Value, DecodeError> {
+        let (status, fork_id) =
+            decode_status(version, rlp.as_raw()).map_err(|e| self.fail(rlp, e))?;
+        let mut rendered = json!({
+            "protocol_version": status.protocol_version,
+            "network_id": status.network_id,
+            "total_difficulty": status.total_difficulty.to_string(),
+            "best_hash": hex::encode(status.best_hash.as_bytes()),
+            "genesis_hash": hex::encode(status.genesis_hash.as_bytes()),
+        });
+        if let Some(fork_id) = fork_id {
+            rendered["fork_id"] = json!({
+                "hash": hex::encode(fork_id.hash.0),
+                "next": fork_id.next,
+            });
+        }
+        Ok(rendered)
+    }
+
+    /// Hash and number of an RLP encoded header.
+    fn header(&self, rlp: &Rlp<'a>) -> Result<Value, DecodeError> {
+        let fields = self.items(rlp)?;
+        let number = fields
+            .get(8)
+            .ok_or_else(|| self.fail(rlp, DecoderError::RlpIncorrectListLen))?;
+        Ok(json!({
+            "hash": hex::encode(keccak256(rlp.as_raw()).as_bytes()),
+            "number": self.val::<u64>(number)?,
+        }))
+    }
+
+    /// Legacy transactions are lists, typed ones are strings of type and payload.
+    fn transaction(&self, rlp: &Rlp<'a>) -> Result<Value, DecodeError> {
+        let (ty, encoded) = if rlp.is_list() {
+            (0, rlp.as_raw())
+        } else {
+            let data = rlp.data().map_err(|e| self.fail(rlp, e))?;
+            match data.first() {
+                Some(&ty) if ty <= 0x7f => (ty, data),
+                Some(&ty) => {
+                    return Err(self.fail(rlp, format!("invalid transaction type {:#x}", ty)))
+                }
+                None => return Err(self.fail(rlp, DecoderError::RlpIsTooShort)),
+            }
+        };
+        Ok(json!({
+            "type": ty,
+            "hash": hex::encode(keccak256(encoded).as_bytes()),
+            "size": encoded.len(),
+        }))
+    }
+
+    fn message(&self, id: EthMessageId, version: u8, rlp: &Rlp<'a>) -> Result<Value, DecodeError> {
+        Ok(match id {
+            EthMessageId::Status => self.status(rlp, version)?,
+            EthMessageId::NewBlockHashes => self
+                .items(rlp)?
+                .iter()
+                .map(|item| {
+                    let announce = self.val::<BlockHashNumber>(item)?;
+                    Ok(json!({
+                        "hash": hex::encode(announce.hash.as_bytes()),
+                        "number": announce.number,
+                    }))
+                })
+                .collect::<Result<_, _>>()?,
+            EthMessageId::GetBlockHeaders => {
+                let request = self.val::<GetBlockHeaders>(rlp)?;
+                let block = match request.block {
+                    BlockId::Hash(hash) => json!({ "hash": hex::encode(hash.as_bytes()) }),
+                    BlockId::Number(number) => json!({ "number": number }),
+                };
+                json!({
+                    "block": block,
+                    "max_headers": request.max_headers,
+                    "skip": request.skip,
+                    "reverse": request.reverse,
+                })
+            }
+            EthMessageId::NewBlock => {
+                let f = self.items(rlp)?;
+                if f.len() != 2 {
+                    return Err(self.fail(rlp, DecoderError::RlpIncorrectListLen));
+                }
+                let block = self.items(&f[0])?;
+                if block.len() < 3 {
+                    return Err(self.fail(&f[0], DecoderError::RlpIncorrectListLen));
+                }
+                let mut header = self.header(&block[0])?;
+                header["transactions"] = self.items(&block[1])?.len().into();
+                header["uncles"] = self.items(&block[2])?.len().into();
+                header["total_difficulty"] = self.val::<U256>(&f[1])?.to_string().into();
+                header
+            }
+            EthMessageId::NewPooledTransactionHashes if version >= ETH_68 => self
+                .val::<NewPooledTransactionHashes68>(rlp)?
+                .announcements()
+                .map(|(ty, size, hash)| {
+                    json!({
+                        "type": ty,
+                        "size": size,
+                        "hash": hex::encode(hash.as_bytes()),
+                    })
+                })
+                .collect(),
+            EthMessageId::NewPooledTransactionHashes
+            | EthMessageId::GetBlockBodies
+            | EthMessageId::GetPooledTransactions
+            | EthMessageId::GetNodeData
+            | EthMessageId::GetReceipts => self.hashes(rlp)?,
+            EthMessageId::NodeData => self
+                .items(rlp)?
+                .iter()
+                .map(|item| Ok(hex::encode(self.val::<Vec<u8>>(item)?).into()))
+                .collect::<Result<_, _>>()?,
+            EthMessageId::Transactions | EthMessageId::PooledTransactions => self
+                .items(rlp)?
+                .iter()
+                .map(|item| self.transaction(item))
+                .collect::<Result<_, _>>()?,
+            EthMessageId::BlockHeaders => self
+                .items(rlp)?
+                .iter()
+                .map(|item| self.header(item))
+                .collect::<Result<_, _>>()?,
+            EthMessageId::BlockBodies => self
+                .items(rlp)?
+                .iter()
+                .map(|item| {
+                    let body = self.items(item)?;
+                    if body.len() < 2 {
+                        return Err(self.fail(item, DecoderError::RlpIncorrectListLen));
+                    }
+                    let mut rendered = json!({
+                        "transactions": self.items(&body[0])?.len(),
+                        "uncles": self.items(&body[1])?.len(),
+                    });
+                    if let Some(withdrawals) = body.get(2) {
+                        let withdrawals = self
+                            .items(withdrawals)?
+                            .iter()
+                            .map(|item| self.val::<Withdrawal>(item))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        rendered["withdrawals"] = withdrawals.len().into();
+                    }
+                    Ok(rendered)
+                })
+                .collect::<Result<_, _>>()?,
+            EthMessageId::Receipts => self
+                .items(rlp)?
+                .iter()
+                .map(|item| Ok(self.items(item)?.len().into()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Decode the payload of message `id` sent at eth `version` and render it as JSON.
+/// Hashes and byte strings are hex, big numbers are decimal strings.
+pub fn decode_message(id: EthMessageId, version: u8, data: &[u8]) -> Result<Value, DecodeError> {
+    let p = Payload { data };
+    let rlp = Rlp::new(data);
+
+    if data.len() > MAX_ETH_MESSAGE_SIZE {
+        return Err(p.fail(
+            &rlp,
+            format!("payload exceeds limit ({} bytes)", MAX_ETH_MESSAGE_SIZE),
+        ));
+    }
+    if id.to_wire(version.into()).is_none() {
+        return Err(p.fail(&rlp, format!("{:?} does not exist in eth/{}", id, version)));
+    }
+
+    let info = rlp.payload_info().map_err(|e| p.fail(&rlp, e))?;
+    let len = info.header_len + info.value_len;
+    if len < data.len() {
+        return Err(DecodeError {
+            offset: len,
+            error: "trailing bytes after message".into(),
+        });
+    }
+
+    if version >= ETH_66 && id.has_request_id() {
+        let f = p.items(&rlp)?;
+        if f.len() != 2 {
+            return Err(p.fail(&rlp, DecoderError::RlpIncorrectListLen));
+        }
+        Ok(json!({
+            "request_id": p.val::<u64>(&f[0])?,
+            "message": p.message(id, version, &f[1])?,
+        }))
+    } else {
+        Ok(json!({ "message": p.message(id, version, &rlp)? }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::*;
+    use ethereum_forkid::{ForkHash, ForkId};
+    use rlp::RlpStream;
+
+    #[test]
+    fn decode_good_payloads() {
+        let status = StatusMessage {
+            protocol_version: 66,
+            network_id: 1,
+            total_difficulty: 1000.into(),
+            best_hash: H256::repeat_byte(2),
+            genesis_hash: H256::repeat_byte(1),
+            fork_id: ForkId {
+                hash: ForkHash([0xfc, 0x64, 0xec, 0x04]),
+                next: 1_150_000,
+            },
+        };
+        assert_eq!(
+            decode_message(EthMessageId::Status, 66, &rlp::encode(&status)).unwrap(),
+            json!({
+                "message": {
+                    "protocol_version": 66,
+                    "network_id": 1,
+                    "total_difficulty": "1000",
+                    "best_hash": hex::encode(H256::repeat_byte(2)),
+                    "genesis_hash": hex::encode(H256::repeat_byte(1)),
+                    "fork_id": { "hash": "fc64ec04", "next": 1_150_000 },
+                }
+            })
+        );
+
+        let request = GetBlockHeaders {
+            block: BlockId::Number(100),
+            max_headers: 192,
+            skip: 0,
+            reverse: false,
+        };
+        let data = wrap_request_id(7, &rlp::encode(&request));
+        assert_eq!(
+            decode_message(EthMessageId::GetBlockHeaders, 66, &data).unwrap(),
+            json!({
+                "request_id": 7,
+                "message": {
+                    "block": { "number": 100 },
+                    "max_headers": 192,
+                    "skip": 0,
+                    "reverse": false,
+                }
+            })
+        );
+
+        let mut s = RlpStream::new_list(1);
+        s.begin_list(2).append(&H256::repeat_byte(3)).append(&5_u64);
+        assert_eq!(
+            decode_message(EthMessageId::NewBlockHashes, 66, &s.out()).unwrap(),
+            json!({ "message": [{ "hash": hex::encode(H256::repeat_byte(3)), "number": 5 }] })
+        );
+
+        let typed = [2_u8, 0xc0];
+        let mut s = RlpStream::new_list(1);
+        s.append(&typed.to_vec());
+        assert_eq!(
+            decode_message(EthMessageId::Transactions, 68, &s.out()).unwrap(),
+            json!({
+                "message": [{
+                    "type": 2,
+                    "hash": hex::encode(keccak256(&typed)),
+                    "size": 2,
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn decode_errors_point_at_the_bad_item() {
+        // Second hash is one byte short.
+        let mut s = RlpStream::new_list(2);
+        s.begin_list(2).append(&H256::repeat_byte(3)).append(&5_u64);
+        s.begin_list(2).append(&vec![3_u8; 31]).append(&6_u64);
+        let data = s.out();
+        let e = decode_message(EthMessageId::NewBlockHashes, 66, &data).unwrap_err();
+        // List header, first announcement.
+        assert_eq!(e.offset, 2 + 35);
+        assert_eq!(e.error, DecoderError::RlpIsTooShort.to_string());
+
+        let mut data = rlp::encode_list(&[H256::repeat_byte(1)]).to_vec();
+        let len = data.len();
+        data.push(0x80);
+        assert_eq!(
+            decode_message(EthMessageId::GetBlockBodies, 63, &data).unwrap_err(),
+            DecodeError {
+                offset: len,
+                error: "trailing bytes after message".into(),
+            }
+        );
+
+        let announcement = NewPooledTransactionHashes68 {
+            types: vec![2, 2],
+            sizes: vec![100],
+            hashes: vec![H256::repeat_byte(1), H256::repeat_byte(2)],
+        };
+        let e = decode_message(
+            EthMessageId::NewPooledTransactionHashes,
+            68,
+            &rlp::encode(&announcement),
+        )
+        .unwrap_err();
+        assert_eq!(e.offset, 0);
+
+        assert!(decode_message(
+            EthMessageId::GetNodeData,
+            67,
+            &rlp::encode_list::<H256, _>(&[])
+        )
+        .unwrap_err()
+        .error
+        .contains("does not exist"));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{config::DisconnectPolicyConfig, eth::EthMessageId};
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectAction {
+    Disconnect,
+    /// Log and drop the message, keeping the peer.
+    Skip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisconnectPolicy {
+    /// Message that fails to decode.
+    pub on_bad_rlp: DisconnectAction,
+    /// Message with unknown ID or received before Status exchange.
+    pub on_unknown_message: DisconnectAction,
+}
+
+impl DisconnectPolicy {
+    /// Requests, responses and Status disconnect on bad RLP, announcements are skipped.
+    pub fn default_for(id: usize) -> Self {
+        let on_bad_rlp = match EthMessageId::from_usize(id) {
+            Some(EthMessageId::NewBlockHashes)
+            | Some(EthMessageId::NewBlock)
+            | Some(EthMessageId::Transactions)
+            | Some(EthMessageId::NewPooledTransactionHashes) => DisconnectAction::Skip,
+            _ => DisconnectAction::Disconnect,
+        };
+
+        Self {
+            on_bad_rlp,
+            on_unknown_message: DisconnectAction::Skip,
+        }
+    }
+}
+
+/// Disconnect policies by message ID, with defaults for IDs not configured.
+#[derive(Debug, Default)]
+pub struct DisconnectPolicies {
+    by_message_id: HashMap<usize, DisconnectPolicy>,
+}
+
+impl DisconnectPolicies {
+    pub fn new(config: &[DisconnectPolicyConfig]) -> Self {
+        let mut by_message_id = HashMap::new();
+        for rule in config {
+            let policy = by_message_id
+                .entry(rule.message_id)
+                .or_insert_with(|| DisconnectPolicy::default_for(rule.message_id));
+            if let Some(action) = rule.on_bad_rlp {
+                policy.on_bad_rlp = action;
+            }
+            if let Some(action) = rule.on_unknown_message {
+                policy.on_unknown_message = action;
+            }
+        }
+
+        Self { by_message_id }
+    }
+
+    pub fn get(&self, id: usize) -> DisconnectPolicy {
+        self.by_message_id
+            .get(&id)
+            .copied()
+            .unwrap_or_else(|| DisconnectPolicy::default_for(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::ToPrimitive;
+
+    #[test]
+    fn defaults_and_overrides() {
+        let id = |id: EthMessageId| id.to_usize().unwrap();
+
+        let policies = DisconnectPolicies::new(&[
+            DisconnectPolicyConfig {
+                message_id: id(EthMessageId::BlockHeaders),
+                on_bad_rlp: Some(DisconnectAction::Skip),
+                on_unknown_message: None,
+            },
+            DisconnectPolicyConfig {
+                message_id: 0x20,
+                on_bad_rlp: None,
+                on_unknown_message: Some(DisconnectAction::Disconnect),
+            },
+        ]);
+
+        assert_eq!(
+            policies.get(id(EthMessageId::GetBlockHeaders)),
+            DisconnectPolicy {
+                on_bad_rlp: DisconnectAction::Disconnect,
+                on_unknown_message: DisconnectAction::Skip,
+            }
+        );
+        assert_eq!(
+            policies
+                .get(id(EthMessageId::NewPooledTransactionHashes))
+                .on_bad_rlp,
+            DisconnectAction::Skip
+        );
+        assert_eq!(
+            policies.get(id(EthMessageId::BlockHeaders)),
+            DisconnectPolicy {
+                on_bad_rlp: DisconnectAction::Skip,
+                on_unknown_message: DisconnectAction::Skip,
+            }
+        );
+        assert_eq!(
+            policies.get(0x20).on_unknown_message,
+            DisconnectAction::Disconnect
+        );
+        assert_eq!(
+            policies.get(0x21).on_unknown_message,
+            DisconnectAction::Skip
+        );
+    }
+}
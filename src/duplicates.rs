@@ -0,0 +1,253 @@
+use crate::eth::EthMessageId;
+use devp2p::util::keccak256;
+use ethereum_types::H256;
+use probabilistic_collections::bloom::ScalableBloomFilter;
+use rlp::Rlp;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::StreamExt;
+use tokio_util::time::DelayQueue;
+
+/// Number of filters the window is split into, the oldest one is dropped as the window slides.
+const GENERATIONS: u32 = 4;
+const INITIAL_CAPACITY: usize = 1024;
+
+type Key = (EthMessageId, H256);
+
+/// Copies of the same announcement let through within the window, the rest are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CopyLimits {
+    pub new_block_hashes: usize,
+    /// More than one copy guards against a peer that announces a block and withholds it.
+    pub new_block: usize,
+}
+
+impl Default for CopyLimits {
+    fn default() -> Self {
+        Self {
+            new_block_hashes: 1,
+            new_block: 2,
+        }
+    }
+}
+
+impl CopyLimits {
+    fn get(&self, id: EthMessageId) -> usize {
+        match id {
+            EthMessageId::NewBlock => self.new_block,
+            _ => self.new_block_hashes,
+        }
+    }
+}
+
+/// Hash identifying the announcement: of the block header for `NewBlock`, as copies from
+/// different peers may carry different total difficulty, and of the message otherwise.
+fn announcement_hash(id: EthMessageId, data: &[u8]) -> H256 {
+    if id == EthMessageId::NewBlock {
+        if let Ok(header) = Rlp::new(data).at(0).and_then(|block| block.at(0)) {
+            return keccak256(header.as_raw());
+        }
+    }
+    keccak256(data)
+}
+
+#[derive(Debug)]
+struct Generation {
+    id: u64,
+    started: Instant,
+    /// Filter at index `n` holds messages seen for the `n+1`th time.
+    filters: Vec<ScalableBloomFilter<Key>>,
+}
+
+/// Tells messages that have been received from other peers within the window more times
+/// than their copy limit.
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    window: Duration,
+    false_positive_rate: f64,
+    limits: CopyLimits,
+    generations: VecDeque<Generation>,
+    next_id: u64,
+    started: UnboundedSender<u64>,
+}
+
+/// Timer queue that yields the generations of [`DuplicateFilter`] as they leave the window.
+#[derive(Debug)]
+pub struct DuplicateFilterExpiry {
+    window: Duration,
+    started: UnboundedReceiver<u64>,
+    queue: DelayQueue<u64>,
+}
+
+pub fn duplicate_filter(
+    window: Duration,
+    false_positive_rate: f64,
+    limits: CopyLimits,
+) -> (DuplicateFilter, DuplicateFilterExpiry) {
+    let (started_tx, started_rx) = unbounded_channel();
+    (
+        DuplicateFilter {
+            window,
+            false_positive_rate,
+            limits,
+            generations: VecDeque::with_capacity(GENERATIONS as usize + 1),
+            next_id: 0,
+            started: started_tx,
+        },
+        DuplicateFilterExpiry {
+            window,
+            started: started_rx,
+            queue: DelayQueue::new(),
+        },
+    )
+}
+
+impl DuplicateFilter {
+    /// Whether the message has already been seen within the window as many times as its
+    /// copy limit allows, records it if not.
+    pub fn check(&mut self, id: EthMessageId, data: &[u8], now: Instant) -> bool {
+        let key = (id, announcement_hash(id, data));
+        let copy = match (0..self.limits.get(id).max(1)).find(|&copy| {
+            !self.generations.iter().any(|generation| {
+                generation
+                    .filters
+                    .get(copy)
+                    .map_or(false, |filter| filter.contains(&key))
+            })
+        }) {
+            Some(v) => v,
+            None => return true,
+        };
+
+        let slide = self.window / GENERATIONS;
+        if self.generations.back().map_or(true, |newest| {
+            now.saturating_duration_since(newest.started) >= slide
+        }) {
+            // Older generations are out of the window even if their expiry is late.
+            if self.generations.len() > GENERATIONS as usize {
+                self.generations.pop_front();
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            self.generations.push_back(Generation {
+                id,
+                started: now,
+                filters: vec![],
+            });
+            let _ = self.started.send(id);
+        }
+
+        let filters = &mut self.generations.back_mut().unwrap().filters;
+        while filters.len() <= copy {
+            filters.push(ScalableBloomFilter::new(
+                INITIAL_CAPACITY,
+                self.false_positive_rate,
+                2.0,
+                0.5,
+            ));
+        }
+        filters[copy].insert(&key);
+        false
+    }
+
+    /// Forget messages recorded up to the end of the generation.
+    pub fn expire(&mut self, generation: u64) {
+        self.generations.retain(|g| g.id > generation);
+    }
+}
+
+impl DuplicateFilterExpiry {
+    /// Next generation that has left the window, `None` once the filter is dropped.
+    pub async fn next(&mut self) -> Option<u64> {
+        loop {
+            tokio::select! {
+                generation = self.started.recv() => {
+                    self.queue.insert(generation?, self.window);
+                }
+                Some(Ok(expired)) = self.queue.next() => return Some(expired.into_inner()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::RlpStream;
+
+    #[tokio::test]
+    async fn duplicates_within_window() {
+        let window = Duration::from_millis(40);
+        let (mut filter, mut expiry) = duplicate_filter(window, 0.01, CopyLimits::default());
+        let now = Instant::now();
+
+        assert!(!filter.check(EthMessageId::NewBlockHashes, b"a", now));
+        assert!(filter.check(EthMessageId::NewBlockHashes, b"a", now));
+        assert!(!filter.check(EthMessageId::NewBlock, b"a", now));
+        assert!(!filter.check(EthMessageId::NewBlockHashes, b"b", now + window / 2));
+
+        assert_eq!(expiry.next().await, Some(0));
+        filter.expire(0);
+        assert!(!filter.check(EthMessageId::NewBlockHashes, b"a", now + window));
+        assert!(filter.check(EthMessageId::NewBlockHashes, b"b", now + window));
+
+        // Generations out of the window are dropped even if their expiry is late.
+        for slide in 1..=2 * GENERATIONS {
+            filter.check(
+                EthMessageId::NewBlockHashes,
+                &slide.to_be_bytes(),
+                now + window + window / GENERATIONS * slide,
+            );
+        }
+        assert_eq!(filter.generations.len(), GENERATIONS as usize + 1);
+        assert!(!filter.check(EthMessageId::NewBlockHashes, b"b", now + window * 3));
+
+        drop(filter);
+        while expiry.next().await.is_some() {}
+    }
+
+    #[tokio::test]
+    async fn propagation_storm_forwards_copy_limit() {
+        let (mut filter, _expiry) = duplicate_filter(
+            Duration::from_secs(5),
+            0.01,
+            CopyLimits {
+                new_block_hashes: 1,
+                new_block: 2,
+            },
+        );
+        let now = Instant::now();
+
+        let new_block = |number: u64, total_difficulty: u64| {
+            let mut header = RlpStream::new_list(2);
+            header.append(&H256::repeat_byte(1)).append(&number);
+            let mut block = RlpStream::new_list(3);
+            block
+                .append_raw(&header.out(), 1)
+                .begin_list(0)
+                .begin_list(0);
+            let mut s = RlpStream::new_list(2);
+            s.append_raw(&block.out(), 1).append(&total_difficulty);
+            s.out()
+        };
+        let new_block_hashes = rlp::encode_list(&[H256::repeat_byte(2)]);
+
+        // 20 peers announce the same block, with total difficulty as each of them sees it.
+        let mut forwarded = (0, 0);
+        for peer in 0..20 {
+            if !filter.check(EthMessageId::NewBlock, &new_block(100, 1000 + peer), now) {
+                forwarded.0 += 1;
+            }
+            if !filter.check(EthMessageId::NewBlockHashes, &new_block_hashes, now) {
+                forwarded.1 += 1;
+            }
+        }
+        assert_eq!(forwarded, (2, 1));
+
+        // Another block is not affected.
+        assert!(!filter.check(EthMessageId::NewBlock, &new_block(101, 1000), now));
+    }
+}
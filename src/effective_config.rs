@@ -0,0 +1,180 @@
+use crate::config::Config;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Fields that are never shown, only whether they are set.
+const SECRET_FIELDS: &[&str] = &["node_key"];
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Default,
+    File,
+    /// Command line argument or environment variable.
+    Cli,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigValue {
+    pub value: Value,
+    pub source: ConfigSource,
+}
+
+/// Configuration the process runs with, keyed by dotted field path,
+/// with the source of every value.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct EffectiveConfig {
+    entries: BTreeMap<String, ConfigValue>,
+}
+
+fn flatten(
+    path: String,
+    value: Value,
+    file: Option<&Value>,
+    out: &mut BTreeMap<String, ConfigValue>,
+) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                flatten(path, value, file.and_then(|file| file.get(&key)), out);
+            }
+        }
+        value => {
+            let source = if file.is_some() {
+                ConfigSource::File
+            } else {
+                ConfigSource::Default
+            };
+            out.insert(path, ConfigValue { value, source });
+        }
+    }
+}
+
+impl EffectiveConfig {
+    /// `file` is the config file as parsed into `config`, used to tell which values were set there.
+    pub fn new(config: &Config, file: &Value) -> anyhow::Result<Self> {
+        let mut entries = BTreeMap::new();
+        flatten(
+            String::new(),
+            serde_json::to_value(config)?,
+            Some(file),
+            &mut entries,
+        );
+
+        for &field in SECRET_FIELDS {
+            if let Some(entry) = entries.get_mut(field) {
+                if !entry.value.is_null() {
+                    entry.value = REDACTED.into();
+                }
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn insert(&mut self, path: impl Into<String>, value: impl Serialize, source: ConfigSource) {
+        self.entries.insert(
+            path.into(),
+            ConfigValue {
+                value: serde_json::to_value(value).unwrap_or(Value::Null),
+                source,
+            },
+        );
+    }
+
+    /// Add command line option, which takes the default value if not set.
+    pub fn insert_cli(
+        &mut self,
+        path: impl Into<String>,
+        value: Option<impl Serialize>,
+        default: impl Serialize,
+    ) {
+        match value {
+            Some(value) => self.insert(path, value, ConfigSource::Cli),
+            None => self.insert(path, default, ConfigSource::Default),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ConfigValue> {
+        self.entries.get(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn provenance_and_redaction() {
+        let file = r#"
+            node_key = "deadbeef"
+            max_peers = 10
+
+            [discv4]
+            port = 30305
+
+            [bandwidth_budget]
+            egress_bytes = 1000
+        "#;
+        let config = toml::from_str::<Config>(file).unwrap();
+        let mut effective = EffectiveConfig::new(
+            &config,
+            &serde_json::to_value(toml::from_str::<toml::Value>(file).unwrap()).unwrap(),
+        )
+        .unwrap();
+        effective.insert_cli("max_eth_version", Some(66), 68);
+        effective.insert_cli("min_eth_version", None::<usize>, 64);
+
+        let entry = |path| effective.get(path).unwrap().clone();
+        let value = |value: Value, source| ConfigValue { value, source };
+
+        assert_eq!(
+            entry("node_key"),
+            value(json!(REDACTED), ConfigSource::File)
+        );
+        assert_eq!(entry("max_peers"), value(json!(10), ConfigSource::File));
+        assert_eq!(
+            entry("listen_port"),
+            value(json!(30303), ConfigSource::Default)
+        );
+        assert_eq!(
+            entry("discv4.port"),
+            value(json!(30305), ConfigSource::File)
+        );
+        assert_eq!(
+            entry("discv4.cache"),
+            value(json!(20), ConfigSource::Default)
+        );
+        assert_eq!(entry("discv5"), value(Value::Null, ConfigSource::Default));
+        assert_eq!(
+            entry("bandwidth_budget.egress_bytes"),
+            value(json!(1000), ConfigSource::File)
+        );
+        assert_eq!(
+            entry("bandwidth_budget.period_secs"),
+            value(json!(86400), ConfigSource::Default)
+        );
+        assert_eq!(
+            entry("max_eth_version"),
+            value(json!(66), ConfigSource::Cli)
+        );
+        assert_eq!(
+            entry("min_eth_version"),
+            value(json!(64), ConfigSource::Default)
+        );
+
+        // Unset secret is shown as unset.
+        let effective =
+            EffectiveConfig::new(&Config::default(), &toml::from_str("").unwrap()).unwrap();
+        assert_eq!(effective.get("node_key").unwrap().value, Value::Null);
+    }
+}
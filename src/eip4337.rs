@@ -0,0 +1,121 @@
+//! Experimental gossip of EIP-4337 user operations over the `aa` capability.
+
+use crate::{
+    forwarding::{ForwardQueue, Stamp},
+    grpc::sentry::InboundMessage,
+    routers::MessageRouter,
+};
+use arrayvec::ArrayString;
+use async_trait::async_trait;
+use devp2p::*;
+use enum_primitive_derive::*;
+use num_traits::FromPrimitive;
+use rlp::Rlp;
+use std::sync::Arc;
+use tracing::*;
+
+/// Added to `aa` message IDs forwarded to the control, as `MessageId` of the sentry proto
+/// has no values for them.
+pub const EIP4337_MESSAGE_ID_PREFIX: i32 = 0x4337_00;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Primitive)]
+pub enum UserOperationMessageId {
+    GetUserOperationsByHash = 0,
+    SendUserOperation = 1,
+    UserOperationHashAnnouncement = 2,
+}
+
+/// Number of message IDs reserved by `aa/1`.
+pub const AA_MESSAGE_COUNT: usize = 3;
+
+pub fn aa_capability() -> CapabilityId {
+    CapabilityId {
+        name: CapabilityName(ArrayString::from("aa").unwrap()),
+        version: 1,
+    }
+}
+
+/// Forwards `aa` messages to the control like eth gossip, with IDs prefixed by
+/// `EIP4337_MESSAGE_ID_PREFIX`. Peers are never answered by the sentry itself.
+#[derive(Debug)]
+pub struct Eip4337CapabilityServer {
+    sender: Arc<ForwardQueue>,
+}
+
+impl Eip4337CapabilityServer {
+    pub fn new(sender: Arc<ForwardQueue>) -> Self {
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl MessageRouter for Eip4337CapabilityServer {
+    async fn on_message(&self, peer: PeerId, message: Message) -> Option<Message> {
+        let received = Stamp::now();
+        let id = match UserOperationMessageId::from_usize(message.id) {
+            Some(id) => id,
+            None => {
+                debug!("Dropping unknown aa message {} from {}", message.id, peer);
+                return None;
+            }
+        };
+        if !Rlp::new(&message.data).is_list() {
+            debug!("Dropping malformed {:?} from {}", id, peer);
+            return None;
+        }
+
+        if self
+            .sender
+            .send(
+                InboundMessage {
+                    id: EIP4337_MESSAGE_ID_PREFIX | id as i32,
+                    data: message.data,
+                    peer_id: Some(peer.into()),
+                },
+                received,
+            )
+            .is_err()
+        {
+            debug!("No control subscribed, dropping {:?} from {}", id, peer);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn forwards_with_prefixed_id() {
+        let sender = Arc::new(ForwardQueue::new("data", 4));
+        let mut receiver = sender.subscribe();
+        let server = Eip4337CapabilityServer::new(sender.clone());
+        let peer = PeerId::from_low_u64_be(1);
+        let hashes = rlp::encode_list(&[ethereum_types::H256::repeat_byte(1)]).freeze();
+
+        for message in vec![
+            Message {
+                id: UserOperationMessageId::UserOperationHashAnnouncement as usize,
+                data: hashes.clone(),
+            },
+            Message {
+                id: AA_MESSAGE_COUNT,
+                data: hashes.clone(),
+            },
+            Message {
+                id: UserOperationMessageId::SendUserOperation as usize,
+                data: Bytes::from_static(&[0x80]),
+            },
+        ] {
+            assert!(server.on_message(peer, message).await.is_none());
+        }
+
+        let forwarded = receiver.recv().await.unwrap().message;
+        assert_eq!(forwarded.id, 0x4337_02);
+        assert_eq!(forwarded.data, hashes);
+        assert_eq!(sender.stats(std::time::Instant::now()).depth, 0);
+    }
+}
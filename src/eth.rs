@@ -1,17 +1,124 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use arrayvec::ArrayString;
+use bytes::{BufMut, Bytes, BytesMut};
 use devp2p::*;
 use enum_primitive_derive::*;
 use ethereum_forkid::{ForkFilter, ForkId};
 use ethereum_types::*;
+use hex_literal::hex;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
 use rlp_derive::*;
 use serde::Deserialize;
-use std::{collections::BTreeSet, convert::TryFrom};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+};
 
 pub fn capability_name() -> CapabilityName {
     CapabilityName(ArrayString::from("eth").unwrap())
 }
 
+/// Highest eth protocol version defined by the spec that this sentry knows about.
+pub const MAX_KNOWN_ETH_VERSION: usize = 68;
+
+/// Oldest eth version, only advertised in compatibility mode. Its Status has no fork ID.
+pub const ETH_63: u8 = 63;
+/// First eth version that wraps requests and responses with request IDs.
+pub const ETH_66: u8 = 66;
+/// First eth version that announces transaction types and sizes with the hashes.
+pub const ETH_68: u8 = 68;
+
+/// Capability version checked against a supported range known at compile time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BoundedCapabilityVersion<const MIN: usize, const MAX: usize>(CapabilityVersion);
+
+impl<const MIN: usize, const MAX: usize> BoundedCapabilityVersion<MIN, MAX> {
+    /// Fails to compile if the range is empty or goes beyond the known protocol versions.
+    const BOUNDS_CHECK: () = assert!(MIN <= MAX && MAX <= MAX_KNOWN_ETH_VERSION);
+
+    pub const MIN: CapabilityVersion = MIN;
+    pub const MAX: CapabilityVersion = MAX;
+
+    /// Returns `None` if version is outside of the supported range.
+    pub fn new(version: CapabilityVersion) -> Option<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::BOUNDS_CHECK;
+
+        if (MIN..=MAX).contains(&version) {
+            Some(Self(version))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> CapabilityVersion {
+        self.0
+    }
+}
+
+/// eth versions the handlers in this sentry are written for.
+pub type EthVersion = BoundedCapabilityVersion<63, 68>;
+
+/// Supported eth protocol versions.
+pub const SUPPORTED_ETH_VERSIONS: &[CapabilityVersion] = &[64, 65, 68];
+
+/// Capabilities to advertise given optional eth version pins. eth/63 is added to the
+/// supported versions if `eth63_compat` is set. eth/66 and later are left out unless
+/// `normalize_request_ids` is set, as the control sends requests without request IDs.
+pub fn eth_capabilities(
+    min_version: Option<CapabilityVersion>,
+    max_version: Option<CapabilityVersion>,
+    eth63_compat: bool,
+    normalize_request_ids: bool,
+) -> anyhow::Result<BTreeMap<CapabilityId, usize>> {
+    if let (Some(min), Some(max)) = (min_version, max_version) {
+        if min > max {
+            bail!(
+                "Minimum eth version ({}) is greater than maximum eth version ({})",
+                min,
+                max
+            );
+        }
+    }
+
+    let capabilities = SUPPORTED_ETH_VERSIONS
+        .iter()
+        .copied()
+        .chain(Some(ETH_63 as usize).filter(|_| eth63_compat))
+        .filter(|&version| {
+            EthVersion::new(version).is_some()
+                && (normalize_request_ids || version < ETH_66 as usize)
+                && min_version.map(|min| version >= min).unwrap_or(true)
+                && max_version.map(|max| version <= max).unwrap_or(true)
+        })
+        .map(|version| {
+            (
+                CapabilityId {
+                    name: capability_name(),
+                    version,
+                },
+                message_space_length(version),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    if capabilities.is_empty() {
+        bail!(
+            "No supported eth version within [{}, {}], supported versions are: {:?} \
+             (eth/66 and later need normalize_request_ids)",
+            min_version
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".into()),
+            max_version
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".into()),
+            SUPPORTED_ETH_VERSIONS
+        );
+    }
+
+    Ok(capabilities)
+}
+
 #[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
 pub struct StatusMessage {
     pub protocol_version: usize,
@@ -22,17 +129,110 @@ pub struct StatusMessage {
     pub fork_id: ForkId,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Status of eth/63, which predates fork IDs.
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct Eth63StatusMessage {
+    pub protocol_version: usize,
+    pub network_id: u64,
+    pub total_difficulty: U256,
+    pub best_hash: H256,
+    pub genesis_hash: H256,
+}
+
+impl StatusMessage {
+    /// Encode as sent at its protocol version, without the fork ID at eth/63.
+    pub fn encode_for_version(&self) -> Bytes {
+        if self.protocol_version == ETH_63 as usize {
+            rlp::encode(&Eth63StatusMessage {
+                protocol_version: self.protocol_version,
+                network_id: self.network_id,
+                total_difficulty: self.total_difficulty,
+                best_hash: self.best_hash,
+                genesis_hash: self.genesis_hash,
+            })
+            .freeze()
+        } else {
+            rlp::encode(self).freeze()
+        }
+    }
+}
+
+/// Decode Status sent at the eth version into the fields all versions share and
+/// the fork ID, which eth/63 does not have.
+pub fn decode_status(
+    version: u8,
+    data: &[u8],
+) -> Result<(Eth63StatusMessage, Option<ForkId>), DecoderError> {
+    if version == ETH_63 {
+        return Ok((rlp::decode(data)?, None));
+    }
+
+    let status = rlp::decode::<StatusMessage>(data)?;
+    Ok((
+        Eth63StatusMessage {
+            protocol_version: status.protocol_version,
+            network_id: status.network_id,
+            total_difficulty: status.total_difficulty,
+            best_hash: status.best_hash,
+            genesis_hash: status.genesis_hash,
+        },
+        Some(status.fork_id),
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
 pub struct Forks {
     pub genesis: H256,
     pub forks: BTreeSet<u64>,
 }
 
+/// Chain parameters sufficient to compute fork ID without a running node.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChainConfig {
+    pub genesis_hash: H256,
+    pub fork_blocks: BTreeSet<u64>,
+}
+
+impl ChainConfig {
+    pub fn mainnet() -> Self {
+        Self {
+            genesis_hash: hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3")
+                .into(),
+            fork_blocks: [
+                1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000, 9_069_000,
+                9_200_000, 12_244_000, 12_965_000, 13_773_000, 15_050_000,
+            ]
+            .iter()
+            .copied()
+            .collect(),
+        }
+    }
+
+    pub fn fork_filter(&self, head_block: u64) -> ForkFilter {
+        ForkFilter::new(
+            head_block,
+            self.genesis_hash,
+            self.fork_blocks.iter().copied().collect::<Vec<_>>(),
+        )
+    }
+}
+
+pub trait ForkIdExt {
+    fn from_chain_config(config: &ChainConfig, head_block: u64) -> Self;
+}
+
+impl ForkIdExt for ForkId {
+    fn from_chain_config(config: &ChainConfig, head_block: u64) -> Self {
+        config.fork_filter(head_block).current()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StatusData {
     pub network_id: u64,
     pub total_difficulty: U256,
     pub best_hash: H256,
+    pub best_block: u64,
     pub fork_data: Forks,
 }
 
@@ -42,7 +242,91 @@ pub struct FullStatusData {
     pub fork_filter: ForkFilter,
 }
 
-impl TryFrom<crate::grpc::sentry::StatusData> for FullStatusData {
+impl StatusData {
+    pub fn fork_filter(&self) -> ForkFilter {
+        ForkFilter::new(
+            self.best_block,
+            self.fork_data.genesis,
+            self.fork_data.forks.iter().copied().collect::<Vec<_>>(),
+        )
+    }
+
+    /// Number of forks activated at our head.
+    fn fork_era(&self) -> usize {
+        self.fork_data.forks.range(1..=self.best_block).count()
+    }
+}
+
+/// Whether `new` head is on a different branch than `old` one: lower, or another block at
+/// the same height.
+pub fn is_reorg(old: &StatusData, new: &StatusData) -> bool {
+    new.best_block < old.best_block
+        || (new.best_block == old.best_block && new.best_hash != old.best_hash)
+}
+
+/// How a status update relates to the previous status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusChange {
+    /// First status, or genesis or fork list differ.
+    ForksChanged,
+    /// Head has crossed a fork block, our fork ID changes.
+    ForkBoundary,
+    /// Head has moved back or switched to another block at the same height.
+    Reorg,
+    /// Head has moved within the same fork, fork ID stays the same.
+    HeadMoved,
+}
+
+impl StatusChange {
+    pub fn classify(previous: Option<&StatusData>, status: &StatusData) -> Self {
+        match previous {
+            Some(previous) if previous.fork_data == status.fork_data => {
+                if previous.fork_era() != status.fork_era() {
+                    Self::ForkBoundary
+                } else if is_reorg(previous, status) {
+                    Self::Reorg
+                } else {
+                    Self::HeadMoved
+                }
+            }
+            _ => Self::ForksChanged,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ForksChanged => "forks_changed",
+            Self::ForkBoundary => "fork_boundary",
+            Self::Reorg => "reorg",
+            Self::HeadMoved => "head_moved",
+        }
+    }
+}
+
+impl FullStatusData {
+    pub fn new(status: StatusData) -> Self {
+        Self {
+            fork_filter: status.fork_filter(),
+            status,
+        }
+    }
+
+    /// Replace `current` status, reusing its fork filter if the head has only moved forward
+    /// within the same fork. Filter is rebuilt from scratch otherwise.
+    pub fn update(current: &mut Option<Self>, status: StatusData) -> StatusChange {
+        let change = StatusChange::classify(current.as_ref().map(|s| &s.status), &status);
+        match (change, current.as_mut()) {
+            (StatusChange::HeadMoved, Some(current)) => {
+                current.fork_filter.set_head(status.best_block);
+                current.status = status;
+            }
+            _ => *current = Some(Self::new(status)),
+        }
+        change
+    }
+}
+
+impl TryFrom<crate::grpc::sentry::StatusData> for StatusData {
     type Error = anyhow::Error;
 
     fn try_from(value: crate::grpc::sentry::StatusData) -> Result<Self, Self::Error> {
@@ -60,27 +344,132 @@ impl TryFrom<crate::grpc::sentry::StatusData> for FullStatusData {
             .ok_or_else(|| anyhow!("no genesis"))?
             .into();
 
-        let fork_filter = ForkFilter::new(max_block, genesis, fork_data.forks.clone());
-        let status = StatusData {
+        Ok(Self {
             network_id,
             total_difficulty: total_difficulty
                 .ok_or_else(|| anyhow!("no total difficulty"))?
                 .into(),
             best_hash: best_hash.ok_or_else(|| anyhow!("no best hash"))?.into(),
+            best_block: max_block,
             fork_data: Forks {
                 genesis,
                 forks: fork_data.forks.into_iter().collect(),
             },
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockId {
+    Hash(H256),
+    Number(u64),
+}
+
+impl Encodable for BlockId {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match self {
+            Self::Hash(hash) => s.append(hash),
+            Self::Number(number) => s.append(number),
         };
+    }
+}
 
-        Ok(Self {
-            status,
-            fork_filter,
+impl Decodable for BlockId {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        Ok(if rlp.size() == H256::len_bytes() {
+            Self::Hash(rlp.as_val()?)
+        } else {
+            Self::Number(rlp.as_val()?)
         })
     }
 }
 
-#[derive(Clone, Copy, Debug, Primitive)]
+/// eth/68 NewPooledTransactionHashes: `[types: B, [size, ...], [hash, ...]]`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NewPooledTransactionHashes68 {
+    pub types: Vec<u8>,
+    pub sizes: Vec<u32>,
+    pub hashes: Vec<H256>,
+}
+
+impl NewPooledTransactionHashes68 {
+    /// `(type, size, hash)` of each announced transaction.
+    pub fn announcements(&self) -> impl Iterator<Item = (u8, u32, H256)> + '_ {
+        self.types
+            .iter()
+            .zip(&self.sizes)
+            .zip(&self.hashes)
+            .map(|((&ty, &size), &hash)| (ty, size, hash))
+    }
+}
+
+impl Encodable for NewPooledTransactionHashes68 {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(3);
+        s.append(&self.types);
+        s.append_list(&self.sizes);
+        s.append_list(&self.hashes);
+    }
+}
+
+impl Decodable for NewPooledTransactionHashes68 {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 3 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        let v = Self {
+            types: rlp.val_at(0)?,
+            sizes: rlp.list_at(1)?,
+            hashes: rlp.list_at(2)?,
+        };
+
+        if v.types.len() != v.hashes.len() || v.sizes.len() != v.hashes.len() {
+            return Err(DecoderError::Custom(
+                "transaction types, sizes and hashes differ in length",
+            ));
+        }
+
+        Ok(v)
+    }
+}
+
+/// Entry of NewBlockHashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct BlockHashNumber {
+    pub hash: H256,
+    pub number: u64,
+}
+
+#[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+pub struct GetBlockHeaders {
+    pub block: BlockId,
+    pub max_headers: u64,
+    pub skip: u64,
+    pub reverse: bool,
+}
+
+/// Validator withdrawal included in post-Shanghai block bodies.
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct Withdrawal {
+    pub index: u64,
+    pub validator_index: u64,
+    pub address: Address,
+    /// Amount in Gwei.
+    pub amount: u64,
+}
+
+/// Root of the trie of RLP encoded withdrawals keyed by their position in the body.
+pub fn withdrawals_root(withdrawals: &[Withdrawal]) -> H256 {
+    ethereum::util::ordered_trie_root(withdrawals.iter().map(rlp::encode))
+}
+
+/// Whether withdrawals in the block body match the root committed to in the header.
+pub fn verify_withdrawals(header_withdrawals_root: H256, body_withdrawals: &[Withdrawal]) -> bool {
+    withdrawals_root(body_withdrawals) == header_withdrawals_root
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Primitive)]
 pub enum EthMessageId {
     Status = 0,
     NewBlockHashes = 1,
@@ -98,3 +487,694 @@ pub enum EthMessageId {
     GetReceipts = 15,
     Receipts = 16,
 }
+
+/// What a message is for, which also tells which side sends it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Sent once by both sides right after connecting.
+    Handshake,
+    /// Unsolicited gossip.
+    Announcement,
+    Request,
+    Response,
+}
+
+const ETH_64_MESSAGES: &[EthMessageId] = &[
+    EthMessageId::Status,
+    EthMessageId::NewBlockHashes,
+    EthMessageId::Transactions,
+    EthMessageId::GetBlockHeaders,
+    EthMessageId::BlockHeaders,
+    EthMessageId::GetBlockBodies,
+    EthMessageId::BlockBodies,
+    EthMessageId::NewBlock,
+    EthMessageId::GetNodeData,
+    EthMessageId::NodeData,
+    EthMessageId::GetReceipts,
+    EthMessageId::Receipts,
+];
+
+/// eth/65 adds transaction pool messages, eth/66 keeps the same set.
+const ETH_65_MESSAGES: &[EthMessageId] = &[
+    EthMessageId::Status,
+    EthMessageId::NewBlockHashes,
+    EthMessageId::Transactions,
+    EthMessageId::GetBlockHeaders,
+    EthMessageId::BlockHeaders,
+    EthMessageId::GetBlockBodies,
+    EthMessageId::BlockBodies,
+    EthMessageId::NewBlock,
+    EthMessageId::NewPooledTransactionHashes,
+    EthMessageId::GetPooledTransactions,
+    EthMessageId::PooledTransactions,
+    EthMessageId::GetNodeData,
+    EthMessageId::NodeData,
+    EthMessageId::GetReceipts,
+    EthMessageId::Receipts,
+];
+
+/// eth/67 removes state sync messages, eth/68 keeps the same set.
+const ETH_67_MESSAGES: &[EthMessageId] = &[
+    EthMessageId::Status,
+    EthMessageId::NewBlockHashes,
+    EthMessageId::Transactions,
+    EthMessageId::GetBlockHeaders,
+    EthMessageId::BlockHeaders,
+    EthMessageId::GetBlockBodies,
+    EthMessageId::BlockBodies,
+    EthMessageId::NewBlock,
+    EthMessageId::NewPooledTransactionHashes,
+    EthMessageId::GetPooledTransactions,
+    EthMessageId::PooledTransactions,
+    EthMessageId::GetReceipts,
+    EthMessageId::Receipts,
+];
+
+/// Messages that exist at the eth version, empty for unknown versions.
+pub fn eth_messages(version: CapabilityVersion) -> &'static [EthMessageId] {
+    match version {
+        // eth/64 only adds the fork ID to Status.
+        63 | 64 => ETH_64_MESSAGES,
+        65 | 66 => ETH_65_MESSAGES,
+        67 | 68 => ETH_67_MESSAGES,
+        _ => &[],
+    }
+}
+
+/// Length of the message ID space that the eth version takes in the capability registry.
+pub fn message_space_length(version: CapabilityVersion) -> usize {
+    eth_messages(version)
+        .iter()
+        .map(|id| id.to_usize().unwrap() + 1)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Largest eth message payload other clients accept.
+pub const MAX_ETH_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+impl EthMessageId {
+    /// Message with the wire ID at the eth version, `None` if there is no such message
+    /// at that version.
+    pub fn from_wire(version: CapabilityVersion, id: usize) -> Option<Self> {
+        Self::from_usize(id).filter(|id| eth_messages(version).contains(id))
+    }
+
+    /// Wire ID of the message at the eth version, `None` if it does not exist there.
+    pub fn to_wire(self, version: CapabilityVersion) -> Option<usize> {
+        if eth_messages(version).contains(&self) {
+            self.to_usize()
+        } else {
+            None
+        }
+    }
+
+    pub fn kind(self) -> MessageKind {
+        match self {
+            Self::Status => MessageKind::Handshake,
+            Self::NewBlockHashes
+            | Self::Transactions
+            | Self::NewBlock
+            | Self::NewPooledTransactionHashes => MessageKind::Announcement,
+            Self::GetBlockHeaders
+            | Self::GetBlockBodies
+            | Self::GetPooledTransactions
+            | Self::GetNodeData
+            | Self::GetReceipts => MessageKind::Request,
+            Self::BlockHeaders
+            | Self::BlockBodies
+            | Self::PooledTransactions
+            | Self::NodeData
+            | Self::Receipts => MessageKind::Response,
+        }
+    }
+
+    /// Whether the message may only be exchanged after a valid Status.
+    pub fn requires_status(self) -> bool {
+        self.kind() != MessageKind::Handshake
+    }
+
+    /// Whether the message carries a request ID since eth/66.
+    pub fn has_request_id(self) -> bool {
+        !matches!(
+            self,
+            Self::Status
+                | Self::NewBlockHashes
+                | Self::Transactions
+                | Self::NewBlock
+                | Self::NewPooledTransactionHashes
+        )
+    }
+
+    /// Response to this request.
+    pub fn response(self) -> Option<Self> {
+        match self {
+            Self::GetBlockHeaders => Some(Self::BlockHeaders),
+            Self::GetBlockBodies => Some(Self::BlockBodies),
+            Self::GetPooledTransactions => Some(Self::PooledTransactions),
+            Self::GetNodeData => Some(Self::NodeData),
+            Self::GetReceipts => Some(Self::Receipts),
+            _ => None,
+        }
+    }
+}
+
+/// Wrap request or response payload with eth/66 request ID.
+pub fn wrap_request_id(request_id: u64, payload: &[u8]) -> Bytes {
+    let mut s = RlpStream::new_list(2);
+    s.append(&request_id);
+    s.append_raw(payload, 1);
+    s.out().freeze()
+}
+
+/// RLP list of already encoded items, written into a buffer of the exact size.
+///
+/// Unlike `RlpStream`, the list header is written first from the precomputed
+/// payload length, so items are copied once and never moved.
+pub fn encode_raw_list(items: &[Bytes]) -> Bytes {
+    let payload_len = items.iter().map(Bytes::len).sum::<usize>();
+    let len_bytes = payload_len.to_be_bytes();
+    let len_bytes = &len_bytes[(payload_len.leading_zeros() / 8) as usize..];
+
+    let mut out = BytesMut::with_capacity(1 + len_bytes.len() + payload_len);
+    if payload_len < 56 {
+        out.put_u8(0xc0 + payload_len as u8);
+    } else {
+        out.put_u8(0xf7 + len_bytes.len() as u8);
+        out.put_slice(len_bytes);
+    }
+    for item in items {
+        out.put_slice(item);
+    }
+    out.freeze()
+}
+
+/// Split eth/66 request or response into request ID and payload.
+pub fn unwrap_request_id(data: &[u8]) -> Result<(u64, Bytes), DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? != 2 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+    Ok((rlp.val_at(0)?, Bytes::copy_from_slice(rlp.at(1)?.as_raw())))
+}
+
+/// Frame version-agnostic payload for a peer with negotiated `version`.
+/// `request_id` is used only if the message carries one at this version.
+pub fn frame_message(id: EthMessageId, version: u8, request_id: u64, payload: Bytes) -> Bytes {
+    if version >= ETH_66 && id.has_request_id() {
+        wrap_request_id(request_id, &payload)
+    } else {
+        payload
+    }
+}
+
+fn check_list_of_lists(rlp: &Rlp) -> Result<(), DecoderError> {
+    if !rlp.is_list() {
+        return Err(DecoderError::RlpExpectedToBeList);
+    }
+    for item in rlp.iter() {
+        if !item.is_list() {
+            return Err(DecoderError::RlpExpectedToBeList);
+        }
+    }
+    Ok(())
+}
+
+/// Check that the payload is a structurally valid message with this id for a peer
+/// with negotiated `version`. Contents are not checked.
+pub fn validate_message(id: EthMessageId, version: u8, data: &[u8]) -> anyhow::Result<()> {
+    if data.len() > MAX_ETH_MESSAGE_SIZE {
+        bail!(
+            "{:?} payload size ({}) exceeds limit ({} bytes)",
+            id,
+            data.len(),
+            MAX_ETH_MESSAGE_SIZE
+        );
+    }
+
+    if id.to_wire(version.into()).is_none() {
+        bail!("{:?} does not exist in eth/{}", id, version);
+    }
+
+    let check = || -> Result<(), DecoderError> {
+        let rlp = Rlp::new(data);
+        let info = rlp.payload_info()?;
+        if info.header_len + info.value_len != data.len() {
+            return Err(DecoderError::RlpInconsistentLengthAndData);
+        }
+
+        let rlp = if version >= ETH_66 && id.has_request_id() {
+            if rlp.item_count()? != 2 {
+                return Err(DecoderError::RlpIncorrectListLen);
+            }
+            rlp.val_at::<u64>(0)?;
+            rlp.at(1)?
+        } else {
+            rlp
+        };
+
+        match id {
+            EthMessageId::Status => {
+                decode_status(version, rlp.as_raw())?;
+            }
+            EthMessageId::NewBlockHashes => {
+                rlp.as_list::<BlockHashNumber>()?;
+            }
+            EthMessageId::GetBlockHeaders => {
+                rlp.as_val::<GetBlockHeaders>()?;
+            }
+            EthMessageId::NewBlock => {
+                if rlp.item_count()? != 2 || rlp.at(0)?.item_count()? < 3 {
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                rlp.val_at::<U256>(1)?;
+            }
+            EthMessageId::NewPooledTransactionHashes if version >= ETH_68 => {
+                rlp.as_val::<NewPooledTransactionHashes68>()?;
+            }
+            EthMessageId::NewPooledTransactionHashes
+            | EthMessageId::GetBlockBodies
+            | EthMessageId::GetPooledTransactions
+            | EthMessageId::GetNodeData
+            | EthMessageId::GetReceipts => {
+                rlp.as_list::<H256>()?;
+            }
+            EthMessageId::NodeData => {
+                rlp.as_list::<Vec<u8>>()?;
+            }
+            // Legacy transactions are lists, typed ones are strings.
+            EthMessageId::Transactions | EthMessageId::PooledTransactions => {
+                if !rlp.is_list() {
+                    return Err(DecoderError::RlpExpectedToBeList);
+                }
+            }
+            EthMessageId::BlockHeaders | EthMessageId::BlockBodies | EthMessageId::Receipts => {
+                check_list_of_lists(&rlp)?;
+            }
+        }
+
+        Ok(())
+    };
+
+    check().map_err(|e| anyhow!("invalid {:?} payload: {}", id, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_forkid::ForkHash;
+
+    #[test]
+    fn supported_versions_within_bounds() {
+        for &version in SUPPORTED_ETH_VERSIONS {
+            assert_eq!(EthVersion::new(version).map(EthVersion::get), Some(version));
+            assert_eq!(message_space_length(version), 17);
+        }
+
+        assert_eq!(EthVersion::new(EthVersion::MIN - 1), None);
+        assert_eq!(EthVersion::new(EthVersion::MAX + 1), None);
+    }
+
+    #[test]
+    fn request_id_versions_need_normalization() {
+        let versions = eth_capabilities(None, None, false, false)
+            .unwrap()
+            .keys()
+            .map(|cap| cap.version)
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec![64, 65]);
+        assert!(eth_capabilities(Some(68), None, false, false).is_err());
+        assert!(eth_capabilities(Some(68), None, false, true).is_ok());
+    }
+
+    #[test]
+    fn eth63_compat() {
+        let versions = |compat| {
+            eth_capabilities(None, None, compat, true)
+                .unwrap()
+                .keys()
+                .map(|cap| cap.version)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(false), SUPPORTED_ETH_VERSIONS);
+        assert_eq!(versions(true)[0], 63);
+        assert_eq!(message_space_length(63), 17);
+        assert_eq!(
+            EthMessageId::from_wire(63, 0x0d),
+            Some(EthMessageId::GetNodeData)
+        );
+        assert_eq!(EthMessageId::from_wire(63, 0x08), None);
+
+        let status = StatusMessage {
+            protocol_version: 63,
+            network_id: 1,
+            total_difficulty: 1.into(),
+            best_hash: H256::repeat_byte(1),
+            genesis_hash: H256::repeat_byte(2),
+            fork_id: ForkId {
+                hash: ForkHash(hex!("fc64ec04")),
+                next: 0,
+            },
+        };
+        let encoded = status.encode_for_version();
+        assert_eq!(Rlp::new(&encoded).item_count().unwrap(), 5);
+        let (decoded, fork_id) = decode_status(ETH_63, &encoded).unwrap();
+        assert_eq!(decoded.genesis_hash, status.genesis_hash);
+        assert_eq!(fork_id, None);
+        assert!(validate_message(EthMessageId::Status, ETH_63, &encoded).is_ok());
+        assert!(validate_message(EthMessageId::Status, 64, &encoded).is_err());
+
+        let status = StatusMessage {
+            protocol_version: 64,
+            ..status
+        };
+        assert_eq!(
+            decode_status(64, &status.encode_for_version()).unwrap().1,
+            Some(status.fork_id)
+        );
+    }
+
+    #[test]
+    fn messages_by_version() {
+        use EthMessageId::*;
+
+        // Which of the eth/64-66 wire IDs each version accepts.
+        let expected: &[(usize, Option<EthMessageId>, [bool; 3])] = &[
+            (0x00, Some(Status), [true, true, true]),
+            (0x01, Some(NewBlockHashes), [true, true, true]),
+            (0x02, Some(Transactions), [true, true, true]),
+            (0x03, Some(GetBlockHeaders), [true, true, true]),
+            (0x04, Some(BlockHeaders), [true, true, true]),
+            (0x05, Some(GetBlockBodies), [true, true, true]),
+            (0x06, Some(BlockBodies), [true, true, true]),
+            (0x07, Some(NewBlock), [true, true, true]),
+            (0x08, Some(NewPooledTransactionHashes), [false, true, true]),
+            (0x09, Some(GetPooledTransactions), [false, true, true]),
+            (0x0a, Some(PooledTransactions), [false, true, true]),
+            (0x0b, None, [false, false, false]),
+            (0x0c, None, [false, false, false]),
+            (0x0d, Some(GetNodeData), [true, true, true]),
+            (0x0e, Some(NodeData), [true, true, true]),
+            (0x0f, Some(GetReceipts), [true, true, true]),
+            (0x10, Some(Receipts), [true, true, true]),
+            (0x11, None, [false, false, false]),
+        ];
+
+        for &(wire_id, message, accepted) in expected {
+            for (&version, &accepted) in [64, 65, 66].iter().zip(&accepted) {
+                let decoded = EthMessageId::from_wire(version, wire_id);
+                assert_eq!(
+                    decoded,
+                    message.filter(|_| accepted),
+                    "{:#x} at eth/{}",
+                    wire_id,
+                    version
+                );
+                if let Some(message) = decoded {
+                    assert_eq!(message.to_wire(version), Some(wire_id));
+                }
+            }
+        }
+
+        for &version in &[64, 65, 66] {
+            assert_eq!(message_space_length(version), 17);
+        }
+        assert_eq!(EthMessageId::from_wire(68, 0x0d), None);
+        assert_eq!(
+            EthMessageId::from_wire(68, 0x08),
+            Some(NewPooledTransactionHashes)
+        );
+        assert_eq!(EthMessageId::from_wire(63, 0x00), None);
+
+        assert!(!Status.requires_status());
+        assert_eq!(GetReceipts.kind(), MessageKind::Request);
+        assert_eq!(Receipts.kind(), MessageKind::Response);
+    }
+
+    #[test]
+    fn new_pooled_transaction_hashes_68() {
+        let msg = NewPooledTransactionHashes68 {
+            types: vec![0, 2],
+            sizes: vec![110, 1_000_000],
+            hashes: vec![H256::repeat_byte(1), H256::repeat_byte(2)],
+        };
+
+        let decoded = rlp::decode::<NewPooledTransactionHashes68>(&rlp::encode(&msg)).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(
+            decoded.announcements().collect::<Vec<_>>(),
+            vec![
+                (0, 110, H256::repeat_byte(1)),
+                (2, 1_000_000, H256::repeat_byte(2))
+            ]
+        );
+
+        let mut s = RlpStream::new_list(3);
+        s.append(&vec![0_u8]);
+        s.append_list(&[110_u32, 120]);
+        s.append_list(&[H256::repeat_byte(1)]);
+        assert!(rlp::decode::<NewPooledTransactionHashes68>(&s.out()).is_err());
+    }
+
+    #[test]
+    fn request_id_envelope() {
+        let payload = rlp::encode_list(&[H256::repeat_byte(1)]).freeze();
+
+        let id = EthMessageId::GetBlockBodies;
+        assert_eq!(frame_message(id, 65, 7, payload.clone()), payload);
+        let framed = frame_message(id, 68, 7, payload.clone());
+        assert!(validate_message(id, 68, &framed).is_ok());
+        assert_eq!(unwrap_request_id(&framed).unwrap(), (7, payload.clone()));
+
+        // Announcements are never wrapped.
+        let id = EthMessageId::NewPooledTransactionHashes;
+        assert_eq!(frame_message(id, 68, 7, payload.clone()), payload);
+
+        assert!(unwrap_request_id(&payload).is_err());
+        assert!(unwrap_request_id(&rlp::encode_list(&[1_u64, 2, 3])).is_err());
+    }
+
+    #[test]
+    fn raw_list_encoding() {
+        for &(count, size) in &[(0, 0), (3, 10), (1, 54), (1, 55), (300, 600)] {
+            let items = (0..count)
+                .map(|i| rlp::encode(&vec![i as u8; size]).freeze())
+                .collect::<Vec<_>>();
+            let encoded = encode_raw_list(&items);
+
+            let mut s = RlpStream::new_list(items.len());
+            for item in &items {
+                s.append_raw(item, 1);
+            }
+            assert_eq!(encoded, s.out().freeze());
+        }
+    }
+
+    #[test]
+    fn outbound_message_validation() {
+        let headers_request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1),
+            max_headers: 10,
+            skip: 0,
+            reverse: false,
+        });
+        let mut s = RlpStream::new_list(2);
+        s.append(&1_u64);
+        s.append_raw(&headers_request, 1);
+        let wrapped_headers_request = s.out();
+
+        let id = EthMessageId::GetBlockHeaders;
+        assert!(validate_message(id, 65, &headers_request).is_ok());
+        assert!(validate_message(id, 68, &wrapped_headers_request).is_ok());
+        assert!(validate_message(id, 68, &headers_request).is_err());
+        assert!(validate_message(id, 65, &headers_request[..headers_request.len() - 1]).is_err());
+        let mut trailing = headers_request.to_vec();
+        trailing.push(0x80);
+        assert!(validate_message(id, 65, &trailing).is_err());
+
+        let status = rlp::encode(&StatusMessage {
+            protocol_version: 68,
+            network_id: 1,
+            total_difficulty: 1.into(),
+            best_hash: H256::repeat_byte(1),
+            genesis_hash: H256::repeat_byte(2),
+            fork_id: ForkId {
+                hash: ForkHash(hex!("fc64ec04")),
+                next: 0,
+            },
+        });
+        assert!(validate_message(EthMessageId::Status, 68, &status).is_ok());
+        assert!(
+            validate_message(EthMessageId::Status, 68, &rlp::encode_list(&[1_u64, 1])).is_err()
+        );
+
+        let id = EthMessageId::NewBlockHashes;
+        let mut s = RlpStream::new_list(1);
+        s.begin_list(2).append(&H256::repeat_byte(1)).append(&1_u64);
+        assert!(validate_message(id, 68, &s.out()).is_ok());
+        let mut s = RlpStream::new_list(1);
+        s.begin_list(2).append(&1_u64).append(&H256::repeat_byte(1));
+        assert!(validate_message(id, 68, &s.out()).is_err());
+
+        let id = EthMessageId::NewPooledTransactionHashes;
+        let hashes = rlp::encode_list(&[H256::repeat_byte(1)]);
+        let announcement = rlp::encode(&NewPooledTransactionHashes68 {
+            types: vec![2],
+            sizes: vec![100],
+            hashes: vec![H256::repeat_byte(1)],
+        });
+        assert!(validate_message(id, 65, &hashes).is_ok());
+        assert!(validate_message(id, 68, &hashes).is_err());
+        assert!(validate_message(id, 68, &announcement).is_ok());
+
+        assert!(validate_message(
+            EthMessageId::Transactions,
+            65,
+            &vec![0; MAX_ETH_MESSAGE_SIZE + 1]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn withdrawal_verification() {
+        let withdrawals = vec![
+            Withdrawal {
+                index: 0,
+                validator_index: 0,
+                address: Address::from_low_u64_be(1),
+                amount: 1_000_000_000,
+            },
+            Withdrawal {
+                index: 1,
+                validator_index: 1,
+                address: Address::from_low_u64_be(2),
+                amount: 2_000_000_000,
+            },
+            Withdrawal {
+                index: 2,
+                validator_index: 5,
+                address: Address::repeat_byte(0x0b),
+                amount: 3,
+            },
+        ];
+
+        // Empty trie root.
+        assert!(verify_withdrawals(
+            H256(hex!(
+                "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+            )),
+            &[]
+        ));
+        assert!(verify_withdrawals(
+            H256(hex!(
+                "e72cd43d94c7a0da93da4e20daf873120888970bf600b3038bffdffc6a705c9b"
+            )),
+            &withdrawals[..1]
+        ));
+        let root = H256(hex!(
+            "edd189419cbd74d977367c62faf41e532bacac932eb869fdce48aa907d097591"
+        ));
+        assert!(verify_withdrawals(root, &withdrawals));
+
+        let mut tampered = withdrawals;
+        tampered[2].amount += 1;
+        assert!(!verify_withdrawals(root, &tampered));
+        tampered.truncate(2);
+        assert!(!verify_withdrawals(root, &tampered));
+    }
+
+    #[test]
+    fn mainnet_fork_ids() {
+        let mainnet = ChainConfig::mainnet();
+
+        for (head_block, hash, next) in [
+            (0, hex!("fc64ec04"), 1_150_000),
+            (1_150_000, hex!("97c2c34c"), 1_920_000),
+            (4_370_000, hex!("a00bc324"), 7_280_000),
+            (12_965_000, hex!("b715077d"), 13_773_000),
+        ]
+        .iter()
+        .copied()
+        {
+            assert_eq!(
+                ForkId::from_chain_config(&mainnet, head_block),
+                ForkId {
+                    hash: ForkHash(hash),
+                    next
+                },
+                "head block {}",
+                head_block
+            );
+        }
+    }
+
+    #[test]
+    fn fork_filter_reused_within_fork() {
+        let status = |best_block, forks: &[u64]| StatusData {
+            network_id: 1,
+            total_difficulty: 1.into(),
+            best_hash: H256::from_low_u64_be(best_block),
+            best_block,
+            fork_data: Forks {
+                genesis: H256::repeat_byte(7),
+                forks: forks.iter().copied().collect(),
+            },
+        };
+
+        let mut current = None;
+        assert_eq!(
+            FullStatusData::update(&mut current, status(1, &[10, 20])),
+            StatusChange::ForksChanged
+        );
+        // Head moves forward, then reorgs back below the second fork.
+        let heads = (2..=25)
+            .map(|head| match head {
+                10 | 20 => (head, StatusChange::ForkBoundary),
+                _ => (head, StatusChange::HeadMoved),
+            })
+            .chain(vec![
+                (19, StatusChange::ForkBoundary),
+                (15, StatusChange::Reorg),
+                (16, StatusChange::HeadMoved),
+                (20, StatusChange::ForkBoundary),
+            ]);
+        for (head, expected) in heads {
+            let new = status(head, &[10, 20]);
+            assert_eq!(
+                FullStatusData::update(&mut current, new.clone()),
+                expected,
+                "head {}",
+                head
+            );
+            let current = current.as_ref().unwrap();
+            assert_eq!(current.status.best_block, head);
+            assert_eq!(
+                current.fork_filter.current(),
+                new.fork_filter().current(),
+                "head {}",
+                head
+            );
+        }
+
+        // Another block at the same height.
+        let mut sibling = status(20, &[10, 20]);
+        sibling.best_hash = H256::repeat_byte(2);
+        assert_eq!(
+            FullStatusData::update(&mut current, sibling),
+            StatusChange::Reorg
+        );
+        assert_eq!(
+            current.as_ref().unwrap().status.best_hash,
+            H256::repeat_byte(2)
+        );
+
+        assert_eq!(
+            FullStatusData::update(&mut current, status(20, &[10, 20, 30])),
+            StatusChange::ForksChanged
+        );
+        assert_eq!(
+            current.unwrap().fork_filter.current(),
+            status(20, &[10, 20, 30]).fork_filter().current()
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use devp2p::{DisconnectReason, PeerId};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Remote disconnects later than this after our Status are not attributed to it.
+pub const STATUS_RESPONSE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Detects the situation when our advertised fork ID is rejected by most of the network,
+/// which usually means that the node or status provider missed an upgrade.
+#[derive(Debug)]
+pub struct ForkHealth {
+    window: usize,
+    min_samples: usize,
+    threshold: f64,
+
+    status_sent: HashMap<PeerId, Instant>,
+    /// Recent Status exchanges, `true` if remote is on a fork unknown to us.
+    status_outcomes: VecDeque<bool>,
+    /// Recent disconnects before remote's Status, `true` if remote cited useless peer.
+    disconnect_outcomes: VecDeque<bool>,
+
+    mismatch: bool,
+}
+
+fn push_outcome(outcomes: &mut VecDeque<bool>, window: usize, outcome: bool) {
+    if outcomes.len() >= window {
+        outcomes.pop_front();
+    }
+    outcomes.push_back(outcome);
+}
+
+impl ForkHealth {
+    pub fn new(window: usize, min_samples: usize, threshold: f64) -> Self {
+        Self {
+            window: window.max(1),
+            min_samples,
+            threshold,
+            status_sent: Default::default(),
+            status_outcomes: Default::default(),
+            disconnect_outcomes: Default::default(),
+            mismatch: false,
+        }
+    }
+
+    /// Whether fork mismatch is currently suspected.
+    pub fn is_mismatch(&self) -> bool {
+        self.mismatch
+    }
+
+    pub fn on_status_sent(&mut self, peer: PeerId, now: Instant) {
+        self.status_sent.insert(peer, now);
+    }
+
+    /// Record remote's Status. `remote_newer` is set if its fork ID failed validation
+    /// because it is on a fork we do not know about.
+    ///
+    /// Returns new value of the health flag if it has changed.
+    pub fn on_status_received(&mut self, peer: PeerId, remote_newer: bool) -> Option<bool> {
+        self.status_sent.remove(&peer);
+        push_outcome(&mut self.status_outcomes, self.window, remote_newer);
+
+        self.update()
+    }
+
+    /// Forget the peer without recording any outcome, e.g. if its Status was malformed.
+    pub fn forget(&mut self, peer: PeerId) {
+        self.status_sent.remove(&peer);
+    }
+
+    /// Drop outcomes recorded against our previous fork ID, e.g. after crossing a fork block.
+    ///
+    /// Returns new value of the health flag if it has changed.
+    pub fn reset(&mut self) -> Option<bool> {
+        self.status_sent.clear();
+        self.status_outcomes.clear();
+        self.disconnect_outcomes.clear();
+
+        self.update()
+    }
+
+    /// Record peer's disconnect.
+    ///
+    /// Returns new value of the health flag if it has changed.
+    pub fn on_disconnect(
+        &mut self,
+        peer: PeerId,
+        reason: Option<DisconnectReason>,
+        now: Instant,
+    ) -> Option<bool> {
+        // Disconnect happened before the remote has sent its Status.
+        if let Some(sent) = self.status_sent.remove(&peer) {
+            if now.saturating_duration_since(sent) <= STATUS_RESPONSE_WINDOW {
+                push_outcome(
+                    &mut self.disconnect_outcomes,
+                    self.window,
+                    matches!(reason, Some(DisconnectReason::UselessPeer)),
+                );
+
+                return self.update();
+            }
+        }
+
+        None
+    }
+
+    fn exceeds_threshold(&self, outcomes: &VecDeque<bool>) -> bool {
+        if outcomes.len() < self.min_samples || outcomes.is_empty() {
+            return false;
+        }
+
+        let failed = outcomes.iter().filter(|&&v| v).count();
+
+        failed as f64 / outcomes.len() as f64 > self.threshold
+    }
+
+    fn update(&mut self) -> Option<bool> {
+        let mismatch = self.exceeds_threshold(&self.status_outcomes)
+            || self.exceeds_threshold(&self.disconnect_outcomes);
+
+        if mismatch != self.mismatch {
+            self.mismatch = mismatch;
+            return Some(mismatch);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+
+        (0..n)
+            .map(|_| PeerId::from_low_u64_be(NEXT.fetch_add(1, Ordering::Relaxed)))
+            .collect()
+    }
+
+    #[test]
+    fn majority_on_newer_fork() {
+        let mut health = ForkHealth::new(100, 20, 0.5);
+        let now = Instant::now();
+
+        // 10 compatible peers, 9 on newer fork: below min samples and below threshold.
+        for (i, peer) in peers(19).into_iter().enumerate() {
+            health.on_status_sent(peer, now);
+            assert_eq!(health.on_status_received(peer, i % 2 == 1), None);
+        }
+        assert!(!health.is_mismatch());
+
+        // Population switches to a fork we do not know about.
+        let mut changes = vec![];
+        for peer in peers(30) {
+            health.on_status_sent(peer, now);
+            changes.extend(health.on_status_received(peer, true));
+        }
+        assert_eq!(changes, vec![true]);
+        assert!(health.is_mismatch());
+
+        // Recovery after upgrade.
+        let mut changes = vec![];
+        for peer in peers(100) {
+            health.on_status_sent(peer, now);
+            changes.extend(health.on_status_received(peer, false));
+        }
+        assert_eq!(changes, vec![false]);
+        assert!(!health.is_mismatch());
+    }
+
+    #[test]
+    fn stale_remotes_are_not_mismatch() {
+        let mut health = ForkHealth::new(100, 20, 0.5);
+        let now = Instant::now();
+
+        // Remotes that are stale themselves are validated as compatible direction-wise.
+        for peer in peers(50) {
+            health.on_status_sent(peer, now);
+            assert_eq!(health.on_status_received(peer, false), None);
+        }
+        assert!(!health.is_mismatch());
+    }
+
+    #[test]
+    fn useless_peer_right_after_status() {
+        let mut health = ForkHealth::new(50, 20, 0.5);
+        let now = Instant::now();
+
+        let population = peers(40);
+        for &peer in &population {
+            health.on_status_sent(peer, now);
+        }
+
+        // A quarter disconnects for other reasons.
+        let mut changes = vec![];
+        for (i, &peer) in population.iter().enumerate() {
+            let reason = if i % 4 == 0 {
+                DisconnectReason::TooManyPeers
+            } else {
+                DisconnectReason::UselessPeer
+            };
+            changes.extend(health.on_disconnect(peer, Some(reason), now));
+        }
+        assert_eq!(changes, vec![true]);
+        assert!(health.is_mismatch());
+    }
+
+    #[test]
+    fn late_and_post_status_disconnects_are_ignored() {
+        let mut health = ForkHealth::new(50, 5, 0.5);
+        let now = Instant::now();
+
+        // Peers that have already exchanged Status, e.g. kicked by us.
+        for peer in peers(10) {
+            health.on_status_sent(peer, now);
+            health.on_status_received(peer, false);
+            assert_eq!(
+                health.on_disconnect(peer, Some(DisconnectReason::UselessPeer), now),
+                None
+            );
+        }
+
+        // Peers that disconnect long after our Status.
+        for peer in peers(10) {
+            health.on_status_sent(peer, now);
+            assert_eq!(
+                health.on_disconnect(
+                    peer,
+                    Some(DisconnectReason::UselessPeer),
+                    now + STATUS_RESPONSE_WINDOW * 2
+                ),
+                None
+            );
+        }
+
+        assert!(!health.is_mismatch());
+    }
+}
@@ -0,0 +1,306 @@
+//! Queues of peer messages forwarded to the control, with timestamps of the stages each
+//! message passes: handling by the sentry, waiting in the queue, and sending over gRPC.
+//!
+//! Timestamps are taken only with the `pipeline-timing` feature, without it `Stamp` is
+//! empty and no stage durations or queue ages are reported.
+
+use crate::{
+    eth::EthMessageId,
+    grpc::sentry::{InboundMessage, MessageId},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::{
+    channel as broadcast, error::RecvError, error::SendError, Receiver as BroadcastReceiver,
+    Sender as BroadcastSender,
+};
+
+pub const FORWARD_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queue that peer messages are forwarded to the control through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlQueue {
+    Data,
+    UploadRequests,
+}
+
+/// Queue and sentry protocol ID of messages forwarded to the control, `None` for messages
+/// the sentry handles itself. Lists every message so that a new one cannot be left out.
+pub fn control_route(id: EthMessageId) -> Option<(ControlQueue, MessageId)> {
+    Some(match id {
+        EthMessageId::BlockHeaders => (ControlQueue::Data, MessageId::BlockHeaders),
+        EthMessageId::BlockBodies => (ControlQueue::Data, MessageId::BlockBodies),
+        EthMessageId::NodeData => (ControlQueue::Data, MessageId::NodeData),
+        EthMessageId::GetBlockHeaders => (ControlQueue::UploadRequests, MessageId::GetBlockHeaders),
+        EthMessageId::GetBlockBodies => (ControlQueue::UploadRequests, MessageId::GetBlockBodies),
+        EthMessageId::GetNodeData => (ControlQueue::UploadRequests, MessageId::GetNodeData),
+        // Handled during the handshake.
+        EthMessageId::Status => return None,
+        // Only checked against the duplicate filter, the control is not told of them.
+        EthMessageId::NewBlockHashes | EthMessageId::NewBlock => return None,
+        // Served from the sentry's transaction pool.
+        EthMessageId::GetPooledTransactions => return None,
+        // The sentry protocol has no message ID for announcements of pooled transactions.
+        EthMessageId::NewPooledTransactionHashes => return None,
+        EthMessageId::Transactions
+        | EthMessageId::PooledTransactions
+        | EthMessageId::GetReceipts
+        | EthMessageId::Receipts => return None,
+    })
+}
+
+/// Time a message has reached a stage.
+#[derive(Clone, Copy, Debug)]
+pub struct Stamp {
+    #[cfg(feature = "pipeline-timing")]
+    at: Instant,
+}
+
+#[cfg(feature = "pipeline-timing")]
+impl Stamp {
+    #[inline]
+    pub fn now() -> Self {
+        Self { at: Instant::now() }
+    }
+
+    /// Time from `earlier` to this stamp.
+    #[inline]
+    pub fn since(&self, earlier: &Stamp) -> Option<Duration> {
+        Some(self.at.saturating_duration_since(earlier.at))
+    }
+
+    #[inline]
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        Some(now.saturating_duration_since(self.at))
+    }
+}
+
+#[cfg(not(feature = "pipeline-timing"))]
+impl Stamp {
+    #[inline]
+    pub fn now() -> Self {
+        Self {}
+    }
+
+    #[inline]
+    pub fn since(&self, _: &Stamp) -> Option<Duration> {
+        None
+    }
+
+    #[inline]
+    pub fn age(&self, _: Instant) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Forwarded {
+    pub message: InboundMessage,
+    /// Peer event carrying the message has been picked up.
+    pub received: Stamp,
+    /// Message has been put into the queue.
+    pub queued: Stamp,
+    seq: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages not yet taken by the slowest subscriber.
+    pub depth: usize,
+    /// How long the oldest of them has been queued.
+    pub oldest_age: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    next_seq: u64,
+    /// Queue times of messages that may not have been taken by all subscribers, oldest first.
+    queued: VecDeque<(u64, Stamp)>,
+    /// Next message each subscriber takes.
+    subscribers: Vec<Weak<AtomicU64>>,
+}
+
+/// Broadcast of forwarded messages to all control subscriptions.
+#[derive(Debug)]
+pub struct ForwardQueue {
+    name: &'static str,
+    capacity: usize,
+    sender: BroadcastSender<Forwarded>,
+    state: Mutex<QueueState>,
+}
+
+impl ForwardQueue {
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            // Channel rounds its capacity up.
+            capacity: capacity.next_power_of_two(),
+            sender: broadcast(capacity).0,
+            state: Default::default(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queue message received at `received`. Fails if no control is subscribed.
+    pub fn send(
+        &self,
+        message: InboundMessage,
+        received: Stamp,
+    ) -> Result<(), SendError<InboundMessage>> {
+        let mut state = self.state.lock();
+        let seq = state.next_seq;
+        let queued = Stamp::now();
+        self.sender
+            .send(Forwarded {
+                message,
+                received,
+                queued,
+                seq,
+            })
+            .map_err(|SendError(forwarded)| SendError(forwarded.message))?;
+
+        state.next_seq += 1;
+        state.queued.push_back((seq, queued));
+        // Older messages have been overwritten in the channel.
+        while state.queued.len() > self.capacity {
+            state.queued.pop_front();
+        }
+
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        let mut state = self.state.lock();
+        let next = Arc::new(AtomicU64::new(state.next_seq));
+        state.subscribers.push(Arc::downgrade(&next));
+        Subscription {
+            receiver: self.sender.subscribe(),
+            next,
+        }
+    }
+
+    pub fn stats(&self, now: Instant) -> QueueStats {
+        let mut state = self.state.lock();
+        state.subscribers.retain(|next| next.strong_count() > 0);
+        let slowest = match state
+            .subscribers
+            .iter()
+            .filter_map(|next| Some(next.upgrade()?.load(Ordering::Relaxed)))
+            .min()
+        {
+            Some(v) => v,
+            None => {
+                state.queued.clear();
+                return QueueStats::default();
+            }
+        };
+
+        while state
+            .queued
+            .front()
+            .map_or(false, |&(seq, _)| seq < slowest)
+        {
+            state.queued.pop_front();
+        }
+        QueueStats {
+            depth: ((state.next_seq - slowest) as usize).min(self.capacity),
+            oldest_age: state.queued.front().and_then(|(_, queued)| queued.age(now)),
+        }
+    }
+}
+
+pub struct Subscription {
+    receiver: BroadcastReceiver<Forwarded>,
+    next: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// Next message, skipping those overwritten before the subscriber got to them.
+    pub async fn recv(&mut self) -> Option<Forwarded> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(forwarded) => {
+                    self.next.store(forwarded.seq + 1, Ordering::Relaxed);
+                    return Some(forwarded);
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eth::eth_messages, metrics::Metrics};
+
+    fn message(id: i32) -> InboundMessage {
+        InboundMessage {
+            id,
+            data: Default::default(),
+            peer_id: None,
+        }
+    }
+
+    #[test]
+    fn control_routes_match_sentry_ids() {
+        for &id in eth_messages(66) {
+            if let Some((_, message_id)) = control_route(id) {
+                assert_eq!(EthMessageId::from(message_id), id);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_age_of_slow_control() {
+        let metrics = Metrics::new().unwrap();
+        let queue = ForwardQueue::new("data", 4);
+        assert!(queue.send(message(0), Stamp::now()).is_err());
+
+        let mut fast = queue.subscribe();
+        let mut slow = queue.subscribe();
+        for id in 0..3 {
+            queue.send(message(id), Stamp::now()).unwrap();
+        }
+        let start = Instant::now();
+        for id in 0..3 {
+            assert_eq!(fast.recv().await.unwrap().message.id, id);
+        }
+
+        // Slow control has not taken anything for 5 seconds.
+        let stats = queue.stats(start + Duration::from_secs(5));
+        assert_eq!(stats.depth, 3);
+        metrics.set_forward_queue(queue.name(), stats);
+        #[cfg(feature = "pipeline-timing")]
+        {
+            assert!(stats.oldest_age.unwrap() >= Duration::from_secs(5));
+            let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+            assert!(encoded.contains("sentry_forward_queue_oldest_seconds{queue=\"data\"} 5"));
+        }
+
+        assert_eq!(slow.recv().await.unwrap().message.id, 0);
+        assert_eq!(queue.stats(Instant::now()).depth, 2);
+
+        // Overwritten messages are skipped.
+        for id in 3..7 {
+            queue.send(message(id), Stamp::now()).unwrap();
+        }
+        assert_eq!(queue.stats(Instant::now()).depth, 4);
+        assert_eq!(slow.recv().await.unwrap().message.id, 3);
+
+        drop(slow);
+        drop(fast);
+        assert_eq!(queue.stats(Instant::now()), QueueStats::default());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{eth::*, types::H256Map};
+use bytes::Bytes;
+use devp2p::util::keccak256;
+use ethereum_types::H256;
+use rlp::{DecoderError, Rlp};
+use std::collections::BTreeMap;
+
+/// Maximum number of headers served from cache in a single response.
+pub const MAX_HEADERS_SERVE: u64 = 1024;
+
+/// Number and hash of an RLP encoded header, as `HeaderCache::insert` takes them.
+pub fn parse_header(rlp: Bytes) -> Result<(u64, H256, Bytes), DecoderError> {
+    let number = Rlp::new(&rlp).val_at(8)?;
+    let hash = keccak256(&rlp);
+    Ok((number, hash, rlp))
+}
+
+#[derive(Clone, Debug)]
+struct CachedHeader {
+    hash: H256,
+    rlp: Bytes,
+}
+
+/// Bounded store of recent headers pushed by the control.
+#[derive(Debug)]
+pub struct HeaderCache {
+    window: u64,
+    by_number: BTreeMap<u64, CachedHeader>,
+    by_hash: H256Map<u64>,
+}
+
+impl HeaderCache {
+    /// Create cache keeping headers no older than `window` blocks behind the highest one.
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            by_number: Default::default(),
+            by_hash: Default::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_number.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_number.is_empty()
+    }
+
+    pub fn highest(&self) -> Option<u64> {
+        self.by_number.keys().next_back().copied()
+    }
+
+    /// Insert header. Differing hash at an existing height is treated as reorg
+    /// and invalidates that height and everything above it.
+    pub fn insert(&mut self, number: u64, hash: H256, rlp: Bytes) {
+        if let Some(existing) = self.by_number.get(&number) {
+            if existing.hash == hash {
+                return;
+            }
+
+            self.invalidate_from(number);
+        }
+
+        self.by_hash.insert(hash, number);
+        self.by_number.insert(number, CachedHeader { hash, rlp });
+
+        self.evict();
+    }
+
+    /// Drop all headers at `number` and above.
+    pub fn invalidate_from(&mut self, number: u64) {
+        for (_, header) in self.by_number.split_off(&number) {
+            self.by_hash.remove(&header.hash);
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some(highest) = self.highest() {
+            let lowest = highest.saturating_sub(self.window);
+            let retained = self.by_number.split_off(&lowest);
+            for (_, header) in std::mem::replace(&mut self.by_number, retained) {
+                self.by_hash.remove(&header.hash);
+            }
+        }
+    }
+
+    pub fn number_by_hash(&self, hash: H256) -> Option<u64> {
+        self.by_hash.get(&hash).copied()
+    }
+
+    pub fn get(&self, number: u64) -> Option<Bytes> {
+        self.by_number.get(&number).map(|header| header.rlp.clone())
+    }
+
+    /// Answer the request fully from cache. Returns `None` if any header that
+    /// may exist is missing, so the request should go elsewhere.
+    pub fn resolve(&self, request: &GetBlockHeaders) -> Option<Vec<Bytes>> {
+        let highest = self.highest()?;
+        let mut number = match request.block {
+            BlockId::Hash(hash) => self.number_by_hash(hash)?,
+            BlockId::Number(number) => number,
+        };
+
+        let step = request.skip.checked_add(1)?;
+        let mut headers = Vec::new();
+        for _ in 0..request.max_headers.min(MAX_HEADERS_SERVE) {
+            if number > highest {
+                break;
+            }
+
+            headers.push(self.get(number)?);
+
+            number = if request.reverse {
+                match number.checked_sub(step) {
+                    Some(number) => number,
+                    None => break,
+                }
+            } else {
+                number.checked_add(step)?
+            };
+        }
+
+        if headers.is_empty() {
+            return None;
+        }
+
+        Some(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(number: u64, fork: u8) -> (u64, H256, Bytes) {
+        let mut hash = H256::from_low_u64_be(number);
+        hash.0[0] = fork;
+        (number, hash, Bytes::from(rlp::encode(&hash).to_vec()))
+    }
+
+    fn cache(window: u64, range: std::ops::RangeInclusive<u64>) -> HeaderCache {
+        let mut cache = HeaderCache::new(window);
+        for number in range {
+            let (number, hash, rlp) = header(number, 0);
+            cache.insert(number, hash, rlp);
+        }
+        cache
+    }
+
+    fn request(block: BlockId, max_headers: u64, skip: u64, reverse: bool) -> GetBlockHeaders {
+        GetBlockHeaders {
+            block,
+            max_headers,
+            skip,
+            reverse,
+        }
+    }
+
+    #[test]
+    fn hits() {
+        let cache = cache(100, 1000..=1010);
+
+        assert_eq!(
+            cache.resolve(&request(BlockId::Number(1000), 3, 0, false)),
+            Some(vec![
+                header(1000, 0).2,
+                header(1001, 0).2,
+                header(1002, 0).2
+            ])
+        );
+        assert_eq!(
+            cache.resolve(&request(BlockId::Hash(header(1010, 0).1), 3, 1, true)),
+            Some(vec![
+                header(1010, 0).2,
+                header(1008, 0).2,
+                header(1006, 0).2
+            ])
+        );
+        // Tip range is truncated at the highest known header.
+        assert_eq!(
+            cache.resolve(&request(BlockId::Number(1009), 10, 0, false)),
+            Some(vec![header(1009, 0).2, header(1010, 0).2])
+        );
+    }
+
+    #[test]
+    fn misses_fall_through() {
+        let cache = cache(100, 1000..=1010);
+
+        assert_eq!(
+            cache.resolve(&request(BlockId::Number(990), 20, 0, false)),
+            None
+        );
+        assert_eq!(
+            cache.resolve(&request(
+                BlockId::Hash(H256::repeat_byte(0xff)),
+                1,
+                0,
+                false
+            )),
+            None
+        );
+        assert_eq!(
+            cache.resolve(&request(BlockId::Number(1020), 1, 0, false)),
+            None
+        );
+    }
+
+    #[test]
+    fn eviction_by_window() {
+        let cache = cache(5, 1000..=1010);
+
+        assert_eq!(cache.len(), 6);
+        assert_eq!(cache.get(1004), None);
+        assert_eq!(cache.number_by_hash(header(1004, 0).1), None);
+        assert_eq!(cache.get(1005), Some(header(1005, 0).2));
+    }
+
+    #[test]
+    fn reorg_invalidation() {
+        let mut cache = cache(100, 1000..=1010);
+
+        let (number, hash, rlp) = header(1005, 1);
+        cache.insert(number, hash, rlp);
+
+        assert_eq!(cache.highest(), Some(1005));
+        assert_eq!(cache.number_by_hash(header(1007, 0).1), None);
+        assert_eq!(cache.number_by_hash(hash), Some(1005));
+        assert_eq!(
+            cache.resolve(&request(BlockId::Number(1004), 2, 0, false)),
+            Some(vec![header(1004, 0).2, header(1005, 1).2])
+        );
+    }
+
+    #[test]
+    fn parse_header_reads_number_and_hashes_whole_header() {
+        let mut stream = rlp::RlpStream::new_list(15);
+        for field in 0..15u64 {
+            stream.append(&if field == 8 { 17_034_240 } else { field });
+        }
+        let rlp = stream.out().freeze();
+
+        let (number, hash, parsed) = parse_header(rlp.clone()).unwrap();
+        assert_eq!(number, 17_034_240);
+        assert_eq!(hash, keccak256(&rlp));
+        assert_eq!(parsed, rlp);
+
+        assert!(parse_header(rlp::encode_list(&[1u64, 2]).freeze()).is_err());
+        assert!(parse_header(Bytes::from_static(&[0x80])).is_err());
+    }
+}
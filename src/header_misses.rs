@@ -0,0 +1,271 @@
+//! Hashes the control has had no headers for. Peers doing skeleton sync keep asking for
+//! headers anchored a little ahead of our head, and every such request costs a round
+//! trip to the control that comes back empty.
+
+use crate::{eth::*, types::H256Map};
+use devp2p::PeerId;
+use ethereum_types::H256;
+use rlp::Rlp;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Short enough not to hold back serving a block that has just arrived.
+pub const HEADER_MISS_TTL: Duration = Duration::from_secs(3);
+/// Forwarded requests of a peer awaiting the control's reply.
+const MAX_PENDING_PER_PEER: usize = 64;
+/// Forwarded requests not answered within this time are forgotten.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_MISSES: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    request_id: Option<u64>,
+    hash: H256,
+    height: u64,
+    forwarded: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Miss {
+    /// Hash is expected to be at or below this height.
+    height: u64,
+    expires: Instant,
+}
+
+/// Request ID, if any, and hash the GetBlockHeaders is anchored at.
+pub fn hash_anchor(data: &[u8], with_request_id: bool) -> Option<(Option<u64>, H256)> {
+    let (request_id, request) = if with_request_id {
+        let (request_id, payload) = unwrap_request_id(data).ok()?;
+        (
+            Some(request_id),
+            rlp::decode::<GetBlockHeaders>(&payload).ok()?,
+        )
+    } else {
+        (None, rlp::decode::<GetBlockHeaders>(data).ok()?)
+    };
+    match request.block {
+        BlockId::Hash(hash) => Some((request_id, hash)),
+        BlockId::Number(_) => None,
+    }
+}
+
+/// Request ID, if any, and whether the BlockHeaders carries no headers.
+pub fn empty_reply(data: &[u8]) -> Option<(Option<u64>, bool)> {
+    let rlp = Rlp::new(data);
+    // Headers are lists, request ID is not.
+    if rlp.item_count().ok()? == 2 && rlp.at(0).ok()?.is_data() {
+        Some((
+            Some(rlp.val_at(0).ok()?),
+            rlp.at(1).ok()?.item_count().ok()? == 0,
+        ))
+    } else {
+        Some((None, rlp.item_count().ok()? == 0))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HeaderMisses {
+    pending: HashMap<PeerId, VecDeque<Pending>>,
+    misses: H256Map<Miss>,
+}
+
+impl HeaderMisses {
+    /// GetBlockHeaders of `peer` anchored at `hash` has been forwarded to the control.
+    /// `height` is where the hash is expected to be found: the peer's block, or above our
+    /// head as we do not have it.
+    pub fn on_forwarded(
+        &mut self,
+        peer: PeerId,
+        request_id: Option<u64>,
+        hash: H256,
+        height: u64,
+        now: Instant,
+    ) {
+        let pending = self.pending.entry(peer).or_default();
+        expire_pending(pending, now);
+        if pending.len() >= MAX_PENDING_PER_PEER {
+            pending.pop_front();
+        }
+        pending.push_back(Pending {
+            request_id,
+            hash,
+            height,
+            forwarded: now,
+        });
+    }
+
+    /// Control has answered GetBlockHeaders of `peer`. Replies without request ID answer
+    /// requests in order, but are only tied to the hash if no other request is pending.
+    pub fn on_reply(&mut self, peer: PeerId, request_id: Option<u64>, empty: bool, now: Instant) {
+        let pending = match self.pending.get_mut(&peer) {
+            Some(pending) => pending,
+            None => return,
+        };
+        expire_pending(pending, now);
+        let request = match request_id {
+            Some(request_id) => pending
+                .iter()
+                .position(|p| p.request_id == Some(request_id))
+                .and_then(|position| pending.remove(position)),
+            None => {
+                let unambiguous = pending.len() == 1;
+                pending.pop_front().filter(|_| unambiguous)
+            }
+        };
+        if pending.is_empty() {
+            self.pending.remove(&peer);
+        }
+        let request = match request {
+            Some(request) => request,
+            None => return,
+        };
+
+        if !empty {
+            self.misses.remove(&request.hash);
+            return;
+        }
+        if self.misses.len() >= MAX_MISSES {
+            self.misses.retain(|_, miss| miss.expires > now);
+            if self.misses.len() >= MAX_MISSES {
+                return;
+            }
+        }
+        self.misses.insert(
+            request.hash,
+            Miss {
+                height: request.height,
+                expires: now + HEADER_MISS_TTL,
+            },
+        );
+    }
+
+    /// Whether the control is known to have nothing for requests anchored at `hash`.
+    /// Forgotten once our head reaches the height the hash was expected at.
+    pub fn is_miss(&mut self, hash: H256, best_block: u64, now: Instant) -> bool {
+        match self.misses.get(&hash) {
+            Some(miss) if miss.expires > now && best_block < miss.height => true,
+            Some(_) => {
+                self.misses.remove(&hash);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.pending.remove(&peer);
+    }
+}
+
+fn expire_pending(pending: &mut VecDeque<Pending>, now: Instant) {
+    while pending.front().map_or(false, |p| {
+        now.saturating_duration_since(p.forwarded) >= PENDING_TIMEOUT
+    }) {
+        pending.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skeleton_sync_requests_spare_the_control() {
+        let peers = [PeerId::from_low_u64_be(1), PeerId::from_low_u64_be(2)];
+        let ahead = H256::repeat_byte(0xaa);
+        let start = Instant::now();
+        let mut best_block = 100;
+        let mut misses = HeaderMisses::default();
+        let mut control_calls = 0;
+
+        // Each peer asks every half a second, the block arrives at 10 s.
+        for tick in 0..40_u64 {
+            let now = start + Duration::from_millis(500 * tick);
+            if tick == 20 {
+                best_block = 101;
+            }
+            for (i, &peer) in peers.iter().enumerate() {
+                if misses.is_miss(ahead, best_block, now) {
+                    continue;
+                }
+                let request_id = Some(tick * 2 + i as u64);
+                misses.on_forwarded(peer, request_id, ahead, 101, now);
+                control_calls += 1;
+                // Control has the header only once our head has reached it.
+                misses.on_reply(peer, request_id, best_block < 101, now);
+            }
+        }
+
+        // Once per TTL while missing, then every time once it is there.
+        assert_eq!(control_calls, 4 + 40);
+        assert!(misses.misses.is_empty());
+        assert!(misses.pending.is_empty());
+    }
+
+    #[test]
+    fn replies_match_requests() {
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+        let mut misses = HeaderMisses::default();
+
+        misses.on_forwarded(peer, None, H256::repeat_byte(1), 10, now);
+        misses.on_reply(peer, None, false, now);
+        misses.on_forwarded(peer, None, H256::repeat_byte(2), 10, now);
+        misses.on_reply(peer, None, true, now);
+        assert!(!misses.is_miss(H256::repeat_byte(1), 5, now));
+        assert!(misses.is_miss(H256::repeat_byte(2), 5, now));
+        assert!(!misses.is_miss(H256::repeat_byte(2), 10, now));
+        assert!(!misses.is_miss(H256::repeat_byte(2), 5, now));
+
+        misses.on_forwarded(peer, Some(7), H256::repeat_byte(3), 10, now);
+        misses.on_forwarded(peer, Some(8), H256::repeat_byte(4), 10, now);
+        misses.on_reply(peer, Some(8), true, now);
+        assert!(misses.is_miss(H256::repeat_byte(4), 5, now));
+        assert!(!misses.is_miss(H256::repeat_byte(3), 5, now));
+        assert!(!misses.is_miss(H256::repeat_byte(4), 5, now + HEADER_MISS_TTL));
+
+        misses.on_reply(peer, Some(7), false, now);
+        assert!(misses.pending.is_empty());
+
+        let request = GetBlockHeaders {
+            block: BlockId::Hash(H256::repeat_byte(5)),
+            max_headers: 1,
+            skip: 0,
+            reverse: false,
+        };
+        let data = wrap_request_id(9, &rlp::encode(&request));
+        assert_eq!(
+            hash_anchor(&data, true),
+            Some((Some(9), H256::repeat_byte(5)))
+        );
+        assert_eq!(
+            empty_reply(&wrap_request_id(9, &rlp::EMPTY_LIST_RLP)),
+            Some((Some(9), true))
+        );
+        assert_eq!(empty_reply(&rlp::EMPTY_LIST_RLP), Some((None, true)));
+    }
+
+    #[test]
+    fn unanswered_requests_do_not_take_later_replies() {
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+        let mut misses = HeaderMisses::default();
+
+        // Empty reply without request ID cannot be tied to either hash.
+        misses.on_forwarded(peer, None, H256::repeat_byte(1), 10, now);
+        misses.on_forwarded(peer, None, H256::repeat_byte(2), 10, now);
+        misses.on_reply(peer, None, true, now);
+        assert!(!misses.is_miss(H256::repeat_byte(1), 5, now));
+        assert!(!misses.is_miss(H256::repeat_byte(2), 5, now));
+
+        // Request the control never answered has expired by the time of the next reply.
+        let later = now + PENDING_TIMEOUT;
+        misses.on_forwarded(peer, None, H256::repeat_byte(3), 10, later);
+        misses.on_reply(peer, None, true, later);
+        assert!(!misses.is_miss(H256::repeat_byte(2), 5, later));
+        assert!(misses.is_miss(H256::repeat_byte(3), 5, later));
+        assert!(misses.pending.is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use devp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Window in which peers have to send enough messages to keep their slot.
+pub const IDLE_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct PeerActivity {
+    connected: Instant,
+    /// Times of the latest received messages, at most `min_messages` of them.
+    received: VecDeque<Instant>,
+    /// Time of the latest received message other than Status.
+    last_activity: Option<Instant>,
+}
+
+/// Tells peers that occupy a slot without sending messages.
+#[derive(Debug)]
+pub struct IdlePeers {
+    window: Duration,
+    min_messages: usize,
+    peers: HashMap<PeerId, PeerActivity>,
+}
+
+impl IdlePeers {
+    /// Peers are never idle if `min_messages` is 0.
+    pub fn new(window: Duration, min_messages: usize) -> Self {
+        Self {
+            window,
+            min_messages,
+            peers: Default::default(),
+        }
+    }
+
+    pub fn min_messages(&self) -> usize {
+        self.min_messages
+    }
+
+    pub fn on_connect(&mut self, peer: PeerId, now: Instant) {
+        self.peers.insert(
+            peer,
+            PeerActivity {
+                connected: now,
+                received: VecDeque::with_capacity(self.min_messages),
+                last_activity: None,
+            },
+        );
+    }
+
+    /// Record received message, Status does not count as activity.
+    pub fn on_message(&mut self, peer: PeerId, status: bool, now: Instant) {
+        if let Some(activity) = self.peers.get_mut(&peer) {
+            if !status {
+                activity.last_activity = Some(now);
+            }
+            if self.min_messages > 0 {
+                if activity.received.len() >= self.min_messages {
+                    activity.received.pop_front();
+                }
+                activity.received.push_back(now);
+            }
+        }
+    }
+
+    /// Returns when the peer connected.
+    pub fn on_disconnect(&mut self, peer: PeerId) -> Option<Instant> {
+        self.peers.remove(&peer).map(|activity| activity.connected)
+    }
+
+    /// Peers connected for at least the window that have sent fewer than
+    /// `min_messages` messages within it.
+    pub fn idle(&self, now: Instant) -> Vec<PeerId> {
+        if self.min_messages == 0 {
+            return vec![];
+        }
+        let within_window = |time: Instant| now.saturating_duration_since(time) < self.window;
+        self.peers
+            .iter()
+            .filter(|(_, activity)| {
+                !within_window(activity.connected)
+                    && (activity.received.len() < self.min_messages
+                        || !within_window(activity.received[0]))
+            })
+            .map(|(&peer, _)| peer)
+            .collect()
+    }
+
+    /// Whether the peer has been connected for at least `window` and has sent nothing
+    /// but Status within it.
+    pub fn is_passive(&self, peer: PeerId, window: Duration, now: Instant) -> bool {
+        self.peers.get(&peer).map_or(false, |activity| {
+            let within_window = |time: Instant| now.saturating_duration_since(time) < window;
+            !within_window(activity.connected)
+                && !activity.last_activity.map_or(false, within_window)
+        })
+    }
+
+    /// Passive peers, longest connected first.
+    pub fn passive(&self, window: Duration, now: Instant) -> Vec<PeerId> {
+        let mut passive = self
+            .peers
+            .iter()
+            .filter(|(&peer, _)| self.is_passive(peer, window, now))
+            .map(|(&peer, activity)| (activity.connected, peer))
+            .collect::<Vec<_>>();
+        passive.sort();
+        passive.into_iter().map(|(_, peer)| peer).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_after_window() {
+        let mut idle_peers = IdlePeers::new(IDLE_WINDOW, 2);
+        let quiet = PeerId::from_low_u64_be(1);
+        let chatty = PeerId::from_low_u64_be(2);
+        let now = Instant::now();
+
+        idle_peers.on_connect(quiet, now);
+        idle_peers.on_connect(chatty, now);
+        idle_peers.on_message(quiet, false, now);
+        for i in 0..5 {
+            idle_peers.on_message(
+                chatty,
+                false,
+                now + IDLE_WINDOW / 2 + Duration::from_secs(i),
+            );
+        }
+
+        // Just connected peers are left alone.
+        assert!(idle_peers.idle(now + IDLE_WINDOW / 2).is_empty());
+        assert_eq!(idle_peers.idle(now + IDLE_WINDOW), vec![quiet]);
+
+        let mut idle = idle_peers.idle(now + IDLE_WINDOW * 2);
+        idle.sort();
+        assert_eq!(idle, vec![quiet, chatty]);
+
+        idle_peers.on_disconnect(quiet);
+        assert_eq!(idle_peers.idle(now + IDLE_WINDOW * 2), vec![chatty]);
+
+        let mut idle_peers = IdlePeers::new(IDLE_WINDOW, 0);
+        idle_peers.on_connect(quiet, now);
+        assert!(idle_peers.idle(now + IDLE_WINDOW * 2).is_empty());
+    }
+
+    #[test]
+    fn passive_peers() {
+        let window = Duration::from_secs(60);
+        let mut idle_peers = IdlePeers::new(IDLE_WINDOW, 0);
+        let silent = PeerId::from_low_u64_be(1);
+        let active = PeerId::from_low_u64_be(2);
+        let status_only = PeerId::from_low_u64_be(3);
+        let now = Instant::now();
+
+        idle_peers.on_connect(status_only, now);
+        idle_peers.on_connect(silent, now + Duration::from_secs(1));
+        idle_peers.on_connect(active, now + Duration::from_secs(1));
+        idle_peers.on_message(status_only, true, now);
+
+        assert!(idle_peers.passive(window, now + window / 2).is_empty());
+        assert_eq!(
+            idle_peers.passive(window, now + window * 2),
+            vec![status_only, silent, active]
+        );
+
+        for second in (0..120).step_by(10) {
+            idle_peers.on_message(active, false, now + Duration::from_secs(second));
+        }
+        let later = now + window * 2;
+        assert!(!idle_peers.is_passive(active, window, later));
+        assert!(idle_peers.is_passive(silent, window, later));
+        assert_eq!(idle_peers.passive(window, later), vec![status_only, silent]);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, ensure};
+use devp2p::PeerId;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+
+pub const MAX_LABELS_PER_PEER: usize = 16;
+pub const MAX_LABEL_KEY_LEN: usize = 64;
+pub const MAX_LABEL_VALUE_LEN: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct PeerLabel {
+    pub value: String,
+    pub persistent: bool,
+}
+
+/// Opaque application-level labels attached to peers by the control.
+#[derive(Debug, Default)]
+pub struct PeerLabels {
+    labels: HashMap<PeerId, BTreeMap<String, PeerLabel>>,
+}
+
+impl PeerLabels {
+    /// Set label for the peer. Empty value removes the label. Returns the previous label.
+    pub fn set(
+        &mut self,
+        peer: PeerId,
+        key: String,
+        value: String,
+        persistent: bool,
+    ) -> anyhow::Result<Option<PeerLabel>> {
+        ensure!(!key.is_empty(), "label key is empty");
+        ensure!(
+            key.len() <= MAX_LABEL_KEY_LEN,
+            "label key is too long ({} > {})",
+            key.len(),
+            MAX_LABEL_KEY_LEN
+        );
+        ensure!(
+            value.len() <= MAX_LABEL_VALUE_LEN,
+            "label value is too long ({} > {})",
+            value.len(),
+            MAX_LABEL_VALUE_LEN
+        );
+
+        if value.is_empty() {
+            return Ok(self.remove(peer, &key));
+        }
+
+        let labels = self.labels.entry(peer).or_default();
+        if !labels.contains_key(&key) && labels.len() >= MAX_LABELS_PER_PEER {
+            bail!("too many labels for peer (max {})", MAX_LABELS_PER_PEER);
+        }
+
+        Ok(labels.insert(key, PeerLabel { value, persistent }))
+    }
+
+    pub fn remove(&mut self, peer: PeerId, key: &str) -> Option<PeerLabel> {
+        if let Entry::Occupied(mut entry) = self.labels.entry(peer) {
+            let label = entry.get_mut().remove(key);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+
+            return label;
+        }
+
+        None
+    }
+
+    pub fn get(&self, peer: PeerId) -> BTreeMap<String, String> {
+        self.labels
+            .get(&peer)
+            .map(|labels| {
+                labels
+                    .iter()
+                    .map(|(k, label)| (k.clone(), label.value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drop all labels of the peer that are not marked persistent. Returns the dropped labels.
+    pub fn on_disconnect(&mut self, peer: PeerId) -> BTreeMap<String, PeerLabel> {
+        let mut dropped = BTreeMap::new();
+        if let Entry::Occupied(mut entry) = self.labels.entry(peer) {
+            let labels = std::mem::take(entry.get_mut());
+            for (key, label) in labels {
+                if label.persistent {
+                    entry.get_mut().insert(key, label);
+                } else {
+                    dropped.insert(key, label);
+                }
+            }
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+        dropped
+    }
+
+    /// Restore labels dropped on disconnect. Labels set since then take precedence.
+    pub fn restore(&mut self, peer: PeerId, labels: BTreeMap<String, PeerLabel>) {
+        if labels.is_empty() {
+            return;
+        }
+
+        let entry = self.labels.entry(peer).or_default();
+        for (key, label) in labels {
+            if entry.len() >= MAX_LABELS_PER_PEER {
+                break;
+            }
+            entry.entry(key).or_insert(label);
+        }
+    }
+
+    pub fn persistent(&self) -> HashMap<PeerId, BTreeMap<String, String>> {
+        self.labels
+            .iter()
+            .filter_map(|(&peer, labels)| {
+                let labels = labels
+                    .iter()
+                    .filter(|(_, label)| label.persistent)
+                    .map(|(k, label)| (k.clone(), label.value.clone()))
+                    .collect::<BTreeMap<_, _>>();
+
+                if labels.is_empty() {
+                    None
+                } else {
+                    Some((peer, labels))
+                }
+            })
+            .collect()
+    }
+
+    pub fn load_persistent(&mut self, persistent: HashMap<PeerId, BTreeMap<String, String>>) {
+        for (peer, labels) in persistent {
+            let entry = self.labels.entry(peer).or_default();
+            for (key, value) in labels.into_iter().take(MAX_LABELS_PER_PEER) {
+                entry.insert(
+                    key,
+                    PeerLabel {
+                        value,
+                        persistent: true,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(labels: &mut PeerLabels, peer: PeerId, key: &str, value: &str, persistent: bool) {
+        labels
+            .set(peer, key.into(), value.into(), persistent)
+            .unwrap();
+    }
+
+    #[test]
+    fn set_validates_and_returns_previous() {
+        let mut labels = PeerLabels::default();
+        let peer = PeerId::from_low_u64_be(1);
+
+        assert!(labels.set(peer, "".into(), "v".into(), false).is_err());
+        assert!(labels
+            .set(peer, "k".repeat(MAX_LABEL_KEY_LEN + 1), "v".into(), false)
+            .is_err());
+        assert!(labels
+            .set(peer, "k".into(), "v".repeat(MAX_LABEL_VALUE_LEN + 1), false)
+            .is_err());
+
+        assert!(labels
+            .set(peer, "k".into(), "a".into(), true)
+            .unwrap()
+            .is_none());
+        let previous = labels
+            .set(peer, "k".into(), "b".into(), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(previous.value, "a");
+        assert!(previous.persistent);
+        assert!(labels.persistent().is_empty());
+
+        let removed = labels.set(peer, "k".into(), "".into(), false).unwrap();
+        assert_eq!(removed.unwrap().value, "b");
+        assert!(labels.get(peer).is_empty());
+
+        for i in 0..MAX_LABELS_PER_PEER {
+            set(&mut labels, peer, &i.to_string(), "v", false);
+        }
+        assert!(labels.set(peer, "k".into(), "v".into(), false).is_err());
+        // Existing label can still be changed.
+        set(&mut labels, peer, "0", "w", false);
+        assert_eq!(labels.get(peer)["0"], "w");
+    }
+
+    #[test]
+    fn disconnect_keeps_persistent_labels() {
+        let mut labels = PeerLabels::default();
+        let peer = PeerId::from_low_u64_be(1);
+        set(&mut labels, peer, "kept", "a", true);
+        set(&mut labels, peer, "dropped", "b", false);
+
+        let dropped = labels.on_disconnect(peer);
+        assert_eq!(dropped.keys().collect::<Vec<_>>(), vec!["dropped"]);
+        assert_eq!(labels.get(peer).keys().collect::<Vec<_>>(), vec!["kept"]);
+        assert_eq!(labels.persistent()[&peer]["kept"], "a");
+
+        // Label set since the disconnect takes precedence.
+        set(&mut labels, peer, "dropped", "c", false);
+        labels.restore(peer, dropped);
+        assert_eq!(labels.get(peer)["dropped"], "c");
+
+        let mut loaded = PeerLabels::default();
+        loaded.load_persistent(labels.persistent());
+        assert_eq!(loaded.get(peer).keys().collect::<Vec<_>>(), vec!["kept"]);
+    }
+}
@@ -0,0 +1,227 @@
+use devp2p::{ConnectionDirection, DisconnectReason, PeerId};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Lifetimes kept per direction and cause for the summaries.
+pub const RECENT_LIFETIMES: usize = 256;
+
+/// Coarse grouping of disconnect reasons, regardless of which side disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DisconnectCause {
+    /// Disconnect without a specific reason, or client shutting down.
+    Requested,
+    /// Connection broke, or no reason was given.
+    Network,
+    /// Misbehaviour or failed identity checks.
+    Protocol,
+    /// Peer found no use in the connection.
+    Useless,
+    /// No free slot, or a connection to the peer exists already.
+    Capacity,
+}
+
+impl DisconnectCause {
+    pub const ALL: [Self; 5] = [
+        Self::Requested,
+        Self::Network,
+        Self::Protocol,
+        Self::Useless,
+        Self::Capacity,
+    ];
+
+    pub fn new(reason: Option<DisconnectReason>) -> Self {
+        match reason {
+            Some(DisconnectReason::DisconnectRequested)
+            | Some(DisconnectReason::ClientQuitting) => Self::Requested,
+            None
+            | Some(DisconnectReason::TcpSubsystemError)
+            | Some(DisconnectReason::PingTimeout) => Self::Network,
+            Some(DisconnectReason::ProtocolBreach)
+            | Some(DisconnectReason::IncompatibleP2PProtocolVersion)
+            | Some(DisconnectReason::NullNodeIdentity)
+            | Some(DisconnectReason::UnexpectedHandshakeIdentity)
+            | Some(DisconnectReason::ConnectedToSelf) => Self::Protocol,
+            Some(DisconnectReason::UselessPeer) | Some(DisconnectReason::SubprotocolSpecific) => {
+                Self::Useless
+            }
+            Some(DisconnectReason::TooManyPeers) | Some(DisconnectReason::AlreadyConnected) => {
+                Self::Capacity
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Requested => "requested",
+            Self::Network => "network",
+            Self::Protocol => "protocol",
+            Self::Useless => "useless",
+            Self::Capacity => "capacity",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LifetimeSummary {
+    pub count: usize,
+    pub median_secs: Option<u64>,
+}
+
+impl LifetimeSummary {
+    fn new(mut lifetimes: Vec<Duration>) -> Self {
+        lifetimes.sort_unstable();
+        Self {
+            count: lifetimes.len(),
+            median_secs: lifetimes.get(lifetimes.len() / 2).map(Duration::as_secs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DirectionSummary {
+    #[serde(flatten)]
+    pub total: LifetimeSummary,
+    pub by_cause: BTreeMap<&'static str, LifetimeSummary>,
+}
+
+/// Human readable duration, minutes for most connections.
+pub struct LifetimeDisplay(pub u64);
+
+impl fmt::Display for LifetimeDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            secs if secs < 60 => write!(f, "{} s", secs),
+            secs if secs < 3 * 60 * 60 => write!(f, "{} min", secs / 60),
+            secs => write!(f, "{} h", secs / (60 * 60)),
+        }
+    }
+}
+
+/// Direction of connected peers and lifetimes of recently closed connections.
+#[derive(Debug, Default)]
+pub struct ConnectionLifetimes {
+    directions: HashMap<PeerId, ConnectionDirection>,
+    recent: HashMap<(ConnectionDirection, DisconnectCause), VecDeque<Duration>>,
+}
+
+impl ConnectionLifetimes {
+    pub fn on_connect(&mut self, peer: PeerId, direction: ConnectionDirection) {
+        self.directions.insert(peer, direction);
+    }
+
+    /// Record lifetime of the peer's connection if its start is known, returns its
+    /// direction and cause.
+    pub fn on_disconnect(
+        &mut self,
+        peer: PeerId,
+        connected: Option<Instant>,
+        reason: Option<DisconnectReason>,
+        now: Instant,
+    ) -> Option<(ConnectionDirection, DisconnectCause, Duration)> {
+        let direction = self.directions.remove(&peer)?;
+        let cause = DisconnectCause::new(reason);
+        let lifetime = now.saturating_duration_since(connected?);
+
+        let recent = self.recent.entry((direction, cause)).or_default();
+        if recent.len() >= RECENT_LIFETIMES {
+            recent.pop_front();
+        }
+        recent.push_back(lifetime);
+
+        Some((direction, cause, lifetime))
+    }
+
+    pub fn direction(&self, peer: PeerId) -> Option<ConnectionDirection> {
+        self.directions.get(&peer).copied()
+    }
+
+    pub fn summary(&self, direction: ConnectionDirection) -> DirectionSummary {
+        let lifetimes = |cause| {
+            self.recent
+                .get(&(direction, cause))
+                .into_iter()
+                .flatten()
+                .copied()
+        };
+        DirectionSummary {
+            total: LifetimeSummary::new(
+                DisconnectCause::ALL
+                    .iter()
+                    .flat_map(|&cause| lifetimes(cause))
+                    .collect(),
+            ),
+            by_cause: DisconnectCause::ALL
+                .iter()
+                .filter(|&&cause| self.recent.contains_key(&(direction, cause)))
+                .map(|&cause| {
+                    (
+                        cause.as_str(),
+                        LifetimeSummary::new(lifetimes(cause).collect()),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConnectionDirection::*;
+    use DisconnectReason::*;
+
+    #[test]
+    fn lifetimes_by_direction_and_cause() {
+        let mut lifetimes = ConnectionLifetimes::default();
+        let now = Instant::now();
+        let minutes = |m| Duration::from_secs(m * 60);
+
+        // Outbound peers mostly stay, inbound ones are turned away quickly.
+        for (i, &(direction, lifetime, reason)) in [
+            (Outbound, 40, PingTimeout),
+            (Outbound, 42, PingTimeout),
+            (Outbound, 90, UselessPeer),
+            (Inbound, 0, TooManyPeers),
+            (Inbound, 0, TooManyPeers),
+            (Inbound, 5, ProtocolBreach),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let peer = PeerId::from_low_u64_be(i as u64);
+            lifetimes.on_connect(peer, direction);
+            assert_eq!(lifetimes.direction(peer), Some(direction));
+            assert_eq!(
+                lifetimes.on_disconnect(peer, Some(now), Some(reason), now + minutes(lifetime)),
+                Some((
+                    direction,
+                    DisconnectCause::new(Some(reason)),
+                    minutes(lifetime)
+                ))
+            );
+        }
+
+        let outbound = lifetimes.summary(Outbound);
+        assert_eq!(outbound.total.count, 3);
+        assert_eq!(outbound.total.median_secs, Some(42 * 60));
+        assert_eq!(outbound.by_cause["network"].median_secs, Some(42 * 60));
+        assert_eq!(outbound.by_cause["useless"].count, 1);
+        assert_eq!(
+            LifetimeDisplay(outbound.total.median_secs.unwrap()).to_string(),
+            "42 min"
+        );
+
+        let inbound = lifetimes.summary(Inbound);
+        assert_eq!(inbound.total.median_secs, Some(0));
+        assert_eq!(inbound.by_cause["capacity"].count, 2);
+        assert!(!inbound.by_cause.contains_key("network"));
+
+        // Unknown peer was never set up.
+        let unknown = PeerId::from_low_u64_be(100);
+        assert_eq!(lifetimes.on_disconnect(unknown, Some(now), None, now), None);
+    }
+}
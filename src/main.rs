@@ -1,60 +1,184 @@
 #![allow(dead_code, clippy::upper_case_acronyms)]
 
 use crate::{
+    adaptive_headers::AdaptiveHeaders,
+    admin::AdminApi,
+    announce::HeadAnnouncer,
+    api_auth::ApiToken,
+    asn::{AsnLimiter, DEFAULT_MAX_PEERS_PER_ASN},
+    bandwidth::*,
+    bans::*,
+    breaches::*,
+    capture::PeerCapture,
+    churn::*,
+    coalesce::RequestCoalescer,
     config::*,
+    config_file::load_config,
+    crawler::{Crawler, DEFAULT_CRAWL_COOLDOWN, DEFAULT_CRAWL_RATE},
+    disconnect_policy::*,
+    duplicates::*,
+    effective_config::*,
     eth::*,
+    fork_health::ForkHealth,
+    forwarding::{
+        control_route, ControlQueue, ForwardQueue, QueueStats, Stamp, FORWARD_STATS_INTERVAL,
+    },
     grpc::sentry::{sentry_server::SentryServer, InboundMessage},
+    header_cache::HeaderCache,
+    header_misses::{hash_anchor, HeaderMisses},
+    idle_peers::*,
+    labels::*,
+    lifetimes::*,
+    message_log::{Direction, PeerMessageLog, PeerMessageLogEntry},
+    message_signing::MessageSigner,
+    metrics::Metrics,
+    peer_timers::*,
+    peer_watch::*,
+    pending_tx::PendingTxSizes,
+    persistence::{self, BansFile, MetricsFile, PeersFile},
+    pipeline::{pipeline, PeerEvent, Pipeline},
+    reconnect::*,
+    replay::ReplayDataProvider,
+    request_ids::RequestIds,
+    response_quality::*,
+    routers::*,
+    self_test::RuntimeChecks,
+    served::*,
     services::*,
+    shutdown::{drained, Shutdown, DRAIN_PERIOD},
+    siblings::*,
+    stalls::*,
+    static_peers::StaticPeers,
+    syncing::SyncingClassifier,
+    tasks::*,
+    tx_pool::TxPool,
+    wall_clock::WallClock,
+    web3::{Web3StatusProvider, Web3Transport, WEB3_POLL_INTERVAL},
+    whitelist::*,
 };
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use async_stream::stream;
 use async_trait::async_trait;
+use bytes::Bytes;
 use clap::Clap;
 use devp2p::*;
 use educe::Educe;
-use futures::stream::BoxStream;
+use ethereum_forkid::ValidationError;
+use ethereum_types::H256;
+use futures::stream::{BoxStream, Stream};
 use grpc::sentry;
-use maplit::btreemap;
 use num_traits::{FromPrimitive, ToPrimitive};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use rlp::{Rlp, RlpStream};
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
     collections::{btree_map::Entry, hash_map::Entry as HashMapEntry, BTreeMap, HashMap, HashSet},
-    convert::TryFrom,
     fmt::Debug,
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use task_group::TaskGroup;
 use tokio::{
-    sync::{
-        broadcast::{channel as broadcast, Sender as BroadcastSender},
-        mpsc::{channel, Sender},
-        Mutex as AsyncMutex,
-    },
+    signal::unix::SignalKind,
+    sync::{mpsc::channel, oneshot, Mutex as AsyncMutex, Notify, Semaphore},
     time::sleep,
 };
 use tokio_stream::{StreamExt, StreamMap};
 use tonic::transport::Server;
+use tower::{Service, ServiceExt};
 use tracing::*;
 use tracing_subscriber::EnvFilter;
 use trust_dns_resolver::{config::*, TokioAsyncResolver};
+use ulid::Ulid;
 
+mod adaptive_headers;
+mod admin;
+mod announce;
+mod api_auth;
+mod asn;
+mod bandwidth;
+mod bans;
+mod breaches;
+mod capture;
+mod churn;
+mod coalesce;
 mod config;
+mod config_file;
+mod crawler;
+mod decode;
+mod disconnect_policy;
+mod duplicates;
+mod effective_config;
+#[cfg(feature = "eip4337")]
+mod eip4337;
 mod eth;
+mod fork_health;
+mod forwarding;
 mod grpc;
+mod header_cache;
+mod header_misses;
+mod idle_peers;
+mod labels;
+mod lifetimes;
+mod message_log;
+mod message_signing;
+mod metrics;
+mod peer_timers;
+mod peer_watch;
+mod pending_tx;
+mod persistence;
+mod pipeline;
+mod reconnect;
+mod replay;
+mod request_ids;
+mod response_quality;
+mod routers;
+mod self_test;
+mod served;
 mod services;
+mod shutdown;
+mod siblings;
+mod stalls;
+mod static_peers;
+mod syncing;
+mod tasks;
+mod telemetry;
+mod tx_pool;
 mod types;
+mod wall_clock;
+mod web3;
+mod whitelist;
 
-type OutboundSender = Sender<OutboundEvent>;
+type OutboundSender = SendQueue<OutboundEvent>;
 type OutboundReceiver = Arc<AsyncMutex<BoxStream<'static, OutboundEvent>>>;
 
 pub const BUFFERING_FACTOR: usize = 5;
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+const ERROR_LOG_CAPACITY: usize = 1024;
+const PEER_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+const HEAD_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+const RESPONSE_QUALITY_INTERVAL: Duration = Duration::from_secs(1);
+const PENDING_TX_CAPACITY: usize = 65536;
+const TX_POOL_CAPACITY: usize = 16384;
+/// Peers served the most that are listed in the periodic report.
+const TOP_CONSUMERS: usize = 10;
+#[cfg(feature = "quic")]
+const QUIC_DIAL_INTERVAL: Duration = Duration::from_secs(5);
+/// Ban changes made within this time of each other are saved together.
+const BANS_SAVE_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 struct Pipes {
     sender: OutboundSender,
     receiver: OutboundReceiver,
+    monitor: Arc<QueueMonitor>,
+    /// Correlates logs of this connection, new on every connect.
+    connection_id: Ulid,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -100,6 +224,10 @@ impl BlockTracker {
         }
     }
 
+    fn block_number(&self, peer: PeerId) -> Option<u64> {
+        self.block_by_peer.get(&peer).copied()
+    }
+
     fn peers_with_min_block(&self, block: u64) -> HashSet<PeerId> {
         self.peers_by_block
             .range(block..)
@@ -110,322 +238,2884 @@ impl BlockTracker {
     }
 }
 
-#[derive(Educe)]
+#[derive(Clone, Educe)]
 #[educe(Debug)]
 pub struct CapabilityServerImpl {
     #[educe(Debug(ignore))]
-    peer_pipes: Arc<RwLock<HashMap<PeerId, Pipes>>>,
+    peer_pipes: Arc<scc::HashMap<PeerId, Pipes>>,
+    /// Sizes of `peer_pipes` and `valid_peers`, read without locking.
+    peer_count: Arc<AtomicUsize>,
+    valid_peer_count: Arc<AtomicUsize>,
     block_tracker: Arc<RwLock<BlockTracker>>,
 
     status_message: Arc<RwLock<Option<FullStatusData>>>,
     valid_peers: Arc<RwLock<HashSet<PeerId>>>,
+    /// Valid peers that are too far behind us to be selected for requests.
+    syncing_peers: Arc<RwLock<HashSet<PeerId>>>,
+    syncing_classifier: SyncingClassifier,
+    protocol_version_by_peer: Arc<RwLock<HashMap<PeerId, u8>>>,
+    /// Addresses peers accept connections on, if known.
+    peer_addrs: Arc<Mutex<HashMap<PeerId, SocketAddr>>>,
+    fork_health: Arc<Mutex<ForkHealth>>,
+    /// Not kept in read-only mode, see `serve_data`.
+    header_cache: Option<Arc<RwLock<HeaderCache>>>,
+    /// Not kept in read-only mode or if `tx_pool_ttl_secs` is 0.
+    tx_pool: Option<Arc<Mutex<TxPool>>>,
+    churn_tracker: Arc<Mutex<PeerChurnTracker>>,
+    reconnects: Arc<Mutex<ReconnectTracker>>,
+    validate_outbound: bool,
+    serve_data: bool,
+    disconnect_policies: Arc<DisconnectPolicies>,
+    normalize_request_ids: bool,
+    request_ids: Arc<Mutex<RequestIds>>,
+    adaptive_headers: Arc<Mutex<AdaptiveHeaders>>,
+    /// Signs and checks eth messages if set.
+    message_signer: Option<MessageSigner>,
+    idle_peers: Arc<Mutex<IdlePeers>>,
+    connection_lifetimes: Arc<Mutex<ConnectionLifetimes>>,
+    passive_window: Duration,
+    passive_eviction: PassivePeerEviction,
+    peer_whitelist: Arc<RwLock<PeerWhitelist>>,
+    evict_passive_above_peers: usize,
+    large_message_threshold: usize,
+    served: Arc<Mutex<ServedData>>,
+    bandwidth_budget: Arc<Mutex<BandwidthBudget>>,
+    reject_peers_when_budget_exhausted: bool,
+    #[educe(Debug(ignore))]
+    error_log_limiter: Arc<LogLimiter<(PeerId, &'static str)>>,
+    #[educe(Debug(ignore))]
+    peer_watch: Arc<PeerWatch>,
+    request_coalescer: Arc<Mutex<RequestCoalescer>>,
+    header_misses: Arc<Mutex<HeaderMisses>>,
+    head_announcer: Arc<Mutex<HeadAnnouncer>>,
+    response_quality: Arc<Mutex<ResponseQuality>>,
+    pending_tx_size_by_hash: Arc<Mutex<PendingTxSizes>>,
+    message_log: Arc<Mutex<PeerMessageLog>>,
+    breach_log: Arc<Mutex<BreachLog>>,
+    wall_clock: WallClock,
+    /// Payloads of messages that made us disconnect the peer.
+    breach_dumps: Arc<RwLock<Option<Arc<BreachDumps>>>>,
+    /// Notified when the peer connects again, see `refresh_peer`.
+    reconnect_waiters: Arc<Mutex<HashMap<PeerId, Vec<oneshot::Sender<()>>>>>,
+    peer_timers: PeerTimers,
+    /// Taken by the task that runs expired timers, see `take_peer_timer_queue`.
+    peer_timer_queue: Arc<Mutex<Option<PeerTimerQueue>>>,
+    status_timeout: Option<Duration>,
+    /// Drops block announcements already received from another peer.
+    duplicate_filter: Option<Arc<Mutex<DuplicateFilter>>>,
+    /// Taken by the task that slides the filter's window, see `take_duplicate_filter_expiry`.
+    duplicate_filter_expiry: Arc<Mutex<Option<DuplicateFilterExpiry>>>,
+    peer_event_permits: Arc<Semaphore>,
+    tasks: TaskRegistry,
+    capability_registry: Arc<RwLock<Option<CapabilityRegistry>>>,
+    /// Lets refreshed peers be dialed again at once, see `refresh_peer`.
+    redial_exemptions: Arc<RwLock<Option<RedialExemptions>>>,
+    asn_limiter: Arc<Mutex<Option<AsnLimiter>>>,
+    crawler: Arc<RwLock<Option<Arc<Crawler>>>>,
+    peer_capture: Arc<RwLock<Option<Arc<PeerCapture>>>>,
+    replay: Arc<RwLock<Option<Arc<ReplayDataProvider>>>>,
+    /// Exports a span per inbound eth message if set.
+    otlp_tracer: Arc<RwLock<Option<opentelemetry::sdk::trace::Tracer>>>,
+    /// Control has set our status, which takes precedence over one from the web3 endpoint.
+    /// Held while the status is set, so that a web3 poll in flight cannot overwrite the
+    /// control's status.
+    status_from_control: Arc<Mutex<bool>>,
+    shutdown: Arc<Shutdown>,
+    bans: Arc<Mutex<BanList>>,
+    ban_ttl: Duration,
+    remote_ban_ttl: Duration,
+    bans_file: Option<PathBuf>,
+    /// Wakes the task saving the bans file.
+    bans_changed: Arc<Notify>,
+    sibling_gossip: Arc<RwLock<Option<SiblingGossip>>>,
+    capability_routers: Arc<RwLock<CapabilityRouters>>,
+    #[educe(Debug(ignore))]
+    discv4: Option<Arc<discv4::Node>>,
+    metrics: Arc<Metrics>,
+
+    peer_labels: Arc<RwLock<PeerLabels>>,
+    peers_file: Option<PathBuf>,
+
+    data_sender: Arc<ForwardQueue>,
+    upload_requests_sender: Arc<ForwardQueue>,
+    tx_message_sender: Arc<ForwardQueue>,
 
-    data_sender: BroadcastSender<InboundMessage>,
-    upload_requests_sender: BroadcastSender<InboundMessage>,
-    tx_message_sender: BroadcastSender<InboundMessage>,
+    /// Built once in `new`. `None` only in the copy of the server the pipeline holds.
+    #[educe(Debug(ignore))]
+    pipeline: Option<Pipeline>,
 }
 
 impl CapabilityServerImpl {
-    fn setup_peer(&self, peer: PeerId, p: Pipes) {
-        let mut pipes = self.peer_pipes.write();
+    pub fn new(
+        opts: &Config,
+        message_signer: Option<MessageSigner>,
+        discv4: Option<Arc<discv4::Node>>,
+        metrics: Arc<Metrics>,
+        peer_labels: PeerLabels,
+        tasks: TaskRegistry,
+    ) -> Self {
+        let (peer_timers, peer_timer_queue) = peer_timers();
+        let (duplicate_filter, duplicate_filter_expiry) = match opts.duplicate_filter.window_secs {
+            0 => (None, None),
+            window_secs => {
+                let (filter, expiry) = duplicate_filter(
+                    Duration::from_secs(window_secs),
+                    opts.duplicate_filter.false_positive_rate,
+                    CopyLimits {
+                        new_block_hashes: opts.duplicate_filter.new_block_hashes_copies,
+                        new_block: opts.duplicate_filter.new_block_copies,
+                    },
+                );
+                (Some(Arc::new(Mutex::new(filter))), Some(expiry))
+            }
+        };
+
+        let mut this = Self {
+            peer_pipes: Default::default(),
+            peer_count: Default::default(),
+            valid_peer_count: Default::default(),
+            block_tracker: Default::default(),
+            status_message: Default::default(),
+            valid_peers: Default::default(),
+            syncing_peers: Default::default(),
+            syncing_classifier: SyncingClassifier::new(
+                opts.syncing_td_percent,
+                opts.syncing_block_tolerance,
+            ),
+            protocol_version_by_peer: Default::default(),
+            peer_addrs: Default::default(),
+            fork_health: Arc::new(Mutex::new(ForkHealth::new(
+                opts.fork_health.window,
+                opts.fork_health.min_samples,
+                opts.fork_health.threshold,
+            ))),
+            header_cache: Some(opts.header_cache_window)
+                .filter(|_| opts.serve_data)
+                .map(|window| Arc::new(RwLock::new(HeaderCache::new(window)))),
+            tx_pool: Some(Duration::from_secs(opts.tx_pool_ttl_secs))
+                .filter(|ttl| opts.serve_data && !ttl.is_zero())
+                .map(|ttl| Arc::new(Mutex::new(TxPool::new(ttl, TX_POOL_CAPACITY)))),
+            churn_tracker: Default::default(),
+            reconnects: Arc::new(Mutex::new(ReconnectTracker::new(
+                Duration::from_secs(opts.reconnect_window_secs),
+                opts.max_reconnects_per_minute,
+            ))),
+            validate_outbound: opts.validate_outbound,
+            serve_data: opts.serve_data,
+            disconnect_policies: Arc::new(DisconnectPolicies::new(&opts.disconnect_policy)),
+            normalize_request_ids: opts.normalize_request_ids,
+            request_ids: Default::default(),
+            adaptive_headers: Arc::new(Mutex::new(AdaptiveHeaders::new(Duration::from_millis(
+                opts.adaptive_headers_latency_threshold_ms,
+            )))),
+            message_signer,
+            idle_peers: Arc::new(Mutex::new(IdlePeers::new(
+                IDLE_WINDOW,
+                opts.min_messages_per_window,
+            ))),
+            connection_lifetimes: Default::default(),
+            passive_window: Duration::from_secs(opts.passive_peers.window_secs),
+            passive_eviction: opts.passive_peers.eviction,
+            peer_whitelist: Arc::new(RwLock::new(PeerWhitelist::new(
+                opts.peer_whitelist_mode,
+                opts.peer_whitelist.iter().copied(),
+            ))),
+            evict_passive_above_peers: opts.passive_peers.evict_above_peers,
+            large_message_threshold: opts.large_message_threshold,
+            served: Default::default(),
+            bandwidth_budget: Arc::new(Mutex::new(BandwidthBudget::new(
+                Duration::from_secs(opts.bandwidth_budget.period_secs),
+                opts.bandwidth_budget.ingress_bytes,
+                opts.bandwidth_budget.egress_bytes,
+                opts.bandwidth_budget.nearly_exhausted_percent,
+                Instant::now(),
+            ))),
+            reject_peers_when_budget_exhausted: opts.bandwidth_budget.reject_peers_when_exhausted,
+            error_log_limiter: Arc::new(LogLimiter::new(ERROR_LOG_INTERVAL, ERROR_LOG_CAPACITY)),
+            peer_watch: Default::default(),
+            request_coalescer: Default::default(),
+            header_misses: Default::default(),
+            head_announcer: Default::default(),
+            response_quality: Arc::new(Mutex::new(ResponseQuality::new(
+                opts.response_quality.window,
+                opts.response_quality.min_samples,
+                opts.response_quality.threshold,
+                Duration::from_secs(opts.response_quality.cooldown_secs),
+            ))),
+            pending_tx_size_by_hash: Arc::new(Mutex::new(PendingTxSizes::new(PENDING_TX_CAPACITY))),
+            message_log: Arc::new(Mutex::new(PeerMessageLog::new(opts.peer_message_log_size))),
+            breach_log: Arc::new(Mutex::new(BreachLog::new(&opts.breach_log))),
+            wall_clock: Default::default(),
+            breach_dumps: Default::default(),
+            reconnect_waiters: Default::default(),
+            peer_timers,
+            peer_timer_queue: Arc::new(Mutex::new(Some(peer_timer_queue))),
+            status_timeout: Some(Duration::from_secs(opts.status_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+            duplicate_filter,
+            duplicate_filter_expiry: Arc::new(Mutex::new(duplicate_filter_expiry)),
+            peer_event_permits: Arc::new(Semaphore::new(
+                opts.max_parallel_peer_events
+                    .unwrap_or_else(|| 4 * num_cpus::get())
+                    .max(1),
+            )),
+            tasks,
+            capability_registry: Default::default(),
+            redial_exemptions: Default::default(),
+            asn_limiter: Default::default(),
+            crawler: Default::default(),
+            peer_capture: Default::default(),
+            replay: Default::default(),
+            otlp_tracer: Default::default(),
+            status_from_control: Default::default(),
+            shutdown: Default::default(),
+            bans: Default::default(),
+            ban_ttl: Duration::from_secs(opts.siblings.ban_ttl_secs),
+            remote_ban_ttl: Duration::from_secs(opts.siblings.remote_ban_ttl_secs),
+            bans_file: opts.bans_file.clone(),
+            bans_changed: Default::default(),
+            sibling_gossip: Default::default(),
+            capability_routers: Default::default(),
+            discv4,
+            metrics,
+            peer_labels: Arc::new(RwLock::new(peer_labels)),
+            peers_file: opts.peers_file.clone(),
+            data_sender: Arc::new(ForwardQueue::new("data", opts.max_peers * BUFFERING_FACTOR)),
+            upload_requests_sender: Arc::new(ForwardQueue::new(
+                "upload_requests",
+                opts.max_peers * BUFFERING_FACTOR,
+            )),
+            tx_message_sender: Arc::new(ForwardQueue::new(
+                "tx_messages",
+                opts.max_peers * BUFFERING_FACTOR,
+            )),
+            pipeline: None,
+        };
+        this.pipeline = Some(pipeline(this.clone()));
+        this
+    }
+
+    /// Server built from `opts` alone, without message signing, discovery or peer labels.
+    #[cfg(test)]
+    pub fn for_test(opts: &Config) -> Self {
+        Self::new(
+            opts,
+            None,
+            None,
+            Arc::new(Metrics::new().unwrap()),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    /// Register peer. Pipes are in place before the peer is visible anywhere else,
+    /// so its events can be handled as soon as this returns.
+    ///
+    /// If the peer is already set up, the new connection replaces the old one.
+    fn setup_peer(
+        &self,
+        peer: PeerId,
+        p: Pipes,
+        protocol_version: u8,
+        resumed: Option<SessionState>,
+    ) {
         let mut block_tracker = self.block_tracker.write();
+        let mut protocol_version_by_peer = self.protocol_version_by_peer.write();
+        let mut peer_labels = self.peer_labels.write();
 
-        assert!(pipes.insert(peer, p).is_none());
-        block_tracker.set_block_number(peer, 0, true);
+        match self.peer_pipes.entry(peer) {
+            scc::hash_map::Entry::Occupied(mut e) => {
+                debug!("Peer {} is already set up, replacing its pipes", peer);
+                *e.get_mut() = p;
+            }
+            scc::hash_map::Entry::Vacant(e) => {
+                e.insert_entry(p);
+                self.peer_count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        protocol_version_by_peer.insert(peer, protocol_version);
+        match resumed {
+            Some(SessionState {
+                block,
+                labels,
+                response_outcomes,
+            }) => {
+                block_tracker.set_block_number(peer, block, true);
+                peer_labels.restore(peer, labels);
+                self.response_quality
+                    .lock()
+                    .restore_outcomes(peer, response_outcomes);
+            }
+            None => block_tracker.set_block_number(peer, 0, true),
+        }
+        self.message_log.lock().on_connect(peer);
+        self.breach_log.lock().on_connect(peer);
+        self.tasks.on_peer_connect(peer);
+        for waiter in self
+            .reconnect_waiters
+            .lock()
+            .remove(&peer)
+            .unwrap_or_default()
+        {
+            let _ = waiter.send(());
+        }
+        self.churn_tracker
+            .lock()
+            .record(ChurnEvent::Connect, Instant::now());
+    }
+    /// Correlation ID of the peer's current connection.
+    pub fn connection_id(&self, peer: PeerId) -> Option<Ulid> {
+        self.peer_pipes.read(&peer, |_, pipes| pipes.connection_id)
+    }
+    /// Attach correlation ID of the peer's connection to the current span.
+    fn record_connection_id(&self, peer: PeerId) {
+        if let Some(connection_id) = self.connection_id(peer) {
+            Span::current().record("connection_id", &field::display(connection_id));
+        }
     }
     fn get_pipes(&self, peer: PeerId) -> Option<Pipes> {
-        self.peer_pipes.read().get(&peer).cloned()
+        self.peer_pipes.read(&peer, |_, pipes| pipes.clone())
     }
     pub fn sender(&self, peer: PeerId) -> Option<OutboundSender> {
-        self.peer_pipes
-            .read()
-            .get(&peer)
-            .map(|pipes| pipes.sender.clone())
+        self.peer_pipes.read(&peer, |_, pipes| pipes.sender.clone())
+    }
+
+    /// Queue event for the peer without an async context.
+    /// Returns `false` if the peer is not connected or its queue is full.
+    ///
+    /// Event goes into the peer's outbound queue, the same one async code sends to, so order
+    /// is kept. That queue holds a single event, so this fails while an earlier event has not
+    /// been picked up yet.
+    #[cfg(any(test, feature = "sync-send"))]
+    pub fn send_sync(&self, peer: PeerId, event: OutboundEvent) -> bool {
+        self.sender(peer)
+            .map(|sender| sender.try_send(event).is_ok())
+            .unwrap_or(false)
     }
+
     fn receiver(&self, peer: PeerId) -> Option<OutboundReceiver> {
         self.peer_pipes
-            .read()
-            .get(&peer)
-            .map(|pipes| pipes.receiver.clone())
+            .read(&peer, |_, pipes| pipes.receiver.clone())
     }
-    fn teardown_peer(&self, peer: PeerId) {
-        let mut pipes = self.peer_pipes.write();
+    fn teardown_peer(&self, peer: PeerId, reason: Option<DisconnectReason>) {
         let mut block_tracker = self.block_tracker.write();
         let mut valid_peers = self.valid_peers.write();
+        let mut protocol_version_by_peer = self.protocol_version_by_peer.write();
+        let mut peer_labels = self.peer_labels.write();
+        let mut syncing_peers = self.syncing_peers.write();
 
-        pipes.remove(&peer);
+        if self.peer_pipes.remove(&peer).is_some() {
+            self.peer_count.fetch_sub(1, Ordering::Relaxed);
+        }
+        let block = block_tracker.block_number(peer).unwrap_or_default();
         block_tracker.remove_peer(peer);
-        valid_peers.remove(&peer);
+        if valid_peers.remove(&peer) {
+            self.valid_peer_count.fetch_sub(1, Ordering::Relaxed);
+        }
+        protocol_version_by_peer.remove(&peer);
+        let labels = peer_labels.on_disconnect(peer);
+        syncing_peers.remove(&peer);
+        self.request_coalescer.lock().on_disconnect(peer);
+        self.header_misses.lock().on_disconnect(peer);
+        self.peer_addrs.lock().remove(&peer);
+        if let Some(asn_limiter) = &mut *self.asn_limiter.lock() {
+            asn_limiter.on_disconnect(peer);
+        }
+        if let Some(crawler) = self.crawler() {
+            crawler.on_disconnect(peer);
+        }
+        self.request_ids.lock().on_disconnect(peer);
+        self.adaptive_headers.lock().on_disconnect(peer);
+        self.pending_tx_size_by_hash.lock().on_disconnect(peer);
+        let connected = self.idle_peers.lock().on_disconnect(peer);
+        let lifetime =
+            self.connection_lifetimes
+                .lock()
+                .on_disconnect(peer, connected, reason, Instant::now());
+        if let Some((direction, cause, lifetime)) = lifetime {
+            self.metrics
+                .observe_connection_lifetime(direction, cause, lifetime);
+        }
+        self.peer_timers.cancel(peer);
+        self.served.lock().on_disconnect(peer);
+        let response_outcomes = self.response_quality.lock().forget(peer);
+        self.reconnects.lock().on_disconnect(
+            peer,
+            SessionState {
+                block,
+                labels,
+                response_outcomes,
+            },
+            Instant::now(),
+        );
+        self.message_log.lock().on_disconnect(peer, Instant::now());
+        self.breach_log.lock().on_disconnect(peer);
+        self.tasks.on_peer_disconnect(peer, Instant::now());
+        self.churn_tracker
+            .lock()
+            .record(ChurnEvent::Disconnect, Instant::now());
+
+        let routers = self.capability_routers.write().on_peer_disconnect(peer);
+        for router in routers {
+            router.on_peer_disconnect(peer);
+        }
     }
 
-    pub fn all_peers(&self) -> HashSet<PeerId> {
-        self.peer_pipes.read().keys().copied().collect()
+    pub fn set_peer_label(
+        &self,
+        peer: PeerId,
+        key: String,
+        value: String,
+        persistent: bool,
+    ) -> anyhow::Result<()> {
+        if !self.peer_pipes.contains(&peer) {
+            bail!("peer {} is not connected", peer);
+        }
+
+        // Persistent value being replaced or removed is in the peers file too.
+        let previous = self.peer_labels.write().set(peer, key, value, persistent)?;
+
+        if persistent || previous.map_or(false, |label| label.persistent) {
+            self.save_peers_file();
+        }
+
+        Ok(())
     }
 
-    pub fn connected_peers(&self) -> usize {
-        self.valid_peers.read().len()
+    pub fn peer_labels(&self, peer: PeerId) -> BTreeMap<String, String> {
+        self.peer_labels.read().get(peer)
     }
 
-    #[instrument(skip(self))]
-    async fn handle_event(
-        &self,
-        peer: PeerId,
-        event: InboundEvent,
-    ) -> Result<Option<Message>, DisconnectReason> {
-        match event {
-            InboundEvent::Disconnect { reason } => {
-                debug!("Peer disconnect (reason: {:?}), tearing down peer.", reason);
-                self.teardown_peer(peer);
+    fn save_peers_file(&self) {
+        if let Some(path) = &self.peers_file {
+            // File that fails to load is kept as is rather than overwritten.
+            let res = persistence::load::<PeersFile>(path).and_then(|mut peers_file| {
+                peers_file.set_peer_labels(self.peer_labels.read().persistent());
+                persistence::save(&peers_file, path)
+            });
+            if let Err(e) = res {
+                warn!("Failed to save peers file: {:?}", e);
             }
-            InboundEvent::Message {
-                message: Message { id, data },
-                ..
-            } => {
-                let valid_peer = self.valid_peers.read().contains(&peer);
-                let message_id = EthMessageId::from_usize(id);
-                match message_id {
-                    None => {
-                        debug!("Unknown message");
-                    }
-                    Some(EthMessageId::Status) => {
-                        let v = rlp::decode::<StatusMessage>(&data).map_err(|e| {
-                            debug!("Failed to decode status message: {}! Kicking peer.", e);
+        }
+    }
 
-                            DisconnectReason::ProtocolBreach
-                        })?;
+    pub fn all_peers(&self) -> HashSet<PeerId> {
+        let mut peers = HashSet::with_capacity(self.peer_pipes.len());
+        self.peer_pipes.scan(|&peer, _| {
+            peers.insert(peer);
+        });
+        peers
+    }
 
-                        debug!("Decoded status message: {:?}", v);
+    /// Valid peers as of the last peer watch update, then peers as they become valid. Follows
+    /// the peer watch, so a peer shows up within `PEER_WATCH_INTERVAL` of `mark_valid`. Ends
+    /// when the server is dropped.
+    pub fn all_peers_stream(&self) -> impl Stream<Item = PeerId> + Send + 'static {
+        self.peer_watch.valid_peers()
+    }
 
-                        let status_data = self.status_message.read();
-                        let mut valid_peers = self.valid_peers.write();
-                        if let Some(FullStatusData { fork_filter, .. }) = &*status_data {
-                            fork_filter.validate(v.fork_id).map_err(|reason| {
-                                debug!("Kicking peer with incompatible fork ID: {:?}", reason);
+    pub fn connected_peers(&self) -> usize {
+        self.peer_count.load(Ordering::Relaxed)
+    }
 
-                                DisconnectReason::UselessPeer
-                            })?;
+    pub fn valid_peers_count(&self) -> usize {
+        self.valid_peer_count.load(Ordering::Relaxed)
+    }
 
-                            valid_peers.insert(peer);
-                        }
-                    }
-                    Some(inbound_id) if valid_peer => {
-                        if let Some(sender) = match inbound_id {
-                            EthMessageId::BlockBodies
-                            | EthMessageId::BlockHeaders
-                            | EthMessageId::NodeData => Some(&self.data_sender),
-                            EthMessageId::GetBlockBodies
-                            | EthMessageId::GetBlockHeaders
-                            | EthMessageId::GetNodeData => Some(&self.upload_requests_sender),
-                            // EthMessageId::Transactions
-                            // | EthMessageId::NewPooledTransactionHashes
-                            // | EthMessageId::GetPooledTransactions
-                            // | EthMessageId::PooledTransactions => Some(&self.tx_message_sender),
-                            _ => None,
-                        } {
-                            if sender
-                                .send(InboundMessage {
-                                    id: sentry::MessageId::try_from(inbound_id).unwrap() as i32,
-                                    data,
-                                    peer_id: Some(peer.into()),
-                                })
-                                .is_err()
-                            {
-                                warn!("no connected sentry, dropping status and peer");
-                                *self.status_message.write() = None;
+    /// Depth and oldest message age of each queue to the control, by queue name.
+    pub fn forward_queue_stats(&self, now: Instant) -> Vec<(&'static str, QueueStats)> {
+        [
+            &self.data_sender,
+            &self.upload_requests_sender,
+            &self.tx_message_sender,
+        ]
+        .iter()
+        .map(|queue| (queue.name(), queue.stats(now)))
+        .collect()
+    }
 
-                                return Err(DisconnectReason::ClientQuitting);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    #[cfg(test)]
+    fn mark_valid(&self, peer: PeerId) {
+        if self.valid_peers.write().insert(peer) {
+            self.valid_peer_count.fetch_add(1, Ordering::Relaxed);
         }
+    }
 
-        Ok(None)
+    /// Valid peers as enode URLs, suitable for static or trusted peer lists of other nodes.
+    /// Peers with unknown address are skipped.
+    pub fn connected_enode_urls(&self) -> Vec<String> {
+        let valid_peers = self.valid_peers.read();
+        let peer_addrs = self.peer_addrs.lock();
+        let mut urls = valid_peers
+            .iter()
+            .filter_map(|&id| {
+                peer_addrs
+                    .get(&id)
+                    .map(|&addr| NodeRecord { id, addr }.to_string())
+            })
+            .collect::<Vec<_>>();
+        urls.sort();
+        urls
     }
-}
 
-#[async_trait]
-impl CapabilityServer for CapabilityServerImpl {
-    #[instrument(skip(self, peer), level = "debug", fields(peer=&*peer.to_string()))]
-    fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>) {
-        let first_events = if let Some(FullStatusData {
-            status,
-            fork_filter,
-        }) = &*self.status_message.read()
-        {
-            let status_message = StatusMessage {
-                protocol_version: *caps
-                    .get(&capability_name())
-                    .expect("peer without this cap would have been disconnected"),
-                network_id: status.network_id,
-                total_difficulty: status.total_difficulty,
-                best_hash: status.best_hash,
-                genesis_hash: status.fork_data.genesis,
-                fork_id: fork_filter.current(),
-            };
-
-            vec![OutboundEvent::Message {
-                capability_name: capability_name(),
-                message: Message {
-                    id: EthMessageId::Status.to_usize().unwrap(),
-                    data: rlp::encode(&status_message).into(),
-                },
-            }]
-        } else {
-            vec![OutboundEvent::Disconnect {
-                reason: DisconnectReason::DisconnectRequested,
-            }]
-        };
+    /// Whether most of the network appears to reject our fork ID.
+    pub fn fork_mismatch_suspected(&self) -> bool {
+        self.fork_health.lock().is_mismatch()
+    }
 
-        let (sender, mut receiver) = channel(1);
-        self.setup_peer(
-            peer,
-            Pipes {
-                sender,
-                receiver: Arc::new(AsyncMutex::new(Box::pin(stream! {
-                    for event in first_events {
-                        yield event;
-                    }
+    fn on_fork_health_change(&self, mismatch: Option<bool>) {
+        match mismatch {
+            Some(true) => {
+                warn!("Likely fork mismatch — check node/provider upgrade! Most recent peers reject our fork ID.");
+            }
+            Some(false) => {
+                info!("Fork ID is accepted by peers again");
+            }
+            None => {}
+        }
+    }
 
-                    while let Some(event) = receiver.recv().await {
-                        yield event;
-                    }
-                }))),
-            },
-        );
+    pub fn set_status(&self, status: StatusData) {
+        self.head_announcer
+            .lock()
+            .on_new_head(status.best_block, status.best_hash, Instant::now());
+
+        let (best_block, forks) = (status.best_block, status.fork_data.forks.len());
+        // Fork filter is rebuilt under the write lock, so peers connecting meanwhile wait
+        // for the new status instead of being sent a stale one or none.
+        let change = FullStatusData::update(&mut self.status_message.write(), status);
+        self.metrics.observe_status_update(change);
+        match change {
+            StatusChange::HeadMoved => {
+                trace!("Head moved to {}", best_block);
+            }
+            StatusChange::Reorg => {
+                debug!("Reorg to block {}", best_block);
+            }
+            StatusChange::ForkBoundary => {
+                info!("Crossed fork boundary at block {}", best_block);
+                // Peers judged our previous fork ID.
+                let mismatch = self.fork_health.lock().reset();
+                self.on_fork_health_change(mismatch);
+            }
+            StatusChange::ForksChanged => {
+                info!(
+                    "Fork schedule set: {} forks, head at block {}",
+                    forks, best_block
+                );
+            }
+        }
     }
-    #[instrument(skip(self, peer, event), level = "debug", fields(peer=&*peer.to_string(), event=&*event.to_string()))]
-    async fn on_peer_event(&self, peer: PeerId, event: InboundEvent) {
-        debug!("Received message");
 
-        if let Some(ev) = self.handle_event(peer, event).await.transpose() {
-            let _ = self
-                .sender(peer)
-                .unwrap()
-                .send(match ev {
-                    Ok(message) => OutboundEvent::Message {
-                        capability_name: capability_name(),
-                        message,
-                    },
-                    Err(reason) => OutboundEvent::Disconnect { reason },
-                })
-                .await;
+    /// Status set by the control, status from the web3 endpoint is ignored from now on.
+    pub fn set_control_status(&self, status: StatusData) {
+        let mut status_from_control = self.status_from_control.lock();
+        *status_from_control = true;
+        self.set_status(status);
+    }
+
+    /// Status from the web3 endpoint. Returns `false` if the control sets our status.
+    pub fn set_web3_status(&self, status: StatusData) -> bool {
+        let status_from_control = self.status_from_control.lock();
+        if *status_from_control {
+            return false;
         }
+        self.set_status(status);
+        true
     }
 
-    #[instrument(skip(self, peer), level = "debug", fields(peer=&*peer.to_string()))]
-    async fn next(&self, peer: PeerId) -> OutboundEvent {
-        self.receiver(peer)
-            .unwrap()
-            .lock()
-            .await
-            .next()
-            .await
-            .unwrap_or(OutboundEvent::Disconnect {
-                reason: DisconnectReason::DisconnectRequested,
-            })
+    pub fn status(&self) -> Option<FullStatusData> {
+        self.status_message.read().clone()
     }
-}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            if std::env::var(EnvFilter::DEFAULT_ENV)
-                .unwrap_or_default()
-                .is_empty()
-            {
-                EnvFilter::new("info")
-            } else {
-                EnvFilter::from_default_env()
-            },
-        )
-        .init();
+    /// Returns `false` if the peer is not connected.
+    pub async fn disconnect_peer(&self, peer: PeerId, reason: DisconnectReason) -> bool {
+        match self.sender(peer) {
+            Some(sender) => {
+                let _ = sender.send(OutboundEvent::Disconnect { reason }).await;
+                true
+            }
+            None => false,
+        }
+    }
 
-    let opts =
-        toml::from_str::<Config>(&std::fs::read_to_string(Opts::parse().config_path).unwrap())
-            .unwrap();
+    pub fn take_peer_timer_queue(&self) -> Option<PeerTimerQueue> {
+        self.peer_timer_queue.lock().take()
+    }
 
-    let secret_key;
-    if let Some(data) = opts.node_key {
-        secret_key = SecretKey::from_slice(&hex::decode(data)?)?;
-        info!("Loaded node key from config");
-    } else {
-        secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
-        info!("Generated new node key: {}", secret_key);
-    };
+    pub fn take_duplicate_filter_expiry(&self) -> Option<DuplicateFilterExpiry> {
+        self.duplicate_filter_expiry.lock().take()
+    }
 
-    let listen_addr = format!("0.0.0.0:{}", opts.listen_port);
+    pub fn expire_duplicates(&self, generation: u64) {
+        if let Some(duplicate_filter) = &self.duplicate_filter {
+            duplicate_filter.lock().expire(generation);
+        }
+    }
 
-    info!("Starting Ethereum sentry");
+    pub async fn on_timed_action(&self, action: TimedPeerAction) {
+        match action {
+            TimedPeerAction::DisconnectIfNotValid(peer) => {
+                if !self.valid_peers.read().contains(&peer) {
+                    debug!("Peer {} has not sent a valid Status in time", peer);
+                    self.disconnect_peer(peer, DisconnectReason::UselessPeer)
+                        .await;
+                }
+            }
+            TimedPeerAction::RemoveFromBanList(peer) => {
+                if self.bans.lock().expire(peer, Instant::now()) {
+                    debug!("Ban of peer {} has expired", peer);
+                    self.bans_changed.notify_one();
+                }
+            }
+        }
+    }
 
-    info!(
-        "Node ID: {}",
-        hex::encode(
-            devp2p::util::pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key)).as_bytes()
-        )
-    );
+    /// Disconnect valid peers that have sent too few messages recently.
+    pub async fn disconnect_idle_peers(&self) {
+        let (idle, min_messages) = {
+            let valid_peers = self.valid_peers.read();
+            let idle_peers = self.idle_peers.lock();
+            let peer_whitelist = self.peer_whitelist.read();
+            let idle = idle_peers
+                .idle(Instant::now())
+                .into_iter()
+                .filter(|&peer| valid_peers.contains(&peer) && !peer_whitelist.contains(peer))
+                .collect::<Vec<_>>();
+            (idle, idle_peers.min_messages())
+        };
 
-    if let Some(cidr_filter) = &opts.cidr {
-        info!("Peers restricted to range {}", cidr_filter);
+        for peer in idle {
+            info!(
+                "Disconnecting peer {} that has sent fewer than {} messages in {:?}",
+                peer, min_messages, IDLE_WINDOW
+            );
+            self.disconnect_peer(peer, DisconnectReason::UselessPeer)
+                .await;
+        }
     }
 
-    let mut discovery_tasks = StreamMap::new();
+    pub fn outbound_queue_depths(&self, now: Instant) -> Vec<(PeerId, QueueDepth)> {
+        let mut depths = Vec::with_capacity(self.peer_pipes.len());
+        self.peer_pipes
+            .scan(|&peer, pipes| depths.push((peer, pipes.monitor.depth(now))));
+        depths
+    }
 
-    if let Some(dnsdisc_opts) = opts.dnsdisc {
-        info!("Starting DNS discovery fetch from {}", dnsdisc_opts.address);
-        let dns_resolver = dnsdisc::Resolver::new(Arc::new(
-            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
-                .context("Failed to start DNS resolver")?,
-        ));
+    /// Fail sends waiting for the queue of a peer that does not read what is sent to it.
+    /// The swarm is to drop its connection then, which tears the peer down as usual.
+    /// Returns `false` if the peer is not connected.
+    pub fn on_stalled_peer(&self, peer: PeerId) -> bool {
+        let pipes = match self.get_pipes(peer) {
+            Some(v) => v,
+            None => return false,
+        };
 
-        discovery_tasks.insert(
-            "dnsdisc".to_string(),
-            Box::pin(DnsDiscovery::new(
-                Arc::new(dns_resolver),
-                dnsdisc_opts.address,
-                None,
-            )) as Discovery,
+        let depth = pipes.monitor.depth(Instant::now());
+        warn!(
+            "Peer {} has not read its outbound queue for {:?} with {} sends waiting, disconnecting",
+            peer, depth.blocked_for, depth.waiting
         );
+        self.metrics.stalled_peers.inc();
+        pipes.monitor.abort();
+        true
     }
 
-    if let Some(discv4_opts) = opts.discv4 {
-        info!("Starting discv4 at port {}", discv4_opts.port);
+    /// Disconnect the longest connected passive peer to make room for a peer being dialed.
+    pub async fn evict_passive_peer(
+        &self,
+        connected_peers: usize,
+        candidates_waiting: bool,
+    ) -> Option<PeerId> {
+        if self.passive_eviction == PassivePeerEviction::Off
+            || !candidates_waiting
+            || connected_peers <= self.evict_passive_above_peers
+        {
+            return None;
+        }
 
-        let bootstrap_nodes = discv4_opts
-            .bootnodes
-            .into_iter()
-            .map(|Dicv4NR(nr)| nr)
-            .collect::<Vec<_>>();
+        let peer = {
+            let valid_peers = self.valid_peers.read();
+            let peer_whitelist = self.peer_whitelist.read();
+            self.idle_peers
+                .lock()
+                .passive(self.passive_window, Instant::now())
+                .into_iter()
+                .find(|&peer| valid_peers.contains(&peer) && !peer_whitelist.contains(peer))?
+        };
 
-        if bootstrap_nodes.is_empty() {
-            warn!("discv4 cannot work without bootstrap nodes!");
+        if self.passive_eviction == PassivePeerEviction::Enforce {
+            info!("Disconnecting passive peer {} to make room", peer);
+            self.disconnect_peer(peer, DisconnectReason::UselessPeer)
+                .await;
+        } else if let Some(suppressed) = self.error_log_limiter.check((peer, "passive")) {
+            info!(
+                "Would disconnect passive peer {} to make room{}",
+                peer, suppressed
+            );
         }
-        discovery_tasks.insert(
-            "discv4".to_string(),
-            Box::pin(
-                Discv4Builder::default()
-                    .with_cache(discv4_opts.cache)
-                    .with_concurrent_lookups(discv4_opts.concurrent_lookups)
-                    .build(
-                        discv4::Node::new(
-                            format!("0.0.0.0:{}", discv4_opts.port).parse().unwrap(),
-                            secret_key,
-                            bootstrap_nodes,
-                            None,
-                            true,
-                            opts.listen_port,
-                        )
-                        .await
-                        .unwrap(),
-                    ),
-            ),
-        );
+
+        Some(peer)
     }
 
-    if let Some(discv5_opts) = opts.discv5 {
+    pub fn whitelisted_peers(&self) -> Vec<PeerId> {
+        self.peer_whitelist.read().peers()
+    }
+
+    /// Returns `false` if the peer is already whitelisted.
+    pub fn whitelist_peer(&self, peer: PeerId) -> bool {
+        self.peer_whitelist.write().add(peer)
+    }
+
+    /// Returns `false` if the peer was not whitelisted. In strict mode the peer is
+    /// disconnected.
+    pub async fn unwhitelist_peer(&self, peer: PeerId) -> bool {
+        let (removed, allowed) = {
+            let mut peer_whitelist = self.peer_whitelist.write();
+            (peer_whitelist.remove(peer), peer_whitelist.allows(peer))
+        };
+        if !allowed {
+            self.disconnect_peer(peer, DisconnectReason::UselessPeer)
+                .await;
+        }
+        removed
+    }
+
+    /// Returns number of peers disconnected.
+    pub async fn disconnect_all_peers(&self, reason: DisconnectReason) -> usize {
+        let peers = self.all_peers();
+        for &peer in &peers {
+            self.disconnect_peer(peer, reason).await;
+        }
+        peers.len()
+    }
+
+    /// Record block announcements broadcast by the control.
+    pub fn on_control_message(&self, id: usize, data: &[u8]) {
+        let number = match EthMessageId::from_usize(id) {
+            Some(EthMessageId::NewBlockHashes) => Rlp::new(data)
+                .iter()
+                .filter_map(|announce| announce.val_at::<u64>(1).ok())
+                .max(),
+            Some(EthMessageId::NewBlock) => Rlp::new(data)
+                .at(0)
+                .and_then(|block| block.at(0))
+                .and_then(|header| header.val_at::<u64>(8))
+                .ok(),
+            _ => None,
+        };
+
+        if let Some(number) = number {
+            self.head_announcer.lock().on_control_announce(number);
+        }
+    }
+
+    /// Announce our new best block to valid peers if the control has not done so.
+    /// Peers that already have the block are left out, and so are peers whose queue is full
+    /// rather than holding up the rest.
+    fn announce_head(&self) {
+        let (number, hash) = match self.head_announcer.lock().poll(Instant::now()) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let mut s = RlpStream::new_list(1);
+        s.begin_list(2);
+        s.append(&hash);
+        s.append(&number);
+        let data = s.out().freeze();
+
+        // Announcement that has already gone around is not sent again, and copies of ours
+        // coming back from peers are dropped as duplicates.
+        if let Some(duplicate_filter) = &self.duplicate_filter {
+            if duplicate_filter
+                .lock()
+                .check(EthMessageId::NewBlockHashes, &data, Instant::now())
+            {
+                debug!("Head #{} has been announced already", number);
+                return;
+            }
+        }
+
+        debug!("Announcing new head #{} ({:?}) to peers", number, hash);
+        let peers = {
+            let block_tracker = self.block_tracker.read();
+            let valid_peers = self.valid_peers.read();
+            valid_peers
+                .iter()
+                .copied()
+                .filter(|&peer| {
+                    block_tracker
+                        .block_number(peer)
+                        .map_or(true, |block| block < number)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut skipped = 0;
+        for peer in peers {
+            if let Some(sender) = self.sender(peer) {
+                let res = sender.try_send(OutboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::NewBlockHashes.to_usize().unwrap(),
+                        data: data.clone(),
+                    },
+                });
+                if res.is_err() {
+                    skipped += 1;
+                }
+            }
+        }
+        if skipped > 0 {
+            debug!("Head #{} not announced to {} busy peers", number, skipped);
+        }
+    }
+
+    /// Message from the control has been sent to `peer`, as framed on the wire. `directed`
+    /// messages are those the control has sent to this peer alone with SendMessageById.
+    pub fn on_message_sent(&self, peer: PeerId, id: usize, data: &[u8], directed: bool) {
+        match EthMessageId::from_usize(id) {
+            Some(EthMessageId::GetBlockHeaders) => {
+                self.on_headers_request_sent(peer, data, directed)
+            }
+            Some(EthMessageId::BlockHeaders) if directed => self.on_headers_reply_sent(peer, data),
+            Some(EthMessageId::GetPooledTransactions) => {
+                if let Some((_, hashes)) = self.decode_pooled_transactions_request(peer, data) {
+                    let mut pending = self.pending_tx_size_by_hash.lock();
+                    for hash in hashes {
+                        pending.remove(hash);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Ask the peer for the smallest announced transactions first, so that more of them
+    /// fit into its reply.
+    pub fn sort_transaction_fetch(&self, peer: PeerId, id: usize, data: Bytes) -> Bytes {
+        if EthMessageId::from_usize(id) != Some(EthMessageId::GetPooledTransactions) {
+            return data;
+        }
+        let (request_id, mut hashes) = match self.decode_pooled_transactions_request(peer, &data) {
+            Some(v) => v,
+            None => return data,
+        };
+        self.pending_tx_size_by_hash
+            .lock()
+            .sort_by_size(&mut hashes);
+        let payload = rlp::encode_list(&hashes);
+        match request_id {
+            Some(request_id) => wrap_request_id(request_id, &payload),
+            None => payload.freeze(),
+        }
+    }
+
+    fn decode_pooled_transactions_request(
+        &self,
+        peer: PeerId,
+        data: &[u8],
+    ) -> Option<(Option<u64>, Vec<H256>)> {
+        if self.peer_version(peer).unwrap_or_default() >= ETH_66 {
+            let (request_id, payload) = unwrap_request_id(data).ok()?;
+            Some((Some(request_id), Rlp::new(&payload).as_list().ok()?))
+        } else {
+            Some((None, Rlp::new(data).as_list().ok()?))
+        }
+    }
+
+    /// Only replies to requests the control has directed at the peer count towards its
+    /// response quality.
+    fn on_headers_request_sent(&self, peer: PeerId, data: &[u8], directed: bool) {
+        let (request_id, payload) = if self.peer_version(peer).unwrap_or_default() >= ETH_66 {
+            match unwrap_request_id(data) {
+                Ok((request_id, payload)) => (Some(request_id), payload),
+                Err(_) => return,
+            }
+        } else {
+            (None, Bytes::copy_from_slice(data))
+        };
+        let expected = if directed {
+            match rlp::decode::<GetBlockHeaders>(&payload) {
+                Ok(request) => {
+                    // Block 0 is what peers start with until their head is known.
+                    let peer_head = self
+                        .block_tracker
+                        .read()
+                        .block_number(peer)
+                        .filter(|&block| block > 0);
+                    Some(expected_headers(&request, peer_head))
+                }
+                Err(_) => return,
+            }
+        } else {
+            None
+        };
+        self.response_quality
+            .lock()
+            .on_request_sent(peer, request_id, expected, Instant::now());
+    }
+
+    /// Control has answered GetBlockHeaders forwarded from `peer`.
+    fn on_headers_reply_sent(&self, peer: PeerId, data: &[u8]) {
+        let now = Instant::now();
+        self.adaptive_headers.lock().on_response(peer, now);
+        if let Some((request_id, empty)) = header_misses::empty_reply(data) {
+            self.header_misses
+                .lock()
+                .on_reply(peer, request_id, empty, now);
+        }
+    }
+
+    /// Height up to which a GetBlockHeaders anchor missing from the control is expected.
+    fn header_miss_height(&self, peer: PeerId) -> u64 {
+        let best_block = self.best_block();
+        let peer_block = self.block_tracker.read().block_number(peer);
+        peer_block.unwrap_or_default().max(best_block + 1)
+    }
+
+    fn best_block(&self) -> u64 {
+        match &*self.status_message.read() {
+            Some(FullStatusData { status, .. }) => status.best_block,
+            None => 0,
+        }
+    }
+
+    /// Lower `max_headers` of GetBlockHeaders forwarded to the control while it is slow
+    /// to answer the peer. Requests that fail to decode are left to the control.
+    fn limit_headers_request(&self, peer: PeerId, data: Bytes, with_request_id: bool) -> Bytes {
+        let max_headers = self.adaptive_headers.lock().max_headers(peer);
+        let (request_id, payload) = if with_request_id {
+            match unwrap_request_id(&data) {
+                Ok((request_id, payload)) => (Some(request_id), payload),
+                Err(_) => return data,
+            }
+        } else {
+            (None, data.clone())
+        };
+
+        match rlp::decode::<GetBlockHeaders>(&payload) {
+            Ok(mut request) if request.max_headers > max_headers => {
+                trace!(
+                    "Limiting GetBlockHeaders from {} to {} headers",
+                    request.max_headers,
+                    max_headers
+                );
+                request.max_headers = max_headers;
+                let payload = rlp::encode(&request);
+                match request_id {
+                    Some(request_id) => wrap_request_id(request_id, &payload),
+                    None => payload.freeze(),
+                }
+            }
+            _ => data,
+        }
+    }
+
+    fn on_headers_response(&self, peer: PeerId, data: &[u8], with_request_id: bool) {
+        let (request_id, payload) = if with_request_id {
+            match unwrap_request_id(data) {
+                Ok((request_id, payload)) => (Some(request_id), payload),
+                Err(_) => return,
+            }
+        } else {
+            (None, Bytes::copy_from_slice(data))
+        };
+        let received = Rlp::new(&payload).item_count().unwrap_or(0);
+        if self
+            .response_quality
+            .lock()
+            .on_response(peer, request_id, received, Instant::now())
+        {
+            self.on_peer_demoted(peer);
+        }
+    }
+
+    fn expire_directed_requests(&self) {
+        let demoted = self.response_quality.lock().expire(Instant::now());
+        for peer in demoted {
+            self.on_peer_demoted(peer);
+        }
+    }
+
+    fn on_peer_demoted(&self, peer: PeerId) {
+        warn!(
+            "Peer {} keeps answering directed requests with nothing, excluding it from peer selection for a while",
+            peer
+        );
+    }
+
+    /// Drop peers that are cooling down after repeated empty or missing responses.
+    pub fn without_demoted(&self, peers: impl IntoIterator<Item = PeerId>) -> Vec<PeerId> {
+        let response_quality = self.response_quality.lock();
+        let now = Instant::now();
+        peers
+            .into_iter()
+            .filter(|&peer| !response_quality.is_demoted(peer, now))
+            .collect()
+    }
+
+    /// Update peer's block as reported by the control, promoting it if it has caught up.
+    pub fn set_peer_min_block(&self, peer: PeerId, block: u64) {
+        let block = {
+            let mut block_tracker = self.block_tracker.write();
+            block_tracker.set_block_number(peer, block, false);
+            block_tracker.block_number(peer)
+        };
+
+        let our_best_block = match &*self.status_message.read() {
+            Some(FullStatusData { status, .. }) => status.best_block,
+            None => return,
+        };
+
+        if let Some(block) = block {
+            if self.syncing_classifier.caught_up(block, our_best_block)
+                && self.syncing_peers.write().remove(&peer)
+            {
+                debug!("Peer {} has caught up at block {}", peer, block);
+            }
+        }
+    }
+
+    /// Drop peers that are still syncing or cooling down after bad responses.
+    pub fn selectable_peers(&self, peers: impl IntoIterator<Item = PeerId>) -> Vec<PeerId> {
+        let syncing_peers = self.syncing_peers.read();
+        self.without_demoted(
+            peers
+                .into_iter()
+                .filter(|peer| !syncing_peers.contains(peer)),
+        )
+    }
+
+    /// Reject inbound peers from autonomous systems that already have too many peers.
+    pub fn attach_asn_limiter(&self, asn_limiter: AsnLimiter) {
+        *self.asn_limiter.lock() = Some(asn_limiter);
+    }
+
+    /// Switch to crawl mode: peers are disconnected as soon as their Status is recorded,
+    /// and never become valid.
+    pub fn attach_crawler(&self, crawler: Arc<Crawler>) {
+        *self.crawler.write() = Some(crawler);
+    }
+
+    pub fn crawler(&self) -> Option<Arc<Crawler>> {
+        self.crawler.read().clone()
+    }
+
+    /// Record GetBlockHeaders of peers and our replies to them.
+    pub fn attach_peer_capture(&self, capture: Arc<PeerCapture>) {
+        *self.peer_capture.write() = Some(capture);
+    }
+
+    /// Answer GetBlockHeaders with recorded replies instead of asking the control.
+    pub fn attach_replay(&self, replay: Arc<ReplayDataProvider>) {
+        *self.replay.write() = Some(replay);
+    }
+
+    fn capture(&self, peer: PeerId, id: EthMessageId, data: &Bytes) {
+        if let Some(capture) = &*self.peer_capture.read() {
+            capture.record(
+                peer,
+                id.to_usize().unwrap(),
+                data.clone(),
+                self.wall_clock.now(),
+            );
+        }
+    }
+
+    pub fn attach_otlp_tracer(&self, tracer: opentelemetry::sdk::trace::Tracer) {
+        *self.otlp_tracer.write() = Some(tracer);
+    }
+
+    /// Tracer slot, read by the pipeline on every message so a tracer attached later is used.
+    pub fn otlp_tracer(&self) -> Arc<RwLock<Option<opentelemetry::sdk::trace::Tracer>>> {
+        self.otlp_tracer.clone()
+    }
+
+    /// Let capabilities be registered at runtime through the swarm's registry.
+    pub fn attach_capability_registry(&self, registry: CapabilityRegistry) {
+        *self.capability_registry.write() = Some(registry);
+    }
+
+    pub fn attach_redial_exemptions(&self, redial_exemptions: RedialExemptions) {
+        *self.redial_exemptions.write() = Some(redial_exemptions);
+    }
+
+    /// Advertise a new capability to peers connecting from now on and route its messages
+    /// to `handler`. Existing peers are not affected.
+    pub fn register_capability(
+        &self,
+        cap: CapabilityId,
+        message_count: usize,
+        handler: Box<dyn MessageRouter>,
+    ) -> anyhow::Result<()> {
+        if cap.name == capability_name() {
+            bail!("{} is handled by the sentry itself", cap.name);
+        }
+
+        let registry = self.capability_registry.read();
+        let registry = registry
+            .as_ref()
+            .ok_or_else(|| anyhow!("Capabilities cannot be registered before swarm start"))?;
+
+        self.capability_routers
+            .write()
+            .insert(cap, Arc::from(handler));
+        registry.register(cap, message_count);
+
+        info!("Registered capability {}", cap);
+
+        Ok(())
+    }
+
+    /// Make the peer re-handshake with our current Status, which can only be sent once per session.
+    /// Disconnects the peer and returns whether it has connected again within `deadline`.
+    ///
+    /// Until the deadline the peer is dialed regardless of redial delays and the dial cooldown.
+    /// Its reconnect starts a fresh session and does not count toward `max_reconnects_per_minute`.
+    pub async fn refresh_peer(&self, peer: PeerId, deadline: Duration) -> anyhow::Result<bool> {
+        let sender = self
+            .sender(peer)
+            .ok_or_else(|| anyhow!("peer {} is not connected", peer))?;
+
+        let (tx, rx) = oneshot::channel();
+        self.reconnect_waiters
+            .lock()
+            .entry(peer)
+            .or_default()
+            .push(tx);
+        self.reconnects.lock().on_refresh(peer);
+        if let Some(redial_exemptions) = &*self.redial_exemptions.read() {
+            redial_exemptions.exempt(peer, Instant::now() + deadline);
+        }
+
+        debug!("Refreshing peer {}", peer);
+        // Full queue would hold the Disconnect past the deadline.
+        let queued = sender.try_send(OutboundEvent::Disconnect {
+            reason: DisconnectReason::DisconnectRequested,
+        });
+        drop(sender);
+        if queued.is_err() {
+            drop(rx);
+            self.reconnects.lock().cancel_refresh(peer);
+            self.forget_reconnect_waiters(peer);
+            bail!("outbound queue of peer {} is full", peer);
+        }
+
+        let reconnected = matches!(tokio::time::timeout(deadline, rx).await, Ok(Ok(())));
+        if !reconnected {
+            self.forget_reconnect_waiters(peer);
+        }
+
+        Ok(reconnected)
+    }
+
+    /// Drop waiters of `refresh_peer` calls that have given up.
+    fn forget_reconnect_waiters(&self, peer: PeerId) {
+        let mut reconnect_waiters = self.reconnect_waiters.lock();
+        if let Some(waiters) = reconnect_waiters.get_mut(&peer) {
+            waiters.retain(|waiter| !waiter.is_closed());
+            if waiters.is_empty() {
+                reconnect_waiters.remove(&peer);
+            }
+        }
+    }
+
+    /// Stop advertising the capability and disconnect all peers using it.
+    pub async fn unregister_capability(&self, cap: CapabilityId) -> anyhow::Result<()> {
+        let unregistered = self
+            .capability_registry
+            .read()
+            .as_ref()
+            .map(|registry| registry.unregister(cap))
+            .unwrap_or(false);
+        if !unregistered {
+            bail!("Capability {} is not registered", cap);
+        }
+
+        let peers = self.capability_routers.write().remove(cap);
+
+        info!(
+            "Unregistered capability {}, disconnecting {} peers using it",
+            cap,
+            peers.len()
+        );
+
+        for peer in peers {
+            if let Some(sender) = self.sender(peer) {
+                let _ = sender
+                    .send(OutboundEvent::Disconnect {
+                        reason: DisconnectReason::SubprotocolSpecific,
+                    })
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn route_capability_message(&self, peer: PeerId, cap: CapabilityName, message: Message) {
+        let router = match self.capability_routers.read().get(peer, cap) {
+            Some(v) => v,
+            None => {
+                debug!("No router for capability {}, dropping message", cap);
+                return;
+            }
+        };
+
+        if let Some(reply) = router.on_message(peer, message).await {
+            if let Some(sender) = self.sender(peer) {
+                let _ = sender
+                    .send(OutboundEvent::Message {
+                        capability_name: cap,
+                        message: reply,
+                    })
+                    .await;
+            }
+        }
+    }
+
+    /// Last `last_n` messages exchanged with the peer, oldest first.
+    /// Available for a while after the peer has disconnected.
+    pub fn peer_message_log(&self, peer: PeerId, last_n: usize) -> Vec<PeerMessageLogEntry> {
+        self.message_log.lock().get(peer, last_n, Instant::now())
+    }
+
+    /// Peers we have disconnected for breaching the protocol, newest first.
+    pub fn breach_records(&self) -> Vec<BreachRecord> {
+        self.breach_log.lock().records()
+    }
+
+    /// Keep full payloads of messages that made us disconnect the peer.
+    pub fn attach_breach_dumps(&self, dumps: Arc<BreachDumps>) {
+        *self.breach_dumps.write() = Some(dumps);
+    }
+
+    fn on_protocol_breach(
+        &self,
+        peer: PeerId,
+        reason: DisconnectReason,
+        offending: Option<Message>,
+    ) {
+        let now = self.wall_clock.now();
+        let dump = match (&*self.breach_dumps.read(), offending) {
+            (Some(dumps), Some(message)) => dumps.dump(peer, message.id, message.data, now),
+            _ => None,
+        };
+        self.breach_log.lock().on_breach(peer, reason, dump, now);
+        self.ban_peer(peer);
+    }
+
+    /// Share bans with sibling sentries. Until then peers are never banned.
+    pub fn attach_sibling_gossip(&self, gossip: SiblingGossip) {
+        *self.sibling_gossip.write() = Some(gossip);
+    }
+
+    /// Ban the peer for the configured time and share the ban with siblings.
+    pub fn ban_peer(&self, peer: PeerId) {
+        let gossip = match &*self.sibling_gossip.read() {
+            Some(gossip) => gossip.clone(),
+            None => return,
+        };
+        if self
+            .bans
+            .lock()
+            .insert(peer, self.ban_ttl, BanProvenance::Local, Instant::now())
+        {
+            self.peer_timers
+                .schedule(TimedPeerAction::RemoveFromBanList(peer), self.ban_ttl);
+            debug!("Banned peer {} for {:?}", peer, self.ban_ttl);
+            gossip.push(peer, BanUpdate::Add(self.ban_ttl));
+            self.bans_changed.notify_one();
+        }
+    }
+
+    /// Lift the ban of the peer here and at siblings. Returns whether it has been banned.
+    pub fn unban_peer(&self, peer: PeerId) -> bool {
+        let removed = self.bans.lock().remove(peer, BanProvenance::Local);
+        if let Some(gossip) = &*self.sibling_gossip.read() {
+            gossip.push(peer, BanUpdate::Remove);
+        }
+        if removed {
+            self.bans_changed.notify_one();
+        }
+        removed
+    }
+
+    /// Restore local bans saved by a previous run.
+    pub fn load_bans_file(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.bans_file {
+            let bans_file = persistence::load::<BansFile>(path)?;
+            let now = Instant::now();
+            let mut bans = self.bans.lock();
+            for (peer, left) in bans_file.bans(self.wall_clock.now()) {
+                // File may have been written with a longer ban time, or tampered with.
+                let left = left.min(self.ban_ttl);
+                if bans.insert(peer, left, BanProvenance::Local, now) {
+                    self.peer_timers
+                        .schedule(TimedPeerAction::RemoveFromBanList(peer), left);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notified when the bans file is due to be saved.
+    pub fn bans_changed(&self) -> Arc<Notify> {
+        self.bans_changed.clone()
+    }
+
+    pub async fn save_bans_file(&self) {
+        if let Some(path) = self.bans_file.clone() {
+            let now = Instant::now();
+            let wall_now = self.wall_clock.now();
+            let bans = self
+                .bans
+                .lock()
+                .bans(now)
+                .into_iter()
+                .filter(|(_, ban)| ban.provenance == BanProvenance::Local)
+                .map(|(peer, ban)| (peer, wall_now + ban.until.saturating_duration_since(now)))
+                .collect::<Vec<_>>();
+            let res =
+                tokio::task::spawn_blocking(move || persistence::save(&BansFile::new(bans), &path))
+                    .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Failed to save bans file: {:?}", e),
+                Err(e) => warn!("Failed to save bans file: {}", e),
+            }
+        }
+    }
+
+    /// Apply ban update from a sibling. Returns whether the peer should be disconnected.
+    pub fn on_sibling_ban(&self, peer: PeerId, ban: SiblingBan) -> bool {
+        match &*self.sibling_gossip.read() {
+            Some(gossip) if gossip.origin() != ban.origin => {}
+            Some(_) => {
+                debug!("Dropping our own ban of {} sent back by a sibling", peer);
+                return false;
+            }
+            None => {
+                debug!(
+                    "Ban sharing is disabled, ignoring ban of {} from {}",
+                    peer, ban.origin
+                );
+                return false;
+            }
+        }
+
+        let mut bans = self.bans.lock();
+        match ban.update {
+            BanUpdate::Add(ttl) => {
+                let ttl = ttl.min(self.remote_ban_ttl);
+                if bans.insert(peer, ttl, BanProvenance::Remote, Instant::now()) {
+                    self.peer_timers
+                        .schedule(TimedPeerAction::RemoveFromBanList(peer), ttl);
+                    debug!(
+                        "Peer {} banned by sibling {} for {:?}",
+                        peer, ban.origin, ttl
+                    );
+                }
+                true
+            }
+            BanUpdate::Remove => {
+                if bans.remove(peer, BanProvenance::Remote) {
+                    debug!("Peer {} unbanned by sibling {}", peer, ban.origin);
+                }
+                false
+            }
+        }
+    }
+
+    /// Peers currently banned, with the time left and where the ban comes from.
+    pub fn banned_peers(&self) -> Vec<(PeerId, Duration, BanProvenance)> {
+        let now = Instant::now();
+        self.bans
+            .lock()
+            .bans(now)
+            .into_iter()
+            .map(|(peer, ban)| {
+                (
+                    peer,
+                    ban.until.saturating_duration_since(now),
+                    ban.provenance,
+                )
+            })
+            .collect()
+    }
+
+    /// Number of times each peer has reconnected right after disconnecting over the last minute.
+    pub fn reconnect_rates(&self) -> HashMap<PeerId, usize> {
+        self.reconnects.lock().reconnect_rates(Instant::now())
+    }
+
+    /// Live background tasks, oldest first.
+    pub fn debug_tasks(&self) -> Vec<TaskInfo> {
+        self.tasks.snapshot()
+    }
+
+    /// Nodes in the discv4 routing table, empty if discv4 is disabled.
+    pub fn dump_routing_table(&self) -> Vec<discv4::TableEntry> {
+        self.discv4
+            .as_ref()
+            .map(|node| node.table_entries())
+            .unwrap_or_default()
+    }
+
+    /// Peers that are waiting for the same reply the control has just sent to `peer`,
+    /// along with the reply for each of them.
+    pub fn take_request_waiters(
+        &self,
+        peer: PeerId,
+        response: EthMessageId,
+        data: &Bytes,
+    ) -> Vec<(PeerId, Bytes)> {
+        self.request_coalescer
+            .lock()
+            .on_response(peer, response, data)
+    }
+
+    /// Feed recent headers from the control into the header cache.
+    /// Returns `false` if the cache is disabled because data is not served.
+    pub fn push_headers(&self, headers: impl IntoIterator<Item = (u64, H256, Bytes)>) -> bool {
+        let mut header_cache = match &self.header_cache {
+            Some(header_cache) => header_cache.write(),
+            None => return false,
+        };
+        for (number, hash, rlp) in headers {
+            header_cache.insert(number, hash, rlp);
+        }
+        true
+    }
+
+    fn on_traffic(&self, inbound: bool, bytes: usize) {
+        let change = {
+            let mut budget = self.bandwidth_budget.lock();
+            if !budget.is_limited() {
+                return;
+            }
+            if inbound {
+                budget.on_ingress(bytes, Instant::now())
+            } else {
+                budget.on_egress(bytes, Instant::now())
+            }
+        };
+
+        match change {
+            Some(BudgetState::NearlyExhausted) => warn!(
+                "Bandwidth budget is nearly exhausted, no longer serving bulk data and reducing gossip"
+            ),
+            Some(BudgetState::Exhausted) => warn!("Bandwidth budget is exhausted"),
+            Some(BudgetState::Normal) => info!("Bandwidth budget has been reset"),
+            None => {}
+        }
+    }
+
+    pub fn bandwidth_state(&self) -> BudgetState {
+        self.bandwidth_budget.lock().state(Instant::now())
+    }
+
+    /// Relay gossip to fewer peers when bandwidth budget is running low.
+    pub fn gossip_targets(&self, peers: impl IntoIterator<Item = PeerId>) -> Vec<PeerId> {
+        let mut peers = peers.into_iter().collect::<Vec<_>>();
+        if self.bandwidth_state() >= BudgetState::NearlyExhausted {
+            let n = (peers.len() as f64).sqrt().ceil() as usize;
+            peers.truncate(n);
+        }
+        peers
+    }
+
+    /// Response to a bulk data request that carries no data.
+    fn empty_response(&self, peer: PeerId, request: EthMessageId, data: &[u8]) -> Option<Message> {
+        let id = match request {
+            EthMessageId::GetBlockHeaders => EthMessageId::BlockHeaders,
+            EthMessageId::GetBlockBodies => EthMessageId::BlockBodies,
+            EthMessageId::GetNodeData => EthMessageId::NodeData,
+            EthMessageId::GetReceipts => EthMessageId::Receipts,
+            EthMessageId::GetPooledTransactions => EthMessageId::PooledTransactions,
+            _ => return None,
+        };
+
+        let data = if self.peer_version(peer).unwrap_or_default() >= ETH_66 {
+            let request_id = Rlp::new(data).val_at::<u64>(0).ok()?;
+            let mut s = RlpStream::new_list(2);
+            s.append(&request_id);
+            s.begin_list(0);
+            s.out().freeze()
+        } else {
+            Bytes::from_static(&rlp::EMPTY_LIST_RLP)
+        };
+
+        Some(Message {
+            id: id.to_usize().unwrap(),
+            data,
+        })
+    }
+
+    /// Try to answer GetBlockHeaders from header cache.
+    fn serve_headers_from_cache(&self, data: &[u8]) -> Option<Message> {
+        let request = rlp::decode::<GetBlockHeaders>(data).ok()?;
+        let headers = self.header_cache.as_ref()?.read().resolve(&request)?;
+
+        trace!("Serving {} headers from cache", headers.len());
+
+        Some(Message {
+            id: EthMessageId::BlockHeaders.to_usize().unwrap(),
+            data: encode_raw_list(&headers),
+        })
+    }
+
+    /// Try to answer GetBlockHeaders with a recorded reply.
+    fn serve_headers_from_replay(&self, peer: PeerId, data: &[u8]) -> Option<Message> {
+        let replay = self.replay.read().clone()?;
+        let version = self.peer_version(peer).unwrap_or_default();
+        let (request_id, request) = if version >= ETH_66 {
+            unwrap_request_id(data).ok()?
+        } else {
+            (0, Bytes::copy_from_slice(data))
+        };
+        let headers = replay.block_headers(&request)?;
+
+        trace!("Serving recorded headers");
+
+        Some(Message {
+            id: EthMessageId::BlockHeaders.to_usize().unwrap(),
+            data: frame_message(EthMessageId::BlockHeaders, version, request_id, headers),
+        })
+    }
+
+    /// Answer GetPooledTransactions with the transactions found in the pool, which may
+    /// be none. The control is not asked for the others.
+    fn serve_pooled_transactions(
+        &self,
+        peer: PeerId,
+        data: &[u8],
+    ) -> Result<Option<Message>, DisconnectReason> {
+        let tx_pool = match &self.tx_pool {
+            Some(tx_pool) => tx_pool,
+            None => return Ok(None),
+        };
+
+        let version = self.peer_version(peer).unwrap_or_default();
+        let decoded = if version >= ETH_66 {
+            unwrap_request_id(data).and_then(|(request_id, payload)| {
+                Ok((request_id, Rlp::new(&payload).as_list::<H256>()?))
+            })
+        } else {
+            Rlp::new(data).as_list::<H256>().map(|hashes| (0, hashes))
+        };
+        let (request_id, hashes) = match decoded {
+            Ok(v) => v,
+            Err(e) => {
+                self.on_bad_rlp(peer, EthMessageId::GetPooledTransactions, e)?;
+                return Ok(None);
+            }
+        };
+
+        let txs = tx_pool
+            .lock()
+            .get(&hashes, Instant::now())
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        trace!(
+            "Serving {} of {} pooled transactions from pool",
+            txs.len(),
+            hashes.len()
+        );
+
+        Ok(Some(Message {
+            id: EthMessageId::PooledTransactions.to_usize().unwrap(),
+            data: frame_message(
+                EthMessageId::PooledTransactions,
+                version,
+                request_id,
+                encode_raw_list(&txs),
+            ),
+        }))
+    }
+
+    /// Keep transactions we send to peers, to serve them from the pool later.
+    fn on_transactions_sent(&self, peer: PeerId, message: &Message) {
+        let tx_pool = match &self.tx_pool {
+            Some(tx_pool) => tx_pool,
+            None => return,
+        };
+
+        let data = match EthMessageId::from_usize(message.id) {
+            Some(EthMessageId::Transactions) => message.data.clone(),
+            Some(EthMessageId::PooledTransactions)
+                if self.peer_version(peer).unwrap_or_default() >= ETH_66 =>
+            {
+                match unwrap_request_id(&message.data) {
+                    Ok((_, payload)) => payload,
+                    Err(_) => return,
+                }
+            }
+            Some(EthMessageId::PooledTransactions) => message.data.clone(),
+            _ => return,
+        };
+
+        if let Err(e) = tx_pool.lock().insert_list(&data, Instant::now()) {
+            debug!("Not keeping transactions sent to {}: {}", peer, e);
+        }
+    }
+
+    /// Count headers and bodies of a response sent to `peer`, as sent on the wire.
+    pub fn on_served(&self, peer: PeerId, message: &Message, source: ServedSource) {
+        let kind = match EthMessageId::from_usize(message.id) {
+            Some(EthMessageId::BlockHeaders) => {
+                self.capture(peer, EthMessageId::BlockHeaders, &message.data);
+                ServedKind::Headers
+            }
+            Some(EthMessageId::BlockBodies) => ServedKind::Bodies,
+            _ => return,
+        };
+
+        let items = if self.peer_version(peer).unwrap_or_default() >= ETH_66 {
+            unwrap_request_id(&message.data)
+                .and_then(|(_, payload)| Rlp::new(&payload).item_count())
+        } else {
+            Rlp::new(&message.data).item_count()
+        };
+        let count = ServedCount {
+            items: items.unwrap_or_default() as u64,
+            bytes: message.data.len() as u64,
+        };
+
+        self.served.lock().record(peer, kind, source, count);
+        self.metrics.observe_served(kind, source, count);
+    }
+
+    pub fn served_total(&self, kind: ServedKind, source: ServedSource) -> ServedCount {
+        self.served.lock().total(kind, source)
+    }
+
+    pub fn top_consumers(&self, limit: usize) -> Vec<(PeerId, ServedCount)> {
+        self.served.lock().top_consumers(limit)
+    }
+
+    /// Lifetimes of recently closed connections in the direction.
+    pub fn connection_lifetimes(&self, direction: ConnectionDirection) -> DirectionSummary {
+        self.connection_lifetimes.lock().summary(direction)
+    }
+
+    /// Current state of the peer table.
+    pub fn peer_snapshot(&self) -> PeerSnapshot {
+        let block_tracker = self.block_tracker.read();
+        let valid_peers = self.valid_peers.read();
+        let protocol_version_by_peer = self.protocol_version_by_peer.read();
+        let peer_labels = self.peer_labels.read();
+        let syncing_peers = self.syncing_peers.read();
+        let idle_peers = self.idle_peers.lock();
+        let now = Instant::now();
+
+        protocol_version_by_peer
+            .iter()
+            .map(|(&id, &protocol_version)| {
+                (
+                    id,
+                    PeerRecord {
+                        id,
+                        protocol_version,
+                        valid: valid_peers.contains(&id),
+                        syncing: syncing_peers.contains(&id),
+                        passive: valid_peers.contains(&id)
+                            && idle_peers.is_passive(id, self.passive_window, now),
+                        min_block: block_tracker.block_number(id).unwrap_or_default(),
+                        labels: peer_labels.get(id),
+                        connection_id: self.connection_id(id),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Stream of full peer table followed by its diffs.
+    pub fn watch_peers(&self) -> BoxStream<'static, WatchUpdate> {
+        self.peer_watch.subscribe()
+    }
+
+    pub fn peer_churn_rate(&self) -> ChurnRate {
+        self.churn_tracker.lock().rate_per_minute(Instant::now())
+    }
+
+    fn peer_version(&self, peer: PeerId) -> Option<u8> {
+        self.protocol_version_by_peer.read().get(&peer).copied()
+    }
+
+    /// Check that message from the control is structurally valid for the peer's eth version,
+    /// so that the peer does not disconnect us over a bug in the control.
+    pub fn validate_outbound_message(
+        &self,
+        peer: PeerId,
+        id: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        if !self.validate_outbound {
+            return Ok(());
+        }
+
+        let id =
+            EthMessageId::from_usize(id).ok_or_else(|| anyhow!("unknown message id {}", id))?;
+        // Peer that is gone will not receive the message anyway.
+        match self.peer_version(peer) {
+            Some(version) if self.normalize_request_ids => validate_message(
+                id,
+                version,
+                &frame_message(id, version, 0, Bytes::copy_from_slice(data)),
+            ),
+            Some(version) => validate_message(id, version, data),
+            None => Ok(()),
+        }
+    }
+
+    /// Add request ID to the message from the control if the peer expects one.
+    /// Returns `None` if the message is a response and the peer has no request to answer.
+    pub fn frame_outbound_message(&self, peer: PeerId, id: usize, data: Bytes) -> Option<Bytes> {
+        if !self.normalize_request_ids {
+            return Some(data);
+        }
+
+        let (id, version) = match (EthMessageId::from_usize(id), self.peer_version(peer)) {
+            (Some(id), Some(version)) if version >= ETH_66 && id.has_request_id() => (id, version),
+            _ => return Some(data),
+        };
+
+        let mut request_ids = self.request_ids.lock();
+        let now = Instant::now();
+        let request_id = if let Some(response) = id.response() {
+            request_ids.on_outbound_request(peer, response, now)
+        } else {
+            match request_ids.take_response_id(peer, id, now) {
+                Some(request_id) => request_id,
+                None => {
+                    debug!("Peer {} has no pending request answered by {:?}", peer, id);
+                    return None;
+                }
+            }
+        };
+
+        Some(frame_message(id, version, request_id, data))
+    }
+
+    /// Message from the peer could not be decoded. Returns `Err` if the peer should be
+    /// disconnected according to the policy for the message.
+    fn on_bad_rlp(
+        &self,
+        peer: PeerId,
+        id: EthMessageId,
+        e: rlp::DecoderError,
+    ) -> Result<(), DisconnectReason> {
+        let action = self
+            .disconnect_policies
+            .get(id.to_usize().unwrap())
+            .on_bad_rlp;
+        if let Some(suppressed) = self.error_log_limiter.check((peer, "bad rlp")) {
+            debug!(
+                "Failed to decode {:?} message: {}! {}{}",
+                id,
+                e,
+                match action {
+                    DisconnectAction::Disconnect => "Kicking peer.",
+                    DisconnectAction::Skip => "Skipping message.",
+                },
+                suppressed
+            );
+        }
+
+        match action {
+            DisconnectAction::Disconnect => Err(DisconnectReason::ProtocolBreach),
+            DisconnectAction::Skip => Ok(()),
+        }
+    }
+
+    /// Message with unknown ID or received before Status exchange.
+    fn on_unknown_message(&self, id: usize) -> Result<(), DisconnectReason> {
+        match self.disconnect_policies.get(id).on_unknown_message {
+            DisconnectAction::Disconnect => {
+                debug!("Unexpected message {}, kicking peer", id);
+                Err(DisconnectReason::ProtocolBreach)
+            }
+            DisconnectAction::Skip => {
+                debug!("Unexpected message {}", id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Record sizes of transactions announced by eth/68 peer.
+    fn on_pooled_transaction_hashes(
+        &self,
+        peer: PeerId,
+        data: &[u8],
+    ) -> Result<(), DisconnectReason> {
+        if self.peer_version(peer).unwrap_or_default() < ETH_68 {
+            return Ok(());
+        }
+
+        let msg = match rlp::decode::<NewPooledTransactionHashes68>(data) {
+            Ok(v) => v,
+            Err(e) => return self.on_bad_rlp(peer, EthMessageId::NewPooledTransactionHashes, e),
+        };
+
+        let mut pending = self.pending_tx_size_by_hash.lock();
+        for (_, size, hash) in msg.announcements() {
+            pending.insert(peer, hash, size);
+        }
+
+        Ok(())
+    }
+
+    /// Announced size of a transaction that has not been fetched yet.
+    pub fn pending_tx_size(&self, hash: H256) -> Option<u32> {
+        self.pending_tx_size_by_hash.lock().get(hash)
+    }
+
+    /// Number of peers by negotiated eth protocol version.
+    pub fn peers_by_protocol_version(&self) -> HashMap<u8, usize> {
+        let mut peers = HashMap::new();
+        for &version in self.protocol_version_by_peer.read().values() {
+            *peers.entry(version).or_default() += 1;
+        }
+        peers
+    }
+
+    #[instrument(skip(self), fields(connection_id = tracing::field::Empty))]
+    async fn handle_event(
+        &self,
+        peer: PeerId,
+        event: InboundEvent,
+    ) -> Result<Option<Message>, DisconnectReason> {
+        self.record_connection_id(peer);
+        let mut pipeline = self.pipeline.clone().expect("pipeline is built in new");
+        pipeline
+            .ready()
+            .await?
+            .call(PeerEvent::new(peer, event))
+            .await
+    }
+
+    /// Account for eth message from `peer` and check it before dispatch.
+    /// Returns the payload, or `None` if the message is to be dropped.
+    fn verify_inbound(
+        &self,
+        peer: PeerId,
+        id: usize,
+        data: Bytes,
+    ) -> Result<Option<Bytes>, DisconnectReason> {
+        self.metrics.observe_inbound_message(id, data.len());
+        self.on_traffic(true, data.len());
+        self.message_log
+            .lock()
+            .record(peer, Direction::In, id, data.len(), Instant::now());
+        self.breach_log.lock().record(peer, id, &data);
+        let message_id = EthMessageId::from_usize(id);
+        self.idle_peers.lock().on_message(
+            peer,
+            message_id == Some(EthMessageId::Status),
+            Instant::now(),
+        );
+
+        let data = match &self.message_signer {
+            Some(signer) => match signer.verify(peer, id, &data) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Dropping message {} from {}: {}", id, peer, e);
+                    return Ok(None);
+                }
+            },
+            None => data,
+        };
+
+        if let (Some(message_id), Some(version)) = (message_id, self.peer_version(peer)) {
+            if message_id.to_wire(version.into()).is_none() {
+                debug!(
+                    "Peer {} sent {:?}, which does not exist in eth/{}",
+                    peer, message_id, version
+                );
+                return Err(DisconnectReason::ProtocolBreach);
+            }
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Handle disconnect or eth message that has passed `verify_inbound`.
+    async fn dispatch_event(
+        &self,
+        peer: PeerId,
+        event: InboundEvent,
+        received: Stamp,
+        forwarded: &AtomicBool,
+    ) -> Result<Option<Message>, DisconnectReason> {
+        match event {
+            InboundEvent::Disconnect { reason } => {
+                debug!("Peer disconnect (reason: {:?}), tearing down peer.", reason);
+                let mismatch = self
+                    .fork_health
+                    .lock()
+                    .on_disconnect(peer, reason, Instant::now());
+                self.on_fork_health_change(mismatch);
+                self.teardown_peer(peer, reason);
+            }
+            InboundEvent::Message {
+                message: Message { id, data },
+                ..
+            } => {
+                let message_id = EthMessageId::from_usize(id);
+                let valid_peer = self.valid_peers.read().contains(&peer);
+                match message_id {
+                    None => {
+                        self.on_unknown_message(id)?;
+                    }
+                    Some(EthMessageId::Status) => {
+                        let version = self.peer_version(peer).unwrap_or_default();
+                        let (v, fork_id) = match decode_status(version, &data) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                self.fork_health.lock().forget(peer);
+                                self.on_bad_rlp(peer, EthMessageId::Status, e)?;
+                                return Ok(None);
+                            }
+                        };
+
+                        debug!("Decoded status message: {:?}, fork ID {:?}", v, fork_id);
+
+                        if let Some(crawler) = self.crawler() {
+                            crawler.on_status(
+                                peer,
+                                &v,
+                                fork_id,
+                                Instant::now(),
+                                self.wall_clock.now(),
+                            );
+                            return Err(DisconnectReason::DisconnectRequested);
+                        }
+
+                        let status_data = self.status_message.read();
+                        let mut valid_peers = self.valid_peers.write();
+                        if let Some(FullStatusData {
+                            status,
+                            fork_filter,
+                        }) = &*status_data
+                        {
+                            match fork_id {
+                                Some(fork_id) => {
+                                    let res = fork_filter.validate(fork_id);
+                                    let mismatch = self.fork_health.lock().on_status_received(
+                                        peer,
+                                        matches!(
+                                            res,
+                                            Err(ValidationError::LocalIncompatibleOrStale { .. })
+                                        ),
+                                    );
+                                    self.on_fork_health_change(mismatch);
+
+                                    res.map_err(|reason| {
+                                        if let Some(suppressed) =
+                                            self.error_log_limiter.check((peer, "fork id"))
+                                        {
+                                            debug!(
+                                                "Kicking peer with incompatible fork ID: {:?}{}",
+                                                reason, suppressed
+                                            );
+                                        }
+
+                                        DisconnectReason::UselessPeer
+                                    })?;
+                                }
+                                // eth/63 has no fork ID, the genesis is all we can check.
+                                None => {
+                                    self.fork_health.lock().forget(peer);
+                                    if v.genesis_hash != status.fork_data.genesis {
+                                        debug!(
+                                            "Kicking eth/63 peer with genesis {}",
+                                            v.genesis_hash
+                                        );
+                                        return Err(DisconnectReason::UselessPeer);
+                                    }
+                                }
+                            }
+
+                            if valid_peers.insert(peer) {
+                                self.valid_peer_count.fetch_add(1, Ordering::Relaxed);
+                            }
+
+                            if v.best_hash != status.best_hash
+                                && self
+                                    .syncing_classifier
+                                    .is_syncing(v.total_difficulty, status.total_difficulty)
+                            {
+                                debug!(
+                                    "Peer is syncing: total difficulty {} vs ours {}",
+                                    v.total_difficulty, status.total_difficulty
+                                );
+                                self.syncing_peers.write().insert(peer);
+                            }
+                        }
+                    }
+                    Some(inbound_id) if valid_peer => {
+                        // Requests and responses of eth/66+ carry request IDs, which
+                        // the cache does not handle.
+                        let without_request_ids =
+                            self.peer_version(peer).unwrap_or_default() < ETH_66;
+
+                        if let EthMessageId::GetBlockHeaders = inbound_id {
+                            self.capture(peer, inbound_id, &data);
+                            if let Some(reply) = self.serve_headers_from_replay(peer, &data) {
+                                return Ok(Some(reply));
+                            }
+                        }
+
+                        if !self.serve_data {
+                            if let Some(reply) = self.empty_response(peer, inbound_id, &data) {
+                                trace!("Answering {:?} with no data in read-only mode", inbound_id);
+                                return Ok(Some(reply));
+                            }
+                        }
+
+                        if self.bandwidth_state() >= BudgetState::NearlyExhausted {
+                            if let Some(reply) = self.empty_response(peer, inbound_id, &data) {
+                                trace!("Answering {:?} with no data to save bandwidth", inbound_id);
+                                return Ok(Some(reply));
+                            }
+                        }
+
+                        if let EthMessageId::GetPooledTransactions = inbound_id {
+                            return self.serve_pooled_transactions(peer, &data);
+                        }
+
+                        let header_anchor = match inbound_id {
+                            EthMessageId::GetBlockHeaders => {
+                                hash_anchor(&data, !without_request_ids)
+                            }
+                            _ => None,
+                        };
+                        if let Some((_, hash)) = header_anchor {
+                            if self.header_misses.lock().is_miss(
+                                hash,
+                                self.best_block(),
+                                Instant::now(),
+                            ) {
+                                if let Some(reply) = self.empty_response(peer, inbound_id, &data) {
+                                    trace!(
+                                        "Answering GetBlockHeaders for {} known to be missing",
+                                        hash
+                                    );
+                                    self.metrics.header_miss_cache_hits.inc();
+                                    return Ok(Some(reply));
+                                }
+                            }
+                        }
+
+                        if let EthMessageId::BlockHeaders = inbound_id {
+                            self.on_headers_response(peer, &data, !without_request_ids);
+                        }
+
+                        if without_request_ids {
+                            if let EthMessageId::GetBlockHeaders = inbound_id {
+                                if let Some(reply) = self.serve_headers_from_cache(&data) {
+                                    self.on_served(peer, &reply, ServedSource::Cache);
+                                    return Ok(Some(reply));
+                                }
+                            }
+                        }
+
+                        if let EthMessageId::NewPooledTransactionHashes = inbound_id {
+                            self.on_pooled_transaction_hashes(peer, &data)?;
+                        }
+
+                        let data = if self.normalize_request_ids
+                            && !without_request_ids
+                            && inbound_id.has_request_id()
+                        {
+                            match unwrap_request_id(&data) {
+                                Ok((request_id, payload)) => {
+                                    let now = Instant::now();
+                                    match inbound_id.response() {
+                                        Some(response) => self
+                                            .request_ids
+                                            .lock()
+                                            .on_request(peer, response, request_id, now),
+                                        None => {
+                                            if !self
+                                                .request_ids
+                                                .lock()
+                                                .on_response(peer, inbound_id, request_id, now)
+                                            {
+                                                debug!(
+                                                    "Dropping {:?} from {} that answers no request of ours",
+                                                    inbound_id, peer
+                                                );
+                                                return Ok(None);
+                                            }
+                                        }
+                                    }
+                                    payload
+                                }
+                                Err(e) => {
+                                    self.on_bad_rlp(peer, inbound_id, e)?;
+                                    return Ok(None);
+                                }
+                            }
+                        } else {
+                            data
+                        };
+
+                        let with_request_id = !without_request_ids && !self.normalize_request_ids;
+
+                        if RequestCoalescer::is_coalescable(inbound_id)
+                            && !self.request_coalescer.lock().on_request(
+                                peer,
+                                inbound_id,
+                                data.clone(),
+                                with_request_id,
+                                Instant::now(),
+                            )
+                        {
+                            trace!("Coalesced {:?} with identical pending request", inbound_id);
+                            self.metrics.coalesced_requests.inc();
+                            return Ok(None);
+                        }
+
+                        let data = if let EthMessageId::GetBlockHeaders = inbound_id {
+                            self.limit_headers_request(peer, data, with_request_id)
+                        } else {
+                            data
+                        };
+
+                        if let EthMessageId::NewBlockHashes | EthMessageId::NewBlock = inbound_id {
+                            if let Some(duplicate_filter) = &self.duplicate_filter {
+                                if duplicate_filter
+                                    .lock()
+                                    .check(inbound_id, &data, Instant::now())
+                                {
+                                    trace!(
+                                        "Dropping {:?} already received from other peers",
+                                        inbound_id
+                                    );
+                                    self.metrics.observe_duplicate_filtered(inbound_id);
+                                    return Ok(None);
+                                }
+                            }
+                        }
+
+                        if let Some((queue, message_id)) = control_route(inbound_id) {
+                            let sender = match queue {
+                                ControlQueue::Data => &self.data_sender,
+                                ControlQueue::UploadRequests => &self.upload_requests_sender,
+                            };
+
+                            if data.len() > self.large_message_threshold {
+                                self.metrics.large_forwarded_messages.inc();
+                                if let Some(suppressed) =
+                                    self.error_log_limiter.check((peer, "large message"))
+                                {
+                                    warn!(
+                                        "Forwarding {} bytes {:?} from {} to control{}",
+                                        data.len(),
+                                        inbound_id,
+                                        peer,
+                                        suppressed
+                                    );
+                                }
+                            }
+
+                            if sender
+                                .send(
+                                    InboundMessage {
+                                        id: message_id as i32,
+                                        data,
+                                        peer_id: Some(peer.into()),
+                                    },
+                                    received,
+                                )
+                                .is_err()
+                            {
+                                warn!("no connected sentry, dropping status and peer");
+                                let mut status_from_control = self.status_from_control.lock();
+                                *self.status_message.write() = None;
+                                *status_from_control = false;
+
+                                return Err(DisconnectReason::ClientQuitting);
+                            }
+                            forwarded.store(true, Ordering::Relaxed);
+
+                            if let EthMessageId::GetBlockHeaders = inbound_id {
+                                self.adaptive_headers
+                                    .lock()
+                                    .on_request(peer, Instant::now());
+                            }
+
+                            if let Some((request_id, hash)) = header_anchor {
+                                let height = self.header_miss_height(peer);
+                                self.header_misses.lock().on_forwarded(
+                                    peer,
+                                    request_id,
+                                    hash,
+                                    height,
+                                    Instant::now(),
+                                );
+                            }
+                        }
+                    }
+                    Some(_) => {
+                        self.on_unknown_message(id)?;
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl CapabilityServer for CapabilityServerImpl {
+    fn on_peer_client_version(&self, peer: PeerId, client_version: &str) {
+        if let Some(crawler) = self.crawler() {
+            crawler.on_client_version(peer, client_version);
+        }
+    }
+
+    #[instrument(skip(self, peer), level = "debug", fields(peer=&*peer.to_string(), connection_id = tracing::field::Empty))]
+    fn on_peer_connect(
+        &self,
+        peer: PeerId,
+        addr: Option<SocketAddr>,
+        direction: ConnectionDirection,
+        caps: HashMap<CapabilityName, CapabilityVersion>,
+    ) {
+        // Peer may have negotiated only dynamically registered capabilities, it is
+        // disconnected as unsupported eth version then.
+        let protocol_version = caps.get(&capability_name()).copied().unwrap_or_default();
+
+        let connection_id = Ulid::new();
+        Span::current().record("connection_id", &field::display(connection_id));
+        debug!(
+            "Peer {} connected ({:?}, {})",
+            peer, direction, connection_id
+        );
+
+        let crawler = self.crawler();
+        if let Some(crawler) = &crawler {
+            crawler.on_connect(peer, addr, &caps, Instant::now());
+        }
+
+        let budget_exhausted = self.reject_peers_when_budget_exhausted
+            && self.bandwidth_state() == BudgetState::Exhausted;
+        let (resumed, rejected) = match self.reconnects.lock().on_connect(peer, Instant::now()) {
+            ReconnectDecision::Accept(resumed) => (resumed, false),
+            ReconnectDecision::Reject => (None, true),
+        };
+        let asn_rejected = match (&mut *self.asn_limiter.lock(), addr) {
+            (Some(asn_limiter), Some(addr)) => {
+                asn_limiter.on_connect(peer, addr.ip(), direction).err()
+            }
+            _ => None,
+        };
+        if resumed.is_some() {
+            debug!(
+                "Peer {} has reconnected, resuming its previous session",
+                peer
+            );
+            self.metrics.resumed_sessions.inc();
+        }
+
+        let first_events = match (
+            EthVersion::new(protocol_version),
+            &*self.status_message.read(),
+        ) {
+            _ if self.bans.lock().is_banned(peer, Instant::now()) => {
+                debug!("Peer {} is banned, rejecting", peer);
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::UselessPeer,
+                }]
+            }
+            _ if !self.peer_whitelist.read().allows(peer) => {
+                debug!("Peer {} is not whitelisted, rejecting", peer);
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::UselessPeer,
+                }]
+            }
+            _ if budget_exhausted => {
+                debug!("Bandwidth budget is exhausted, rejecting peer {}", peer);
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::TooManyPeers,
+                }]
+            }
+            _ if asn_rejected.is_some() => {
+                debug!(
+                    "Too many peers from AS{}, rejecting peer {}",
+                    asn_rejected.unwrap_or_default(),
+                    peer
+                );
+                self.metrics.rejected_asn_peers.inc();
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::TooManyPeers,
+                }]
+            }
+            _ if rejected => {
+                debug!("Peer {} reconnects too often, rejecting", peer);
+                self.metrics.rejected_reconnects.inc();
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::TooManyPeers,
+                }]
+            }
+            (None, _) => {
+                warn!(
+                    "Peer {} negotiated unsupported eth version {}, disconnecting",
+                    peer, protocol_version
+                );
+                vec![OutboundEvent::Disconnect {
+                    reason: DisconnectReason::UselessPeer,
+                }]
+            }
+            (
+                Some(version),
+                Some(FullStatusData {
+                    status,
+                    fork_filter,
+                }),
+            ) => {
+                let status_message = StatusMessage {
+                    protocol_version: version.get(),
+                    network_id: status.network_id,
+                    total_difficulty: status.total_difficulty,
+                    best_hash: status.best_hash,
+                    genesis_hash: status.fork_data.genesis,
+                    fork_id: fork_filter.current(),
+                };
+
+                self.fork_health.lock().on_status_sent(peer, Instant::now());
+
+                vec![OutboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::Status.to_usize().unwrap(),
+                        data: status_message.encode_for_version(),
+                    },
+                }]
+            }
+            // Most nodes send their Status without waiting for ours.
+            (Some(_), None) if crawler.is_some() => vec![],
+            (Some(_), None) => vec![OutboundEvent::Disconnect {
+                reason: DisconnectReason::DisconnectRequested,
+            }],
+        };
+
+        match addr {
+            Some(addr) => self.peer_addrs.lock().insert(peer, addr),
+            None => self.peer_addrs.lock().remove(&peer),
+        };
+        self.idle_peers.lock().on_connect(peer, Instant::now());
+        self.connection_lifetimes.lock().on_connect(peer, direction);
+        if let Some(timeout) = self.status_timeout {
+            self.peer_timers
+                .schedule(TimedPeerAction::DisconnectIfNotValid(peer), timeout);
+        }
+
+        let (sender, mut receiver) = channel(1);
+        let (sender, monitor) = SendQueue::new(sender);
+        self.setup_peer(
+            peer,
+            Pipes {
+                sender,
+                receiver: Arc::new(AsyncMutex::new(Box::pin(stream! {
+                    for event in first_events {
+                        yield event;
+                    }
+
+                    while let Some(event) = receiver.recv().await {
+                        yield event;
+                    }
+                }))),
+                monitor: Arc::new(monitor),
+                connection_id,
+            },
+            protocol_version as u8,
+            resumed,
+        );
+
+        for (&name, &version) in &caps {
+            if name != capability_name() {
+                let cap = CapabilityId { name, version };
+                let router = self.capability_routers.write().on_peer_connect(peer, cap);
+                if let Some(router) = router {
+                    router.on_peer_connect(peer);
+                }
+            }
+        }
+    }
+    #[instrument(skip(self, peer, event), level = "debug", fields(peer=&*peer.to_string(), event=&*event.to_string(), connection_id = tracing::field::Empty))]
+    async fn on_peer_event(&self, peer: PeerId, event: InboundEvent) {
+        match self.connection_id(peer) {
+            Some(connection_id) => {
+                Span::current().record("connection_id", &field::display(connection_id));
+            }
+            None => {
+                debug!("Dropping event for peer that is not set up");
+                self.metrics.unknown_peer_events.inc();
+                return;
+            }
+        }
+        debug!("Received message");
+
+        // Kept in case the peer has to be disconnected for it.
+        let offending = match &event {
+            InboundEvent::Message { message, .. } => Some(message.clone()),
+            _ => None,
+        };
+
+        // Handle in a separate task so that a slow handler occupies only its own task,
+        // but wait for it to keep events of this peer in order.
+        let permit = self
+            .peer_event_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let this = self.clone();
+        let res = tokio::spawn(
+            self.tasks.track(
+                "peer event",
+                TaskOwner::Peer(peer),
+                async move {
+                    let _permit = permit;
+                    this.handle_event(peer, event).await
+                }
+                .in_current_span(),
+            ),
+        )
+        .await;
+
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Peer event handler failed: {}", e);
+                return;
+            }
+        };
+
+        if let Some(ev) = res.transpose() {
+            if let Err(reason @ DisconnectReason::ProtocolBreach) = ev {
+                self.on_protocol_breach(peer, reason, offending);
+            }
+            if let Some(sender) = self.sender(peer) {
+                let _ = sender
+                    .send(match ev {
+                        Ok(message) => OutboundEvent::Message {
+                            capability_name: capability_name(),
+                            message,
+                        },
+                        Err(reason) => OutboundEvent::Disconnect { reason },
+                    })
+                    .await;
+            }
+        }
+    }
+
+    #[instrument(skip(self, peer), level = "debug", fields(peer=&*peer.to_string(), connection_id = tracing::field::Empty))]
+    async fn next(&self, peer: PeerId) -> OutboundEvent {
+        self.record_connection_id(peer);
+        let event = match self.receiver(peer) {
+            Some(receiver) => receiver.lock().await.next().await,
+            None => None,
+        }
+        .unwrap_or(OutboundEvent::Disconnect {
+            reason: DisconnectReason::DisconnectRequested,
+        });
+
+        let event = match (event, &self.message_signer) {
+            (
+                OutboundEvent::Message {
+                    capability_name: cap,
+                    message: Message { id, data },
+                },
+                Some(signer),
+            ) if cap == capability_name() => OutboundEvent::Message {
+                capability_name: cap,
+                message: Message {
+                    id,
+                    data: match signer.sign(id, &data) {
+                        Ok(signed) => signed,
+                        Err(e) => {
+                            warn!("Failed to sign message {} to {}: {}", id, peer, e);
+                            data
+                        }
+                    },
+                },
+            },
+            (event, _) => event,
+        };
+
+        if let OutboundEvent::Message { message, .. } = &event {
+            self.on_transactions_sent(peer, message);
+            self.metrics
+                .observe_outbound_message(message.id, message.data.len());
+            self.on_traffic(false, message.data.len());
+            self.message_log.lock().record(
+                peer,
+                Direction::Out,
+                message.id,
+                message.data.len(),
+                Instant::now(),
+            );
+        }
+
+        event
+    }
+}
+
+fn save_metrics_file(metrics: &Metrics, path: &Path) {
+    if let Err(e) = persistence::save(&metrics.snapshot(), path) {
+        warn!("Failed to save metrics file: {:?}", e);
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            if std::env::var(EnvFilter::DEFAULT_ENV)
+                .unwrap_or_default()
+                .is_empty()
+            {
+                EnvFilter::new("info")
+            } else {
+                EnvFilter::from_default_env()
+            },
+        )
+        .init();
+
+    let cli = Opts::parse();
+    let (mut opts, config_file) = load_config(&cli.config_path)?;
+    let mut effective_config =
+        EffectiveConfig::new(&opts, &config_file).context("Failed to collect effective config")?;
+
+    let eth_capabilities = eth_capabilities(
+        cli.min_eth_version,
+        cli.max_eth_version,
+        cli.eth63_compat,
+        opts.normalize_request_ids,
+    )
+    .context("Invalid eth version pin")?;
+    let eth_versions = eth_capabilities
+        .keys()
+        .map(|cap| cap.version)
+        .collect::<Vec<_>>();
+    let api_token = match (&cli.api_token, &cli.api_token_file) {
+        (Some(_), Some(_)) => bail!("Only one of --api-token and --api-token-file may be set"),
+        (Some(token), None) => Some(ApiToken::new(token)?),
+        (None, Some(path)) => Some(ApiToken::from_file(path)?),
+        (None, None) => None,
+    };
+
+    effective_config.insert("config_path", &cli.config_path, ConfigSource::Cli);
+    if let Some(serve_data) = cli.serve_data {
+        opts.serve_data = serve_data;
+        effective_config.insert("serve_data", serve_data, ConfigSource::Cli);
+    }
+    if !cli.peer_whitelist.is_empty() {
+        opts.peer_whitelist = cli.peer_whitelist.clone();
+        effective_config.insert("peer_whitelist", &opts.peer_whitelist, ConfigSource::Cli);
+    }
+    if let Some(mode) = cli.peer_whitelist_mode {
+        opts.peer_whitelist_mode = mode;
+        effective_config.insert("peer_whitelist_mode", mode, ConfigSource::Cli);
+    }
+    if let Some(ttl) = cli.tx_pool_ttl {
+        opts.tx_pool_ttl_secs = ttl;
+        effective_config.insert("tx_pool_ttl_secs", ttl, ConfigSource::Cli);
+    }
+    if let Some(max) = cli.max_parallel_peer_events {
+        opts.max_parallel_peer_events = Some(max);
+        effective_config.insert("max_parallel_peer_events", max, ConfigSource::Cli);
+    }
+    if let Some(threshold) = cli.adaptive_headers_latency_threshold {
+        opts.adaptive_headers_latency_threshold_ms = threshold;
+        effective_config.insert(
+            "adaptive_headers_latency_threshold_ms",
+            threshold,
+            ConfigSource::Cli,
+        );
+    }
+    if let Some(min) = cli.min_messages_per_window {
+        opts.min_messages_per_window = min;
+        effective_config.insert("min_messages_per_window", min, ConfigSource::Cli);
+    }
+    if let Some(threshold) = cli.stall_depth_threshold {
+        opts.stall_depth_threshold = threshold;
+        effective_config.insert("stall_depth_threshold", threshold, ConfigSource::Cli);
+    }
+    if let Some(timeout) = cli.stall_timeout {
+        opts.stall_timeout_secs = timeout;
+        effective_config.insert("stall_timeout_secs", timeout, ConfigSource::Cli);
+    }
+    if opts.peer_whitelist_mode == PeerWhitelistMode::Strict && opts.peer_whitelist.is_empty() {
+        bail!("Strict peer whitelist mode needs a non-empty peer whitelist");
+    }
+    effective_config.insert_cli("min_eth_version", cli.min_eth_version, eth_versions.first());
+    effective_config.insert_cli("max_eth_version", cli.max_eth_version, eth_versions.last());
+    effective_config.insert_cli("geoip_db", cli.geoip_db.as_ref(), serde_json::Value::Null);
+    effective_config.insert_cli(
+        "max_peers_per_asn",
+        cli.max_peers_per_asn,
+        DEFAULT_MAX_PEERS_PER_ASN,
+    );
+    effective_config.insert_cli("crawl", Some(cli.crawl).filter(|&v| v), false);
+    effective_config.insert_cli("crawl_rate", cli.crawl_rate, DEFAULT_CRAWL_RATE);
+    effective_config.insert_cli(
+        "crawl_cooldown",
+        cli.crawl_cooldown,
+        DEFAULT_CRAWL_COOLDOWN.as_secs(),
+    );
+    effective_config.insert_cli(
+        "crawl_output",
+        cli.crawl_output.as_ref(),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "web3_url",
+        cli.web3_url.as_ref().map(ToString::to_string),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "web3_ws_url",
+        cli.web3_ws_url.as_ref().map(ToString::to_string),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli("web3_pool_size", cli.web3_pool_size, 1);
+    effective_config.insert_cli("chain_id", cli.chain_id, 1);
+    effective_config.insert_cli(
+        "genesis_hash",
+        cli.genesis_hash,
+        ChainConfig::mainnet().genesis_hash,
+    );
+    effective_config.insert_cli(
+        "fork_blocks",
+        Some(&cli.fork_blocks).filter(|v| !v.is_empty()),
+        &ChainConfig::mainnet().fork_blocks,
+    );
+    effective_config.insert_cli(
+        "api_token",
+        cli.api_token.as_ref().map(|_| REDACTED),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "api_token_file",
+        cli.api_token_file.as_ref(),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "admin_rest_addr",
+        cli.admin_rest_addr.as_ref(),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "unified_addr",
+        cli.unified_addr.as_ref(),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "otlp_endpoint",
+        cli.otlp_endpoint.as_ref(),
+        serde_json::Value::Null,
+    );
+    effective_config.insert_cli(
+        "export_peers_on_signal",
+        Some(cli.export_peers_on_signal).filter(|&v| v),
+        false,
+    );
+    effective_config.insert_cli("p2p_quic_port", cli.p2p_quic_port, serde_json::Value::Null);
+    effective_config.insert_cli(
+        "sign_messages",
+        Some(cli.sign_messages).filter(|&v| v),
+        false,
+    );
+    effective_config.insert_cli("eth63_compat", Some(cli.eth63_compat).filter(|&v| v), false);
+    effective_config.insert_cli("self_test", Some(cli.self_test).filter(|&v| v), false);
+
+    if cli.dump_config {
+        println!("{}", serde_json::to_string_pretty(&effective_config)?);
+        return Ok(());
+    }
+    if cli.dump_peers_json {
+        let addr = cli
+            .unified_addr
+            .as_ref()
+            .or_else(|| cli.admin_rest_addr.as_ref())
+            .context("--dump-peers-json needs --unified-addr or --admin-rest-addr")?;
+        return admin::dump_peers(addr, api_token.as_ref()).await;
+    }
+    let effective_config = Arc::new(effective_config);
+
+    let resolver = Arc::new(
+        TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+            .context("Failed to start DNS resolver")?,
+    );
+
+    let runtime_checks: RuntimeChecks = {
+        let node_key = opts.node_key.clone();
+        let dnsdisc_address = opts.dnsdisc.as_ref().map(|c| c.address.clone());
+        let resolver = resolver.clone();
+        Arc::new(move || {
+            let mut checks = vec![self_test::node_key_check(node_key.clone())];
+            if let Some(address) = &dnsdisc_address {
+                checks.push(self_test::dnsdisc_check(resolver.clone(), address.clone()));
+            }
+            checks.push(self_test::external_address_check());
+            checks
+        })
+    };
+
+    if cli.self_test {
+        let mut checks = runtime_checks();
+        checks.push(self_test::tcp_bind_check(
+            "RLPx port",
+            SocketAddr::from(([0, 0, 0, 0], opts.listen_port)),
+        ));
+        match &cli.unified_addr {
+            Some(unified_addr) => checks.push(self_test::tcp_bind_check(
+                "unified API address",
+                unified_addr.parse()?,
+            )),
+            None => checks.push(self_test::tcp_bind_check(
+                "sentry API address",
+                opts.sentry_addr.parse()?,
+            )),
+        }
+        if let Some(discv4_opts) = &opts.discv4 {
+            checks.push(self_test::udp_bind_check(
+                "discv4 port",
+                SocketAddr::from(([0, 0, 0, 0], discv4_opts.port)),
+            ));
+        }
+        if let Some(discv5_opts) = &opts.discv5 {
+            checks.push(self_test::udp_bind_check(
+                "discv5 address",
+                discv5_opts.addr.parse()?,
+            ));
+        }
+        if let (Some(admin_rest_addr), None) = (&cli.admin_rest_addr, &cli.unified_addr) {
+            checks.push(self_test::tcp_bind_check(
+                "admin REST address",
+                admin_rest_addr.parse()?,
+            ));
+        }
+
+        let reports = self_test::run_checks(checks).await;
+        for report in &reports {
+            println!(
+                "[{}] {}: {}{}",
+                if report.passed { "ok" } else { "FAIL" },
+                report.name,
+                report.detail,
+                if report.required { "" } else { " (optional)" }
+            );
+        }
+        if !self_test::passed(&reports) {
+            bail!("Self-test failed");
+        }
+        return Ok(());
+    }
+
+    info!("Advertising eth versions: {:?}", eth_versions);
+
+    let secret_key;
+    if let Some(data) = opts.node_key.take() {
+        secret_key = SecretKey::from_slice(&hex::decode(data)?)?;
+        info!("Loaded node key from config");
+    } else {
+        secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        info!("Generated new node key: {}", secret_key);
+    };
+    let message_signer = if cli.sign_messages {
+        info!("Signing eth messages with node key");
+        Some(MessageSigner::new(secret_key))
+    } else {
+        None
+    };
+
+    let listen_addr = format!("0.0.0.0:{}", opts.listen_port);
+
+    info!("Starting Ethereum sentry");
+    if !opts.serve_data {
+        info!("Read-only mode: data requests are answered with empty responses");
+    }
+    if opts.peer_whitelist_mode == PeerWhitelistMode::Strict {
+        info!(
+            "Only {} whitelisted peers may connect",
+            opts.peer_whitelist.len()
+        );
+    }
+
+    info!(
+        "Node ID: {}",
+        hex::encode(
+            devp2p::util::pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key)).as_bytes()
+        )
+    );
+
+    if let Some(cidr_filter) = &opts.cidr {
+        info!("Peers restricted to range {}", cidr_filter);
+    }
+
+    let mut discovery_tasks = StreamMap::new();
+
+    if let Some(dnsdisc_opts) = opts.dnsdisc.take() {
+        info!("Starting DNS discovery fetch from {}", dnsdisc_opts.address);
+        let dns_resolver = dnsdisc::Resolver::new(resolver.clone());
+
+        discovery_tasks.insert(
+            "dnsdisc".to_string(),
+            Box::pin(DnsDiscovery::new(
+                Arc::new(dns_resolver),
+                dnsdisc_opts.address,
+                None,
+            )) as Discovery,
+        );
+    }
+
+    let mut discv4_node = None;
+    if let Some(discv4_opts) = opts.discv4.take() {
+        info!("Starting discv4 at port {}", discv4_opts.port);
+
+        let bootstrap_nodes = discv4_opts
+            .bootnodes
+            .into_iter()
+            .map(|Dicv4NR(nr)| nr)
+            .collect::<Vec<_>>();
+
+        if bootstrap_nodes.is_empty() {
+            warn!("discv4 cannot work without bootstrap nodes!");
+        }
+        let node = discv4::Node::new(
+            format!("0.0.0.0:{}", discv4_opts.port).parse().unwrap(),
+            secret_key,
+            bootstrap_nodes,
+            None,
+            true,
+            opts.listen_port,
+        )
+        .await
+        .unwrap();
+        discv4_node = Some(node.clone());
+        discovery_tasks.insert(
+            "discv4".to_string(),
+            Box::pin(
+                Discv4Builder::default()
+                    .with_cache(discv4_opts.cache)
+                    .with_concurrent_lookups(discv4_opts.concurrent_lookups)
+                    .build(node),
+            ),
+        );
+    }
+
+    if let Some(discv5_opts) = opts.discv5.take() {
         let mut svc = discv5::Discv5::new(
             discv5_opts
                 .enr
@@ -435,89 +3125,2138 @@ async fn main() -> anyhow::Result<()> {
             ),
             Default::default(),
         )
-        .map_err(|e| anyhow!("{}", e))?;
-        svc.start(discv5_opts.addr.parse()?)
+        .map_err(|e| anyhow!("{}", e))?;
+        svc.start(discv5_opts.addr.parse()?)
+            .await
+            .map_err(|e| anyhow!("{}", e))
+            .context("Failed to start discv5")?;
+        info!("Starting discv5 at {}", discv5_opts.addr);
+
+        for bootnode in discv5_opts.bootnodes {
+            svc.add_enr(bootnode).unwrap();
+        }
+        discovery_tasks.insert("discv5".to_string(), Box::pin(Discv5::new(svc, 20)));
+    }
+
+    let greylist_exempt = opts
+        .reserved_peers
+        .iter()
+        .chain(&opts.quic_peers)
+        .filter_map(|peer| peer.host.parse::<IpAddr>().ok())
+        .chain(opts.greylist.exempt.iter().copied())
+        .collect::<Vec<_>>();
+
+    if !opts.reserved_peers.is_empty() {
+        info!("Enabling reserved peers: {:?}", opts.reserved_peers);
+        discovery_tasks.insert(
+            "reserved peers".to_string(),
+            StaticPeers::new(
+                std::mem::take(&mut opts.reserved_peers),
+                resolver.clone(),
+                static_peers::RESOLVE_TTL,
+            )
+            .into_discovery(),
+        );
+    }
+
+    let mut peer_labels = PeerLabels::default();
+    if let Some(peers_file) = &opts.peers_file {
+        let peers_file = persistence::load::<PeersFile>(peers_file)?;
+        peer_labels.load_persistent(peers_file.peer_labels());
+    }
+
+    let tasks = Arc::new(TaskGroup::new());
+    let task_registry = TaskRegistry::default();
+
+    let metrics = Arc::new(match &opts.metrics_file {
+        Some(path) => Metrics::restore(&persistence::load::<MetricsFile>(path)?)?,
+        None => Metrics::new()?,
+    });
+    if let Some(metrics_addr) = &opts.metrics_addr {
+        let metrics_addr = metrics_addr.parse()?;
+        let metrics = metrics.clone();
+        task_registry.spawn(
+            &tasks,
+            "metrics server",
+            TaskOwner::Subsystem("metrics"),
+            async move {
+                if let Err(e) = metrics::serve(metrics_addr, metrics).await {
+                    error!("{:?}", e);
+                }
+            },
+        );
+    }
+
+    let false_positive_rate = opts.duplicate_filter.false_positive_rate;
+    if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+        bail!(
+            "Duplicate filter false positive rate must be between 0 and 1, got {}",
+            false_positive_rate
+        );
+    }
+    if opts.duplicate_filter.new_block_hashes_copies == 0
+        || opts.duplicate_filter.new_block_copies == 0
+    {
+        bail!("Duplicate filter must forward at least one copy of each announcement");
+    }
+
+    let capability_server = Arc::new(CapabilityServerImpl::new(
+        &opts,
+        message_signer,
+        discv4_node,
+        metrics.clone(),
+        peer_labels,
+        task_registry.clone(),
+    ));
+    capability_server.load_bans_file()?;
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        capability_server.attach_otlp_tracer(
+            telemetry::install(endpoint).context("Failed to start OTLP exporter")?,
+        );
+        info!("Exporting inbound message spans to {}", endpoint);
+    }
+    if let Some(dir) = &opts.breach_log.dump_dir {
+        capability_server
+            .attach_breach_dumps(Arc::new(BreachDumps::new(dir, opts.breach_log.max_dumps)?));
+    }
+    if let Some(dir) = &opts.capture_dir {
+        capability_server.attach_peer_capture(Arc::new(PeerCapture::new(dir)?));
+        info!("Recording GetBlockHeaders and replies to {}", dir.display());
+    }
+    if let Some(dir) = &opts.replay_dir {
+        let replay = ReplayDataProvider::new(dir)?;
+        info!(
+            "Answering GetBlockHeaders with {} replies recorded in {}",
+            replay.len(),
+            dir.display()
+        );
+        capability_server.attach_replay(Arc::new(replay));
+    }
+    let crawl_dial_interval = if cli.crawl {
+        let crawl_rate = cli.crawl_rate.unwrap_or(DEFAULT_CRAWL_RATE);
+        if crawl_rate.is_nan() || crawl_rate <= 0.0 {
+            bail!("Crawl rate must be positive");
+        }
+        capability_server.attach_crawler(Arc::new(Crawler::new(cli.crawl_output.as_deref())?));
+        info!("Crawling at {} nodes per second", crawl_rate);
+        Some(Duration::from_secs_f64(1.0 / crawl_rate))
+    } else {
+        None
+    };
+    if let Some(path) = &cli.geoip_db {
+        match asn::open_database(path) {
+            Ok(lookup) => {
+                let max_peers_per_asn = cli.max_peers_per_asn.unwrap_or(DEFAULT_MAX_PEERS_PER_ASN);
+                info!("Limiting inbound peers to {} per ASN", max_peers_per_asn);
+                capability_server.attach_asn_limiter(AsnLimiter::new(lookup, max_peers_per_asn));
+            }
+            Err(e) => warn!("{:#}, not limiting peers per ASN", e),
+        }
+    }
+
+    if !opts.siblings.addrs.is_empty() {
+        let origin = format!("{:016x}", rand::random::<u64>());
+        info!(
+            "Sharing bans with {} sibling sentries as {}",
+            opts.siblings.addrs.len(),
+            origin
+        );
+        let (gossip, updates) = SiblingGossip::new(origin.clone());
+        capability_server.attach_sibling_gossip(gossip);
+        task_registry.spawn(
+            &tasks,
+            "sibling gossip",
+            TaskOwner::Subsystem("sibling gossip"),
+            run_sibling_gossip(
+                origin,
+                opts.siblings.addrs.clone(),
+                api_token.clone(),
+                updates,
+            ),
+        );
+    }
+
+    task_registry.spawn(&tasks, "peer watch", TaskOwner::Subsystem("peer watch"), {
+        let capability_server = Arc::downgrade(&capability_server);
+        async move {
+            while let Some(capability_server) = capability_server.upgrade() {
+                capability_server
+                    .peer_watch
+                    .publish(capability_server.peer_snapshot());
+                drop(capability_server);
+
+                sleep(PEER_WATCH_INTERVAL).await;
+            }
+        }
+    });
+
+    task_registry.spawn(
+        &tasks,
+        "response quality",
+        TaskOwner::Subsystem("response quality"),
+        {
+            let capability_server = Arc::downgrade(&capability_server);
+            async move {
+                while let Some(capability_server) = capability_server.upgrade() {
+                    capability_server.expire_directed_requests();
+                    drop(capability_server);
+
+                    sleep(RESPONSE_QUALITY_INTERVAL).await;
+                }
+            }
+        },
+    );
+
+    if let Some(mut peer_timer_queue) = capability_server.take_peer_timer_queue() {
+        task_registry.spawn(
+            &tasks,
+            "peer timers",
+            TaskOwner::Subsystem("peer timers"),
+            {
+                let capability_server = Arc::downgrade(&capability_server);
+                async move {
+                    while let Some(action) = peer_timer_queue.next().await {
+                        match capability_server.upgrade() {
+                            Some(capability_server) => {
+                                capability_server.on_timed_action(action).await
+                            }
+                            None => return,
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    if opts.bans_file.is_some() {
+        task_registry.spawn(&tasks, "bans file", TaskOwner::Subsystem("bans"), {
+            let bans_changed = capability_server.bans_changed();
+            let capability_server = Arc::downgrade(&capability_server);
+            async move {
+                loop {
+                    bans_changed.notified().await;
+                    // Save changes made meanwhile too.
+                    sleep(BANS_SAVE_DELAY).await;
+                    match capability_server.upgrade() {
+                        Some(capability_server) => capability_server.save_bans_file().await,
+                        None => return,
+                    }
+                }
+            }
+        });
+    }
+
+    let web3_url = match (&cli.web3_url, &cli.web3_ws_url) {
+        (Some(_), Some(_)) => bail!("Only one of --web3-url and --web3-ws-url may be set"),
+        (url, ws_url) => url.as_ref().or_else(|| ws_url.as_ref()),
+    };
+    if let Some(url) = web3_url.cloned() {
+        let mainnet = ChainConfig::mainnet();
+        let provider = Web3StatusProvider::new(
+            Web3Transport::new(url.clone(), cli.web3_pool_size.unwrap_or(1))?,
+            cli.chain_id.unwrap_or(1),
+            Forks {
+                genesis: cli.genesis_hash.unwrap_or(mainnet.genesis_hash),
+                forks: if cli.fork_blocks.is_empty() {
+                    mainnet.fork_blocks
+                } else {
+                    cli.fork_blocks.iter().copied().collect()
+                },
+            },
+        );
+        info!("Taking status from {} until the control sets it", url);
+        task_registry.spawn(&tasks, "web3 status", TaskOwner::Subsystem("web3"), {
+            let capability_server = Arc::downgrade(&capability_server);
+            async move {
+                while let Some(capability_server) = capability_server.upgrade() {
+                    match provider.get_status_data().await {
+                        Ok(status) => {
+                            if capability_server.set_web3_status(status) {
+                                trace!("Status updated from web3 endpoint");
+                            }
+                        }
+                        Err(e) => warn!("Failed to get status from web3 endpoint: {}", e),
+                    }
+                    drop(capability_server);
+                    sleep(WEB3_POLL_INTERVAL).await;
+                }
+            }
+        });
+    }
+
+    if let Some(mut duplicate_filter_expiry) = capability_server.take_duplicate_filter_expiry() {
+        task_registry.spawn(
+            &tasks,
+            "duplicate filter",
+            TaskOwner::Subsystem("duplicate filter"),
+            {
+                let capability_server = Arc::downgrade(&capability_server);
+                async move {
+                    while let Some(generation) = duplicate_filter_expiry.next().await {
+                        match capability_server.upgrade() {
+                            Some(capability_server) => {
+                                capability_server.expire_duplicates(generation)
+                            }
+                            None => return,
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    task_registry.spawn(&tasks, "forwarding", TaskOwner::Subsystem("forwarding"), {
+        let capability_server = Arc::downgrade(&capability_server);
+        async move {
+            while let Some(capability_server) = capability_server.upgrade() {
+                for (queue, stats) in capability_server.forward_queue_stats(Instant::now()) {
+                    capability_server.metrics.set_forward_queue(queue, stats);
+                }
+                drop(capability_server);
+
+                sleep(FORWARD_STATS_INTERVAL).await;
+            }
+        }
+    });
+
+    task_registry.spawn(&tasks, "idle peers", TaskOwner::Subsystem("idle peers"), {
+        let capability_server = Arc::downgrade(&capability_server);
+        async move {
+            while let Some(capability_server) = capability_server.upgrade() {
+                capability_server.disconnect_idle_peers().await;
+                drop(capability_server);
+
+                sleep(IDLE_CHECK_INTERVAL).await;
+            }
+        }
+    });
+
+    if opts.announce_head {
+        info!("Announcing our new head to peers if control does not");
+        task_registry.spawn(
+            &tasks,
+            "head announcer",
+            TaskOwner::Subsystem("head announcer"),
+            {
+                let capability_server = Arc::downgrade(&capability_server);
+                async move {
+                    while let Some(capability_server) = capability_server.upgrade() {
+                        capability_server.announce_head();
+                        drop(capability_server);
+
+                        sleep(HEAD_ANNOUNCE_INTERVAL).await;
+                    }
+                }
+            },
+        );
+    }
+
+    let mut redial_policy = RedialPolicy::default();
+    for rule in &opts.redial_policy {
+        let reason = DisconnectReason::from_u8(rule.reason).ok_or_else(|| {
+            anyhow!(
+                "Unknown disconnect reason in redial policy: {}",
+                rule.reason
+            )
+        })?;
+        if !rule.delay_multiplier.is_finite() || rule.delay_multiplier < 0.0 {
+            bail!(
+                "Redial delay multiplier for disconnect reason {} must be finite and not negative, got {}",
+                rule.reason,
+                rule.delay_multiplier
+            );
+        }
+        redial_policy = redial_policy.with_rule(
+            reason,
+            RedialRule::new(rule.delay_multiplier, rule.max_attempts, rule.alert),
+        );
+    }
+
+    let p2p_protocol_version =
+        ProtocolVersion::from_usize(opts.p2p_protocol_version).ok_or_else(|| {
+            anyhow!(
+                "Unsupported p2p protocol version: {}",
+                opts.p2p_protocol_version
+            )
+        })?;
+
+    let mut swarm_builder = Swarm::builder()
+        .with_task_group(tasks.clone())
+        .with_listen_options(ListenOptions {
+            discovery_tasks,
+            max_peers: opts.max_peers,
+            addr: listen_addr.parse().unwrap(),
+            cidr: opts.cidr,
+        })
+        .with_client_version(format!("sentry/v{}", env!("CARGO_PKG_VERSION")))
+        .with_handshake_threads(opts.handshake_threads)
+        .with_redial_policy(redial_policy)
+        .with_greylist_policy(GreylistPolicy {
+            max_failures: opts.greylist.max_failures,
+            window: Duration::from_secs(opts.greylist.window_secs),
+            period: Duration::from_secs(opts.greylist.period_secs),
+            capacity: opts.greylist.capacity,
+        })
+        .with_p2p_protocol_version(p2p_protocol_version);
+    if cli.crawl {
+        // Crawled sessions are short, the crawl rate limits dials instead of max_peers.
+        swarm_builder = swarm_builder.with_unlimited_dials().with_dial_cooldown(
+            cli.crawl_cooldown
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CRAWL_COOLDOWN),
+        );
+    }
+    #[cfg(feature = "quic")]
+    if let Some(port) = cli.p2p_quic_port {
+        warn!(
+            "Experimental QUIC transport enabled on port {}, only other sentries can use it",
+            port
+        );
+        swarm_builder = swarm_builder.with_quic_listener(SocketAddr::new([0; 4].into(), port));
+    } else if !opts.quic_peers.is_empty() {
+        bail!("QUIC peers configured without --p2p-quic-port");
+    }
+    #[cfg(not(feature = "quic"))]
+    if cli.p2p_quic_port.is_some() || !opts.quic_peers.is_empty() {
+        bail!("QUIC transport is not available, sentry was built without the quic feature");
+    }
+    let swarm = swarm_builder
+        .build(eth_capabilities, capability_server.clone(), secret_key)
+        .await
+        .context("Failed to start RLPx node")?;
+    for ip in greylist_exempt {
+        swarm.exempt_from_greylist(ip);
+    }
+    capability_server.attach_capability_registry(swarm.capability_registry());
+    capability_server.attach_redial_exemptions(swarm.redial_exemptions());
+    #[cfg(feature = "eip4337")]
+    capability_server
+        .register_capability(
+            eip4337::aa_capability(),
+            eip4337::AA_MESSAGE_COUNT,
+            Box::new(eip4337::Eip4337CapabilityServer::new(
+                capability_server.data_sender.clone(),
+            )),
+        )
+        .context("Failed to register aa capability")?;
+
+    info!("RLPx node listening at {}", listen_addr);
+
+    #[cfg(feature = "quic")]
+    if !opts.quic_peers.is_empty() {
+        let mut quic_peers = StaticPeers::new(
+            std::mem::take(&mut opts.quic_peers),
+            resolver.clone(),
+            static_peers::RESOLVE_TTL,
+        );
+        task_registry.spawn(
+            &tasks,
+            "QUIC dialer",
+            TaskOwner::Subsystem("QUIC dialer"),
+            {
+                let swarm = Arc::downgrade(&swarm);
+                async move {
+                    while let Some(record) = quic_peers.next_record(Instant::now()).await {
+                        let swarm = match swarm.upgrade() {
+                            Some(swarm) => swarm,
+                            None => return,
+                        };
+                        match record {
+                            Ok(record) => {
+                                if let Err(e) = swarm.add_quic_peer(record).await {
+                                    debug!("Failed to dial QUIC peer {}: {}", record.id, e);
+                                }
+                            }
+                            Err(e) => warn!("{:?}", e),
+                        }
+                        drop(swarm);
+
+                        sleep(QUIC_DIAL_INTERVAL).await;
+                    }
+                }
+            },
+        );
+    }
+
+    task_registry.spawn(&tasks, "stalled peers", TaskOwner::Subsystem("stalls"), {
+        let capability_server = Arc::downgrade(&capability_server);
+        let swarm = Arc::downgrade(&swarm);
+        let mut stall_detector = StallDetector::new(
+            opts.stall_depth_threshold,
+            Duration::from_secs(opts.stall_timeout_secs),
+        );
+        async move {
+            while let (Some(capability_server), Some(swarm)) =
+                (capability_server.upgrade(), swarm.upgrade())
+            {
+                let now = Instant::now();
+                let depths = capability_server.outbound_queue_depths(now);
+                for peer in stall_detector.check(depths, now) {
+                    // The peer's queue is full, so Disconnect could not be queued behind it.
+                    if capability_server.on_stalled_peer(peer) {
+                        swarm
+                            .disconnect(peer, DisconnectReason::TcpSubsystemError)
+                            .await;
+                    }
+                }
+                drop(capability_server);
+                drop(swarm);
+
+                sleep(STALL_CHECK_INTERVAL).await;
+            }
+        }
+    });
+
+    let admin_api = AdminApi {
+        capability_server: capability_server.clone(),
+        metrics: metrics.clone(),
+        effective_config: effective_config.clone(),
+        runtime_checks,
+    };
+
+    if let (Some(admin_rest_addr), None) = (&cli.admin_rest_addr, &cli.unified_addr) {
+        let admin_rest_addr = admin_rest_addr.parse()?;
+        let admin_api = admin_api.clone();
+        task_registry.spawn(
+            &tasks,
+            "admin REST server",
+            TaskOwner::Subsystem("admin"),
+            async move {
+                if let Err(e) = admin::serve(admin_rest_addr, admin_api).await {
+                    error!("{:?}", e);
+                }
+            },
+        );
+    }
+
+    if cli.export_peers_on_signal {
+        let mut signals = tokio::signal::unix::signal(SignalKind::user_defined2())
+            .context("Failed to listen for SIGUSR2")?;
+        let capability_server = capability_server.clone();
+        task_registry.spawn(
+            &tasks,
+            "peer export",
+            TaskOwner::Subsystem("peer export"),
+            async move {
+                while signals.recv().await.is_some() {
+                    let urls = capability_server.connected_enode_urls();
+                    info!("Exporting {} peers", urls.len());
+                    for url in urls {
+                        println!("{}", url);
+                    }
+                }
+            },
+        );
+    }
+
+    let sentry_service = SentryService::new(capability_server.clone());
+    let svc = match api_token.clone() {
+        Some(token) => {
+            info!("Sentry gRPC API requires token");
+            SentryServer::with_interceptor(sentry_service, token.interceptor(metrics.clone()))
+        }
+        None => SentryServer::new(sentry_service),
+    };
+    match &cli.unified_addr {
+        Some(unified_addr) => {
+            let unified_addr = unified_addr.parse()?;
+            task_registry.spawn(
+                &tasks,
+                "unified server",
+                TaskOwner::Subsystem("grpc"),
+                async move {
+                    if let Err(e) =
+                        admin::serve_unified(unified_addr, svc, admin_api, api_token).await
+                    {
+                        error!("{:?}", e);
+                    }
+                },
+            );
+        }
+        None => {
+            let sentry_addr = opts.sentry_addr.parse()?;
+            task_registry.spawn(
+                &tasks,
+                "sentry server",
+                TaskOwner::Subsystem("grpc"),
+                async move {
+                    info!("Sentry gRPC server starting on {}", sentry_addr);
+
+                    Server::builder()
+                        .add_service(svc)
+                        .serve(sentry_addr)
+                        .await
+                        .unwrap();
+                },
+            );
+        }
+    }
+
+    let mut terminate = tokio::signal::unix::signal(SignalKind::terminate())
+        .context("Failed to listen for SIGTERM")?;
+    let mut draining = capability_server.shutdown.subscribe();
+    {
+        let capability_server = capability_server.clone();
+        task_registry.spawn(
+            &tasks,
+            "shutdown",
+            TaskOwner::Subsystem("shutdown"),
+            async move {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                info!("Shutting down, draining API clients for {:?}", DRAIN_PERIOD);
+                capability_server.shutdown.begin_draining();
+            },
+        );
+    }
+
+    let mut metrics_saved = Instant::now();
+    loop {
+        info!(
+            "Peer info: {} active ({} valid, +{} dialing) / {} max.",
+            swarm.connected_peers(),
+            capability_server.valid_peers_count(),
+            swarm.dialing(),
+            opts.max_peers
+        );
+
+        let peers_by_protocol_version = swarm.peers_by_protocol_version();
+        info!(
+            "Peers by protocol version: {:?}",
+            peers_by_protocol_version
+                .iter()
+                .map(|(version, count)| format!("eth/{}: {}", version, count))
+                .collect::<Vec<_>>()
+        );
+        metrics.set_peers_by_protocol_version(&peers_by_protocol_version);
+
+        let churn_rate = swarm.peer_churn_rate();
+        metrics.set_peer_churn_rate(churn_rate);
+        match crawl_dial_interval {
+            // Churn is the point of crawling.
+            Some(interval) => swarm.set_dial_interval(interval),
+            None if churn_rate.total() > opts.max_churn_rate => {
+                warn!(
+                    "High peer churn: {} connects, {} disconnects in the last minute (max {}), slowing down dialing",
+                    churn_rate.connects, churn_rate.disconnects, opts.max_churn_rate
+                );
+                swarm.set_dial_interval(DIAL_INTERVAL * 2);
+            }
+            None => swarm.set_dial_interval(DIAL_INTERVAL),
+        }
+
+        let redial_stats = swarm.redial_stats();
+        if !redial_stats.is_empty() {
+            debug!(
+                "Redials by remote disconnect reason: {}",
+                redial_stats
+                    .iter()
+                    .map(|(reason, stats)| format!(
+                        "{:?}: {} scheduled, {} deferred, {} given up",
+                        reason, stats.scheduled, stats.deferred, stats.given_up
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let dial_distribution = swarm.dial_distribution();
+        if !dial_distribution.by_source.is_empty() {
+            debug!(
+                "Dialed peers by discovery: {:?}, by network: {:?}",
+                dial_distribution.by_source,
+                dial_distribution
+                    .by_prefix
+                    .iter()
+                    .map(|(prefix, count)| format!("{}: {}", prefix, count))
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        let lifetimes = [ConnectionDirection::Outbound, ConnectionDirection::Inbound]
+            .iter()
+            .filter_map(|&direction| {
+                let summary = capability_server.connection_lifetimes(direction).total;
+                summary.median_secs.map(|median| {
+                    format!(
+                        "{} {} ({} closed)",
+                        direction,
+                        LifetimeDisplay(median),
+                        summary.count
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        if !lifetimes.is_empty() {
+            debug!("Median connection lifetime: {}", lifetimes.join(", "));
+        }
+
+        for task in task_registry.take_leaked(Instant::now()) {
+            warn!(
+                "Task \"{}\" of {} has outlived the peer (running for {:?})",
+                task.name,
+                task.owner,
+                task.started.elapsed()
+            );
+        }
+
+        let greylisted = swarm.greylisted();
+        if !greylisted.is_empty() {
+            debug!(
+                "Greylisted addresses: {}",
+                greylisted
+                    .iter()
+                    .map(|addr| format!(
+                        "{} ({} dropped, {:?} left)",
+                        addr.ip, addr.hits, addr.remaining
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let accept_errors = swarm.accept_errors();
+        metrics.set_accept_errors(&accept_errors);
+        if let Some(e) = accept_errors.fatal {
+            // Sentry without inbound connectivity slowly loses its peers, better restart.
+            bail!("Listener has failed: {}", e);
+        }
+
+        let handshake_stats = swarm.handshake_stats();
+        debug!(
+            "Handshakes: {} in progress, p50/p90/p99 {:?}/{:?}/{:?} over {} samples.",
+            handshake_stats.queue_depth,
+            handshake_stats.p50,
+            handshake_stats.p90,
+            handshake_stats.p99,
+            handshake_stats.samples
+        );
+        metrics.set_handshake_failures(&handshake_stats);
+
+        capability_server
+            .evict_passive_peer(swarm.connected_peers(), swarm.dialing() > 0)
+            .await;
+
+        let top_consumers = capability_server.top_consumers(TOP_CONSUMERS);
+        if !top_consumers.is_empty() {
+            debug!(
+                "Top consumers: {}",
+                top_consumers
+                    .iter()
+                    .map(|(peer, served)| format!(
+                        "{}: {} items, {} bytes",
+                        peer, served.items, served.bytes
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        if let Some(path) = &opts.metrics_file {
+            if metrics_saved.elapsed() >= Duration::from_secs(opts.metrics_file_interval_secs) {
+                save_metrics_file(&metrics, path);
+                metrics_saved = Instant::now();
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = drained(&mut draining) => break,
+        }
+    }
+
+    // Let API streams end with the shutdown status before connections are closed.
+    sleep(DRAIN_PERIOD).await;
+    if let Some(path) = &opts.metrics_file {
+        save_metrics_file(&metrics, path);
+    }
+    capability_server.save_bans_file().await;
+    if cli.otlp_endpoint.is_some() {
+        telemetry::shutdown();
+    }
+    info!("Sentry has shut down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn rapid_reconnects_of_same_peer() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let peer = PeerId::from_low_u64_be(1);
+
+        let tasks = (0..8)
+            .map(|_| {
+                let capability_server = capability_server.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        capability_server.on_peer_connect(
+                            peer,
+                            None,
+                            ConnectionDirection::Inbound,
+                            std::iter::once((capability_name(), 65)).collect(),
+                        );
+                        capability_server
+                            .on_peer_event(
+                                peer,
+                                InboundEvent::Message {
+                                    capability_name: capability_name(),
+                                    message: Message {
+                                        id: EthMessageId::Transactions.to_usize().unwrap(),
+                                        data: Bytes::new(),
+                                    },
+                                },
+                            )
+                            .await;
+                        let _ = capability_server.next(peer).await;
+                        capability_server
+                            .on_peer_event(peer, InboundEvent::Disconnect { reason: None })
+                            .await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Every connect is followed by a disconnect, so nothing is left behind.
+        assert!(!capability_server.peer_pipes.contains(&peer));
+        assert_eq!(
+            capability_server.block_tracker.read().block_number(peer),
+            None
+        );
+        assert!(capability_server.protocol_version_by_peer.read().is_empty());
+        assert!(capability_server.valid_peers.read().is_empty());
+        assert_eq!(capability_server.tasks.task_count(TaskOwner::Peer(peer)), 0);
+    }
+
+    #[tokio::test]
+    async fn strict_peer_whitelist() {
+        let listed = PeerId::from_low_u64_be(1);
+        let other = PeerId::from_low_u64_be(2);
+        let added = PeerId::from_low_u64_be(3);
+        let capability_server = CapabilityServerImpl::for_test(&Config {
+            peer_whitelist: vec![listed],
+            peer_whitelist_mode: PeerWhitelistMode::Strict,
+            ..Default::default()
+        });
+
+        // Without status every peer is dropped, but only after the whitelist check.
+        assert!(capability_server.whitelist_peer(added));
+        for &(peer, expected) in &[
+            (listed, DisconnectReason::DisconnectRequested),
+            (other, DisconnectReason::UselessPeer),
+            (added, DisconnectReason::DisconnectRequested),
+        ] {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), 65)).collect(),
+            );
+            assert!(matches!(
+                capability_server.next(peer).await,
+                OutboundEvent::Disconnect { reason } if reason == expected
+            ));
+        }
+
+        // Connected peer is dropped once removed from the whitelist.
+        assert!(capability_server.unwhitelist_peer(listed).await);
+        assert!(matches!(
+            capability_server.next(listed).await,
+            OutboundEvent::Disconnect {
+                reason: DisconnectReason::UselessPeer
+            }
+        ));
+        assert_eq!(capability_server.whitelisted_peers(), vec![added]);
+    }
+
+    #[tokio::test]
+    async fn send_sync() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        let event = || OutboundEvent::Message {
+            capability_name: capability_name(),
+            message: Message {
+                id: EthMessageId::Transactions.to_usize().unwrap(),
+                data: Bytes::from_static(&[0xc0]),
+            },
+        };
+
+        assert!(!capability_server.send_sync(peer, event()));
+
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 65)).collect(),
+        );
+        assert!(capability_server.send_sync(peer, event()));
+        // Queue is full until the event is picked up.
+        assert!(!capability_server.send_sync(peer, event()));
+
+        // No status has been set, so the peer is asked to disconnect first.
+        assert!(matches!(
+            capability_server.next(peer).await,
+            OutboundEvent::Disconnect { .. }
+        ));
+        assert!(matches!(
+            capability_server.next(peer).await,
+            OutboundEvent::Message { .. }
+        ));
+    }
+
+    #[test]
+    fn enode_export() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let caps = || std::iter::once((capability_name(), 66)).collect();
+
+        let v4 = PeerId::from_low_u64_be(1);
+        let v6 = PeerId::from_low_u64_be(2);
+        let unknown = PeerId::from_low_u64_be(3);
+        let connect = |peer, addr: Option<&str>| {
+            capability_server.on_peer_connect(
+                peer,
+                addr.map(|addr| addr.parse().unwrap()),
+                ConnectionDirection::Outbound,
+                caps(),
+            )
+        };
+        connect(v4, Some("10.0.0.1:30303"));
+        connect(v6, Some("[::1]:30304"));
+        connect(unknown, None);
+        capability_server
+            .valid_peers
+            .write()
+            .extend([v4, v6, unknown].iter().copied());
+
+        let urls = capability_server.connected_enode_urls();
+        assert_eq!(
+            urls,
+            vec![
+                format!("enode://{}@10.0.0.1:30303", hex::encode(v4.as_bytes())),
+                format!("enode://{}@[::1]:30304", hex::encode(v6.as_bytes())),
+            ]
+        );
+        for url in urls {
+            assert!(url.parse::<NodeRecord>().is_ok());
+        }
+
+        capability_server.teardown_peer(v4, None);
+        assert_eq!(capability_server.connected_enode_urls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn served_accounting() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let eth65 = PeerId::from_low_u64_be(1);
+        let eth66 = PeerId::from_low_u64_be(2);
+        for &(peer, version) in &[(eth65, 65), (eth66, 66)] {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), version)).collect(),
+            );
+            capability_server.mark_valid(peer);
+        }
+
+        let header = |number: u64| rlp::encode_list(&[number]).freeze();
+        for number in 1..=3 {
+            capability_server
+                .header_cache
+                .as_ref()
+                .unwrap()
+                .write()
+                .insert(number, H256::from_low_u64_be(number), header(number));
+        }
+
+        // eth/65 peer is answered from cache.
+        let request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1),
+            max_headers: 3,
+            skip: 0,
+            reverse: false,
+        })
+        .freeze();
+        let reply = capability_server
+            .handle_event(
+                eth65,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::GetBlockHeaders.to_usize().unwrap(),
+                        data: request,
+                    },
+                },
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        let cached_bytes = reply.data.len() as u64;
+
+        // Control answers the eth/66 peer with request ID, and the eth/65 one without.
+        let mut s = RlpStream::new_list(2);
+        s.append_raw(&header(4), 1);
+        s.append_raw(&header(5), 1);
+        let headers = wrap_request_id(7, &s.out());
+        let bodies = rlp::encode_list(&[0_u64]).freeze();
+        for (peer, id, data) in vec![
+            (eth66, EthMessageId::BlockHeaders, headers.clone()),
+            (eth65, EthMessageId::BlockBodies, bodies.clone()),
+            (eth65, EthMessageId::GetBlockHeaders, bodies.clone()),
+        ] {
+            capability_server.on_served(
+                peer,
+                &Message {
+                    id: id.to_usize().unwrap(),
+                    data,
+                },
+                ServedSource::Control,
+            );
+        }
+
+        let count = |items, bytes| ServedCount { items, bytes };
+        assert_eq!(
+            capability_server.served_total(ServedKind::Headers, ServedSource::Cache),
+            count(3, cached_bytes)
+        );
+        assert_eq!(
+            capability_server.served_total(ServedKind::Headers, ServedSource::Control),
+            count(2, headers.len() as u64)
+        );
+        assert_eq!(
+            capability_server.served_total(ServedKind::Bodies, ServedSource::Control),
+            count(1, bodies.len() as u64)
+        );
+        assert_eq!(
+            capability_server.top_consumers(1),
+            vec![(eth65, count(4, cached_bytes + bodies.len() as u64))]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_mode() {
+        let capability_server = CapabilityServerImpl::for_test(&Config {
+            serve_data: false,
+            ..Default::default()
+        });
+        assert!(capability_server.header_cache.is_none());
+        assert!(!capability_server.push_headers(vec![(1, H256::zero(), Bytes::new())]));
+
+        let eth65 = PeerId::from_low_u64_be(1);
+        let eth66 = PeerId::from_low_u64_be(2);
+        for &(peer, version) in &[(eth65, 65), (eth66, 66)] {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), version)).collect(),
+            );
+            capability_server.mark_valid(peer);
+        }
+
+        let request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1),
+            max_headers: 3,
+            skip: 0,
+            reverse: false,
+        })
+        .freeze();
+        let bodies_request = rlp::encode_list(&[H256::repeat_byte(1)]).freeze();
+        // Nobody listens to the control streams, so a forwarded request would fail.
+        for (peer, id, data, reply_id, reply_data) in vec![
+            (
+                eth65,
+                EthMessageId::GetBlockHeaders,
+                request.clone(),
+                EthMessageId::BlockHeaders,
+                Bytes::from_static(&rlp::EMPTY_LIST_RLP),
+            ),
+            (
+                eth66,
+                EthMessageId::GetBlockHeaders,
+                wrap_request_id(7, &request),
+                EthMessageId::BlockHeaders,
+                wrap_request_id(7, &rlp::EMPTY_LIST_RLP),
+            ),
+            (
+                eth66,
+                EthMessageId::GetBlockBodies,
+                wrap_request_id(8, &bodies_request),
+                EthMessageId::BlockBodies,
+                wrap_request_id(8, &rlp::EMPTY_LIST_RLP),
+            ),
+        ] {
+            let reply = capability_server
+                .handle_event(
+                    peer,
+                    InboundEvent::Message {
+                        capability_name: capability_name(),
+                        message: Message {
+                            id: id.to_usize().unwrap(),
+                            data,
+                        },
+                    },
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.id, reply_id.to_usize().unwrap());
+            assert_eq!(reply.data, reply_data);
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_is_torn_down_once_by_swarm() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 66)).collect(),
+        );
+
+        assert!(capability_server.on_stalled_peer(peer));
+        // Sends fail, but the peer is left for the swarm to disconnect.
+        assert!(capability_server
+            .sender(peer)
+            .unwrap()
+            .send(OutboundEvent::Disconnect {
+                reason: DisconnectReason::TcpSubsystemError,
+            })
             .await
-            .map_err(|e| anyhow!("{}", e))
-            .context("Failed to start discv5")?;
-        info!("Starting discv5 at {}", discv5_opts.addr);
+            .is_err());
+        assert_eq!(capability_server.connected_peers(), 1);
 
-        for bootnode in discv5_opts.bootnodes {
-            svc.add_enr(bootnode).unwrap();
+        capability_server
+            .on_peer_event(
+                peer,
+                InboundEvent::Disconnect {
+                    reason: Some(DisconnectReason::TcpSubsystemError),
+                },
+            )
+            .await;
+        assert_eq!(capability_server.connected_peers(), 0);
+        assert!(!capability_server.on_stalled_peer(peer));
+    }
+
+    #[tokio::test]
+    async fn refreshed_peer_reconnects() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let peer = PeerId::from_low_u64_be(1);
+        let connect = || {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), 65)).collect(),
+            )
+        };
+
+        assert!(capability_server
+            .refresh_peer(peer, Duration::from_millis(10))
+            .await
+            .is_err());
+
+        connect();
+        assert!(!capability_server
+            .refresh_peer(peer, Duration::from_millis(10))
+            .await
+            .unwrap());
+        assert!(capability_server.reconnect_waiters.lock().is_empty());
+
+        // Peer has not picked up the previous disconnect, start a new session.
+        capability_server
+            .on_peer_event(peer, InboundEvent::Disconnect { reason: None })
+            .await;
+        connect();
+
+        let refresh = tokio::spawn({
+            let capability_server = capability_server.clone();
+            async move {
+                capability_server
+                    .refresh_peer(peer, Duration::from_secs(5))
+                    .await
+            }
+        });
+        while capability_server.reconnect_waiters.lock().is_empty() {
+            tokio::task::yield_now().await;
         }
-        discovery_tasks.insert("discv5".to_string(), Box::pin(Discv5::new(svc, 20)));
+        capability_server
+            .on_peer_event(peer, InboundEvent::Disconnect { reason: None })
+            .await;
+        connect();
+
+        assert!(refresh.await.unwrap().unwrap());
     }
 
-    if !opts.reserved_peers.is_empty() {
-        info!("Enabling reserved peers: {:?}", opts.reserved_peers);
-        discovery_tasks.insert(
-            "reserved peers".to_string(),
-            Box::pin(Bootnodes::from(
-                opts.reserved_peers
-                    .iter()
-                    .map(|&NR(NodeRecord { addr, id })| (addr, id))
-                    .collect::<HashMap<_, _>>(),
-            )),
+    #[test]
+    fn control_status_overrides_web3_status() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let chain = ChainConfig::mainnet();
+        let status = |best_block| StatusData {
+            network_id: 1,
+            total_difficulty: 1_000_000.into(),
+            best_hash: H256::repeat_byte(1),
+            best_block,
+            fork_data: Forks {
+                genesis: chain.genesis_hash,
+                forks: chain.fork_blocks.iter().copied().collect(),
+            },
+        };
+        let best_block = || capability_server.status().unwrap().status.best_block;
+
+        assert!(capability_server.set_web3_status(status(1)));
+        assert_eq!(best_block(), 1);
+        capability_server.set_control_status(status(2));
+        assert!(!capability_server.set_web3_status(status(3)));
+        assert_eq!(best_block(), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshed_peer_starts_fresh_session() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config {
+            max_reconnects_per_minute: 1,
+            ..Default::default()
+        }));
+        let chain = ChainConfig::mainnet();
+        capability_server.set_status(StatusData {
+            network_id: 1,
+            total_difficulty: 1_000_000.into(),
+            best_hash: H256::repeat_byte(1),
+            best_block: 10_000,
+            fork_data: Forks {
+                genesis: chain.genesis_hash,
+                forks: chain.fork_blocks.iter().copied().collect(),
+            },
+        });
+        let redial_exemptions = RedialExemptions::default();
+        capability_server.attach_redial_exemptions(redial_exemptions.clone());
+
+        let connect = {
+            let capability_server = capability_server.clone();
+            move |peer| {
+                capability_server.on_peer_connect(
+                    peer,
+                    None,
+                    ConnectionDirection::Outbound,
+                    std::iter::once((capability_name(), 66)).collect(),
+                )
+            }
+        };
+
+        // Disconnect cannot be queued behind a full queue, refresh fails at once.
+        let stalled = PeerId::from_low_u64_be(2);
+        connect(stalled);
+        let sender = capability_server.sender(stalled).unwrap();
+        while sender
+            .try_send(OutboundEvent::Message {
+                capability_name: capability_name(),
+                message: Message {
+                    id: EthMessageId::NewBlockHashes.to_usize().unwrap(),
+                    data: rlp::EMPTY_LIST_RLP.to_vec().into(),
+                },
+            })
+            .is_ok()
+        {}
+        assert!(capability_server
+            .refresh_peer(stalled, Duration::from_secs(5))
+            .await
+            .is_err());
+        assert!(capability_server.reconnect_waiters.lock().is_empty());
+
+        let peer = PeerId::from_low_u64_be(1);
+        connect(peer);
+
+        // Scripted peer drops the connection when asked to and dials back at once.
+        let script = tokio::spawn({
+            let capability_server = capability_server.clone();
+            async move {
+                loop {
+                    if let OutboundEvent::Disconnect { reason } = capability_server.next(peer).await
+                    {
+                        capability_server
+                            .on_peer_event(
+                                peer,
+                                InboundEvent::Disconnect {
+                                    reason: Some(reason),
+                                },
+                            )
+                            .await;
+                        connect(peer);
+                    }
+                }
+            }
+        });
+
+        for _ in 0..2 {
+            capability_server
+                .block_tracker
+                .write()
+                .set_block_number(peer, 1_000, true);
+            assert!(capability_server
+                .refresh_peer(peer, Duration::from_secs(5))
+                .await
+                .unwrap());
+            assert!(redial_exemptions.is_exempt(peer, Instant::now()));
+            // Session is not resumed.
+            assert_eq!(
+                capability_server.block_tracker.read().block_number(peer),
+                Some(0)
+            );
+        }
+        assert!(capability_server.reconnect_rates().is_empty());
+        script.abort();
+    }
+
+    #[tokio::test]
+    async fn replaced_persistent_label_is_saved() {
+        let path = std::env::temp_dir().join(format!("sentry-labels-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let capability_server = CapabilityServerImpl::for_test(&Config {
+            peers_file: Some(path.clone()),
+            ..Default::default()
+        });
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 66)).collect(),
         );
+        let saved = || persistence::load::<PeersFile>(&path).unwrap().peer_labels();
+
+        capability_server
+            .set_peer_label(peer, "k".into(), "a".into(), true)
+            .unwrap();
+        assert_eq!(saved()[&peer]["k"], "a");
+
+        capability_server
+            .set_peer_label(peer, "k".into(), "b".into(), false)
+            .unwrap();
+        assert!(saved().is_empty());
+        assert_eq!(capability_server.peer_labels(peer)["k"], "b");
+
+        assert!(capability_server
+            .set_peer_label(PeerId::from_low_u64_be(2), "k".into(), "a".into(), true)
+            .is_err());
+
+        std::fs::remove_file(&path).unwrap();
     }
 
-    let tasks = Arc::new(TaskGroup::new());
+    #[tokio::test]
+    async fn disconnect_policy() {
+        let server = |disconnect_policy| {
+            CapabilityServerImpl::for_test(&Config {
+                disconnect_policy,
+                ..Default::default()
+            })
+        };
+        let peer = PeerId::from_low_u64_be(1);
+        let message = |id: usize| InboundEvent::Message {
+            capability_name: capability_name(),
+            message: Message {
+                id,
+                data: Bytes::from_static(&[0xff]),
+            },
+        };
+        let status = EthMessageId::Status.to_usize().unwrap();
 
-    let data_sender = broadcast(opts.max_peers * BUFFERING_FACTOR).0;
-    let upload_requests_sender = broadcast(opts.max_peers * BUFFERING_FACTOR).0;
-    let tx_message_sender = broadcast(opts.max_peers * BUFFERING_FACTOR).0;
-    let capability_server = Arc::new(CapabilityServerImpl {
-        peer_pipes: Default::default(),
-        block_tracker: Default::default(),
-        status_message: Default::default(),
-        valid_peers: Default::default(),
-        data_sender,
-        upload_requests_sender,
-        tx_message_sender,
-    });
+        let strict = server(vec![]);
+        assert!(matches!(
+            strict.handle_event(peer, message(status)).await,
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+        assert!(matches!(
+            strict.handle_event(peer, message(0x20)).await,
+            Ok(None)
+        ));
 
-    let swarm = Swarm::builder()
-        .with_task_group(tasks.clone())
-        .with_listen_options(ListenOptions {
-            discovery_tasks,
-            max_peers: opts.max_peers,
-            addr: listen_addr.parse().unwrap(),
-            cidr: opts.cidr,
+        let lenient = server(vec![
+            DisconnectPolicyConfig {
+                message_id: status,
+                on_bad_rlp: Some(DisconnectAction::Skip),
+                on_unknown_message: None,
+            },
+            DisconnectPolicyConfig {
+                message_id: 0x20,
+                on_bad_rlp: None,
+                on_unknown_message: Some(DisconnectAction::Disconnect),
+            },
+        ]);
+        assert!(matches!(
+            lenient.handle_event(peer, message(status)).await,
+            Ok(None)
+        ));
+        assert!(matches!(
+            lenient.handle_event(peer, message(0x20)).await,
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_id_normalization() {
+        let capability_server = CapabilityServerImpl::for_test(&Config {
+            normalize_request_ids: true,
+            ..Default::default()
+        });
+        let old = PeerId::from_low_u64_be(1);
+        let new = PeerId::from_low_u64_be(2);
+        for (peer, version) in [(old, 65), (new, 68)].iter().copied() {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), version)).collect(),
+            );
+            capability_server.mark_valid(peer);
+        }
+        let mut upload_requests = capability_server.upload_requests_sender.subscribe();
+
+        let headers_request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1),
+            max_headers: 1,
+            skip: 0,
+            reverse: false,
         })
-        .with_client_version(format!("sentry/v{}", env!("CARGO_PKG_VERSION")))
-        .build(
-            btreemap! {
-                CapabilityId { name: capability_name(), version: 65 } => 17,
+        .freeze();
+        capability_server
+            .handle_event(
+                new,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::GetBlockHeaders.to_usize().unwrap(),
+                        data: wrap_request_id(42, &headers_request),
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            upload_requests.recv().await.unwrap().message.data,
+            headers_request
+        );
+
+        // State sync messages were removed in eth/67.
+        assert!(matches!(
+            capability_server
+                .handle_event(
+                    new,
+                    InboundEvent::Message {
+                        capability_name: capability_name(),
+                        message: Message {
+                            id: EthMessageId::GetNodeData.to_usize().unwrap(),
+                            data: wrap_request_id(43, &rlp::encode_list(&[H256::zero()])),
+                        },
+                    },
+                )
+                .await,
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+
+        // Response is framed with the ID of the request it answers.
+        let headers = RlpStream::new_list(0).out().freeze();
+        let id = EthMessageId::BlockHeaders.to_usize().unwrap();
+        assert_eq!(
+            capability_server.frame_outbound_message(new, id, headers.clone()),
+            Some(wrap_request_id(42, &headers))
+        );
+        assert_eq!(
+            capability_server.frame_outbound_message(new, id, headers.clone()),
+            None
+        );
+        assert_eq!(
+            capability_server.frame_outbound_message(old, id, headers.clone()),
+            Some(headers)
+        );
+
+        // Request fanned out to both peers.
+        let bodies_request = rlp::encode_list(&[H256::repeat_byte(1)]).freeze();
+        let id = EthMessageId::GetBlockBodies.to_usize().unwrap();
+        for peer in [old, new].iter().copied() {
+            assert!(capability_server
+                .validate_outbound_message(peer, id, &bodies_request)
+                .is_ok());
+        }
+        assert_eq!(
+            capability_server.frame_outbound_message(old, id, bodies_request.clone()),
+            Some(bodies_request.clone())
+        );
+        let framed = capability_server
+            .frame_outbound_message(new, id, bodies_request.clone())
+            .unwrap();
+        let (request_id, payload) = unwrap_request_id(&framed).unwrap();
+        assert_eq!(payload, bodies_request);
+        assert!(validate_message(EthMessageId::GetBlockBodies, 68, &framed).is_ok());
+
+        // Reply is forwarded without the ID, replies to no request of ours are dropped.
+        let mut data = capability_server.data_sender.subscribe();
+        let bodies = RlpStream::new_list(0).out().freeze();
+        let reply = |request_id| InboundEvent::Message {
+            capability_name: capability_name(),
+            message: Message {
+                id: EthMessageId::BlockBodies.to_usize().unwrap(),
+                data: wrap_request_id(request_id, &bodies),
             },
-            capability_server.clone(),
-            secret_key,
-        )
-        .await
-        .context("Failed to start RLPx node")?;
+        };
+        for request_id in [request_id + 1, request_id, request_id].iter().copied() {
+            capability_server
+                .handle_event(new, reply(request_id))
+                .await
+                .unwrap();
+        }
+        assert_eq!(data.recv().await.unwrap().message.data, bodies);
+        assert!(futures::FutureExt::now_or_never(data.recv()).is_none());
+    }
 
-    info!("RLPx node listening at {}", listen_addr);
+    #[tokio::test]
+    async fn pooled_transactions_from_pool() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 66)).collect(),
+        );
+        capability_server.mark_valid(peer);
+
+        let tx = rlp::encode_list(&[1_u64, 2, 3]).freeze();
+        capability_server.on_transactions_sent(
+            peer,
+            &Message {
+                id: EthMessageId::Transactions.to_usize().unwrap(),
+                data: encode_raw_list(&[tx.clone()]),
+            },
+        );
+
+        // Unknown transactions are left out of the reply.
+        let hashes = [util::keccak256(&tx), H256::zero()];
+        let reply = capability_server
+            .handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::GetPooledTransactions.to_usize().unwrap(),
+                        data: wrap_request_id(5, &rlp::encode_list(&hashes)),
+                    },
+                },
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            reply.id,
+            EthMessageId::PooledTransactions.to_usize().unwrap()
+        );
+        assert_eq!(reply.data, wrap_request_id(5, &encode_raw_list(&[tx])));
+    }
+
+    #[test]
+    fn empty_replies_of_peer_with_unknown_head_count() {
+        let capability_server = CapabilityServerImpl::for_test(&Config {
+            response_quality: ResponseQualityConfig {
+                min_samples: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Outbound,
+            std::iter::once((capability_name(), 65)).collect(),
+        );
+
+        let request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1_000),
+            max_headers: 16,
+            skip: 0,
+            reverse: false,
+        });
+        for _ in 0..2 {
+            capability_server.on_message_sent(
+                peer,
+                EthMessageId::GetBlockHeaders.to_usize().unwrap(),
+                &request,
+                true,
+            );
+            capability_server.on_headers_response(peer, &rlp::EMPTY_LIST_RLP, false);
+        }
+        assert!(capability_server.without_demoted(vec![peer]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_peers_stream_follows_validation() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let connect = |peer| {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), 66)).collect(),
+            )
+        };
+        let publish = || {
+            capability_server
+                .peer_watch
+                .publish(capability_server.peer_snapshot())
+        };
+        let (first, second, third) = (
+            PeerId::from_low_u64_be(1),
+            PeerId::from_low_u64_be(2),
+            PeerId::from_low_u64_be(3),
+        );
+        connect(first);
+        capability_server.mark_valid(first);
+        connect(second);
+        publish();
 
-    let sentry_addr = opts.sentry_addr.parse()?;
-    tasks.spawn(async move {
-        let svc = SentryServer::new(SentryService::new(capability_server));
+        let mut peers = Box::pin(capability_server.all_peers_stream());
+        assert_eq!(peers.next().await, Some(first));
 
-        info!("Sentry gRPC server starting on {}", sentry_addr);
+        // Not emitted until validated.
+        connect(third);
+        publish();
+        capability_server.mark_valid(second);
+        publish();
+        assert_eq!(peers.next().await, Some(second));
 
-        Server::builder()
-            .add_service(svc)
-            .serve(sentry_addr)
+        drop(capability_server);
+        assert_eq!(peers.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn siblings_share_bans() {
+        let new_sentry = || Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let (a, b) = (new_sentry(), new_sentry());
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(SentryServer::new(SentryService::new(b.clone())))
+                .serve(addr),
+        );
+        let url = format!("http://{}", addr);
+        while sentry::sentry_client::SentryClient::connect(url.clone())
             .await
+            .is_err()
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        // Sibling that accepts connections but never answers does not hold up the other.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("http://{}", silent.local_addr().unwrap());
+
+        let (gossip, updates) = SiblingGossip::new("a".to_string());
+        a.attach_sibling_gossip(gossip);
+        tokio::spawn(run_sibling_gossip(
+            "a".to_string(),
+            vec![silent_url, url],
+            None,
+            updates,
+        ));
+        b.attach_sibling_gossip(SiblingGossip::new("b".to_string()).0);
+
+        let peer = PeerId::from_low_u64_be(1);
+        let wait_for = |sentry: Arc<CapabilityServerImpl>, banned: bool| async move {
+            for _ in 0..100 {
+                if sentry.banned_peers().is_empty() != banned {
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+            sentry.banned_peers()
+        };
+
+        a.ban_peer(peer);
+        let bans = wait_for(b.clone(), true).await;
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, peer);
+        assert_eq!(bans[0].2, BanProvenance::Remote);
+        assert!(bans[0].1 <= Duration::from_secs(600));
+
+        // Sibling refuses the peer banned elsewhere.
+        b.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 66)).collect(),
+        );
+        assert!(matches!(
+            b.next(peer).await,
+            OutboundEvent::Disconnect {
+                reason: DisconnectReason::UselessPeer
+            }
+        ));
+
+        assert!(a.unban_peer(peer));
+        assert!(wait_for(b.clone(), false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn restored_bans_are_clamped_to_ban_ttl() {
+        let path = std::env::temp_dir().join(format!("sentry-bans-{}.json", std::process::id()));
+        let peer = PeerId::from_low_u64_be(1);
+        let far = std::time::SystemTime::now() + Duration::from_secs(365 * 24 * 3600);
+        persistence::save(&BansFile::new(vec![(peer, far)]), &path).unwrap();
+
+        let mut opts = Config::default();
+        opts.bans_file = Some(path.clone());
+        let ban_ttl = Duration::from_secs(opts.siblings.ban_ttl_secs);
+        let capability_server = CapabilityServerImpl::for_test(&opts);
+        capability_server.load_bans_file().unwrap();
+        let bans = capability_server.banned_peers();
+        assert_eq!(bans.len(), 1);
+        assert!(bans[0].1 <= ban_ttl);
+
+        capability_server.save_bans_file().await;
+        let saved = persistence::load::<BansFile>(&path)
+            .unwrap()
+            .bans(std::time::SystemTime::now());
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].1 <= ban_ttl);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn crawl_records_status_and_disconnects() {
+        use ethereum_forkid::{ForkHash, ForkId};
+
+        let output =
+            std::env::temp_dir().join(format!("sentry-crawl-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&output);
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let crawler = Arc::new(Crawler::new(Some(&output)).unwrap());
+        capability_server.attach_crawler(crawler.clone());
+
+        let status = |protocol_version, total_difficulty: u64| StatusMessage {
+            protocol_version,
+            network_id: 1,
+            total_difficulty: total_difficulty.into(),
+            best_hash: H256::repeat_byte(2),
+            genesis_hash: H256::repeat_byte(1),
+            fork_id: ForkId {
+                hash: ForkHash([1, 2, 3, 4]),
+                next: 0,
+            },
+        };
+        // Last node never sends its Status.
+        let nodes = vec![
+            (1, "Geth/v1.10.8", 66, Some(status(66, 100))),
+            (2, "erigon/v2021.08.05", 65, Some(status(65, 200))),
+            (3, "Nethermind/v1.10.79", 66, None),
+        ];
+        for (i, client_version, version, status) in nodes {
+            let peer = PeerId::from_low_u64_be(i);
+            capability_server.on_peer_client_version(peer, client_version);
+            capability_server.on_peer_connect(
+                peer,
+                Some(SocketAddr::new([203, 0, 113, i as u8].into(), 30303)),
+                ConnectionDirection::Outbound,
+                std::iter::once((capability_name(), version)).collect(),
+            );
+            if let Some(status) = status {
+                capability_server
+                    .on_peer_event(
+                        peer,
+                        InboundEvent::Message {
+                            capability_name: capability_name(),
+                            message: Message {
+                                id: EthMessageId::Status.to_usize().unwrap(),
+                                data: status.encode_for_version(),
+                            },
+                        },
+                    )
+                    .await;
+                assert!(matches!(
+                    capability_server.next(peer).await,
+                    OutboundEvent::Disconnect {
+                        reason: DisconnectReason::DisconnectRequested
+                    }
+                ));
+            }
+            capability_server
+                .on_peer_event(peer, InboundEvent::Disconnect { reason: None })
+                .await;
+        }
+
+        assert_eq!(capability_server.valid_peers_count(), 0);
+        let results = crawler.results();
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (
+                    result.client_version.as_deref(),
+                    result.capabilities.clone(),
+                    result.total_difficulty.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some("Geth/v1.10.8"), vec!["eth/66".to_string()], "100"),
+                (
+                    Some("erigon/v2021.08.05"),
+                    vec!["eth/65".to_string()],
+                    "200"
+                ),
+            ]
+        );
+        assert_eq!(
+            results[0].addr,
+            Some(SocketAddr::new([203, 0, 113, 1].into(), 30303))
+        );
+        assert_eq!(results[0].fork_id.as_ref().unwrap().hash, "01020304");
+
+        let mut lines = String::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&output).unwrap();
+            if lines.lines().count() == results.len() && lines.ends_with('\n') {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            lines
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["id"].clone())
+                .collect::<Vec<_>>(),
+            vec![
+                serde_json::json!(results[0].id),
+                serde_json::json!(results[1].id)
+            ]
+        );
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn protocol_breach_record() {
+        let dump_dir = std::env::temp_dir().join(format!("sentry-breaches-{}", std::process::id()));
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config {
+            breach_log: BreachLogConfig {
+                prefix_bytes: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        capability_server.attach_breach_dumps(Arc::new(
+            BreachDumps::new(&dump_dir, BreachLogConfig::default().max_dumps).unwrap(),
+        ));
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 68)).collect(),
+        );
+        capability_server.mark_valid(peer);
+
+        // State sync messages were removed in eth/67.
+        let data = wrap_request_id(43, &rlp::encode_list(&[H256::zero()]));
+        let id = EthMessageId::GetNodeData.to_usize().unwrap();
+        capability_server
+            .on_peer_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id,
+                        data: data.clone(),
+                    },
+                },
+            )
+            .await;
+
+        let records = capability_server.breach_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer, peer);
+        let offending = records[0].messages.last().unwrap();
+        assert_eq!(offending.message_id, id);
+        assert_eq!(offending.size, data.len());
+        assert_eq!(offending.prefix, data.slice(..4));
+        let dump = records[0].dump.as_ref().unwrap();
+        for _ in 0..100 {
+            if std::fs::read(dump).ok().as_deref() == Some(&data[..]) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read(dump).unwrap(), data);
+        std::fs::remove_dir_all(&dump_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn eth68_transaction_announcement_is_not_forwarded() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 68)).collect(),
+        );
+        capability_server.mark_valid(peer);
+        let mut tx_messages = capability_server.tx_message_sender.subscribe();
+
+        let announcement = NewPooledTransactionHashes68 {
+            types: vec![2],
+            sizes: vec![110],
+            hashes: vec![H256::repeat_byte(1)],
+        };
+        let res = capability_server
+            .handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::NewPooledTransactionHashes.to_usize().unwrap(),
+                        data: rlp::encode(&announcement).freeze(),
+                    },
+                },
+            )
+            .await;
+
+        assert!(matches!(res, Ok(None)));
+        assert_eq!(
+            capability_server.pending_tx_size(H256::repeat_byte(1)),
+            Some(110)
+        );
+        assert!(futures::FutureExt::now_or_never(tx_messages.recv()).is_none());
+    }
+
+    #[tokio::test]
+    async fn recorded_headers_are_replayed() {
+        let dir = std::env::temp_dir().join(format!("sentry-replay-{}", std::process::id()));
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 66)).collect(),
+        );
+        capability_server.mark_valid(peer);
+        capability_server.attach_peer_capture(Arc::new(PeerCapture::new(&dir).unwrap()));
+
+        let request = rlp::encode(&GetBlockHeaders {
+            block: BlockId::Number(1),
+            max_headers: 1,
+            skip: 0,
+            reverse: false,
+        });
+        let headers = rlp::encode_list(&[H256::repeat_byte(1)]);
+        capability_server.capture(
+            peer,
+            EthMessageId::GetBlockHeaders,
+            &wrap_request_id(7, &request),
+        );
+        capability_server.on_served(
+            peer,
+            &Message {
+                id: EthMessageId::BlockHeaders.to_usize().unwrap(),
+                data: wrap_request_id(7, &headers),
+            },
+            ServedSource::Control,
+        );
+        for _ in 0..100 {
+            if std::fs::read_dir(&dir).unwrap().count() == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        capability_server.attach_replay(Arc::new(ReplayDataProvider::new(&dir).unwrap()));
+        let res = capability_server
+            .handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::GetBlockHeaders.to_usize().unwrap(),
+                        data: wrap_request_id(8, &request),
+                    },
+                },
+            )
+            .await;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        match res {
+            Ok(Some(reply)) => assert_eq!(reply.data, wrap_request_id(8, &headers)),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn transaction_fetch_is_sorted_by_size() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 68)).collect(),
+        );
+        let (small, big, unknown) = (
+            H256::repeat_byte(1),
+            H256::repeat_byte(2),
+            H256::repeat_byte(3),
+        );
+        let announcement = NewPooledTransactionHashes68 {
+            types: vec![2, 2],
+            sizes: vec![300, 100],
+            hashes: vec![big, small],
+        };
+        capability_server
+            .on_pooled_transaction_hashes(peer, &rlp::encode(&announcement))
             .unwrap();
-    });
 
-    loop {
-        info!(
-            "Peer info: {} active (+{} dialing) / {} max.",
-            swarm.connected_peers(),
-            swarm.dialing(),
-            opts.max_peers
+        let id = EthMessageId::GetPooledTransactions.to_usize().unwrap();
+        let request = wrap_request_id(7, &rlp::encode_list(&[unknown, big, small]));
+        let sorted = capability_server.sort_transaction_fetch(peer, id, request);
+        assert_eq!(
+            sorted,
+            wrap_request_id(7, &rlp::encode_list(&[small, big, unknown]))
+        );
+
+        // Fetched transactions are no longer pending.
+        capability_server.on_message_sent(peer, id, &sorted, false);
+        assert_eq!(capability_server.pending_tx_size(small), None);
+        assert_eq!(capability_server.pending_tx_size(big), None);
+    }
+
+    #[test]
+    fn syncing_peer_is_promoted_on_catch_up() {
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let chain = ChainConfig::mainnet();
+        capability_server.set_status(StatusData {
+            network_id: 1,
+            total_difficulty: 1_000_000.into(),
+            best_hash: H256::repeat_byte(1),
+            best_block: 10_000,
+            fork_data: Forks {
+                genesis: chain.genesis_hash,
+                forks: chain.fork_blocks.iter().copied().collect(),
+            },
+        });
+
+        let peer = PeerId::from_low_u64_be(1);
+        capability_server
+            .block_tracker
+            .write()
+            .set_block_number(peer, 0, true);
+        capability_server.syncing_peers.write().insert(peer);
+        assert!(capability_server.selectable_peers(vec![peer]).is_empty());
+
+        capability_server.set_peer_min_block(peer, 5_000);
+        assert!(capability_server.selectable_peers(vec![peer]).is_empty());
+
+        capability_server.set_peer_min_block(peer, 9_900);
+        assert_eq!(capability_server.selectable_peers(vec![peer]), vec![peer]);
+    }
+
+    #[tokio::test]
+    async fn head_announcement_skips_busy_and_synced_peers() {
+        use futures::FutureExt;
+
+        let capability_server = CapabilityServerImpl::for_test(&Config::default());
+        let chain = ChainConfig::mainnet();
+        capability_server.set_status(StatusData {
+            network_id: 1,
+            total_difficulty: 1_000_000.into(),
+            best_hash: H256::repeat_byte(1),
+            best_block: 10_000,
+            fork_data: Forks {
+                genesis: chain.genesis_hash,
+                forks: chain.fork_blocks.iter().copied().collect(),
+            },
+        });
+
+        let (busy, synced, behind) = (
+            PeerId::from_low_u64_be(1),
+            PeerId::from_low_u64_be(2),
+            PeerId::from_low_u64_be(3),
         );
+        for peer in [busy, synced, behind] {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), 66)).collect(),
+            );
+            capability_server.mark_valid(peer);
+            assert!(matches!(
+                capability_server.next(peer).now_or_never(),
+                Some(OutboundEvent::Message { .. })
+            ));
+        }
+        capability_server
+            .block_tracker
+            .write()
+            .set_block_number(synced, 20_000, true);
+        assert!(capability_server
+            .sender(busy)
+            .unwrap()
+            .try_send(OutboundEvent::Disconnect {
+                reason: DisconnectReason::DisconnectRequested,
+            })
+            .is_ok());
+
+        let observed = Instant::now() - crate::announce::ANNOUNCE_SUPPRESSION_WINDOW;
+        capability_server
+            .head_announcer
+            .lock()
+            .on_new_head(20_000, H256::repeat_byte(2), observed);
+        capability_server.announce_head();
+
+        match capability_server.next(behind).now_or_never() {
+            Some(OutboundEvent::Message {
+                message: Message { id, data },
+                ..
+            }) => {
+                assert_eq!(id, EthMessageId::NewBlockHashes.to_usize().unwrap());
+                assert_eq!(
+                    Rlp::new(&data).at(0).unwrap().val_at::<u64>(1).unwrap(),
+                    20_000
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(capability_server.next(synced).now_or_never().is_none());
+        assert!(matches!(
+            capability_server.next(busy).now_or_never(),
+            Some(OutboundEvent::Disconnect { .. })
+        ));
 
-        sleep(Duration::from_secs(5)).await;
+        // Peers have already relayed the next head, ours is not sent again.
+        let mut s = RlpStream::new_list(1);
+        s.begin_list(2);
+        s.append(&H256::repeat_byte(3));
+        s.append(&20_001_u64);
+        assert!(!capability_server
+            .duplicate_filter
+            .as_ref()
+            .unwrap()
+            .lock()
+            .check(EthMessageId::NewBlockHashes, &s.out(), Instant::now()));
+        capability_server
+            .head_announcer
+            .lock()
+            .on_new_head(20_001, H256::repeat_byte(3), observed);
+        capability_server.announce_head();
+        assert!(capability_server.next(behind).now_or_never().is_none());
     }
 }
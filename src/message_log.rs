@@ -0,0 +1,136 @@
+use devp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long message log of a disconnected peer is kept for inspection.
+pub const DISCONNECTED_LOG_RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerMessageLogEntry {
+    pub direction: Direction,
+    pub message_id: u8,
+    pub size: u32,
+    pub timestamp: Instant,
+}
+
+/// Recent messages exchanged with each peer, for debugging message ordering.
+#[derive(Debug)]
+pub struct PeerMessageLog {
+    capacity: usize,
+    logs: HashMap<PeerId, VecDeque<PeerMessageLogEntry>>,
+    disconnected_peer_logs: HashMap<PeerId, (Instant, VecDeque<PeerMessageLogEntry>)>,
+}
+
+impl PeerMessageLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            logs: Default::default(),
+            disconnected_peer_logs: Default::default(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.disconnected_peer_logs.retain(|_, (disconnected, _)| {
+            now.saturating_duration_since(*disconnected) < DISCONNECTED_LOG_RETENTION
+        });
+    }
+
+    pub fn on_connect(&mut self, peer: PeerId) {
+        if self.capacity > 0 {
+            self.disconnected_peer_logs.remove(&peer);
+            self.logs
+                .insert(peer, VecDeque::with_capacity(self.capacity));
+        }
+    }
+
+    /// Record message. Messages of peers that are not connected are ignored.
+    pub fn record(
+        &mut self,
+        peer: PeerId,
+        direction: Direction,
+        message_id: usize,
+        size: usize,
+        now: Instant,
+    ) {
+        if let Some(log) = self.logs.get_mut(&peer) {
+            if log.len() >= self.capacity {
+                log.pop_front();
+            }
+            log.push_back(PeerMessageLogEntry {
+                direction,
+                message_id: message_id as u8,
+                size: size as u32,
+                timestamp: now,
+            });
+        }
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId, now: Instant) {
+        self.prune(now);
+        if let Some(log) = self.logs.remove(&peer) {
+            self.disconnected_peer_logs.insert(peer, (now, log));
+        }
+    }
+
+    /// Last `last_n` messages of a connected or recently disconnected peer, oldest first.
+    pub fn get(&mut self, peer: PeerId, last_n: usize, now: Instant) -> Vec<PeerMessageLogEntry> {
+        self.prune(now);
+        let log = match self.logs.get(&peer) {
+            Some(log) => log,
+            None => match self.disconnected_peer_logs.get(&peer) {
+                Some((_, log)) => log,
+                None => return vec![],
+            },
+        };
+
+        log.iter()
+            .skip(log.len().saturating_sub(last_n))
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_and_retained_after_disconnect() {
+        let mut log = PeerMessageLog::new(3);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        // Not connected yet.
+        log.record(peer, Direction::In, 0, 10, now);
+        assert!(log.get(peer, 10, now).is_empty());
+
+        log.on_connect(peer);
+        for id in 0..5 {
+            log.record(peer, Direction::Out, id, 10, now);
+        }
+        let ids = |entries: Vec<PeerMessageLogEntry>| {
+            entries
+                .into_iter()
+                .map(|e| e.message_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(log.get(peer, 10, now)), vec![2, 3, 4]);
+        assert_eq!(ids(log.get(peer, 2, now)), vec![3, 4]);
+
+        log.on_disconnect(peer, now);
+        log.record(peer, Direction::In, 5, 10, now);
+        assert_eq!(ids(log.get(peer, 10, now)), vec![2, 3, 4]);
+        assert!(log
+            .get(peer, 10, now + DISCONNECTED_LOG_RETENTION)
+            .is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use devp2p::{
+    util::{id2pk, keccak256},
+    PeerId,
+};
+use rlp::{Rlp, RlpStream};
+use secp256k1::{Message, SecretKey, Signature, SECP256K1};
+
+/// Digest signed for a message: its ID followed by its payload without signature.
+fn digest(id: usize, payload: &[u8]) -> anyhow::Result<Message> {
+    let mut data = Vec::with_capacity(8 + payload.len());
+    data.extend_from_slice(&(id as u64).to_be_bytes());
+    data.extend_from_slice(payload);
+    Ok(Message::from_slice(keccak256(&data).as_bytes())?)
+}
+
+/// Signs outbound messages with the node key and checks signatures of inbound ones,
+/// carried as an extra last item of the message's RLP list.
+#[derive(Clone)]
+pub struct MessageSigner {
+    secret_key: SecretKey,
+}
+
+impl std::fmt::Debug for MessageSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageSigner(..)")
+    }
+}
+
+impl MessageSigner {
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self { secret_key }
+    }
+
+    pub fn sign(&self, id: usize, payload: &[u8]) -> anyhow::Result<Bytes> {
+        let rlp = Rlp::new(payload);
+        if !rlp.is_list() {
+            bail!("Payload is not a list");
+        }
+        let signature = SECP256K1.sign(&digest(id, payload)?, &self.secret_key);
+
+        let mut s = RlpStream::new_list(rlp.item_count()? + 1);
+        for item in rlp.iter() {
+            s.append_raw(item.as_raw(), 1);
+        }
+        s.append(&&signature.serialize_compact()[..]);
+        Ok(s.out().freeze())
+    }
+
+    /// Check signature of a message from `peer` and strip it from the payload.
+    pub fn verify(&self, peer: PeerId, id: usize, data: &[u8]) -> anyhow::Result<Bytes> {
+        let rlp = Rlp::new(data);
+        let item_count = rlp.item_count()?;
+        if item_count == 0 {
+            bail!("Message is not signed");
+        }
+
+        let mut s = RlpStream::new_list(item_count - 1);
+        for item in rlp.iter().take(item_count - 1) {
+            s.append_raw(item.as_raw(), 1);
+        }
+        let payload = s.out().freeze();
+
+        let signature = Signature::from_compact(rlp.at(item_count - 1)?.data()?)
+            .map_err(|e| anyhow!("Malformed signature: {}", e))?;
+        SECP256K1
+            .verify(&digest(id, &payload)?, &signature, &id2pk(peer)?)
+            .map_err(|_| anyhow!("Signature does not match peer"))?;
+
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devp2p::util::pk2id;
+    use secp256k1::PublicKey;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let peer = pk2id(&PublicKey::from_secret_key(SECP256K1, &key));
+        let other = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let signer = MessageSigner::new(key);
+
+        let payload = rlp::encode_list(&[1_u64, 2, 3]).freeze();
+        let signed = signer.sign(0x03, &payload).unwrap();
+        assert_eq!(Rlp::new(&signed).item_count().unwrap(), 4);
+        assert_eq!(signer.verify(peer, 0x03, &signed).unwrap(), payload);
+
+        // Signature covers message ID and payload, and only the peer's key matches.
+        assert!(signer.verify(peer, 0x04, &signed).is_err());
+        assert!(signer.verify(peer, 0x03, &payload).is_err());
+        let forged = MessageSigner::new(other).sign(0x03, &payload).unwrap();
+        assert!(signer.verify(peer, 0x03, &forged).is_err());
+
+        assert!(signer.sign(0x03, &rlp::encode(&1_u64)).is_err());
+    }
+}
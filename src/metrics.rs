@@ -0,0 +1,649 @@
+use crate::{
+    churn::ChurnRate,
+    eth::{EthMessageId, StatusChange},
+    forwarding::QueueStats,
+    grpc::sentry,
+    lifetimes::DisconnectCause,
+    persistence::{CounterSample, MetricsFile},
+    served::{ServedCount, ServedKind, ServedSource},
+    services::SendStatus,
+};
+use anyhow::Context;
+use devp2p::{AcceptErrorClass, AcceptErrorStats, ConnectionDirection, HandshakeStats};
+use hyper::{
+    header::CONTENT_TYPE,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use num_traits::FromPrimitive;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::*;
+
+/// Message size buckets: 100B, 1KB, 10KB, 100KB, 1MB.
+const MESSAGE_SIZE_BUCKETS: &[f64] = &[100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0];
+
+/// Connection lifetime buckets: 1s, 10s, 1min, 5min, 15min, 30min, 1h, 3h, 6h, 12h, 24h.
+const LIFETIME_BUCKETS: &[f64] = &[
+    1.0, 10.0, 60.0, 300.0, 900.0, 1_800.0, 3_600.0, 10_800.0, 21_600.0, 43_200.0, 86_400.0,
+];
+
+/// Forwarding stage buckets: 100us, 1ms, 10ms, 100ms, 1s, 10s.
+const STAGE_LATENCY_BUCKETS: &[f64] = &[0.000_1, 0.001, 0.01, 0.1, 1.0, 10.0];
+
+/// Counters kept across restarts if `metrics_file` is set. Histograms and gauges never are.
+const PERSISTENT_COUNTERS: &[&str] = &[
+    "sentry_coalesced_requests_total",
+    "sentry_header_miss_cache_hits_total",
+    "sentry_served_items_total",
+    "sentry_served_bytes_total",
+];
+
+/// Label of persistent counters with the number of restarts they have been carried over.
+/// Restored values start a new series, so that rates do not see them as a jump.
+pub const RESTARTS_LABEL: &str = "restarts";
+
+fn forwarded_message_type(id: i32) -> String {
+    sentry::MessageId::from_i32(id)
+        .map(|id| format!("{:?}", id))
+        .unwrap_or_else(|| "Unknown".into())
+}
+
+fn message_type(id: usize) -> String {
+    EthMessageId::from_usize(id)
+        .map(|id| format!("{:?}", id))
+        .unwrap_or_else(|| "Unknown".into())
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    /// Set if counters are persisted.
+    restarts: Option<u32>,
+    peers_by_protocol_version: IntGaugeVec,
+    peer_churn_rate: IntGaugeVec,
+    pub coalesced_requests: IntCounter,
+    pub header_miss_cache_hits: IntCounter,
+    pub unknown_peer_events: IntCounter,
+    pub resumed_sessions: IntCounter,
+    pub rejected_reconnects: IntCounter,
+    pub rejected_asn_peers: IntCounter,
+    pub rejected_api_calls: IntCounter,
+    pub large_forwarded_messages: IntCounter,
+    pub stalled_peers: IntCounter,
+    inbound_message_bytes: HistogramVec,
+    outbound_message_bytes: HistogramVec,
+    served_items: IntCounterVec,
+    served_bytes: IntCounterVec,
+    duplicate_messages_filtered: IntCounterVec,
+    connection_lifetime_seconds: HistogramVec,
+    control_send_failures: IntCounterVec,
+    status_updates: IntCounterVec,
+    accept_errors: IntCounterVec,
+    handshake_failures: IntCounterVec,
+    forward_stage_seconds: HistogramVec,
+    forward_queue_depth: IntGaugeVec,
+    forward_queue_oldest_seconds: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_restarts(None)
+    }
+
+    /// Metrics with persistent counters continuing from `snapshot`.
+    pub fn restore(snapshot: &MetricsFile) -> anyhow::Result<Self> {
+        let restarts = if snapshot.counters.is_empty() {
+            0
+        } else {
+            snapshot.restarts + 1
+        };
+        let metrics = Self::with_restarts(Some(restarts))?;
+
+        for (name, samples) in &snapshot.counters {
+            for CounterSample { labels, value } in samples {
+                let labels = labels
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect::<HashMap<_, _>>();
+                let counter = match name.as_str() {
+                    "sentry_coalesced_requests_total" => Ok(metrics.coalesced_requests.clone()),
+                    "sentry_header_miss_cache_hits_total" => {
+                        Ok(metrics.header_miss_cache_hits.clone())
+                    }
+                    "sentry_served_items_total" => metrics.served_items.get_metric_with(&labels),
+                    "sentry_served_bytes_total" => metrics.served_bytes.get_metric_with(&labels),
+                    _ => {
+                        debug!("Not restoring counter {}", name);
+                        continue;
+                    }
+                };
+                // Labels may have changed since the file was written.
+                match counter {
+                    Ok(counter) => counter.inc_by(*value),
+                    Err(e) => warn!(
+                        "Not restoring counter {} with labels {:?}: {}",
+                        name, labels, e
+                    ),
+                }
+            }
+        }
+
+        Ok(metrics)
+    }
+
+    fn with_restarts(restarts: Option<u32>) -> anyhow::Result<Self> {
+        let registry = Registry::new();
+        let counter_opts = |name: &str, help: &str| {
+            let opts = Opts::new(name, help);
+            match restarts {
+                Some(restarts) if PERSISTENT_COUNTERS.contains(&name) => {
+                    opts.const_label(RESTARTS_LABEL, restarts.to_string())
+                }
+                _ => opts,
+            }
+        };
+
+        let peers_by_protocol_version = IntGaugeVec::new(
+            Opts::new(
+                "sentry_peers_by_protocol_version",
+                "Number of peers by negotiated eth protocol version",
+            ),
+            &["version"],
+        )?;
+        registry.register(Box::new(peers_by_protocol_version.clone()))?;
+
+        let peer_churn_rate = IntGaugeVec::new(
+            Opts::new(
+                "sentry_peer_churn_rate_per_minute",
+                "Peer connects and disconnects over the last minute",
+            ),
+            &["type"],
+        )?;
+        registry.register(Box::new(peer_churn_rate.clone()))?;
+
+        let coalesced_requests = IntCounter::with_opts(counter_opts(
+            "sentry_coalesced_requests_total",
+            "Peer requests answered with reply to an identical pending request",
+        ))?;
+        registry.register(Box::new(coalesced_requests.clone()))?;
+
+        let header_miss_cache_hits = IntCounter::with_opts(counter_opts(
+            "sentry_header_miss_cache_hits_total",
+            "GetBlockHeaders answered with no headers without asking the control",
+        ))?;
+        registry.register(Box::new(header_miss_cache_hits.clone()))?;
+
+        let unknown_peer_events = IntCounter::new(
+            "sentry_unknown_peer_events_total",
+            "Events dropped because they arrived for a peer that is not set up",
+        )?;
+        registry.register(Box::new(unknown_peer_events.clone()))?;
+
+        let resumed_sessions = IntCounter::new(
+            "sentry_resumed_sessions_total",
+            "Peers that reconnected shortly after disconnecting and resumed their previous session",
+        )?;
+        registry.register(Box::new(resumed_sessions.clone()))?;
+
+        let rejected_reconnects = IntCounter::new(
+            "sentry_rejected_reconnects_total",
+            "Connections rejected because the peer reconnects too often",
+        )?;
+        registry.register(Box::new(rejected_reconnects.clone()))?;
+
+        let rejected_asn_peers = IntCounter::new(
+            "sentry_rejected_asn_peers_total",
+            "Inbound peers rejected because their autonomous system has too many peers",
+        )?;
+        registry.register(Box::new(rejected_asn_peers.clone()))?;
+
+        let rejected_api_calls = IntCounter::new(
+            "sentry_rejected_api_calls_total",
+            "Sentry API calls and streams rejected for a missing or invalid API token",
+        )?;
+        registry.register(Box::new(rejected_api_calls.clone()))?;
+
+        let large_forwarded_messages = IntCounter::new(
+            "sentry_large_forwarded_messages_total",
+            "Peer messages forwarded to the control above the large message threshold",
+        )?;
+        registry.register(Box::new(large_forwarded_messages.clone()))?;
+
+        let duplicate_messages_filtered = IntCounterVec::new(
+            Opts::new(
+                "sentry_duplicate_messages_filtered_total",
+                "Block announcements not forwarded to the control because other peers sent enough copies first, by message type",
+            ),
+            &["message_type"],
+        )?;
+        registry.register(Box::new(duplicate_messages_filtered.clone()))?;
+
+        let stalled_peers = IntCounter::new(
+            "sentry_stalled_peers_total",
+            "Peers disconnected for not reading their outbound queue",
+        )?;
+        registry.register(Box::new(stalled_peers.clone()))?;
+
+        let inbound_message_bytes = HistogramVec::new(
+            HistogramOpts::new(
+                "sentry_inbound_message_bytes",
+                "Size of messages received from peers, before snappy compression",
+            )
+            .buckets(MESSAGE_SIZE_BUCKETS.to_vec()),
+            &["message_type"],
+        )?;
+        registry.register(Box::new(inbound_message_bytes.clone()))?;
+
+        let outbound_message_bytes = HistogramVec::new(
+            HistogramOpts::new(
+                "sentry_outbound_message_bytes",
+                "Size of messages sent to peers, before snappy compression",
+            )
+            .buckets(MESSAGE_SIZE_BUCKETS.to_vec()),
+            &["message_type"],
+        )?;
+        registry.register(Box::new(outbound_message_bytes.clone()))?;
+
+        let served_items = IntCounterVec::new(
+            counter_opts(
+                "sentry_served_items_total",
+                "Headers and bodies served to peers, by where the response came from",
+            ),
+            &["kind", "source"],
+        )?;
+        registry.register(Box::new(served_items.clone()))?;
+
+        let served_bytes = IntCounterVec::new(
+            counter_opts(
+                "sentry_served_bytes_total",
+                "Size of header and body responses served to peers, by where the response came from",
+            ),
+            &["kind", "source"],
+        )?;
+        registry.register(Box::new(served_bytes.clone()))?;
+
+        let connection_lifetime_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "sentry_connection_lifetime_seconds",
+                "How long peer connections lasted, by direction and disconnect cause",
+            )
+            .buckets(LIFETIME_BUCKETS.to_vec()),
+            &["direction", "cause"],
+        )?;
+        registry.register(Box::new(connection_lifetime_seconds.clone()))?;
+
+        let control_send_failures = IntCounterVec::new(
+            Opts::new(
+                "sentry_control_send_failures_total",
+                "Peers a message from the control was not sent to, by reason",
+            ),
+            &["status"],
+        )?;
+        registry.register(Box::new(control_send_failures.clone()))?;
+
+        let status_updates = IntCounterVec::new(
+            Opts::new(
+                "sentry_status_updates_total",
+                "Status updates from the control, by how they change our fork ID",
+            ),
+            &["change"],
+        )?;
+        registry.register(Box::new(status_updates.clone()))?;
+
+        let accept_errors = IntCounterVec::new(
+            Opts::new(
+                "sentry_accept_errors_total",
+                "Errors accepting inbound connections, by class",
+            ),
+            &["class"],
+        )?;
+        registry.register(Box::new(accept_errors.clone()))?;
+
+        let handshake_failures = IntCounterVec::new(
+            Opts::new(
+                "sentry_handshake_failures_total",
+                "Failed RLPx handshakes, by reason",
+            ),
+            &["reason"],
+        )?;
+        registry.register(Box::new(handshake_failures.clone()))?;
+
+        let forward_stage_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "sentry_forward_stage_seconds",
+                "Time messages forwarded to the control spend in each stage, by queue and message type",
+            )
+            .buckets(STAGE_LATENCY_BUCKETS.to_vec()),
+            &["queue", "stage", "message_type"],
+        )?;
+        registry.register(Box::new(forward_stage_seconds.clone()))?;
+
+        let forward_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "sentry_forward_queue_depth",
+                "Messages not yet taken by the slowest control subscription",
+            ),
+            &["queue"],
+        )?;
+        registry.register(Box::new(forward_queue_depth.clone()))?;
+
+        let forward_queue_oldest_seconds = GaugeVec::new(
+            Opts::new(
+                "sentry_forward_queue_oldest_seconds",
+                "How long the oldest message not yet taken by the control has been queued",
+            ),
+            &["queue"],
+        )?;
+        registry.register(Box::new(forward_queue_oldest_seconds.clone()))?;
+
+        Ok(Self {
+            registry,
+            restarts,
+            peers_by_protocol_version,
+            peer_churn_rate,
+            coalesced_requests,
+            header_miss_cache_hits,
+            unknown_peer_events,
+            resumed_sessions,
+            rejected_reconnects,
+            rejected_asn_peers,
+            rejected_api_calls,
+            large_forwarded_messages,
+            duplicate_messages_filtered,
+            stalled_peers,
+            inbound_message_bytes,
+            outbound_message_bytes,
+            served_items,
+            served_bytes,
+            connection_lifetime_seconds,
+            control_send_failures,
+            status_updates,
+            accept_errors,
+            handshake_failures,
+            forward_stage_seconds,
+            forward_queue_depth,
+            forward_queue_oldest_seconds,
+        })
+    }
+
+    pub fn observe_served(&self, kind: ServedKind, source: ServedSource, count: ServedCount) {
+        let labels = [kind.as_str(), source.as_str()];
+        self.served_items
+            .with_label_values(&labels)
+            .inc_by(count.items);
+        self.served_bytes
+            .with_label_values(&labels)
+            .inc_by(count.bytes);
+    }
+
+    pub fn set_peers_by_protocol_version(&self, peers: &HashMap<u8, usize>) {
+        self.peers_by_protocol_version.reset();
+        for (version, count) in peers {
+            self.peers_by_protocol_version
+                .with_label_values(&[&version.to_string()])
+                .set(*count as i64);
+        }
+    }
+
+    pub fn set_peer_churn_rate(&self, rate: ChurnRate) {
+        self.peer_churn_rate
+            .with_label_values(&["connect"])
+            .set(rate.connects as i64);
+        self.peer_churn_rate
+            .with_label_values(&["disconnect"])
+            .set(rate.disconnects as i64);
+    }
+
+    pub fn observe_connection_lifetime(
+        &self,
+        direction: ConnectionDirection,
+        cause: DisconnectCause,
+        lifetime: Duration,
+    ) {
+        self.connection_lifetime_seconds
+            .with_label_values(&[direction.as_str(), cause.as_str()])
+            .observe(lifetime.as_secs_f64());
+    }
+
+    pub fn observe_send_failures(&self, status: SendStatus, count: usize) {
+        self.control_send_failures
+            .with_label_values(&[status.as_str()])
+            .inc_by(count as u64);
+    }
+
+    pub fn observe_status_update(&self, change: StatusChange) {
+        self.status_updates
+            .with_label_values(&[change.as_str()])
+            .inc();
+    }
+
+    /// Catch up with error counts kept by the swarm.
+    pub fn set_accept_errors(&self, stats: &AcceptErrorStats) {
+        for (class, total) in [
+            (
+                AcceptErrorClass::ResourceExhausted,
+                stats.resource_exhausted,
+            ),
+            (AcceptErrorClass::Aborted, stats.aborted),
+            (AcceptErrorClass::Fatal, stats.fatal.is_some() as u64),
+        ]
+        .iter()
+        {
+            let counter = self.accept_errors.with_label_values(&[class.as_str()]);
+            counter.inc_by(total.saturating_sub(counter.get()));
+        }
+    }
+
+    pub fn set_handshake_failures(&self, stats: &HandshakeStats) {
+        let counter = self
+            .handshake_failures
+            .with_label_values(&["identity_mismatch"]);
+        counter.inc_by(stats.identity_mismatches.saturating_sub(counter.get()));
+    }
+
+    pub fn observe_duplicate_filtered(&self, id: EthMessageId) {
+        self.duplicate_messages_filtered
+            .with_label_values(&[&format!("{:?}", id)])
+            .inc();
+    }
+
+    pub fn observe_inbound_message(&self, id: usize, len: usize) {
+        self.inbound_message_bytes
+            .with_label_values(&[&message_type(id)])
+            .observe(len as f64);
+    }
+
+    pub fn observe_outbound_message(&self, id: usize, len: usize) {
+        self.outbound_message_bytes
+            .with_label_values(&[&message_type(id)])
+            .observe(len as f64);
+    }
+
+    pub fn observe_forward_stage(
+        &self,
+        queue: &str,
+        stage: &str,
+        id: i32,
+        elapsed: Option<Duration>,
+    ) {
+        if let Some(elapsed) = elapsed {
+            self.forward_stage_seconds
+                .with_label_values(&[queue, stage, &forwarded_message_type(id)])
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn set_forward_queue(&self, queue: &str, stats: QueueStats) {
+        self.forward_queue_depth
+            .with_label_values(&[queue])
+            .set(stats.depth as i64);
+        self.forward_queue_oldest_seconds
+            .with_label_values(&[queue])
+            .set(stats.oldest_age.unwrap_or_default().as_secs_f64());
+    }
+
+    /// Values of persistent counters to be restored after restart.
+    pub fn snapshot(&self) -> MetricsFile {
+        let counters = self
+            .registry
+            .gather()
+            .into_iter()
+            .filter(|family| PERSISTENT_COUNTERS.contains(&family.get_name()))
+            .map(|family| {
+                let samples = family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| CounterSample {
+                        labels: metric
+                            .get_label()
+                            .iter()
+                            .filter(|label| label.get_name() != RESTARTS_LABEL)
+                            .map(|label| {
+                                (label.get_name().to_string(), label.get_value().to_string())
+                            })
+                            .collect(),
+                        value: metric.get_counter().get_value() as u64,
+                    })
+                    .collect();
+                (family.get_name().to_string(), samples)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        MetricsFile {
+            restarts: self.restarts.unwrap_or_default(),
+            counters,
+        }
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Serve metrics in Prometheus text format.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let make_svc = make_service_fn(move |_: &AddrStream| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    Ok::<_, Infallible>(match metrics.encode() {
+                        Ok(buf) => Response::builder()
+                            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                            .body(Body::from(buf))
+                            .unwrap(),
+                        Err(e) => {
+                            warn!("Failed to encode metrics: {}", e);
+                            Response::builder().status(500).body(Body::empty()).unwrap()
+                        }
+                    })
+                }
+            }))
+        }
+    });
+
+    info!("Metrics server starting on {}", addr);
+
+    Server::try_bind(&addr)
+        .context("Failed to bind metrics server")?
+        .serve(make_svc)
+        .await
+        .context("Metrics server failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence;
+
+    #[test]
+    fn instances_do_not_share_metrics() {
+        let a = Metrics::new().unwrap();
+        let b = Metrics::new().unwrap();
+
+        a.stalled_peers.inc();
+        a.observe_status_update(StatusChange::HeadMoved);
+
+        let encoded = |metrics: &Metrics| String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(encoded(&a).contains("sentry_stalled_peers_total 1"));
+        assert!(encoded(&b).contains("sentry_stalled_peers_total 0"));
+        assert!(!encoded(&b).contains("head_moved"));
+    }
+
+    #[test]
+    fn persistent_counters_continue_after_restart() {
+        let path = std::env::temp_dir().join(format!("sentry-metrics-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let served = ServedCount {
+            items: 3,
+            bytes: 300,
+        };
+
+        for restart in 0..3_u64 {
+            let metrics =
+                Metrics::restore(&persistence::load::<MetricsFile>(&path).unwrap()).unwrap();
+            metrics.coalesced_requests.inc();
+            metrics.stalled_peers.inc();
+            metrics.observe_served(ServedKind::Headers, ServedSource::Cache, served);
+            persistence::save(&metrics.snapshot(), &path).unwrap();
+
+            let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+            assert!(encoded.contains(&format!(
+                "sentry_coalesced_requests_total{{restarts=\"{}\"}} {}",
+                restart,
+                restart + 1
+            )));
+            assert!(encoded.contains(&format!(
+                "sentry_served_bytes_total{{kind=\"headers\",restarts=\"{}\",source=\"cache\"}} {}",
+                restart,
+                300 * (restart + 1)
+            )));
+            // Not persisted.
+            assert!(encoded.contains("sentry_stalled_peers_total 1"));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn samples_with_unknown_labels_are_skipped() {
+        let sample = |labels: &[(&str, &str)], value| CounterSample {
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            value,
+        };
+        let snapshot = MetricsFile {
+            restarts: 0,
+            counters: std::iter::once((
+                "sentry_served_items_total".to_string(),
+                vec![
+                    sample(&[("kind", "headers")], 1),
+                    sample(&[("kind", "headers"), ("source", "cache")], 2),
+                ],
+            ))
+            .collect(),
+        };
+
+        let metrics = Metrics::restore(&snapshot).unwrap();
+        let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(encoded.contains(
+            "sentry_served_items_total{kind=\"headers\",restarts=\"1\",source=\"cache\"} 2"
+        ));
+    }
+}
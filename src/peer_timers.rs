@@ -0,0 +1,167 @@
+use devp2p::PeerId;
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::StreamExt;
+use tokio_util::time::{delay_queue::Key, DelayQueue};
+
+/// Action taken on a peer once its timer expires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimedPeerAction {
+    /// Disconnect the peer unless it has sent a valid Status by then.
+    DisconnectIfNotValid(PeerId),
+    /// Lift the ban of the peer once it has run out. Outlives the peer's connection.
+    RemoveFromBanList(PeerId),
+}
+
+impl TimedPeerAction {
+    pub fn peer(&self) -> PeerId {
+        match *self {
+            Self::DisconnectIfNotValid(peer) | Self::RemoveFromBanList(peer) => peer,
+        }
+    }
+
+    /// Whether the action is dropped when the peer disconnects.
+    fn is_session_scoped(&self) -> bool {
+        match self {
+            Self::DisconnectIfNotValid(_) => true,
+            Self::RemoveFromBanList(_) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Schedule(TimedPeerAction, Duration),
+    Cancel(PeerId),
+}
+
+/// Handle to schedule timed peer actions, which all share a single timer queue.
+#[derive(Clone, Debug)]
+pub struct PeerTimers {
+    commands: UnboundedSender<Command>,
+}
+
+/// Timer queue that yields the actions scheduled through [`PeerTimers`] as they expire.
+#[derive(Debug)]
+pub struct PeerTimerQueue {
+    commands: UnboundedReceiver<Command>,
+    queue: DelayQueue<TimedPeerAction>,
+    keys: HashMap<TimedPeerAction, Key>,
+}
+
+pub fn peer_timers() -> (PeerTimers, PeerTimerQueue) {
+    let (commands_tx, commands_rx) = unbounded_channel();
+    (
+        PeerTimers {
+            commands: commands_tx,
+        },
+        PeerTimerQueue {
+            commands: commands_rx,
+            queue: DelayQueue::new(),
+            keys: HashMap::new(),
+        },
+    )
+}
+
+impl PeerTimers {
+    /// Run the action after `delay`, replacing the same action if it is already scheduled.
+    pub fn schedule(&self, action: TimedPeerAction, delay: Duration) {
+        let _ = self.commands.send(Command::Schedule(action, delay));
+    }
+
+    /// Drop the scheduled actions of the peer that only apply to its current connection.
+    pub fn cancel(&self, peer: PeerId) {
+        let _ = self.commands.send(Command::Cancel(peer));
+    }
+}
+
+impl PeerTimerQueue {
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Schedule(action, delay) => {
+                if let Some(key) = self.keys.remove(&action) {
+                    self.queue.remove(&key);
+                }
+                let key = self.queue.insert(action, delay);
+                self.keys.insert(action, key);
+            }
+            Command::Cancel(peer) => {
+                let queue = &mut self.queue;
+                self.keys.retain(|action, key| {
+                    if action.peer() == peer && action.is_session_scoped() {
+                        queue.remove(key);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+        }
+    }
+
+    /// Next expired action, `None` once all handles are dropped.
+    pub async fn next(&mut self) -> Option<TimedPeerAction> {
+        loop {
+            tokio::select! {
+                command = self.commands.recv() => self.apply(command?),
+                Some(Ok(expired)) = self.queue.next() => {
+                    let action = expired.into_inner();
+                    self.keys.remove(&action);
+                    return Some(action);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn expired_and_cancelled_actions() {
+        let (timers, mut queue) = peer_timers();
+        let a = PeerId::from_low_u64_be(1);
+        let b = PeerId::from_low_u64_be(2);
+
+        timers.schedule(
+            TimedPeerAction::DisconnectIfNotValid(a),
+            Duration::from_millis(10),
+        );
+        timers.schedule(
+            TimedPeerAction::DisconnectIfNotValid(b),
+            Duration::from_millis(20),
+        );
+        timers.schedule(
+            TimedPeerAction::RemoveFromBanList(a),
+            Duration::from_millis(30),
+        );
+        timers.cancel(a);
+        assert_eq!(
+            queue.next().await,
+            Some(TimedPeerAction::DisconnectIfNotValid(b))
+        );
+        // Ban expiry is kept across disconnects.
+        assert_eq!(
+            queue.next().await,
+            Some(TimedPeerAction::RemoveFromBanList(a))
+        );
+
+        // Rescheduling replaces the pending timer.
+        timers.schedule(
+            TimedPeerAction::DisconnectIfNotValid(a),
+            Duration::from_millis(10),
+        );
+        timers.schedule(
+            TimedPeerAction::DisconnectIfNotValid(a),
+            Duration::from_secs(60),
+        );
+        assert!(timeout(Duration::from_millis(50), queue.next())
+            .await
+            .is_err());
+
+        drop(timers);
+        assert_eq!(queue.next().await, None);
+    }
+}
@@ -0,0 +1,302 @@
+use async_stream::stream;
+use devp2p::PeerId;
+use futures::stream::{self, BoxStream, StreamExt};
+use parking_lot::RwLock;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::broadcast::{channel as broadcast, error::RecvError, Sender as BroadcastSender};
+use ulid::Ulid;
+
+const WATCH_BUFFER: usize = 64;
+
+/// Stable per-peer record compared between snapshots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerRecord {
+    pub id: PeerId,
+    pub protocol_version: u8,
+    pub valid: bool,
+    pub syncing: bool,
+    /// Valid peer that has sent nothing but Status for a while.
+    pub passive: bool,
+    pub min_block: u64,
+    pub labels: BTreeMap<String, String>,
+    /// Correlation ID of the current connection.
+    pub connection_id: Option<Ulid>,
+}
+
+/// Changed fields of a peer record, unchanged fields are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerRecordChange {
+    pub protocol_version: Option<u8>,
+    pub valid: Option<bool>,
+    pub syncing: Option<bool>,
+    pub passive: Option<bool>,
+    pub min_block: Option<u64>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub connection_id: Option<Option<Ulid>>,
+}
+
+impl PeerRecord {
+    pub fn diff(&self, new: &Self) -> Option<PeerRecordChange> {
+        fn field<T: Clone + PartialEq>(old: &T, new: &T) -> Option<T> {
+            if old != new {
+                Some(new.clone())
+            } else {
+                None
+            }
+        }
+
+        let change = PeerRecordChange {
+            protocol_version: field(&self.protocol_version, &new.protocol_version),
+            valid: field(&self.valid, &new.valid),
+            syncing: field(&self.syncing, &new.syncing),
+            passive: field(&self.passive, &new.passive),
+            min_block: field(&self.min_block, &new.min_block),
+            labels: field(&self.labels, &new.labels),
+            connection_id: field(&self.connection_id, &new.connection_id),
+        };
+
+        if change == PeerRecordChange::default() {
+            None
+        } else {
+            Some(change)
+        }
+    }
+}
+
+pub type PeerSnapshot = BTreeMap<PeerId, PeerRecord>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    Added(PeerRecord),
+    Removed(PeerId),
+    Changed {
+        id: PeerId,
+        change: PeerRecordChange,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchUpdate {
+    Snapshot(Vec<PeerRecord>),
+    Diff(Vec<PeerEvent>),
+}
+
+pub fn diff_snapshots(old: &PeerSnapshot, new: &PeerSnapshot) -> Vec<PeerEvent> {
+    let mut events = Vec::new();
+
+    for (id, old_record) in old {
+        match new.get(id) {
+            None => events.push(PeerEvent::Removed(*id)),
+            Some(new_record) => {
+                if let Some(change) = old_record.diff(new_record) {
+                    events.push(PeerEvent::Changed { id: *id, change });
+                }
+            }
+        }
+    }
+
+    for (id, new_record) in new {
+        if !old.contains_key(id) {
+            events.push(PeerEvent::Added(new_record.clone()));
+        }
+    }
+
+    events
+}
+
+/// Turns periodic snapshots of the peer table into a stream of diffs.
+#[derive(Debug)]
+pub struct PeerWatch {
+    last: RwLock<PeerSnapshot>,
+    sender: BroadcastSender<Arc<Vec<PeerEvent>>>,
+}
+
+impl Default for PeerWatch {
+    fn default() -> Self {
+        Self {
+            last: Default::default(),
+            sender: broadcast(WATCH_BUFFER).0,
+        }
+    }
+}
+
+impl PeerWatch {
+    pub fn publish(&self, snapshot: PeerSnapshot) {
+        let mut last = self.last.write();
+        let events = diff_snapshots(&last, &snapshot);
+        *last = snapshot;
+
+        if !events.is_empty() {
+            let _ = self.sender.send(Arc::new(events));
+        }
+    }
+
+    fn snapshot(&self) -> Vec<PeerRecord> {
+        self.last.read().values().cloned().collect()
+    }
+
+    /// Full snapshot first, then only diffs. Lagging subscriber is resynced with a fresh snapshot.
+    /// Ends when the watch is dropped.
+    pub fn subscribe(self: &Arc<Self>) -> BoxStream<'static, WatchUpdate> {
+        let this = Arc::downgrade(self);
+        let (snapshot, mut receiver) = {
+            let last = self.last.read();
+            (
+                last.values().cloned().collect::<Vec<_>>(),
+                self.sender.subscribe(),
+            )
+        };
+
+        Box::pin(stream! {
+            yield WatchUpdate::Snapshot(snapshot);
+
+            loop {
+                match receiver.recv().await {
+                    Ok(events) => yield WatchUpdate::Diff((*events).clone()),
+                    Err(RecvError::Lagged(_)) => {
+                        let this = match this.upgrade() {
+                            Some(v) => v,
+                            None => break,
+                        };
+                        receiver = this.sender.subscribe();
+                        let snapshot = this.snapshot();
+                        drop(this);
+                        yield WatchUpdate::Snapshot(snapshot);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Valid peers, then peers as they become valid. A peer may be yielded again after it
+    /// reconnects, or when the subscriber has fallen behind.
+    pub fn valid_peers(self: &Arc<Self>) -> BoxStream<'static, PeerId> {
+        Box::pin(self.subscribe().flat_map(|update| {
+            let peers = match update {
+                WatchUpdate::Snapshot(records) => records
+                    .into_iter()
+                    .filter(|record| record.valid)
+                    .map(|record| record.id)
+                    .collect::<Vec<_>>(),
+                WatchUpdate::Diff(events) => events
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        PeerEvent::Added(record) if record.valid => Some(record.id),
+                        PeerEvent::Changed {
+                            id,
+                            change:
+                                PeerRecordChange {
+                                    valid: Some(true), ..
+                                },
+                        } => Some(id),
+                        _ => None,
+                    })
+                    .collect(),
+            };
+            stream::iter(peers)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(n: u64) -> PeerRecord {
+        PeerRecord {
+            id: PeerId::from_low_u64_be(n),
+            protocol_version: 65,
+            valid: true,
+            syncing: false,
+            passive: false,
+            min_block: 0,
+            labels: Default::default(),
+            connection_id: None,
+        }
+    }
+
+    fn snapshot(records: impl IntoIterator<Item = PeerRecord>) -> PeerSnapshot {
+        records.into_iter().map(|r| (r.id, r)).collect()
+    }
+
+    #[test]
+    fn single_counter_change_is_minimal() {
+        let old = snapshot((1..=100).map(record));
+        let mut new = old.clone();
+        new.get_mut(&PeerId::from_low_u64_be(42)).unwrap().min_block = 1000;
+
+        assert_eq!(
+            diff_snapshots(&old, &new),
+            vec![PeerEvent::Changed {
+                id: PeerId::from_low_u64_be(42),
+                change: PeerRecordChange {
+                    min_block: Some(1000),
+                    ..Default::default()
+                }
+            }]
+        );
+        assert_eq!(diff_snapshots(&new, &new), vec![]);
+    }
+
+    #[test]
+    fn added_and_removed() {
+        let old = snapshot(vec![record(1), record(2)]);
+        let new = snapshot(vec![record(2), record(3)]);
+
+        assert_eq!(
+            diff_snapshots(&old, &new),
+            vec![
+                PeerEvent::Removed(PeerId::from_low_u64_be(1)),
+                PeerEvent::Added(record(3))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnecting_subscriber_gets_fresh_snapshot() {
+        let watch = Arc::new(PeerWatch::default());
+        watch.publish(snapshot(vec![record(1)]));
+
+        let mut sub = watch.subscribe();
+        assert_eq!(
+            sub.next().await,
+            Some(WatchUpdate::Snapshot(vec![record(1)]))
+        );
+
+        watch.publish(snapshot(vec![record(1), record(2)]));
+        assert_eq!(
+            sub.next().await,
+            Some(WatchUpdate::Diff(vec![PeerEvent::Added(record(2))]))
+        );
+        drop(sub);
+
+        watch.publish(snapshot(vec![record(2)]));
+
+        let mut sub = watch.subscribe();
+        assert_eq!(
+            sub.next().await,
+            Some(WatchUpdate::Snapshot(vec![record(2)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn valid_peers_follow_validation() {
+        let invalid = |n| PeerRecord {
+            valid: false,
+            ..record(n)
+        };
+        let watch = Arc::new(PeerWatch::default());
+        watch.publish(snapshot(vec![record(1), invalid(2)]));
+
+        let mut peers = watch.valid_peers();
+        assert_eq!(peers.next().await, Some(PeerId::from_low_u64_be(1)));
+
+        watch.publish(snapshot(vec![record(1), invalid(2), invalid(3)]));
+        watch.publish(snapshot(vec![record(1), record(2), invalid(3)]));
+        assert_eq!(peers.next().await, Some(PeerId::from_low_u64_be(2)));
+
+        drop(watch);
+        assert_eq!(peers.next().await, None);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::types::H256Map;
+use devp2p::PeerId;
+use ethereum_types::H256;
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+struct Pending {
+    size: u32,
+    /// Position in the announcement order, to tell stale order entries.
+    seq: u64,
+    announced_by: Vec<PeerId>,
+}
+
+/// Sizes of announced but not yet fetched transactions, used to prioritise fetches.
+/// Oldest announcements are evicted first once `capacity` is reached.
+#[derive(Debug)]
+pub struct PendingTxSizes {
+    capacity: usize,
+    next_seq: u64,
+    pending: H256Map<Pending>,
+    /// Announcements oldest first. Entries of hashes that have been removed since are
+    /// skipped.
+    order: VecDeque<(H256, u64)>,
+}
+
+impl PendingTxSizes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            next_seq: 0,
+            pending: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    pub fn insert(&mut self, peer: PeerId, hash: H256, size: u32) {
+        if let Some(pending) = self.pending.get_mut(&hash) {
+            if !pending.announced_by.contains(&peer) {
+                pending.announced_by.push(peer);
+            }
+            return;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.insert(
+            hash,
+            Pending {
+                size,
+                seq,
+                announced_by: vec![peer],
+            },
+        );
+        self.order.push_back((hash, seq));
+        while self.pending.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((oldest, seq)) => {
+                    if self.pending.get(&oldest).map(|p| p.seq) == Some(seq) {
+                        self.pending.remove(&oldest);
+                    }
+                }
+                None => break,
+            }
+        }
+        if self.order.len() > 2 * self.capacity {
+            self.compact();
+        }
+    }
+
+    pub fn get(&self, hash: H256) -> Option<u32> {
+        self.pending.get(&hash).map(|pending| pending.size)
+    }
+
+    /// Transaction is being fetched.
+    pub fn remove(&mut self, hash: H256) {
+        self.pending.remove(&hash);
+    }
+
+    /// Forget transactions that only the peer has announced.
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.pending.retain(|_, pending| {
+            pending.announced_by.retain(|&p| p != peer);
+            !pending.announced_by.is_empty()
+        });
+        self.compact();
+    }
+
+    /// Order hashes smallest transaction first, unknown sizes last.
+    pub fn sort_by_size(&self, hashes: &mut [H256]) {
+        hashes.sort_by_key(|&hash| self.get(hash).unwrap_or(u32::MAX));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn compact(&mut self) {
+        let pending = &self.pending;
+        self.order
+            .retain(|(hash, seq)| pending.get(hash).map(|p| p.seq) == Some(*seq));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_and_sorted() {
+        let peer = PeerId::from_low_u64_be(1);
+        let mut pending = PendingTxSizes::new(2);
+        let (a, b, c) = (
+            H256::repeat_byte(1),
+            H256::repeat_byte(2),
+            H256::repeat_byte(3),
+        );
+
+        pending.insert(peer, a, 300);
+        pending.insert(peer, b, 100);
+        pending.insert(peer, c, 200);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.get(a), None);
+
+        let mut hashes = vec![a, c, b];
+        pending.sort_by_size(&mut hashes);
+        assert_eq!(hashes, vec![b, c, a]);
+
+        pending.remove(b);
+        assert_eq!(pending.get(b), None);
+        assert_eq!(pending.len(), 1);
+
+        // Announced again after being fetched, so it is the newest now.
+        pending.insert(peer, b, 100);
+        pending.insert(peer, a, 300);
+        assert_eq!(pending.get(c), None);
+        assert_eq!(pending.get(b), Some(100));
+        assert_eq!(pending.get(a), Some(300));
+    }
+
+    #[test]
+    fn forgotten_with_all_announcers() {
+        let (first, second) = (PeerId::from_low_u64_be(1), PeerId::from_low_u64_be(2));
+        let mut pending = PendingTxSizes::new(16);
+        let (shared, own) = (H256::repeat_byte(1), H256::repeat_byte(2));
+
+        pending.insert(first, shared, 100);
+        pending.insert(second, shared, 100);
+        pending.insert(first, own, 200);
+
+        pending.on_disconnect(first);
+        assert_eq!(pending.get(shared), Some(100));
+        assert_eq!(pending.get(own), None);
+
+        pending.on_disconnect(second);
+        assert!(pending.is_empty());
+        assert!(pending.order.is_empty());
+    }
+}
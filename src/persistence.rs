@@ -0,0 +1,321 @@
+use anyhow::{bail, Context};
+use devp2p::PeerId;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Marks files written by the sentry.
+pub const MAGIC: &str = "ethereum-sentry";
+
+/// Format of a file the sentry keeps across restarts. Files are stored in an envelope
+/// with the format version, older versions are migrated on load one version at a time.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Kind of the file, so that one file is not loaded as another.
+    const KIND: &'static str;
+    const VERSION: u32;
+
+    /// Convert payload of `version` to `version + 1`.
+    fn migrate(version: u32, _payload: Value) -> anyhow::Result<Value> {
+        bail!("no migration of {} from version {}", Self::KIND, version)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    magic: String,
+    kind: String,
+    version: u32,
+    payload: Value,
+}
+
+/// Parse file contents. Files written before the envelope was introduced are version 1.
+pub fn decode<T: Versioned>(data: &[u8]) -> anyhow::Result<T> {
+    let value = serde_json::from_slice::<Value>(data)?;
+    let (version, mut payload) = if value.get("magic").is_some() {
+        let envelope = serde_json::from_value::<Envelope>(value)?;
+        if envelope.magic != MAGIC {
+            bail!("not a sentry file");
+        }
+        if envelope.kind != T::KIND {
+            bail!("expected {} file, found {}", T::KIND, envelope.kind);
+        }
+        (envelope.version, envelope.payload)
+    } else {
+        (1, value)
+    };
+
+    if version == 0 || version > T::VERSION {
+        bail!(
+            "{} file has version {}, this sentry supports up to {}",
+            T::KIND,
+            version,
+            T::VERSION
+        );
+    }
+    for version in version..T::VERSION {
+        payload = T::migrate(version, payload)
+            .with_context(|| format!("Failed to migrate {} file", T::KIND))?;
+    }
+
+    Ok(serde_json::from_value(payload)?)
+}
+
+pub fn encode<T: Versioned>(value: &T) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(&Envelope {
+        magic: MAGIC.to_string(),
+        kind: T::KIND.to_string(),
+        version: T::VERSION,
+        payload: serde_json::to_value(value)?,
+    })?)
+}
+
+/// Load file, or the default if it does not exist yet.
+pub fn load<T: Versioned + Default>(path: &Path) -> anyhow::Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    decode(&fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?)
+        .with_context(|| format!("Failed to load {}", path.display()))
+}
+
+pub fn save<T: Versioned>(value: &T, path: &Path) -> anyhow::Result<()> {
+    write_atomic(path, &encode(value)?)
+}
+
+/// Replace contents of the file, so that it has either the old or the new contents
+/// after a crash.
+pub fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+
+    let mut file =
+        File::create(tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    drop(file);
+    fs::rename(tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to sync directory {}", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PeerEntry {
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Contents of the peers file, keyed by hex-encoded node ID.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PeersFile {
+    pub peers: BTreeMap<String, PeerEntry>,
+}
+
+impl Versioned for PeersFile {
+    const KIND: &'static str = "peers";
+    const VERSION: u32 = 2;
+
+    /// Version 1 has been a map of labels by peer.
+    fn migrate(version: u32, mut payload: Value) -> anyhow::Result<Value> {
+        match version {
+            1 => {
+                let labels = payload
+                    .get_mut("labels")
+                    .map(Value::take)
+                    .unwrap_or_default();
+                let labels = serde_json::from_value::<
+                    Option<BTreeMap<String, BTreeMap<String, String>>>,
+                >(labels)?;
+                Ok(serde_json::to_value(PeersFile {
+                    peers: labels
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(id, labels)| (id, PeerEntry { labels }))
+                        .collect(),
+                })?)
+            }
+            _ => bail!("no migration of peers from version {}", version),
+        }
+    }
+}
+
+impl PeersFile {
+    pub fn peer_labels(&self) -> HashMap<PeerId, BTreeMap<String, String>> {
+        self.peers
+            .iter()
+            .filter(|(_, entry)| !entry.labels.is_empty())
+            .filter_map(|(id, entry)| Some((id.parse().ok()?, entry.labels.clone())))
+            .collect()
+    }
+
+    pub fn set_peer_labels(&mut self, labels: HashMap<PeerId, BTreeMap<String, String>>) {
+        for entry in self.peers.values_mut() {
+            entry.labels.clear();
+        }
+        for (peer, labels) in labels {
+            self.peers
+                .entry(hex::encode(peer.as_bytes()))
+                .or_default()
+                .labels = labels;
+        }
+        self.peers.retain(|_, entry| !entry.labels.is_empty());
+    }
+}
+
+/// Local bans, keyed by hex-encoded node ID, with their expiry in seconds since the Unix epoch.
+/// Bans from sibling sentries are not kept, siblings share them again.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BansFile {
+    pub bans: BTreeMap<String, u64>,
+}
+
+impl Versioned for BansFile {
+    const KIND: &'static str = "bans";
+    const VERSION: u32 = 1;
+}
+
+impl BansFile {
+    pub fn new(bans: impl IntoIterator<Item = (PeerId, SystemTime)>) -> Self {
+        Self {
+            bans: bans
+                .into_iter()
+                .map(|(peer, until)| {
+                    (
+                        hex::encode(peer.as_bytes()),
+                        until
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Bans that have not expired, with the time left.
+    pub fn bans(&self, now: SystemTime) -> Vec<(PeerId, Duration)> {
+        self.bans
+            .iter()
+            .filter_map(|(id, &until)| {
+                let left = (UNIX_EPOCH + Duration::from_secs(until))
+                    .duration_since(now)
+                    .ok()?;
+                Some((id.parse().ok()?, left))
+            })
+            .collect()
+    }
+}
+
+/// Value of a counter with its labels.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CounterSample {
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+}
+
+/// Counters kept across restarts, keyed by metric name, with the number of restarts they
+/// have been carried over.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsFile {
+    pub restarts: u32,
+    pub counters: BTreeMap<String, Vec<CounterSample>>,
+}
+
+impl Versioned for MetricsFile {
+    const KIND: &'static str = "metrics";
+    const VERSION: u32 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versioned_files() {
+        let dir = std::env::temp_dir().join(format!("sentry-persistence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let peers_path = dir.join("peers.json");
+        let bans_path = dir.join("bans.json");
+        let peer = PeerId::from_low_u64_be(1);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let mut peers = PeersFile::default();
+        peers.set_peer_labels(
+            vec![(
+                peer,
+                vec![("role".to_string(), "archive".to_string())]
+                    .into_iter()
+                    .collect(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        save(&peers, &peers_path).unwrap();
+        save(
+            &BansFile::new(vec![(peer, now + Duration::from_secs(60))]),
+            &bans_path,
+        )
+        .unwrap();
+        assert_eq!(
+            load::<PeersFile>(&peers_path).unwrap().peer_labels(),
+            peers.peer_labels()
+        );
+        assert_eq!(
+            load::<BansFile>(&bans_path).unwrap().bans(now),
+            vec![(peer, Duration::from_secs(60))]
+        );
+        assert!(load::<BansFile>(&peers_path).is_err());
+
+        // Peers file written before versioning.
+        fs::write(
+            &peers_path,
+            serde_json::to_vec(&serde_json::json!({
+                "labels": { hex::encode(peer.as_bytes()): { "role": "archive" } }
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            load::<PeersFile>(&peers_path).unwrap().peer_labels(),
+            peers.peer_labels()
+        );
+
+        // Corrupt or newer peers file is an error, and does not affect the bans file.
+        for data in &[
+            b"{\"labels\":".to_vec(),
+            serde_json::to_vec(&serde_json::json!({
+                "magic": MAGIC, "kind": "peers", "version": 3, "payload": {}
+            }))
+            .unwrap(),
+        ] {
+            fs::write(&peers_path, data).unwrap();
+            assert!(load::<PeersFile>(&peers_path).is_err());
+            assert_eq!(load::<BansFile>(&bans_path).unwrap().bans(now).len(), 1);
+        }
+
+        assert!(load::<BansFile>(&dir.join("missing.json"))
+            .unwrap()
+            .bans
+            .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! Handling of peer events as a stack of services: routing to other capabilities, then
+//! accounting and validation, then dispatch by message ID. Metrics, logging or rate
+//! limiting can be layered in between without touching the dispatch.
+
+use crate::{
+    eth::capability_name,
+    forwarding::Stamp,
+    telemetry::{Telemetry, TelemetryLayer},
+    CapabilityServerImpl,
+};
+use devp2p::{DisconnectReason, InboundEvent, Message, PeerId};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    task::{Context, Poll},
+};
+use tower::{Layer, Service, ServiceBuilder};
+
+#[derive(Debug)]
+pub struct PeerEvent {
+    pub peer: PeerId,
+    pub event: InboundEvent,
+    pub received: Stamp,
+    /// Set once the message has been sent to the control.
+    pub forwarded: Arc<AtomicBool>,
+}
+
+impl PeerEvent {
+    pub fn new(peer: PeerId, event: InboundEvent) -> Self {
+        Self {
+            peer,
+            event,
+            received: Stamp::now(),
+            forwarded: Default::default(),
+        }
+    }
+}
+
+/// Reply to the peer, or reason to disconnect it.
+pub type PeerEventFuture = BoxFuture<'static, Result<Option<Message>, DisconnectReason>>;
+
+pub type Pipeline = Routing<Telemetry<Validation<Dispatch>>>;
+
+/// Layers need the server itself, so the server builds its pipeline once it is
+/// constructed and reuses it for every event.
+pub fn pipeline(server: CapabilityServerImpl) -> Pipeline {
+    let server = Arc::new(server);
+    ServiceBuilder::new()
+        .layer(RoutingLayer::new(server.clone()))
+        .layer(TelemetryLayer::new(server.otlp_tracer()))
+        .layer(ValidationLayer::new(server.clone()))
+        .service(Dispatch::new(server))
+}
+
+/// Hands messages of capabilities other than eth to their routers.
+#[derive(Clone)]
+pub struct RoutingLayer {
+    server: Arc<CapabilityServerImpl>,
+}
+
+impl RoutingLayer {
+    pub fn new(server: Arc<CapabilityServerImpl>) -> Self {
+        Self { server }
+    }
+}
+
+impl<S> Layer<S> for RoutingLayer {
+    type Service = Routing<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Routing {
+            server: self.server.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Routing<S> {
+    server: Arc<CapabilityServerImpl>,
+    inner: S,
+}
+
+impl<S> Service<PeerEvent> for Routing<S>
+where
+    S: Service<
+        PeerEvent,
+        Response = Option<Message>,
+        Error = DisconnectReason,
+        Future = PeerEventFuture,
+    >,
+{
+    type Response = Option<Message>;
+    type Error = DisconnectReason;
+    type Future = PeerEventFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PeerEvent) -> Self::Future {
+        match req.event {
+            InboundEvent::Message {
+                capability_name: cap,
+                message,
+            } if cap != capability_name() => {
+                let (server, peer) = (self.server.clone(), req.peer);
+                async move {
+                    // Routers reply on their own.
+                    server.route_capability_message(peer, cap, message).await;
+                    Ok(None)
+                }
+                .boxed()
+            }
+            event => self.inner.call(PeerEvent { event, ..req }),
+        }
+    }
+}
+
+/// Accounts for eth messages and drops or rejects those that fail checks.
+#[derive(Clone)]
+pub struct ValidationLayer {
+    server: Arc<CapabilityServerImpl>,
+}
+
+impl ValidationLayer {
+    pub fn new(server: Arc<CapabilityServerImpl>) -> Self {
+        Self { server }
+    }
+}
+
+impl<S> Layer<S> for ValidationLayer {
+    type Service = Validation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Validation {
+            server: self.server.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Validation<S> {
+    server: Arc<CapabilityServerImpl>,
+    inner: S,
+}
+
+impl<S> Service<PeerEvent> for Validation<S>
+where
+    S: Service<
+        PeerEvent,
+        Response = Option<Message>,
+        Error = DisconnectReason,
+        Future = PeerEventFuture,
+    >,
+{
+    type Response = Option<Message>;
+    type Error = DisconnectReason;
+    type Future = PeerEventFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PeerEvent) -> Self::Future {
+        match req.event {
+            InboundEvent::Message {
+                capability_name,
+                message: Message { id, data },
+            } => match self.server.verify_inbound(req.peer, id, data) {
+                Ok(Some(data)) => self.inner.call(PeerEvent {
+                    event: InboundEvent::Message {
+                        capability_name,
+                        message: Message { id, data },
+                    },
+                    ..req
+                }),
+                res => future::ready(res.map(|_| None)).boxed(),
+            },
+            event => self.inner.call(PeerEvent { event, ..req }),
+        }
+    }
+}
+
+/// Handles eth messages by ID and disconnects.
+#[derive(Clone)]
+pub struct Dispatch {
+    server: Arc<CapabilityServerImpl>,
+}
+
+impl Dispatch {
+    pub fn new(server: Arc<CapabilityServerImpl>) -> Self {
+        Self { server }
+    }
+}
+
+impl Service<PeerEvent> for Dispatch {
+    type Response = Option<Message>;
+    type Error = DisconnectReason;
+    type Future = PeerEventFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: PeerEvent) -> Self::Future {
+        let server = self.server.clone();
+        async move {
+            server
+                .dispatch_event(req.peer, req.event, req.received, &req.forwarded)
+                .await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, eth::EthMessageId};
+    use devp2p::{CapabilityName, CapabilityServer, ConnectionDirection};
+    use num_traits::ToPrimitive;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::{service_fn, ServiceExt};
+
+    fn server() -> Arc<CapabilityServerImpl> {
+        Arc::new(CapabilityServerImpl::for_test(&Config::default()))
+    }
+
+    fn counting(
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<
+        PeerEvent,
+        Response = Option<Message>,
+        Error = DisconnectReason,
+        Future = PeerEventFuture,
+    > + Clone {
+        service_fn(move |_: PeerEvent| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            future::ready(Ok(None)).boxed()
+        })
+    }
+
+    fn message(capability_name: CapabilityName, id: EthMessageId) -> InboundEvent {
+        InboundEvent::Message {
+            capability_name,
+            message: Message {
+                id: id.to_usize().unwrap(),
+                data: rlp::EMPTY_LIST_RLP.to_vec().into(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn routing_keeps_other_capabilities_from_eth() {
+        let peer = PeerId::from_low_u64_be(1);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let routing = RoutingLayer::new(server()).layer(counting(calls.clone()));
+
+        let other = CapabilityName(arrayvec::ArrayString::from("snap").unwrap());
+        for &(cap, expected_calls) in &[(other, 0), (capability_name(), 1)] {
+            let event = PeerEvent::new(peer, message(cap, EthMessageId::GetBlockHeaders));
+            assert!(matches!(routing.clone().oneshot(event).await, Ok(None)));
+            assert_eq!(calls.load(Ordering::Relaxed), expected_calls);
+        }
+    }
+
+    #[tokio::test]
+    async fn validation_rejects_messages_missing_from_version() {
+        let peer = PeerId::from_low_u64_be(1);
+        let server = server();
+        server.on_peer_connect(
+            peer,
+            None,
+            ConnectionDirection::Inbound,
+            std::iter::once((capability_name(), 67)).collect(),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validation = ValidationLayer::new(server).layer(counting(calls.clone()));
+
+        let event = PeerEvent::new(peer, message(capability_name(), EthMessageId::GetNodeData));
+        assert!(matches!(
+            validation.clone().oneshot(event).await,
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        let event = PeerEvent::new(peer, message(capability_name(), EthMessageId::GetReceipts));
+        assert!(matches!(validation.oneshot(event).await, Ok(None)));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{labels::PeerLabel, response_quality::ResponseClass};
+use devp2p::PeerId;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Period over which reconnects of a peer are counted.
+pub const RECONNECT_RATE_PERIOD: Duration = Duration::from_secs(60);
+const MAX_DEPARTED_PEERS: usize = 1024;
+
+/// Per-session state of a peer, carried over if it reconnects shortly after disconnecting.
+#[derive(Debug, Default)]
+pub struct SessionState {
+    pub block: u64,
+    pub labels: BTreeMap<String, PeerLabel>,
+    pub response_outcomes: VecDeque<ResponseClass>,
+}
+
+#[derive(Debug)]
+pub enum ReconnectDecision {
+    /// Accept the connection, resuming the previous session if it has just ended.
+    Accept(Option<SessionState>),
+    /// Peer reconnects too often.
+    Reject,
+}
+
+/// Detects peers that disconnect and reconnect in a loop.
+#[derive(Debug)]
+pub struct ReconnectTracker {
+    window: Duration,
+    max_per_period: usize,
+    departed: HashMap<PeerId, (Instant, SessionState)>,
+    reconnects: HashMap<PeerId, VecDeque<Instant>>,
+    /// Disconnected on purpose to start a fresh session, see `on_refresh`.
+    refreshed: HashSet<PeerId>,
+}
+
+impl ReconnectTracker {
+    /// Reconnect within `window` of a disconnect resumes the session. More than
+    /// `max_per_period` such reconnects within `RECONNECT_RATE_PERIOD` are rejected,
+    /// 0 disables the limit.
+    pub fn new(window: Duration, max_per_period: usize) -> Self {
+        Self {
+            window,
+            max_per_period,
+            departed: Default::default(),
+            reconnects: Default::default(),
+            refreshed: Default::default(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.departed
+            .retain(|_, (departed, _)| now.saturating_duration_since(*departed) < window);
+        self.reconnects.retain(|_, times| {
+            while let Some(&time) = times.front() {
+                if now.saturating_duration_since(time) < RECONNECT_RATE_PERIOD {
+                    break;
+                }
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+    }
+
+    /// Peer is about to be disconnected so that it reconnects with a fresh session.
+    /// That reconnect neither resumes the session nor counts toward the limit.
+    pub fn on_refresh(&mut self, peer: PeerId) {
+        self.refreshed.insert(peer);
+    }
+
+    /// Refresh has not disconnected the peer after all.
+    pub fn cancel_refresh(&mut self, peer: PeerId) {
+        self.refreshed.remove(&peer);
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId, state: SessionState, now: Instant) {
+        self.prune(now);
+
+        if self.refreshed.remove(&peer) {
+            self.departed.remove(&peer);
+            return;
+        }
+
+        // Rejected session had nothing to carry over, keep the state it was rejected with.
+        if self.departed.contains_key(&peer) {
+            return;
+        }
+
+        if self.departed.len() >= MAX_DEPARTED_PEERS {
+            if let Some(oldest) = self
+                .departed
+                .iter()
+                .min_by_key(|(_, (departed, _))| *departed)
+                .map(|(&peer, _)| peer)
+            {
+                self.departed.remove(&oldest);
+            }
+        }
+        self.departed.insert(peer, (now, state));
+    }
+
+    pub fn on_connect(&mut self, peer: PeerId, now: Instant) -> ReconnectDecision {
+        self.prune(now);
+
+        let (departed, state) = match self.departed.remove(&peer) {
+            Some(v) => v,
+            None => return ReconnectDecision::Accept(None),
+        };
+
+        let reconnects = self.reconnects.entry(peer).or_default();
+        reconnects.push_back(now);
+        if self.max_per_period > 0 && reconnects.len() > self.max_per_period {
+            self.departed.insert(peer, (departed, state));
+            return ReconnectDecision::Reject;
+        }
+
+        ReconnectDecision::Accept(Some(state))
+    }
+
+    /// Number of rapid reconnects per peer over the last `RECONNECT_RATE_PERIOD`.
+    pub fn reconnect_rates(&mut self, now: Instant) -> HashMap<PeerId, usize> {
+        self.prune(now);
+        self.reconnects
+            .iter()
+            .map(|(&peer, times)| (peer, times.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn state(block: u64) -> SessionState {
+        SessionState {
+            block,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resumes_and_rate_limits() {
+        let mut tracker = ReconnectTracker::new(WINDOW, 2);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        assert!(matches!(
+            tracker.on_connect(peer, now),
+            ReconnectDecision::Accept(None)
+        ));
+
+        // Late reconnect starts from scratch.
+        tracker.on_disconnect(peer, state(1), now);
+        assert!(matches!(
+            tracker.on_connect(peer, now + WINDOW),
+            ReconnectDecision::Accept(None)
+        ));
+
+        for i in 0..2 {
+            tracker.on_disconnect(peer, state(100 + i), now);
+            match tracker.on_connect(peer, now + Duration::from_secs(1)) {
+                ReconnectDecision::Accept(Some(state)) => assert_eq!(state.block, 100 + i),
+                other => panic!("unexpected decision {:?}", other),
+            }
+        }
+
+        tracker.on_disconnect(peer, state(200), now);
+        assert!(matches!(
+            tracker.on_connect(peer, now + Duration::from_secs(1)),
+            ReconnectDecision::Reject
+        ));
+        assert_eq!(tracker.reconnect_rates(now)[&peer], 3);
+
+        // Rejected session does not overwrite carried over state.
+        tracker.on_disconnect(peer, state(0), now);
+        assert_eq!(tracker.departed[&peer].1.block, 200);
+
+        // Once reconnects are spread out, the peer is accepted again.
+        let later = now + Duration::from_secs(1) + RECONNECT_RATE_PERIOD;
+        tracker.on_disconnect(peer, state(300), later);
+        assert!(matches!(
+            tracker.on_connect(peer, later),
+            ReconnectDecision::Accept(Some(_))
+        ));
+        assert_eq!(tracker.reconnect_rates(later)[&peer], 1);
+    }
+
+    #[test]
+    fn refresh_starts_fresh_session() {
+        let mut tracker = ReconnectTracker::new(WINDOW, 1);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            tracker.on_refresh(peer);
+            tracker.on_disconnect(peer, state(100), now);
+            assert!(matches!(
+                tracker.on_connect(peer, now),
+                ReconnectDecision::Accept(None)
+            ));
+        }
+        assert!(tracker.reconnect_rates(now).is_empty());
+
+        // Cancelled refresh leaves the next disconnect alone.
+        tracker.on_refresh(peer);
+        tracker.cancel_refresh(peer);
+        tracker.on_disconnect(peer, state(200), now);
+        assert!(matches!(
+            tracker.on_connect(peer, now),
+            ReconnectDecision::Accept(Some(SessionState { block: 200, .. }))
+        ));
+    }
+}
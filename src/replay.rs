@@ -0,0 +1,200 @@
+use crate::eth::{unwrap_request_id, EthMessageId};
+use anyhow::Context;
+use bytes::Bytes;
+use devp2p::util::keccak256;
+use ethereum_types::H256;
+use num_traits::FromPrimitive;
+use rlp::Rlp;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+use tracing::*;
+
+/// Number of fields in a `GetBlockHeaders` request without request ID.
+const GET_BLOCK_HEADERS_FIELDS: usize = 4;
+
+/// Serves `BlockHeaders` replies recorded from a real network, in place of a node.
+#[derive(Debug, Default)]
+pub struct ReplayDataProvider {
+    /// Reply payloads by hash of the request payload, both without request ID.
+    responses: HashMap<H256, Bytes>,
+}
+
+impl ReplayDataProvider {
+    /// Load a capture directory written by `PeerCapture`. Each file holds the payload of
+    /// one message and is named `{millis}-{peer}-{message id}.rlp`, like payload dumps of
+    /// protocol breaches. A request is answered by the reply with the same request ID to
+    /// the same peer, or by the next reply to the peer before eth/66.
+    /// Files that cannot be read or decoded are skipped.
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        let mut messages = BTreeMap::<_, Vec<(u128, EthMessageId, Bytes)>>::new();
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("Failed to read capture directory {}", dir.display()))?
+        {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("Failed to list capture directory {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            let fields = name
+                .strip_suffix(".rlp")
+                .map(|name| name.splitn(3, '-').collect::<Vec<_>>());
+            let (millis, peer, id) = match fields.as_deref() {
+                Some(&[millis, peer, id]) => match (
+                    millis.parse(),
+                    id.parse().ok().and_then(EthMessageId::from_usize),
+                ) {
+                    (Ok(millis), Some(id)) => (millis, peer.to_string(), id),
+                    _ => {
+                        debug!("Skipping {}, not a captured message", path.display());
+                        continue;
+                    }
+                },
+                _ => {
+                    debug!("Skipping {}, not a captured message", path.display());
+                    continue;
+                }
+            };
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Skipping {}, failed to read it: {}", path.display(), e);
+                    continue;
+                }
+            };
+            messages
+                .entry(peer)
+                .or_default()
+                .push((millis, id, data.into()));
+        }
+
+        let mut responses = HashMap::new();
+        for (peer, mut messages) in messages {
+            messages.sort_by_key(|&(millis, ..)| millis);
+            let mut pending = VecDeque::new();
+            let mut pending_by_id = HashMap::new();
+            for (_, id, data) in messages {
+                match id {
+                    EthMessageId::GetBlockHeaders => match Rlp::new(&data).item_count() {
+                        Ok(GET_BLOCK_HEADERS_FIELDS) => pending.push_back(keccak256(&data)),
+                        _ => match unwrap_request_id(&data) {
+                            Ok((request_id, request)) => {
+                                pending_by_id.insert(request_id, keccak256(&request));
+                            }
+                            Err(e) => warn!("Skipping GetBlockHeaders of {}: {}", peer, e),
+                        },
+                    },
+                    EthMessageId::BlockHeaders => {
+                        let response = match unwrap_request_id(&data) {
+                            Ok((request_id, response))
+                                if pending_by_id.contains_key(&request_id) =>
+                            {
+                                pending_by_id
+                                    .remove(&request_id)
+                                    .map(|request| (request, response))
+                            }
+                            _ => pending.pop_front().map(|request| (request, data)),
+                        };
+                        match response {
+                            Some((request, response)) => {
+                                responses.insert(request, response);
+                            }
+                            None => debug!("Unsolicited BlockHeaders from {}", peer),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self { responses })
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// Recorded reply to the `GetBlockHeaders` request, both without request ID.
+    pub fn block_headers(&self, request: &[u8]) -> Option<Bytes> {
+        self.responses.get(&keccak256(request)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::{wrap_request_id, BlockId, GetBlockHeaders};
+
+    #[test]
+    fn replay_captured_headers() {
+        let request = |number| {
+            rlp::encode(&GetBlockHeaders {
+                block: BlockId::Number(number),
+                max_headers: 1,
+                skip: 0,
+                reverse: false,
+            })
+            .freeze()
+        };
+        let response = |byte| rlp::encode_list(&[H256::repeat_byte(byte)]).freeze();
+
+        let dir = std::env::temp_dir().join(format!("sentry-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let get_headers = EthMessageId::GetBlockHeaders as usize;
+        let headers = EthMessageId::BlockHeaders as usize;
+        for (name, data) in vec![
+            // eth/65 peer, replies in order.
+            (format!("1-aa-{}", get_headers), request(1)),
+            (format!("2-aa-{}", headers), response(1)),
+            // eth/66 peer, replies out of order.
+            (
+                format!("1-bb-{}", get_headers),
+                wrap_request_id(7, &request(2)),
+            ),
+            (
+                format!("2-bb-{}", get_headers),
+                wrap_request_id(8, &request(3)),
+            ),
+            (
+                format!("3-bb-{}", headers),
+                wrap_request_id(8, &response(3)),
+            ),
+            (
+                format!("4-bb-{}", headers),
+                wrap_request_id(7, &response(2)),
+            ),
+            (
+                format!("5-bb-{}", headers),
+                wrap_request_id(9, &response(4)),
+            ),
+        ] {
+            fs::write(dir.join(format!("{}.rlp", name)), data).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+        fs::write(dir.join(format!("6-bb-{}.rlp", get_headers)), b"\xff").unwrap();
+
+        let provider = ReplayDataProvider::new(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(provider.len(), 3);
+        for &number in &[1, 2, 3] {
+            assert_eq!(
+                provider.block_headers(&request(number)),
+                Some(response(number as u8))
+            );
+        }
+        assert_eq!(provider.block_headers(&request(4)), None);
+    }
+}
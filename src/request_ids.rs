@@ -0,0 +1,215 @@
+use crate::eth::EthMessageId;
+use devp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Requests of a peer kept waiting for the control's response, per response type.
+const MAX_PENDING_REQUESTS: usize = 256;
+
+/// Requests that have not been answered within this time are forgotten.
+pub const REQUEST_ID_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Request IDs of eth/66+ peers, so that the control can send and receive
+/// payloads without the request ID envelope.
+#[derive(Debug, Default)]
+pub struct RequestIds {
+    next_request_id: u64,
+    /// IDs of requests from peers, answered in order because the control's response
+    /// does not carry the ID.
+    pending: HashMap<PeerId, HashMap<EthMessageId, VecDeque<(u64, Instant)>>>,
+    /// IDs we have put on requests to peers, with the response type they expect.
+    sent: HashMap<PeerId, HashMap<u64, (EthMessageId, Instant)>>,
+}
+
+impl RequestIds {
+    /// Peer has sent a request, to be answered with `response`.
+    pub fn on_request(
+        &mut self,
+        peer: PeerId,
+        response: EthMessageId,
+        request_id: u64,
+        now: Instant,
+    ) {
+        let pending = self
+            .pending
+            .entry(peer)
+            .or_default()
+            .entry(response)
+            .or_default();
+        if pending.len() >= MAX_PENDING_REQUESTS {
+            pending.pop_front();
+        }
+        pending.push_back((request_id, now));
+    }
+
+    /// ID of the oldest request of the peer answered by `response` that has not expired.
+    pub fn take_response_id(
+        &mut self,
+        peer: PeerId,
+        response: EthMessageId,
+        now: Instant,
+    ) -> Option<u64> {
+        let by_response = self.pending.get_mut(&peer)?;
+        let mut request_id = None;
+        if let Some(pending) = by_response.get_mut(&response) {
+            while let Some((id, received)) = pending.pop_front() {
+                if now.saturating_duration_since(received) < REQUEST_ID_TIMEOUT {
+                    request_id = Some(id);
+                    break;
+                }
+            }
+        }
+        by_response.retain(|_, pending| !pending.is_empty());
+        if by_response.is_empty() {
+            self.pending.remove(&peer);
+        }
+        request_id
+    }
+
+    /// ID for a new request to the peer, to be answered with `response`.
+    pub fn on_outbound_request(
+        &mut self,
+        peer: PeerId,
+        response: EthMessageId,
+        now: Instant,
+    ) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let sent = self.sent.entry(peer).or_default();
+        sent.retain(|_, (_, sent)| now.saturating_duration_since(*sent) < REQUEST_ID_TIMEOUT);
+        sent.insert(request_id, (response, now));
+        request_id
+    }
+
+    /// Peer has answered with `response` carrying `request_id`.
+    /// Returns `false` if we have not sent such a request or it has expired.
+    pub fn on_response(
+        &mut self,
+        peer: PeerId,
+        response: EthMessageId,
+        request_id: u64,
+        now: Instant,
+    ) -> bool {
+        let sent = match self.sent.get_mut(&peer) {
+            Some(v) => v,
+            None => return false,
+        };
+        let matched = match sent.get(&request_id) {
+            Some(&(expected, sent_at)) if expected == response => {
+                sent.remove(&request_id);
+                now.saturating_duration_since(sent_at) < REQUEST_ID_TIMEOUT
+            }
+            _ => false,
+        };
+        if sent.is_empty() {
+            self.sent.remove(&peer);
+        }
+        matched
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.pending.remove(&peer);
+        self.sent.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_matched_in_order() {
+        let mut ids = RequestIds::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let other = PeerId::from_low_u64_be(2);
+        let now = Instant::now();
+
+        ids.on_request(peer, EthMessageId::BlockHeaders, 10, now);
+        ids.on_request(peer, EthMessageId::BlockHeaders, 11, now);
+        ids.on_request(peer, EthMessageId::BlockBodies, 20, now);
+
+        assert_eq!(
+            ids.take_response_id(other, EthMessageId::BlockHeaders, now),
+            None
+        );
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::BlockHeaders, now),
+            Some(10)
+        );
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::BlockBodies, now),
+            Some(20)
+        );
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::BlockBodies, now),
+            None
+        );
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::BlockHeaders, now),
+            Some(11)
+        );
+        assert!(ids.pending.is_empty());
+
+        for request_id in 0..MAX_PENDING_REQUESTS as u64 + 1 {
+            ids.on_request(peer, EthMessageId::Receipts, request_id, now);
+        }
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::Receipts, now),
+            Some(1)
+        );
+        ids.on_disconnect(peer);
+        assert!(ids.pending.is_empty());
+    }
+
+    #[test]
+    fn unanswered_request_does_not_shift_later_ids() {
+        let mut ids = RequestIds::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        // The control never answers the first request.
+        ids.on_request(peer, EthMessageId::BlockHeaders, 10, now);
+        let later = now + REQUEST_ID_TIMEOUT;
+        ids.on_request(peer, EthMessageId::BlockHeaders, 11, later);
+        assert_eq!(
+            ids.take_response_id(peer, EthMessageId::BlockHeaders, later),
+            Some(11)
+        );
+        assert!(ids.pending.is_empty());
+    }
+
+    #[test]
+    fn replies_are_matched_by_request_id() {
+        let mut ids = RequestIds::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        let dropped = ids.on_outbound_request(peer, EthMessageId::BlockHeaders, now);
+        let answered = ids.on_outbound_request(peer, EthMessageId::BlockHeaders, now);
+        let bodies = ids.on_outbound_request(peer, EthMessageId::BlockBodies, now);
+        assert_ne!(dropped, answered);
+
+        // Reply to the second request is matched even though the first one is unanswered.
+        assert!(ids.on_response(peer, EthMessageId::BlockHeaders, answered, now));
+        assert!(!ids.on_response(peer, EthMessageId::BlockHeaders, answered, now));
+        assert!(!ids.on_response(peer, EthMessageId::BlockHeaders, bodies, now));
+        assert!(!ids.on_response(
+            peer,
+            EthMessageId::BlockHeaders,
+            dropped,
+            now + REQUEST_ID_TIMEOUT
+        ));
+        assert!(!ids.on_response(
+            PeerId::from_low_u64_be(2),
+            EthMessageId::BlockBodies,
+            bodies,
+            now
+        ));
+
+        ids.on_disconnect(peer);
+        assert!(ids.sent.is_empty());
+    }
+}
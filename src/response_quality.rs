@@ -0,0 +1,347 @@
+use crate::eth::{BlockId, GetBlockHeaders};
+use devp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Directed requests not answered within this time are counted as timed out.
+pub const DIRECTED_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_OUTSTANDING_PER_PEER: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseClass {
+    Empty,
+    Partial,
+    Full,
+    Timeout,
+}
+
+impl ResponseClass {
+    fn is_failure(self) -> bool {
+        matches!(self, Self::Empty | Self::Timeout)
+    }
+}
+
+/// Number of headers a peer with head at `peer_head` is able to return for the request.
+pub fn expected_headers(request: &GetBlockHeaders, peer_head: Option<u64>) -> u64 {
+    let (start, head) = match (request.block, peer_head) {
+        (BlockId::Number(start), Some(head)) => (start, head),
+        // Cannot tell what the peer has, assume it has everything.
+        _ => return request.max_headers,
+    };
+
+    if start > head {
+        return 0;
+    }
+
+    let step = request.skip.saturating_add(1);
+    let available = if request.reverse {
+        start / step + 1
+    } else {
+        (head - start) / step + 1
+    };
+
+    available.min(request.max_headers)
+}
+
+/// Classify response with `received` headers. Returns `None` if the peer could not have
+/// returned anything, so the empty response is legitimate and does not count.
+pub fn classify(expected: u64, received: usize) -> Option<ResponseClass> {
+    if expected == 0 {
+        return None;
+    }
+
+    Some(if received == 0 {
+        ResponseClass::Empty
+    } else if (received as u64) < expected {
+        ResponseClass::Partial
+    } else {
+        ResponseClass::Full
+    })
+}
+
+#[derive(Debug)]
+struct OutstandingRequest {
+    /// ID carried by requests to eth/66+ peers.
+    request_id: Option<u64>,
+    /// Headers the peer is able to return, `None` for requests that are not scored.
+    expected: Option<u64>,
+    sent: Instant,
+}
+
+/// Tracks how peers answer our directed GetBlockHeaders and demotes the ones that
+/// keep answering with nothing. Other GetBlockHeaders sent to the peer are tracked too,
+/// so that their replies are not taken for replies to directed ones.
+#[derive(Debug)]
+pub struct ResponseQuality {
+    window: usize,
+    min_samples: usize,
+    threshold: f64,
+    cooldown: Duration,
+
+    outstanding: HashMap<PeerId, VecDeque<OutstandingRequest>>,
+    outcomes: HashMap<PeerId, VecDeque<ResponseClass>>,
+    demoted_until: HashMap<PeerId, Instant>,
+}
+
+impl ResponseQuality {
+    pub fn new(window: usize, min_samples: usize, threshold: f64, cooldown: Duration) -> Self {
+        Self {
+            window: window.max(1),
+            min_samples,
+            threshold,
+            cooldown,
+            outstanding: Default::default(),
+            outcomes: Default::default(),
+            demoted_until: Default::default(),
+        }
+    }
+
+    /// Record GetBlockHeaders sent to the peer. Only requests with `expected` set are scored.
+    pub fn on_request_sent(
+        &mut self,
+        peer: PeerId,
+        request_id: Option<u64>,
+        expected: Option<u64>,
+        now: Instant,
+    ) {
+        let outstanding = self.outstanding.entry(peer).or_default();
+        if outstanding.len() >= MAX_OUTSTANDING_PER_PEER {
+            outstanding.pop_front();
+        }
+        outstanding.push_back(OutstandingRequest {
+            request_id,
+            expected,
+            sent: now,
+        });
+    }
+
+    /// Record response with `received` headers. Response with a request ID answers the
+    /// request with that ID. Without one, responses come in request order, but the
+    /// response is only scored if no other request has been in flight.
+    ///
+    /// Returns `true` if the peer has just been demoted.
+    pub fn on_response(
+        &mut self,
+        peer: PeerId,
+        request_id: Option<u64>,
+        received: usize,
+        now: Instant,
+    ) -> bool {
+        let outstanding = match self.outstanding.get_mut(&peer) {
+            Some(v) => v,
+            None => return false,
+        };
+        let request = match request_id {
+            Some(request_id) => outstanding
+                .iter()
+                .position(|request| request.request_id == Some(request_id))
+                .and_then(|i| outstanding.remove(i)),
+            None => {
+                let unambiguous = outstanding.len() == 1;
+                outstanding.pop_front().filter(|_| unambiguous)
+            }
+        };
+        if outstanding.is_empty() {
+            self.outstanding.remove(&peer);
+        }
+
+        match request
+            .and_then(|request| request.expected)
+            .and_then(|expected| classify(expected, received))
+        {
+            Some(class) => self.record(peer, class, now),
+            None => false,
+        }
+    }
+
+    /// Time out stale requests. Returns peers demoted as a result.
+    pub fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut timed_out = Vec::new();
+        for (&peer, outstanding) in &mut self.outstanding {
+            while let Some(request) = outstanding.front() {
+                if now.saturating_duration_since(request.sent) < DIRECTED_REQUEST_TIMEOUT {
+                    break;
+                }
+
+                if request.expected.unwrap_or_default() > 0 {
+                    timed_out.push(peer);
+                }
+                outstanding.pop_front();
+            }
+        }
+        self.outstanding
+            .retain(|_, outstanding| !outstanding.is_empty());
+        self.demoted_until.retain(|_, &mut until| until > now);
+
+        let mut demoted = Vec::new();
+        for peer in timed_out {
+            if self.record(peer, ResponseClass::Timeout, now) {
+                demoted.push(peer);
+            }
+        }
+        demoted
+    }
+
+    fn record(&mut self, peer: PeerId, class: ResponseClass, now: Instant) -> bool {
+        let outcomes = self.outcomes.entry(peer).or_default();
+        if outcomes.len() >= self.window {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(class);
+
+        if outcomes.len() < self.min_samples {
+            return false;
+        }
+
+        let failures = outcomes.iter().filter(|class| class.is_failure()).count();
+        if failures as f64 / outcomes.len() as f64 <= self.threshold {
+            return false;
+        }
+
+        // Start from scratch once the cool-down is over.
+        outcomes.clear();
+        self.demoted_until.insert(peer, now + self.cooldown);
+        true
+    }
+
+    pub fn is_demoted(&self, peer: PeerId, now: Instant) -> bool {
+        self.demoted_until
+            .get(&peer)
+            .map(|&until| until > now)
+            .unwrap_or(false)
+    }
+
+    /// Forget pending requests and outcomes of a disconnected peer. Demotion stays in force.
+    /// Returns the forgotten outcomes.
+    pub fn forget(&mut self, peer: PeerId) -> VecDeque<ResponseClass> {
+        self.outstanding.remove(&peer);
+        self.outcomes.remove(&peer).unwrap_or_default()
+    }
+
+    /// Restore outcomes of a peer that has resumed its previous session.
+    pub fn restore_outcomes(&mut self, peer: PeerId, outcomes: VecDeque<ResponseClass>) {
+        if !outcomes.is_empty() {
+            self.outcomes.insert(peer, outcomes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    fn request(start: u64, max_headers: u64, skip: u64, reverse: bool) -> GetBlockHeaders {
+        GetBlockHeaders {
+            block: BlockId::Number(start),
+            max_headers,
+            skip,
+            reverse,
+        }
+    }
+
+    #[test]
+    fn expected_against_peer_head() {
+        assert_eq!(
+            expected_headers(&request(100, 192, 0, false), Some(1000)),
+            192
+        );
+        assert_eq!(
+            expected_headers(&request(900, 192, 0, false), Some(1000)),
+            101
+        );
+        assert_eq!(
+            expected_headers(&request(900, 192, 9, false), Some(1000)),
+            11
+        );
+        assert_eq!(
+            expected_headers(&request(1001, 192, 0, false), Some(1000)),
+            0
+        );
+        assert_eq!(expected_headers(&request(10, 192, 0, true), Some(1000)), 11);
+        assert_eq!(expected_headers(&request(10, 192, 0, false), None), 192);
+
+        assert_eq!(classify(0, 0), None);
+        assert_eq!(classify(10, 0), Some(ResponseClass::Empty));
+        assert_eq!(classify(10, 5), Some(ResponseClass::Partial));
+        assert_eq!(classify(10, 10), Some(ResponseClass::Full));
+    }
+
+    #[test]
+    fn repeated_empty_responses_demote() {
+        let mut quality = ResponseQuality::new(10, 5, 0.5, COOLDOWN);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        let mut demoted = false;
+        for i in 0..5 {
+            quality.on_request_sent(peer, None, Some(192), now);
+            demoted = quality.on_response(peer, None, if i == 0 { 192 } else { 0 }, now);
+        }
+        assert!(demoted);
+        assert!(quality.is_demoted(peer, now));
+        assert!(quality.is_demoted(peer, now + COOLDOWN - Duration::from_secs(1)));
+        assert!(!quality.is_demoted(peer, now + COOLDOWN));
+    }
+
+    #[test]
+    fn legitimate_and_partial_responses_do_not_demote() {
+        let mut quality = ResponseQuality::new(10, 5, 0.5, COOLDOWN);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        for _ in 0..20 {
+            // Beyond peer's head.
+            quality.on_request_sent(peer, None, Some(0), now);
+            assert!(!quality.on_response(peer, None, 0, now));
+
+            quality.on_request_sent(peer, None, Some(192), now);
+            assert!(!quality.on_response(peer, None, 10, now));
+        }
+        assert!(!quality.is_demoted(peer, now));
+    }
+
+    #[test]
+    fn timeouts_count_as_failures() {
+        let mut quality = ResponseQuality::new(10, 4, 0.5, COOLDOWN);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        quality.on_request_sent(peer, None, Some(192), now);
+        assert!(!quality.on_response(peer, None, 192, now));
+        for _ in 0..3 {
+            quality.on_request_sent(peer, None, Some(192), now);
+        }
+
+        assert_eq!(quality.expire(now), vec![]);
+        assert_eq!(quality.expire(now + DIRECTED_REQUEST_TIMEOUT), vec![peer]);
+        assert!(quality.is_demoted(peer, now + DIRECTED_REQUEST_TIMEOUT));
+
+        // Late response to an expired request is ignored.
+        assert!(!quality.on_response(peer, None, 0, now + DIRECTED_REQUEST_TIMEOUT));
+    }
+
+    #[test]
+    fn replies_to_other_requests_are_not_scored() {
+        let mut quality = ResponseQuality::new(10, 1, 0.4, COOLDOWN);
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        // Empty reply to a request sent to all peers, then a full one to ours.
+        quality.on_request_sent(peer, Some(1), None, now);
+        quality.on_request_sent(peer, Some(2), Some(192), now);
+        assert!(!quality.on_response(peer, Some(1), 0, now));
+        assert!(!quality.on_response(peer, Some(2), 192, now));
+        assert!(!quality.on_response(peer, Some(3), 0, now));
+
+        // Without request IDs an empty reply cannot be told apart while both are in flight.
+        quality.on_request_sent(peer, None, Some(192), now);
+        quality.on_request_sent(peer, None, Some(192), now);
+        assert!(!quality.on_response(peer, None, 0, now));
+        assert!(quality.on_response(peer, None, 0, now));
+        assert!(quality.is_demoted(peer, now));
+    }
+}
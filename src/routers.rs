@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use devp2p::{CapabilityId, CapabilityName, Message, PeerId};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
+
+/// Handler for a capability registered at runtime next to eth.
+#[async_trait]
+pub trait MessageRouter: Debug + Send + Sync + 'static {
+    /// Peer that has negotiated this capability has connected.
+    fn on_peer_connect(&self, _peer: PeerId) {}
+
+    /// Peer that has negotiated this capability has disconnected.
+    fn on_peer_disconnect(&self, _peer: PeerId) {}
+
+    /// Handle message of this capability, optionally replying to it.
+    async fn on_message(&self, peer: PeerId, message: Message) -> Option<Message>;
+}
+
+/// Routers of dynamically registered capabilities and peers that use them.
+/// Each version of a capability has its own router.
+#[derive(Debug, Default)]
+pub struct CapabilityRouters {
+    routers: HashMap<CapabilityId, Arc<dyn MessageRouter>>,
+    peers: HashMap<CapabilityId, HashSet<PeerId>>,
+}
+
+impl CapabilityRouters {
+    pub fn insert(&mut self, cap: CapabilityId, router: Arc<dyn MessageRouter>) {
+        self.routers.insert(cap, router);
+    }
+
+    /// Remove router. Returns peers that were using it.
+    pub fn remove(&mut self, cap: CapabilityId) -> HashSet<PeerId> {
+        self.routers.remove(&cap);
+        self.peers.remove(&cap).unwrap_or_default()
+    }
+
+    /// Router of the version of the capability that the peer has negotiated.
+    pub fn get(&self, peer: PeerId, name: CapabilityName) -> Option<Arc<dyn MessageRouter>> {
+        self.peers
+            .iter()
+            .find(|(cap, peers)| cap.name == name && peers.contains(&peer))
+            .and_then(|(cap, _)| self.routers.get(cap).cloned())
+    }
+
+    /// Record that peer has negotiated the capability. Returns its router, if any.
+    pub fn on_peer_connect(
+        &mut self,
+        peer: PeerId,
+        cap: CapabilityId,
+    ) -> Option<Arc<dyn MessageRouter>> {
+        let router = self.routers.get(&cap)?.clone();
+        self.peers.entry(cap).or_default().insert(peer);
+        Some(router)
+    }
+
+    /// Forget the peer. Returns routers of the capabilities it was using.
+    pub fn on_peer_disconnect(&mut self, peer: PeerId) -> Vec<Arc<dyn MessageRouter>> {
+        let routers = &self.routers;
+        self.peers
+            .iter_mut()
+            .filter_map(|(cap, peers)| {
+                if peers.remove(&peer) {
+                    routers.get(cap).cloned()
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+
+    /// Replies with its version as the message ID.
+    #[derive(Debug)]
+    struct VersionRouter(usize);
+
+    #[async_trait]
+    impl MessageRouter for VersionRouter {
+        async fn on_message(&self, _: PeerId, message: Message) -> Option<Message> {
+            Some(Message {
+                id: self.0,
+                data: message.data,
+            })
+        }
+    }
+
+    fn cap(version: usize) -> CapabilityId {
+        CapabilityId {
+            name: CapabilityName(ArrayString::from("aa").unwrap()),
+            version,
+        }
+    }
+
+    fn routers() -> CapabilityRouters {
+        let mut routers = CapabilityRouters::default();
+        routers.insert(cap(1), Arc::new(VersionRouter(1)));
+        routers.insert(cap(2), Arc::new(VersionRouter(2)));
+        routers
+    }
+
+    async fn dispatch(routers: &CapabilityRouters, peer: PeerId) -> Option<usize> {
+        let router = routers.get(peer, cap(1).name)?;
+        let message = Message {
+            id: 0,
+            data: Default::default(),
+        };
+        Some(router.on_message(peer, message).await?.id)
+    }
+
+    #[test]
+    fn register_keeps_versions_apart() {
+        let mut routers = routers();
+        let (old, new) = (PeerId::from_low_u64_be(1), PeerId::from_low_u64_be(2));
+
+        assert!(routers.on_peer_connect(old, cap(1)).is_some());
+        assert!(routers.on_peer_connect(new, cap(2)).is_some());
+        assert!(routers
+            .on_peer_connect(PeerId::from_low_u64_be(3), cap(3))
+            .is_none());
+
+        assert_eq!(routers.on_peer_disconnect(old).len(), 1);
+        assert!(routers.get(old, cap(1).name).is_none());
+        assert!(routers.get(new, cap(1).name).is_some());
+    }
+
+    #[test]
+    fn unregister_removes_only_its_version() {
+        let mut routers = routers();
+        let (old, new) = (PeerId::from_low_u64_be(1), PeerId::from_low_u64_be(2));
+        routers.on_peer_connect(old, cap(1));
+        routers.on_peer_connect(new, cap(2));
+
+        assert_eq!(
+            routers.remove(cap(1)),
+            std::iter::once(old).collect::<HashSet<_>>()
+        );
+        assert!(routers.get(old, cap(1).name).is_none());
+        assert!(routers.get(new, cap(2).name).is_some());
+        assert!(routers.on_peer_connect(old, cap(1)).is_none());
+        assert!(routers.on_peer_connect(old, cap(2)).is_some());
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_negotiated_version() {
+        let mut routers = routers();
+        let (old, new) = (PeerId::from_low_u64_be(1), PeerId::from_low_u64_be(2));
+        routers.on_peer_connect(old, cap(1));
+        routers.on_peer_connect(new, cap(2));
+
+        assert_eq!(dispatch(&routers, old).await, Some(1));
+        assert_eq!(dispatch(&routers, new).await, Some(2));
+        assert_eq!(dispatch(&routers, PeerId::from_low_u64_be(3)).await, None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Checks of what the sentry needs from its environment, run on `--self-test`
+//! without joining the network.
+
+use anyhow::{anyhow, bail};
+use futures::future::{join_all, BoxFuture};
+use igd::aio::search_gateway;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use serde::Serialize;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{TcpListener, UdpSocket};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Time each check gets unless it sets its own.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DNSDISC_ROOT_PREFIX: &str = "enrtree-root:v1";
+
+/// Checks that can be repeated while the sentry runs, unlike binding of its ports.
+pub type RuntimeChecks = Arc<dyn Fn() -> Vec<Check> + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CheckReport {
+    pub name: String,
+    /// Failure of a required check fails the self-test.
+    pub required: bool,
+    pub passed: bool,
+    pub detail: String,
+}
+
+pub struct Check {
+    name: String,
+    required: bool,
+    timeout: Duration,
+    run: BoxFuture<'static, anyhow::Result<String>>,
+}
+
+impl Check {
+    /// Check passes if `run` returns `Ok` in time, with details of what it has found.
+    pub fn new(
+        name: impl Into<String>,
+        required: bool,
+        run: impl Future<Output = anyhow::Result<String>> + Send + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            required,
+            timeout: CHECK_TIMEOUT,
+            run: Box::pin(run),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Run all checks concurrently, reports are in the order of the checks.
+pub async fn run_checks(checks: Vec<Check>) -> Vec<CheckReport> {
+    join_all(checks.into_iter().map(|check| async move {
+        let (passed, detail) = match tokio::time::timeout(check.timeout, check.run).await {
+            Ok(Ok(detail)) => (true, detail),
+            Ok(Err(e)) => (false, format!("{:#}", e)),
+            Err(_) => (false, format!("timed out after {:?}", check.timeout)),
+        };
+        CheckReport {
+            name: check.name,
+            required: check.required,
+            passed,
+            detail,
+        }
+    }))
+    .await
+}
+
+/// Whether all required checks have passed.
+pub fn passed(reports: &[CheckReport]) -> bool {
+    reports
+        .iter()
+        .all(|report| report.passed || !report.required)
+}
+
+pub fn node_key_check(node_key: Option<String>) -> Check {
+    Check::new("node key", true, async move {
+        let node_key = match node_key {
+            Some(v) => v,
+            None => return Ok("not configured, a new key is generated on every start".into()),
+        };
+        let secret_key = SecretKey::from_slice(&hex::decode(node_key)?)?;
+        Ok(format!(
+            "node ID {}",
+            hex::encode(
+                devp2p::util::pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key)).as_bytes()
+            )
+        ))
+    })
+}
+
+pub fn tcp_bind_check(name: impl Into<String>, addr: SocketAddr) -> Check {
+    Check::new(name, true, async move {
+        TcpListener::bind(addr).await?;
+        Ok(format!("TCP {} is free", addr))
+    })
+}
+
+pub fn udp_bind_check(name: impl Into<String>, addr: SocketAddr) -> Check {
+    Check::new(name, true, async move {
+        UdpSocket::bind(addr).await?;
+        Ok(format!("UDP {} is free", addr))
+    })
+}
+
+pub fn dnsdisc_check(resolver: Arc<TokioAsyncResolver>, domain: String) -> Check {
+    Check::new("DNS discovery", true, async move {
+        let records = resolver.txt_lookup(domain.as_str()).await?;
+        for record in records.iter() {
+            let text = record
+                .txt_data()
+                .iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect::<String>();
+            if text.starts_with(DNSDISC_ROOT_PREFIX) {
+                return Ok(format!("{} has tree root {}", domain, text));
+            }
+        }
+        bail!("{} has no {} record", domain, DNSDISC_ROOT_PREFIX)
+    })
+}
+
+/// Address peers can reach us at, found through UPnP like discv4 does. Not required, as
+/// the node may be reachable through a port forward instead.
+pub fn external_address_check() -> Check {
+    Check::new("external address", false, async move {
+        let ip = IpAddr::V4(
+            search_gateway(Default::default())
+                .await
+                .map_err(|e| anyhow!("no UPnP gateway: {}", e))?
+                .get_external_ip()
+                .await?,
+        );
+        if !is_routable(ip) {
+            bail!("gateway reports {}, which is not routable", ip);
+        }
+        Ok(format!("{} through UPnP gateway", ip))
+    })
+}
+
+fn is_routable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_checks_with_timeouts() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let reports = run_checks(vec![
+            node_key_check(Some("00".repeat(31) + "01")),
+            node_key_check(Some("zz".into())),
+            tcp_bind_check("RLPx port", taken.local_addr().unwrap()),
+            Check::new("slow", false, async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(String::new())
+            })
+            .with_timeout(Duration::from_millis(10)),
+        ])
+        .await;
+
+        let passed_by_name = reports
+            .iter()
+            .map(|report| (report.name.as_str(), report.passed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            passed_by_name,
+            vec![
+                ("node key", true),
+                ("node key", false),
+                ("RLPx port", false),
+                ("slow", false)
+            ]
+        );
+        assert!(reports[3].detail.contains("timed out"));
+        assert!(!passed(&reports));
+        assert!(passed(&reports[3..]));
+
+        assert!(is_routable("1.1.1.1".parse().unwrap()));
+        assert!(!is_routable("100.64.0.1".parse().unwrap()));
+        assert!(!is_routable("fd00::1".parse().unwrap()));
+    }
+}
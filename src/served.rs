@@ -0,0 +1,152 @@
+use devp2p::PeerId;
+use serde::Serialize;
+use std::{collections::HashMap, ops::AddAssign};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServedKind {
+    Headers,
+    Bodies,
+}
+
+impl ServedKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Headers => "headers",
+            Self::Bodies => "bodies",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServedSource {
+    /// Answered by the sentry from its header cache.
+    Cache,
+    /// Answered by the control.
+    Control,
+}
+
+impl ServedSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cache => "cache",
+            Self::Control => "control",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ServedCount {
+    pub items: u64,
+    pub bytes: u64,
+}
+
+impl AddAssign for ServedCount {
+    fn add_assign(&mut self, other: Self) {
+        self.items += other.items;
+        self.bytes += other.bytes;
+    }
+}
+
+type Breakdown = HashMap<(ServedKind, ServedSource), ServedCount>;
+
+/// Headers and bodies served to peers, in total and per connected peer.
+#[derive(Debug, Default)]
+pub struct ServedData {
+    total: Breakdown,
+    by_peer: HashMap<PeerId, Breakdown>,
+}
+
+impl ServedData {
+    pub fn record(
+        &mut self,
+        peer: PeerId,
+        kind: ServedKind,
+        source: ServedSource,
+        count: ServedCount,
+    ) {
+        *self.total.entry((kind, source)).or_default() += count;
+        *self
+            .by_peer
+            .entry(peer)
+            .or_default()
+            .entry((kind, source))
+            .or_default() += count;
+    }
+
+    pub fn total(&self, kind: ServedKind, source: ServedSource) -> ServedCount {
+        self.total.get(&(kind, source)).copied().unwrap_or_default()
+    }
+
+    /// Everything served to the peer since it connected.
+    pub fn peer_total(&self, peer: PeerId) -> ServedCount {
+        let mut sum = ServedCount::default();
+        for &count in self.by_peer.get(&peer).into_iter().flat_map(|b| b.values()) {
+            sum += count;
+        }
+        sum
+    }
+
+    /// Connected peers that have been served the most bytes, most first.
+    pub fn top_consumers(&self, limit: usize) -> Vec<(PeerId, ServedCount)> {
+        let mut peers = self
+            .by_peer
+            .keys()
+            .map(|&peer| (peer, self.peer_total(peer)))
+            .collect::<Vec<_>>();
+        peers.sort_by(|(a_peer, a), (b_peer, b)| {
+            b.bytes.cmp(&a.bytes).then_with(|| a_peer.cmp(b_peer))
+        });
+        peers.truncate(limit);
+        peers
+    }
+
+    pub fn on_disconnect(&mut self, peer: PeerId) {
+        self.by_peer.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_and_top_consumers() {
+        let mut served = ServedData::default();
+        let a = PeerId::from_low_u64_be(1);
+        let b = PeerId::from_low_u64_be(2);
+        let count = |items, bytes| ServedCount { items, bytes };
+
+        served.record(a, ServedKind::Headers, ServedSource::Cache, count(10, 5000));
+        served.record(
+            a,
+            ServedKind::Headers,
+            ServedSource::Control,
+            count(2, 1000),
+        );
+        served.record(b, ServedKind::Bodies, ServedSource::Control, count(1, 8000));
+        served.record(b, ServedKind::Headers, ServedSource::Cache, count(1, 500));
+
+        assert_eq!(
+            served.total(ServedKind::Headers, ServedSource::Cache),
+            count(11, 5500)
+        );
+        assert_eq!(
+            served.total(ServedKind::Bodies, ServedSource::Cache),
+            count(0, 0)
+        );
+        assert_eq!(served.peer_total(a), count(12, 6000));
+        assert_eq!(
+            served.top_consumers(5),
+            vec![(b, count(2, 8500)), (a, count(12, 6000))]
+        );
+        assert_eq!(served.top_consumers(1), vec![(b, count(2, 8500))]);
+
+        // Totals outlive the peer.
+        served.on_disconnect(b);
+        assert_eq!(served.top_consumers(5), vec![(a, count(12, 6000))]);
+        assert_eq!(
+            served.total(ServedKind::Bodies, ServedSource::Control),
+            count(1, 8000)
+        );
+    }
+}
@@ -1,22 +1,23 @@
 use crate::{
     eth::*,
+    forwarding::{ForwardQueue, Stamp},
     grpc::sentry::{
         sentry_server::*, InboundMessage, OutboundMessageData, PeerMinBlockRequest, SentPeers,
     },
+    served::ServedSource,
+    shutdown::{drained, Shutdown},
+    siblings::decode_sibling_ban,
     CapabilityServerImpl,
 };
+use async_stream::stream;
 use async_trait::async_trait;
 use devp2p::*;
 use futures::{stream::FuturesUnordered, Stream};
-use num_traits::ToPrimitive;
-use std::{
-    convert::{identity, TryFrom},
-    pin::Pin,
-    sync::Arc,
-};
-use tokio::sync::broadcast::Sender as BroadcastSender;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::{collections::HashMap, convert::TryFrom, pin::Pin, sync::Arc};
+use tokio_stream::StreamExt;
 use tonic::Response;
+use tracing::*;
 
 pub type InboundMessageStream =
     Pin<Box<dyn Stream<Item = anyhow::Result<InboundMessage, tonic::Status>> + Send + Sync>>;
@@ -31,61 +32,274 @@ impl SentryService {
     }
 }
 
+/// Failed sends listed in the response metadata, the rest are only counted.
+pub const MAX_REPORTED_SEND_FAILURES: usize = 16;
+/// Response metadata with peers a message was not sent to, as comma separated
+/// `<peer id>:<status>`.
+pub const SEND_FAILURES_KEY: &str = "x-send-failures";
+/// Response metadata with the number of failed sends left out of `SEND_FAILURES_KEY`.
+pub const SEND_FAILURES_OVERFLOW_KEY: &str = "x-send-failures-overflow";
+
+/// Outcome of sending a message from the control to one peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SendStatus {
+    Sent,
+    /// Message is not valid for the peer's eth version.
+    Invalid,
+    /// Response to a request the peer has not made, or has been answered already.
+    NoPendingRequest,
+    /// Peer disconnected before the message could be queued.
+    Disconnected,
+}
+
+impl SendStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Invalid => "invalid",
+            Self::NoPendingRequest => "no_pending_request",
+            Self::Disconnected => "disconnected",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SendFailure {
+    pub peer: PeerId,
+    pub status: SendStatus,
+    pub detail: Option<String>,
+}
+
+/// Peers a message was sent to, and why it was not sent to the others.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SendReport {
+    pub sent: Vec<PeerId>,
+    /// At most `MAX_REPORTED_SEND_FAILURES`.
+    pub failures: Vec<SendFailure>,
+    /// Failures beyond `MAX_REPORTED_SEND_FAILURES`.
+    pub overflow: usize,
+    /// `overflow` by status, for metrics.
+    pub overflow_statuses: HashMap<SendStatus, usize>,
+}
+
+impl SendReport {
+    fn push(&mut self, peer: PeerId, status: SendStatus, detail: Option<String>) {
+        if status == SendStatus::Sent {
+            self.sent.push(peer);
+        } else if self.failures.len() < MAX_REPORTED_SEND_FAILURES {
+            self.failures.push(SendFailure {
+                peer,
+                status,
+                detail,
+            });
+        } else {
+            self.overflow += 1;
+            *self.overflow_statuses.entry(status).or_default() += 1;
+        }
+    }
+
+    /// Sent peers in the body, failures in the metadata as the proto has no place for them.
+    pub fn into_response(self) -> Response<SentPeers> {
+        let mut response = Response::new(SentPeers {
+            peers: self.sent.into_iter().map(Into::into).collect(),
+        });
+        if !self.failures.is_empty() {
+            let failures = self
+                .failures
+                .iter()
+                .map(|failure| {
+                    format!(
+                        "{}:{}",
+                        hex::encode(failure.peer.as_bytes()),
+                        failure.status.as_str()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            let metadata = response.metadata_mut();
+            metadata.insert(SEND_FAILURES_KEY, failures.parse().unwrap());
+            metadata.insert(SEND_FAILURES_OVERFLOW_KEY, self.overflow.into());
+        }
+        response
+    }
+}
+
 impl SentryService {
+    /// Send message to peers selected by `pred`. Peers for which the message is invalid are
+    /// skipped, and if it is invalid for all of them, the request is rejected.
     async fn send_by_predicate<F, IT>(
         &self,
         request: Option<OutboundMessageData>,
         pred: F,
-    ) -> SentPeers
+    ) -> Result<SendReport, tonic::Status>
+    where
+        F: FnOnce(&CapabilityServerImpl) -> IT,
+        IT: IntoIterator<Item = PeerId>,
+    {
+        self.send_to_peers(request, pred, false).await
+    }
+
+    /// Send message to the peers picked by `pred`. Requests sent to a single peer the
+    /// control has picked are `directed`, replies to them are scored.
+    async fn send_to_peers<F, IT>(
+        &self,
+        request: Option<OutboundMessageData>,
+        pred: F,
+        directed: bool,
+    ) -> Result<SendReport, tonic::Status>
     where
         F: FnOnce(&CapabilityServerImpl) -> IT,
         IT: IntoIterator<Item = PeerId>,
     {
+        let mut report = SendReport::default();
         if let Some(request) = request {
             let data = request.data;
             let id = request.id.to_usize().unwrap();
 
-            return SentPeers {
-                peers: (pred)(&*self.capability_server)
-                    .into_iter()
-                    .map(|peer| {
-                        let data = data.clone();
-                        async move {
-                            if let Some(sender) = self.capability_server.sender(peer) {
-                                if sender
-                                    .send(OutboundEvent::Message {
-                                        capability_name: capability_name(),
-                                        message: Message { id, data },
-                                    })
-                                    .await
-                                    .is_ok()
-                                {
-                                    return Some(peer);
-                                }
+            let mut invalid = None;
+            let peers = (pred)(&*self.capability_server)
+                .into_iter()
+                .filter(|&peer| {
+                    match self
+                        .capability_server
+                        .validate_outbound_message(peer, id, &data)
+                    {
+                        Ok(()) => true,
+                        Err(e) => {
+                            debug!("Not sending message to peer {}: {}", peer, e);
+                            report.push(peer, SendStatus::Invalid, Some(e.to_string()));
+                            invalid = Some(e);
+                            false
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            if peers.is_empty() {
+                if let Some(e) = invalid {
+                    return Err(tonic::Status::invalid_argument(e.to_string()));
+                }
+            }
+
+            self.capability_server.on_control_message(id, &data);
+
+            let outcomes = peers
+                .into_iter()
+                .map(|peer| {
+                    let data = data.clone();
+                    async move {
+                        let data = match self
+                            .capability_server
+                            .frame_outbound_message(peer, id, data)
+                        {
+                            Some(data) => data,
+                            None => return (peer, SendStatus::NoPendingRequest),
+                        };
+                        let data = self
+                            .capability_server
+                            .sort_transaction_fetch(peer, id, data);
+                        if let Some(sender) = self.capability_server.sender(peer) {
+                            let message = Message { id, data };
+                            if sender
+                                .send(OutboundEvent::Message {
+                                    capability_name: capability_name(),
+                                    message: message.clone(),
+                                })
+                                .await
+                                .is_ok()
+                            {
+                                self.capability_server.on_served(
+                                    peer,
+                                    &message,
+                                    ServedSource::Control,
+                                );
+                                self.capability_server.on_message_sent(
+                                    peer,
+                                    id,
+                                    &message.data,
+                                    directed,
+                                );
+                                return (peer, SendStatus::Sent);
                             }
+                        }
+
+                        (peer, SendStatus::Disconnected)
+                    }
+                })
+                .collect::<FuturesUnordered<_>>()
+                .collect::<Vec<_>>()
+                .await;
+            for (peer, status) in outcomes {
+                report.push(peer, status, None);
+            }
+        }
 
-                            None
+        for failure in &report.failures {
+            self.capability_server
+                .metrics
+                .observe_send_failures(failure.status, 1);
+        }
+        for (&status, &count) in &report.overflow_statuses {
+            self.capability_server
+                .metrics
+                .observe_send_failures(status, count);
+        }
+        if !report.failures.is_empty() {
+            debug!(
+                "Message from control not sent to {} peers: {}",
+                report.failures.len() + report.overflow,
+                report
+                    .failures
+                    .iter()
+                    .map(|failure| match &failure.detail {
+                        Some(detail) => {
+                            format!("{} {} ({})", failure.peer, failure.status.as_str(), detail)
                         }
+                        None => format!("{} {}", failure.peer, failure.status.as_str()),
                     })
-                    .collect::<FuturesUnordered<_>>()
-                    .filter_map(identity)
-                    .map(|peer_id| peer_id.into())
                     .collect::<Vec<_>>()
-                    .await,
-            };
+                    .join(", ")
+            );
         }
 
-        SentPeers { peers: vec![] }
+        Ok(report)
     }
 
     fn make_channel(
         &self,
-        f: impl Fn(&CapabilityServerImpl) -> &BroadcastSender<InboundMessage>,
+        f: impl Fn(&CapabilityServerImpl) -> &ForwardQueue,
     ) -> Response<InboundMessageStream> {
-        Response::new(Box::pin(
-            BroadcastStream::new((f)(&self.capability_server).subscribe())
-                .filter_map(|res| res.ok().map(Ok)),
-        ))
+        let queue = (f)(&self.capability_server);
+        let name = queue.name();
+        let mut subscription = queue.subscribe();
+        let metrics = self.capability_server.metrics.clone();
+        let mut draining = self.capability_server.shutdown.subscribe();
+        Response::new(Box::pin(stream! {
+            loop {
+                let forwarded = tokio::select! {
+                    forwarded = subscription.recv() => match forwarded {
+                        Some(forwarded) => forwarded,
+                        None => break,
+                    },
+                    // End with a status the control can tell from a broken connection.
+                    _ = drained(&mut draining) => {
+                        yield Err(Shutdown::status());
+                        break;
+                    }
+                };
+                let dequeued = Stamp::now();
+                let id = forwarded.message.id;
+                let handled = forwarded.queued.since(&forwarded.received);
+                metrics.observe_forward_stage(name, "handle", id, handled);
+                let queued = dequeued.since(&forwarded.queued);
+                metrics.observe_forward_stage(name, "queue", id, queued);
+
+                yield Ok(forwarded.message);
+
+                // Resumed once the message has been written to the stream.
+                let sent = Stamp::now().since(&dequeued);
+                metrics.observe_forward_stage(name, "send", id, sent);
+            }
+        }))
     }
 }
 
@@ -95,11 +309,23 @@ impl Sentry for SentryService {
         &self,
         request: tonic::Request<crate::grpc::sentry::PenalizePeerRequest>,
     ) -> Result<Response<()>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
+        let sibling_ban = decode_sibling_ban(request.metadata())?;
         let peer = request
             .into_inner()
             .peer_id
             .ok_or_else(|| tonic::Status::invalid_argument("no peer id"))?
             .into();
+        let disconnect = match sibling_ban {
+            Some(ban) => self.capability_server.on_sibling_ban(peer, ban),
+            None => {
+                self.capability_server.ban_peer(peer);
+                true
+            }
+        };
+        if !disconnect {
+            return Ok(Response::new(()));
+        }
         if let Some(sender) = self.capability_server.sender(peer) {
             let _ = sender
                 .send(OutboundEvent::Disconnect {
@@ -115,79 +341,127 @@ impl Sentry for SentryService {
         &self,
         request: tonic::Request<crate::grpc::sentry::SendMessageByMinBlockRequest>,
     ) -> Result<Response<SentPeers>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         let crate::grpc::sentry::SendMessageByMinBlockRequest { data, min_block } =
             request.into_inner();
-        Ok(Response::new(
-            self.send_by_predicate(data, |capability_server| {
-                capability_server
-                    .block_tracker
-                    .read()
-                    .peers_with_min_block(min_block)
+        Ok(self
+            .send_by_predicate(data, |capability_server| {
+                capability_server.selectable_peers(
+                    capability_server
+                        .block_tracker
+                        .read()
+                        .peers_with_min_block(min_block),
+                )
             })
-            .await,
-        ))
+            .await?
+            .into_response())
     }
 
     async fn send_message_by_id(
         &self,
         request: tonic::Request<crate::grpc::sentry::SendMessageByIdRequest>,
     ) -> Result<Response<SentPeers>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         let crate::grpc::sentry::SendMessageByIdRequest { peer_id, data } = request.into_inner();
 
         let peer = peer_id
             .ok_or_else(|| tonic::Status::invalid_argument("no peer id"))?
             .into();
 
-        Ok(Response::new(
-            self.send_by_predicate(data, |_| std::iter::once(peer))
-                .await,
-        ))
+        // Validate before the reply is delivered to peers waiting for it.
+        if let Some(data) = &data {
+            if let Some(id) = data.id.to_usize() {
+                self.capability_server
+                    .validate_outbound_message(peer, id, &data.data)
+                    .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+            }
+        }
+
+        // Reply to a coalesced request is also delivered to the peers waiting for it.
+        let waiters = data
+            .as_ref()
+            .and_then(|data| {
+                let id = EthMessageId::from_usize(data.id.to_usize()?)?;
+                Some(
+                    self.capability_server
+                        .take_request_waiters(peer, id, &data.data),
+                )
+            })
+            .unwrap_or_default();
+        if let Some(reply) = &data {
+            let (same, reordered): (Vec<_>, Vec<_>) = waiters
+                .into_iter()
+                .partition(|(_, waiter_data)| *waiter_data == reply.data);
+            if !same.is_empty() {
+                self.send_by_predicate(data.clone(), |_| {
+                    same.into_iter().map(|(waiter, _)| waiter)
+                })
+                .await?;
+            }
+            for (waiter, waiter_data) in reordered {
+                self.send_by_predicate(
+                    Some(OutboundMessageData {
+                        id: reply.id,
+                        data: waiter_data,
+                    }),
+                    |_| std::iter::once(waiter),
+                )
+                .await?;
+            }
+        }
+
+        Ok(self
+            .send_to_peers(data, |_| std::iter::once(peer), true)
+            .await?
+            .into_response())
     }
 
     async fn send_message_to_random_peers(
         &self,
         request: tonic::Request<crate::grpc::sentry::SendMessageToRandomPeersRequest>,
     ) -> Result<Response<SentPeers>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         let crate::grpc::sentry::SendMessageToRandomPeersRequest { max_peers, data } =
             request.into_inner();
 
-        Ok(Response::new(
-            self.send_by_predicate(data, |capability_server| {
-                capability_server
-                    .all_peers()
-                    .into_iter()
-                    .take(max_peers as usize)
+        Ok(self
+            .send_by_predicate(data, |capability_server| {
+                capability_server.gossip_targets(
+                    capability_server
+                        .selectable_peers(capability_server.all_peers())
+                        .into_iter()
+                        .take(max_peers as usize),
+                )
             })
-            .await,
-        ))
+            .await?
+            .into_response())
     }
 
     async fn send_message_to_all(
         &self,
         request: tonic::Request<OutboundMessageData>,
     ) -> Result<Response<SentPeers>, tonic::Status> {
-        Ok(Response::new(
-            self.send_by_predicate(Some(request.into_inner()), |capability_server| {
-                capability_server.all_peers()
+        self.capability_server.shutdown.check()?;
+        Ok(self
+            .send_by_predicate(Some(request.into_inner()), |capability_server| {
+                capability_server.gossip_targets(capability_server.all_peers())
             })
-            .await,
-        ))
+            .await?
+            .into_response())
     }
 
     async fn peer_min_block(
         &self,
         request: tonic::Request<PeerMinBlockRequest>,
     ) -> Result<Response<()>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         let PeerMinBlockRequest { peer_id, min_block } = request.into_inner();
 
         let peer = peer_id
             .ok_or_else(|| tonic::Status::invalid_argument("no peer id"))?
             .into();
 
-        self.capability_server
-            .block_tracker
-            .write()
-            .set_block_number(peer, min_block, false);
+        self.capability_server.set_peer_min_block(peer, min_block);
 
         Ok(Response::new(()))
     }
@@ -196,10 +470,11 @@ impl Sentry for SentryService {
         &self,
         request: tonic::Request<crate::grpc::sentry::StatusData>,
     ) -> Result<Response<()>, tonic::Status> {
-        let s = FullStatusData::try_from(request.into_inner())
+        self.capability_server.shutdown.check()?;
+        let s = StatusData::try_from(request.into_inner())
             .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
 
-        *self.capability_server.status_message.write() = Some(s);
+        self.capability_server.set_control_status(s);
 
         Ok(Response::new(()))
     }
@@ -210,6 +485,7 @@ impl Sentry for SentryService {
         &self,
         _request: tonic::Request<()>,
     ) -> Result<Response<Self::ReceiveMessagesStream>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         Ok(self.make_channel(|c| &c.data_sender))
     }
 
@@ -219,6 +495,7 @@ impl Sentry for SentryService {
         &self,
         _request: tonic::Request<()>,
     ) -> Result<Response<Self::ReceiveUploadMessagesStream>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         Ok(self.make_channel(|c| &c.upload_requests_sender))
     }
 
@@ -228,6 +505,140 @@ impl Sentry for SentryService {
         &self,
         _request: tonic::Request<()>,
     ) -> Result<Response<Self::ReceiveTxMessagesStream>, tonic::Status> {
+        self.capability_server.shutdown.check()?;
         Ok(self.make_channel(|c| &c.tx_message_sender))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, shutdown::RETRY_AFTER_KEY};
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn send_outcome_per_peer() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config {
+            normalize_request_ids: true,
+            ..Default::default()
+        }));
+        let answered = PeerId::from_low_u64_be(1);
+        let unanswered = PeerId::from_low_u64_be(2);
+        let eth68 = PeerId::from_low_u64_be(3);
+        let gone = PeerId::from_low_u64_be(4);
+        for &(peer, version) in &[(answered, 66), (unanswered, 66), (eth68, 68)] {
+            capability_server.on_peer_connect(
+                peer,
+                None,
+                ConnectionDirection::Inbound,
+                std::iter::once((capability_name(), version)).collect(),
+            );
+            capability_server.mark_valid(peer);
+        }
+        capability_server
+            .request_ids
+            .lock()
+            .on_request(answered, EthMessageId::NodeData, 7);
+
+        let service = SentryService::new(capability_server);
+        let report = service
+            .send_by_predicate(
+                Some(OutboundMessageData {
+                    id: EthMessageId::NodeData as i32,
+                    data: Bytes::from_static(&rlp::EMPTY_LIST_RLP),
+                }),
+                |_| vec![answered, unanswered, eth68, gone],
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.sent, vec![answered]);
+        let mut failures = report
+            .failures
+            .iter()
+            .map(|failure| (failure.peer, failure.status))
+            .collect::<Vec<_>>();
+        failures.sort_by_key(|&(peer, _)| peer);
+        assert_eq!(
+            failures,
+            vec![
+                (unanswered, SendStatus::NoPendingRequest),
+                (eth68, SendStatus::Invalid),
+                (gone, SendStatus::Disconnected),
+            ]
+        );
+
+        let response = report.into_response();
+        assert_eq!(response.get_ref().peers.len(), 1);
+        assert_eq!(
+            response.metadata().get(SEND_FAILURES_OVERFLOW_KEY).unwrap(),
+            "0"
+        );
+        assert!(response
+            .metadata()
+            .get(SEND_FAILURES_KEY)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains(&format!("{}:invalid", hex::encode(eth68.as_bytes()))));
+    }
+
+    #[tokio::test]
+    async fn overflowed_failures_are_counted() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let service = SentryService::new(capability_server.clone());
+        let gone = (0..MAX_REPORTED_SEND_FAILURES as u64 + 2)
+            .map(PeerId::from_low_u64_be)
+            .collect::<Vec<_>>();
+        let report = service
+            .send_by_predicate(
+                Some(OutboundMessageData {
+                    id: EthMessageId::Transactions as i32,
+                    data: Bytes::from_static(&rlp::EMPTY_LIST_RLP),
+                }),
+                |_| gone.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.failures.len(), MAX_REPORTED_SEND_FAILURES);
+        assert_eq!(report.overflow, 2);
+
+        let metrics = String::from_utf8(capability_server.metrics.encode().unwrap()).unwrap();
+        assert!(metrics.contains(&format!(
+            "sentry_control_send_failures_total{{status=\"disconnected\"}} {}",
+            gone.len()
+        )));
+    }
+
+    #[tokio::test]
+    async fn shutdown_fails_calls_and_ends_streams() {
+        let capability_server = Arc::new(CapabilityServerImpl::for_test(&Config::default()));
+        let service = SentryService::new(capability_server.clone());
+        let mut messages = service
+            .receive_messages(tonic::Request::new(()))
+            .await
+            .unwrap()
+            .into_inner();
+
+        capability_server.shutdown.begin_draining();
+
+        let status = messages.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(status.metadata().get(RETRY_AFTER_KEY).is_some());
+        assert!(messages.next().await.is_none());
+
+        let status = service
+            .peer_min_block(tonic::Request::new(PeerMinBlockRequest {
+                peer_id: Some(PeerId::from_low_u64_be(1).into()),
+                min_block: 1,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let status = service
+            .receive_tx_messages(tonic::Request::new(()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
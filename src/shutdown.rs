@@ -0,0 +1,92 @@
+//! Shutdown of the sentry. While draining, the sentry API refuses calls and ends open
+//! streams with a status that tells the control to go elsewhere, rather than failing
+//! them with errors that look like peer problems.
+
+use std::time::Duration;
+use tokio::sync::watch;
+use tonic::{metadata::MetadataMap, Code, Status};
+
+/// Time given to API clients to see the shutdown status before the sentry exits.
+pub const DRAIN_PERIOD: Duration = Duration::from_secs(5);
+/// Status metadata with seconds after which the sentry may be tried again.
+pub const RETRY_AFTER_KEY: &str = "retry-after";
+
+#[derive(Debug)]
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    // Keeps the channel open, so that sends never fail.
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (draining, receiver) = watch::channel(false);
+        Self { draining, receiver }
+    }
+}
+
+impl Shutdown {
+    /// Returns `false` if already draining.
+    pub fn begin_draining(&self) -> bool {
+        if self.is_draining() {
+            return false;
+        }
+        let _ = self.draining.send(true);
+        true
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.receiver.clone()
+    }
+
+    /// Status of calls made or streams open while draining.
+    pub fn status() -> Status {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(RETRY_AFTER_KEY, DRAIN_PERIOD.as_secs().into());
+        Status::with_metadata(Code::Unavailable, "sentry is shutting down", metadata)
+    }
+
+    /// Fails once draining.
+    pub fn check(&self) -> Result<(), Status> {
+        if self.is_draining() {
+            return Err(Self::status());
+        }
+        Ok(())
+    }
+}
+
+/// Resolves once draining has begun.
+pub async fn drained(receiver: &mut watch::Receiver<bool>) {
+    while !*receiver.borrow() {
+        if receiver.changed().await.is_err() {
+            // Sentry is gone without draining.
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn draining_fails_calls() {
+        let shutdown = Shutdown::default();
+        let mut receiver = shutdown.subscribe();
+        assert!(shutdown.check().is_ok());
+        assert!(drained(&mut receiver).now_or_never().is_none());
+
+        assert!(shutdown.begin_draining());
+        assert!(!shutdown.begin_draining());
+        assert!(drained(&mut receiver).now_or_never().is_some());
+
+        let status = shutdown.check().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.metadata().get(RETRY_AFTER_KEY).unwrap(), "5");
+    }
+}
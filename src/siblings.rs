@@ -0,0 +1,213 @@
+//! Sharing of bans between sentries in front of the same control. Bans are pushed to
+//! siblings as PenalizePeer calls of the sentry API, tagged in the call metadata.
+
+use crate::{
+    api_auth::ApiToken,
+    grpc::sentry::{sentry_client::SentryClient, PenalizePeerRequest, PenaltyKind},
+};
+use devp2p::PeerId;
+use futures::future;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
+};
+use tonic::{metadata::MetadataMap, Request, Status};
+use tracing::*;
+
+/// Sentry that has banned the peer, to drop updates that have come back to it.
+pub const SENTRY_ORIGIN_KEY: &str = "x-sentry-origin";
+/// Ban duration in seconds, or `remove` when the ban has been lifted.
+pub const SENTRY_BAN_KEY: &str = "x-sentry-ban";
+
+const REMOVE: &str = "remove";
+const GOSSIP_BUFFER: usize = 256;
+/// Time a sibling has to accept a connection or a ban update.
+const SIBLING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BanUpdate {
+    Add(Duration),
+    Remove,
+}
+
+/// Ban update received from a sibling.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiblingBan {
+    pub origin: String,
+    pub update: BanUpdate,
+}
+
+/// Ban update carried by a PenalizePeer call, `None` if the call comes from the control.
+pub fn decode_sibling_ban(metadata: &MetadataMap) -> Result<Option<SiblingBan>, Status> {
+    let origin = match metadata.get(SENTRY_ORIGIN_KEY) {
+        Some(origin) => origin
+            .to_str()
+            .map_err(|_| Status::invalid_argument("Invalid sentry origin"))?
+            .to_string(),
+        None => return Ok(None),
+    };
+    let update = match metadata.get(SENTRY_BAN_KEY).map(|value| value.to_str()) {
+        Some(Ok(REMOVE)) => BanUpdate::Remove,
+        Some(Ok(secs)) => BanUpdate::Add(Duration::from_secs(
+            secs.parse()
+                .map_err(|_| Status::invalid_argument("Invalid ban duration"))?,
+        )),
+        _ => return Err(Status::invalid_argument("Missing ban update")),
+    };
+    Ok(Some(SiblingBan { origin, update }))
+}
+
+fn encode_sibling_ban(
+    peer: PeerId,
+    origin: &str,
+    update: BanUpdate,
+) -> Result<Request<PenalizePeerRequest>, Status> {
+    let mut request = Request::new(PenalizePeerRequest {
+        peer_id: Some(peer.into()),
+        penalty: PenaltyKind::Kick as i32,
+    });
+    let metadata = request.metadata_mut();
+    metadata.insert(
+        SENTRY_ORIGIN_KEY,
+        origin
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid sentry origin"))?,
+    );
+    metadata.insert(
+        SENTRY_BAN_KEY,
+        match update {
+            BanUpdate::Add(ttl) => ttl.as_secs().into(),
+            BanUpdate::Remove => REMOVE.parse().unwrap(),
+        },
+    );
+    Ok(request)
+}
+
+/// Queue of local ban updates for sibling sentries.
+#[derive(Clone, Debug)]
+pub struct SiblingGossip {
+    origin: String,
+    sender: Sender<(PeerId, BanUpdate)>,
+}
+
+impl SiblingGossip {
+    pub fn new(origin: String) -> (Self, Receiver<(PeerId, BanUpdate)>) {
+        let (sender, receiver) = channel(GOSSIP_BUFFER);
+        (Self { origin, sender }, receiver)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn push(&self, peer: PeerId, update: BanUpdate) {
+        if self.sender.try_send((peer, update)).is_err() {
+            debug!("Sibling gossip is behind, dropping ban update for {}", peer);
+        }
+    }
+}
+
+/// Push queued ban updates to every sibling. Siblings are expected to share our API token.
+/// Each sibling has a queue of its own, so that one that is down does not hold up the others.
+pub async fn run_sibling_gossip(
+    origin: String,
+    siblings: Vec<String>,
+    api_token: Option<ApiToken>,
+    mut updates: Receiver<(PeerId, BanUpdate)>,
+) {
+    let (senders, pushers): (Vec<_>, Vec<_>) = siblings
+        .into_iter()
+        .map(|addr| {
+            let (sender, receiver) = channel(GOSSIP_BUFFER);
+            let pusher = push_to_sibling(addr.clone(), origin.clone(), api_token.clone(), receiver);
+            ((addr, sender), pusher)
+        })
+        .unzip();
+
+    let fan_out = async move {
+        while let Some((peer, update)) = updates.recv().await {
+            for (addr, sender) in &senders {
+                if sender.try_send((peer, update)).is_err() {
+                    debug!(
+                        "Sibling {} is behind, dropping ban update for {}",
+                        addr, peer
+                    );
+                }
+            }
+        }
+    };
+    future::join(fan_out, future::join_all(pushers)).await;
+}
+
+async fn push_to_sibling(
+    addr: String,
+    origin: String,
+    api_token: Option<ApiToken>,
+    mut updates: Receiver<(PeerId, BanUpdate)>,
+) {
+    let mut client = None;
+    while let Some((peer, update)) = updates.recv().await {
+        if client.is_none() {
+            match timeout(SIBLING_TIMEOUT, SentryClient::connect(addr.clone())).await {
+                Ok(Ok(c)) => client = Some(c),
+                Ok(Err(e)) => {
+                    warn!("Failed to connect to sibling sentry {}: {}", addr, e);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("Timed out connecting to sibling sentry {}", addr);
+                    continue;
+                }
+            }
+        }
+
+        let mut request = match encode_sibling_ban(peer, &origin, update) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to encode ban update: {}", e);
+                continue;
+            }
+        };
+        if let Some(token) = &api_token {
+            token.authorize(&mut request);
+        }
+        if let Some(c) = client.as_mut() {
+            let res = timeout(SIBLING_TIMEOUT, c.penalize_peer(request)).await;
+            let error = match res {
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+            warn!("Failed to share ban of {} with {}: {}", peer, addr, error);
+            client = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_update_metadata() {
+        let peer = PeerId::from_low_u64_be(1);
+        for &update in &[BanUpdate::Add(Duration::from_secs(600)), BanUpdate::Remove] {
+            let request = encode_sibling_ban(peer, "a1", update).unwrap();
+            assert_eq!(
+                decode_sibling_ban(request.metadata()).unwrap(),
+                Some(SiblingBan {
+                    origin: "a1".to_string(),
+                    update
+                })
+            );
+        }
+
+        let mut request = Request::new(());
+        assert_eq!(decode_sibling_ban(request.metadata()).unwrap(), None);
+        request
+            .metadata_mut()
+            .insert(SENTRY_ORIGIN_KEY, "a1".parse().unwrap());
+        assert!(decode_sibling_ban(request.metadata()).is_err());
+    }
+}
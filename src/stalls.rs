@@ -0,0 +1,270 @@
+//! Detection of peers that do not read what is sent to them. Their outbound queue stays
+//! full, so every task sending to them would wait on it.
+//!
+//! The queue of a peer only has room for a single message, so how full it is tells
+//! little. A peer is considered stalled when a send has been waiting for its queue for
+//! longer than the timeout, or when too many sends keep waiting for it.
+
+use devp2p::PeerId;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{error::TrySendError, Sender},
+    watch,
+};
+
+pub const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queue has been closed by its receiver or aborted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueClosed;
+
+/// Sends waiting for room in a queue, with the time each one started waiting.
+#[derive(Debug, Default)]
+struct Waiters {
+    next: u64,
+    since: BTreeMap<u64, Instant>,
+}
+
+/// Sender of a peer's outbound queue that keeps track of sends waiting for room in it.
+pub struct SendQueue<T> {
+    sender: Sender<T>,
+    waiting: Arc<Mutex<Waiters>>,
+    aborted: watch::Receiver<bool>,
+}
+
+impl<T> Clone for SendQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            waiting: self.waiting.clone(),
+            aborted: self.aborted.clone(),
+        }
+    }
+}
+
+/// Sends waiting for a queue and its abort switch, which fails them.
+#[derive(Debug)]
+pub struct QueueMonitor {
+    waiting: Arc<Mutex<Waiters>>,
+    abort: watch::Sender<bool>,
+}
+
+/// How long sends have been waiting for a queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    /// Number of sends waiting for room in the queue.
+    pub waiting: usize,
+    /// Time the oldest of them has been waiting.
+    pub blocked_for: Duration,
+}
+
+struct Waiting<'a> {
+    waiters: &'a Mutex<Waiters>,
+    id: u64,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(waiters: &'a Mutex<Waiters>) -> Self {
+        let mut w = waiters.lock();
+        let id = w.next;
+        w.next += 1;
+        w.since.insert(id, Instant::now());
+        Self { waiters, id }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.waiters.lock().since.remove(&self.id);
+    }
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(sender: Sender<T>) -> (Self, QueueMonitor) {
+        let waiting = Arc::new(Mutex::new(Waiters::default()));
+        let (abort, aborted) = watch::channel(false);
+        (
+            Self {
+                sender,
+                waiting: waiting.clone(),
+                aborted,
+            },
+            QueueMonitor { waiting, abort },
+        )
+    }
+
+    /// Wait for room in the queue, unless the queue is aborted or its monitor is dropped.
+    pub async fn send(&self, value: T) -> Result<(), QueueClosed> {
+        let mut aborted = self.aborted.clone();
+        if *aborted.borrow() {
+            return Err(QueueClosed);
+        }
+
+        let _waiting = Waiting::new(&self.waiting);
+        tokio::select! {
+            res = self.sender.send(value) => res.map_err(|_| QueueClosed),
+            _ = aborted.changed() => Err(QueueClosed),
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.sender.try_send(value)
+    }
+}
+
+impl QueueMonitor {
+    pub fn depth(&self, now: Instant) -> QueueDepth {
+        let waiting = self.waiting.lock();
+        QueueDepth {
+            waiting: waiting.since.len(),
+            blocked_for: waiting
+                .since
+                .values()
+                .next()
+                .map(|&since| now.saturating_duration_since(since))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn abort(&self) {
+        let _ = self.abort.send(true);
+    }
+}
+
+/// Tells peers that have kept a send waiting for longer than the timeout, or that have
+/// had more sends than the threshold waiting for longer than the timeout.
+#[derive(Debug)]
+pub struct StallDetector {
+    depth_threshold: usize,
+    timeout: Duration,
+    deep_since: HashMap<PeerId, Instant>,
+}
+
+impl StallDetector {
+    pub fn new(depth_threshold: usize, timeout: Duration) -> Self {
+        Self {
+            depth_threshold,
+            timeout,
+            deep_since: Default::default(),
+        }
+    }
+
+    /// Record queue depths of all connected peers, returns the peers that have stalled.
+    /// Peers missing from `depths` are forgotten.
+    pub fn check(
+        &mut self,
+        depths: impl IntoIterator<Item = (PeerId, QueueDepth)>,
+        now: Instant,
+    ) -> Vec<PeerId> {
+        let mut deep_since = HashMap::new();
+        let mut stalled = vec![];
+        for (peer, depth) in depths {
+            if depth.blocked_for > self.timeout {
+                stalled.push(peer);
+                continue;
+            }
+            if depth.waiting <= self.depth_threshold {
+                continue;
+            }
+            let since = self.deep_since.get(&peer).copied().unwrap_or(now);
+            if now.saturating_duration_since(since) > self.timeout {
+                stalled.push(peer);
+            } else {
+                deep_since.insert(peer, since);
+            }
+        }
+        self.deep_since = deep_since;
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn waiting(waiting: usize) -> QueueDepth {
+        QueueDepth {
+            waiting,
+            blocked_for: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn stalled_after_timeout() {
+        let mut detector = StallDetector::new(2, Duration::from_secs(5));
+        let slow = PeerId::from_low_u64_be(1);
+        let fast = PeerId::from_low_u64_be(2);
+        let now = Instant::now();
+
+        assert!(detector
+            .check(vec![(slow, waiting(3)), (fast, waiting(3))], now)
+            .is_empty());
+        assert!(detector
+            .check(
+                vec![(slow, waiting(3)), (fast, waiting(2))],
+                now + Duration::from_secs(3)
+            )
+            .is_empty());
+        // Fast peer has drained its queue, so it starts over.
+        assert_eq!(
+            detector.check(
+                vec![(slow, waiting(3)), (fast, waiting(3))],
+                now + Duration::from_secs(6)
+            ),
+            vec![slow]
+        );
+        assert!(detector
+            .check(vec![(fast, waiting(3))], now + Duration::from_secs(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn single_blocked_send_stalls() {
+        let mut detector = StallDetector::new(32, Duration::from_secs(5));
+        let peer = PeerId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        let mut depth = QueueDepth {
+            waiting: 1,
+            blocked_for: Duration::from_secs(4),
+        };
+        assert!(detector.check(vec![(peer, depth)], now).is_empty());
+        depth.blocked_for = Duration::from_secs(6);
+        assert_eq!(detector.check(vec![(peer, depth)], now), vec![peer]);
+    }
+
+    #[tokio::test]
+    async fn abort_releases_waiting_sends() {
+        let (sender, mut receiver) = channel(1);
+        let (queue, monitor) = SendQueue::new(sender);
+
+        queue.send(1).await.unwrap();
+        let waiting = (0..3)
+            .map(|i| {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.send(i).await })
+            })
+            .collect::<Vec<_>>();
+        while monitor.depth(Instant::now()).waiting < 3 {
+            tokio::task::yield_now().await;
+        }
+        let first = monitor.depth(Instant::now()).blocked_for;
+        let later = monitor.depth(Instant::now() + Duration::from_secs(1));
+        assert_eq!(later.waiting, 3);
+        assert!(later.blocked_for >= first + Duration::from_secs(1));
+
+        monitor.abort();
+        for task in waiting {
+            assert_eq!(task.await.unwrap(), Err(QueueClosed));
+        }
+        assert_eq!(monitor.depth(Instant::now()), QueueDepth::default());
+        assert_eq!(queue.send(4).await, Err(QueueClosed));
+        assert_eq!(receiver.recv().await, Some(1));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{anyhow, bail};
+use async_stream::stream;
+use async_trait::async_trait;
+use auto_impl::auto_impl;
+use devp2p::{Discovery, NodeRecord, PeerId};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tracing::*;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// How long resolved addresses of a static peer are reused before resolving again.
+pub const RESOLVE_TTL: Duration = Duration::from_secs(30);
+
+#[async_trait]
+#[auto_impl(&, Box, Arc)]
+pub trait HostResolver: Send + Sync + 'static {
+    async fn lookup(&self, host: &str) -> anyhow::Result<Vec<IpAddr>>;
+}
+
+#[async_trait]
+impl HostResolver for TokioAsyncResolver {
+    async fn lookup(&self, host: &str) -> anyhow::Result<Vec<IpAddr>> {
+        Ok(self.lookup_ip(host).await?.iter().collect())
+    }
+}
+
+/// Peer given by enode URL, whose host may be a DNS name.
+#[derive(Clone, Debug, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub struct StaticPeer {
+    pub id: PeerId,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for StaticPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const PREFIX: &str = "enode://";
+
+        let data = s
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| anyhow!("Not an enode: {}", s))?;
+        let (id, addr) = data
+            .split_once('@')
+            .ok_or_else(|| anyhow!("Failed to read address: {}", s))?;
+        // Discovery port is irrelevant for dialing.
+        let addr = addr.split('?').next().unwrap_or(addr);
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Failed to read port: {}", s))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("Failed to read host: {}", s);
+        }
+
+        Ok(Self {
+            id: id.parse()?,
+            host: host.to_string(),
+            port: port.parse()?,
+        })
+    }
+}
+
+impl Display for StaticPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", self.host),
+            _ => self.host.clone(),
+        };
+        write!(
+            f,
+            "enode://{}@{}:{}",
+            hex::encode(self.id.as_bytes()),
+            host,
+            self.port
+        )
+    }
+}
+
+/// Static peers that are resolved again every time they are handed to the dialer,
+/// so that peers behind dynamic DNS are found after their address changes.
+pub struct StaticPeers<R> {
+    peers: Vec<StaticPeer>,
+    resolver: R,
+    ttl: Duration,
+    cache: HashMap<String, (Instant, Vec<IpAddr>)>,
+    next_peer: usize,
+    /// Index of the address to try next, for names with multiple addresses.
+    next_addr: HashMap<PeerId, usize>,
+}
+
+impl<R: HostResolver> StaticPeers<R> {
+    pub fn new(peers: Vec<StaticPeer>, resolver: R, ttl: Duration) -> Self {
+        Self {
+            peers,
+            resolver,
+            ttl,
+            cache: Default::default(),
+            next_peer: 0,
+            next_addr: Default::default(),
+        }
+    }
+
+    async fn resolve(&mut self, host: &str, now: Instant) -> anyhow::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+
+        if let Some((resolved, addrs)) = self.cache.get(host) {
+            if now.saturating_duration_since(*resolved) < self.ttl {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs = self.resolver.lookup(host).await?;
+        if addrs.is_empty() {
+            bail!("{} has no addresses", host);
+        }
+        trace!("Resolved {} to {:?}", host, addrs);
+        self.cache.insert(host.to_string(), (now, addrs.clone()));
+
+        Ok(addrs)
+    }
+
+    /// Next peer to dial, trying its addresses in order across attempts.
+    pub async fn next_record(&mut self, now: Instant) -> Option<anyhow::Result<NodeRecord>> {
+        if self.peers.is_empty() {
+            return None;
+        }
+
+        let peer = self.peers[self.next_peer % self.peers.len()].clone();
+        self.next_peer = self.next_peer.wrapping_add(1);
+
+        Some(match self.resolve(&peer.host, now).await {
+            Ok(addrs) => {
+                let next_addr = self.next_addr.entry(peer.id).or_default();
+                let ip = addrs[*next_addr % addrs.len()];
+                *next_addr = next_addr.wrapping_add(1);
+
+                Ok(NodeRecord {
+                    id: peer.id,
+                    addr: SocketAddr::new(ip, peer.port),
+                })
+            }
+            Err(e) => Err(e.context(format!("Failed to resolve static peer {}", peer))),
+        })
+    }
+
+    pub fn into_discovery(mut self) -> Discovery {
+        Box::pin(stream! {
+            while let Some(record) = self.next_record(Instant::now()).await {
+                yield record;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    #[derive(Default)]
+    struct StubResolver(Mutex<HashMap<String, Vec<IpAddr>>>);
+
+    #[async_trait]
+    impl HostResolver for StubResolver {
+        async fn lookup(&self, host: &str) -> anyhow::Result<Vec<IpAddr>> {
+            self.0
+                .lock()
+                .get(host)
+                .cloned()
+                .ok_or_else(|| anyhow!("NXDOMAIN"))
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        Ipv4Addr::new(10, 0, 0, last).into()
+    }
+
+    #[test]
+    fn parse() {
+        let id = PeerId::from_low_u64_be(1);
+        let enode = |addr: &str| format!("enode://{}@{}", hex::encode(id.as_bytes()), addr);
+
+        let peer = enode("node.internal:30303").parse::<StaticPeer>().unwrap();
+        assert_eq!(peer.host, "node.internal");
+        assert_eq!(peer.port, 30303);
+        assert_eq!(peer.to_string().parse::<StaticPeer>().unwrap(), peer);
+
+        let peer = enode("[::1]:30303?discport=30301")
+            .parse::<StaticPeer>()
+            .unwrap();
+        assert_eq!(peer.host, "::1");
+        assert_eq!(peer.to_string().parse::<StaticPeer>().unwrap(), peer);
+
+        assert!(enode("node.internal").parse::<StaticPeer>().is_err());
+        assert!("node.internal:30303".parse::<StaticPeer>().is_err());
+    }
+
+    #[tokio::test]
+    async fn address_change_is_picked_up() {
+        let resolver = Arc::new(StubResolver::default());
+        resolver
+            .0
+            .lock()
+            .insert("node.internal".into(), vec![ip(1), ip(2)]);
+
+        let id = PeerId::from_low_u64_be(1);
+        let mut peers = StaticPeers::new(
+            vec![StaticPeer {
+                id,
+                host: "node.internal".into(),
+                port: 30303,
+            }],
+            resolver.clone(),
+            RESOLVE_TTL,
+        );
+        let addr = |record: Option<anyhow::Result<NodeRecord>>| record.unwrap().unwrap().addr.ip();
+
+        let now = Instant::now();
+        assert_eq!(addr(peers.next_record(now).await), ip(1));
+        assert_eq!(addr(peers.next_record(now).await), ip(2));
+
+        resolver
+            .0
+            .lock()
+            .insert("node.internal".into(), vec![ip(3)]);
+        // Still cached.
+        assert_eq!(addr(peers.next_record(now).await), ip(1));
+        assert_eq!(addr(peers.next_record(now + RESOLVE_TTL).await), ip(3));
+
+        resolver.0.lock().clear();
+        assert!(peers
+            .next_record(now + RESOLVE_TTL * 2)
+            .await
+            .unwrap()
+            .is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use ethereum_types::U256;
+
+/// Tells peers that are still syncing apart from peers that can serve us.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncingClassifier {
+    /// Peer is syncing if its total difficulty is below this percentage of ours.
+    td_percent: u64,
+    /// Syncing peer has caught up once it is at most this many blocks behind our head.
+    block_tolerance: u64,
+}
+
+impl SyncingClassifier {
+    pub fn new(td_percent: u64, block_tolerance: u64) -> Self {
+        Self {
+            td_percent: td_percent.min(100),
+            block_tolerance,
+        }
+    }
+
+    /// Initial classification from the total difficulty in peer's Status.
+    pub fn is_syncing(&self, peer_td: U256, our_td: U256) -> bool {
+        peer_td.saturating_mul(100.into()) < our_td.saturating_mul(self.td_percent.into())
+    }
+
+    /// Whether syncing peer with tracked block `peer_block` has caught up with our head.
+    pub fn caught_up(&self, peer_block: u64, our_best_block: u64) -> bool {
+        peer_block.saturating_add(self.block_tolerance) >= our_best_block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_classification() {
+        let classifier = SyncingClassifier::new(90, 128);
+        let ours = U256::from(1_000_000);
+
+        assert!(!classifier.is_syncing(ours, ours));
+        assert!(!classifier.is_syncing(ours * 2, ours));
+        assert!(!classifier.is_syncing(U256::from(900_000), ours));
+        assert!(classifier.is_syncing(U256::from(899_999), ours));
+        assert!(classifier.is_syncing(U256::zero(), ours));
+
+        // Nothing is syncing relative to an empty chain.
+        assert!(!classifier.is_syncing(U256::zero(), U256::zero()));
+    }
+
+    #[test]
+    fn promotion_on_catch_up() {
+        let classifier = SyncingClassifier::new(90, 128);
+
+        assert!(!classifier.caught_up(0, 10_000));
+        assert!(!classifier.caught_up(9_871, 10_000));
+        assert!(classifier.caught_up(9_872, 10_000));
+        assert!(classifier.caught_up(10_500, 10_000));
+    }
+}
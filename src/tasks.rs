@@ -0,0 +1,267 @@
+use devp2p::PeerId;
+use educe::Educe;
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use task_group::TaskGroup;
+use tracing::*;
+
+/// Tasks owned by a disconnected peer are reported as leaked after this long.
+pub const TASK_LEAK_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// Tasks started while this many are live still run, but are not tracked.
+pub const MAX_TRACKED_TASKS: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TaskOwner {
+    Peer(PeerId),
+    Subsystem(&'static str),
+}
+
+impl Display for TaskOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Peer(peer) => write!(f, "peer {}", peer),
+            Self::Subsystem(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub name: Cow<'static, str>,
+    pub owner: TaskOwner,
+    pub started: Instant,
+}
+
+#[derive(Debug)]
+struct LiveTask {
+    info: TaskInfo,
+    reported_leaked: bool,
+}
+
+#[derive(Default, Educe)]
+#[educe(Debug)]
+struct Inner {
+    next_id: AtomicU64,
+    #[educe(Debug(ignore))]
+    live: scc::HashMap<u64, LiveTask>,
+    #[educe(Debug(ignore))]
+    departed_peers: scc::HashMap<PeerId, Instant>,
+    untracked: AtomicUsize,
+}
+
+/// Registry of live background tasks, used to find tasks that outlive their owner.
+///
+/// Tasks are registered on every peer event, so the registry is sharded rather than
+/// behind a single lock.
+#[derive(Clone, Debug, Default)]
+pub struct TaskRegistry {
+    inner: Arc<Inner>,
+}
+
+/// Keeps the task registered until dropped.
+#[derive(Debug)]
+pub struct TaskGuard {
+    registry: TaskRegistry,
+    /// `None` if the registry was full when the task started.
+    id: Option<u64>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.registry.inner.live.remove(&id);
+        }
+    }
+}
+
+impl TaskRegistry {
+    pub fn register(&self, name: impl Into<Cow<'static, str>>, owner: TaskOwner) -> TaskGuard {
+        let inner = &self.inner;
+        let id = if inner.live.len() < MAX_TRACKED_TASKS {
+            let id = inner.next_id.fetch_add(1, Ordering::Relaxed);
+            let _ = inner.live.insert(
+                id,
+                LiveTask {
+                    info: TaskInfo {
+                        name: name.into(),
+                        owner,
+                        started: Instant::now(),
+                    },
+                    reported_leaked: false,
+                },
+            );
+            Some(id)
+        } else {
+            if inner.untracked.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!(
+                    "More than {} live tasks, new tasks are not tracked",
+                    MAX_TRACKED_TASKS
+                );
+            }
+            None
+        };
+
+        TaskGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// Number of tasks that were started while the registry was full.
+    pub fn untracked_count(&self) -> usize {
+        self.inner.untracked.load(Ordering::Relaxed)
+    }
+
+    /// Wrap the future so that it is registered until it completes or is cancelled.
+    pub fn track<F: Future>(
+        &self,
+        name: impl Into<Cow<'static, str>>,
+        owner: TaskOwner,
+        fut: F,
+    ) -> impl Future<Output = F::Output> {
+        let guard = self.register(name, owner);
+        async move {
+            let _guard = guard;
+            fut.await
+        }
+    }
+
+    /// Spawn a tracked task onto the task group.
+    pub fn spawn<F>(
+        &self,
+        tasks: &TaskGroup,
+        name: impl Into<Cow<'static, str>>,
+        owner: TaskOwner,
+        fut: F,
+    ) where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        tasks.spawn_with_name(name.to_string(), self.track(name, owner, fut));
+    }
+
+    pub fn snapshot(&self) -> Vec<TaskInfo> {
+        let mut tasks = vec![];
+        self.inner
+            .live
+            .scan(|_, task| tasks.push(task.info.clone()));
+        tasks.sort_by_key(|task| task.started);
+        tasks
+    }
+
+    pub fn task_count(&self, owner: TaskOwner) -> usize {
+        let mut count = 0;
+        self.inner.live.scan(|_, task| {
+            if task.info.owner == owner {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    pub fn on_peer_connect(&self, peer: PeerId) {
+        self.inner.departed_peers.remove(&peer);
+    }
+
+    pub fn on_peer_disconnect(&self, peer: PeerId, now: Instant) {
+        self.inner.departed_peers.upsert(peer, now);
+    }
+
+    /// Tasks of disconnected peers that have survived the grace period.
+    /// Each task is returned only once.
+    pub fn take_leaked(&self, now: Instant) -> Vec<TaskInfo> {
+        let Inner {
+            live,
+            departed_peers,
+            ..
+        } = &*self.inner;
+
+        let mut leaked = vec![];
+        live.retain(|_, task| {
+            if let TaskOwner::Peer(peer) = task.info.owner {
+                if let Some(departed) = departed_peers.read(&peer, |_, departed| *departed) {
+                    if !task.reported_leaked
+                        && now.saturating_duration_since(departed) >= TASK_LEAK_GRACE_PERIOD
+                    {
+                        task.reported_leaked = true;
+                        leaked.push(task.info.clone());
+                    }
+                }
+            }
+            true
+        });
+
+        departed_peers.retain(|peer, departed| {
+            now.saturating_duration_since(*departed) < TASK_LEAK_GRACE_PERIOD
+                || live.any(|_, task| task.info.owner == TaskOwner::Peer(*peer))
+        });
+
+        leaked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tracks_and_detects_leaks() {
+        let registry = TaskRegistry::default();
+        let peer = PeerId::from_low_u64_be(1);
+        let owner = TaskOwner::Peer(peer);
+
+        registry.track("done", owner, async {}).await;
+        assert_eq!(registry.task_count(owner), 0);
+
+        // Cancelled task is unregistered as well.
+        drop(registry.track("cancelled", owner, std::future::pending::<()>()));
+        assert_eq!(registry.task_count(owner), 0);
+
+        let guard = registry.register("stuck", owner);
+        let _subsystem = registry.register("watch", TaskOwner::Subsystem("peer watch"));
+        assert_eq!(registry.snapshot().len(), 2);
+
+        let now = Instant::now();
+        registry.on_peer_disconnect(peer, now);
+        assert!(registry.take_leaked(now).is_empty());
+
+        let later = now + TASK_LEAK_GRACE_PERIOD;
+        let leaked = registry.take_leaked(later);
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].name, "stuck");
+        assert!(registry.take_leaked(later).is_empty());
+
+        drop(guard);
+        assert_eq!(registry.task_count(owner), 0);
+        assert!(registry.take_leaked(later).is_empty());
+        assert!(registry.inner.departed_peers.is_empty());
+    }
+
+    #[test]
+    fn stops_tracking_when_full() {
+        let registry = TaskRegistry::default();
+        let owner = TaskOwner::Subsystem("test");
+
+        let guards = (0..MAX_TRACKED_TASKS)
+            .map(|_| registry.register("task", owner))
+            .collect::<Vec<_>>();
+        assert_eq!(registry.untracked_count(), 0);
+
+        let untracked = registry.register("untracked", owner);
+        assert_eq!(registry.task_count(owner), MAX_TRACKED_TASKS);
+        assert_eq!(registry.untracked_count(), 1);
+
+        drop(untracked);
+        drop(guards);
+        assert_eq!(registry.task_count(owner), 0);
+        assert!(registry.register("task", owner).id.is_some());
+    }
+}
@@ -0,0 +1,219 @@
+//! Export of a span for every inbound eth message to an OTLP collector, so that single
+//! messages can be followed in Tempo or Jaeger next to the Prometheus totals.
+
+use crate::{
+    eth::EthMessageId,
+    pipeline::{PeerEvent, PeerEventFuture},
+};
+use devp2p::{DisconnectReason, InboundEvent, Message};
+use futures::FutureExt;
+use num_traits::FromPrimitive;
+use opentelemetry::{
+    sdk::{
+        trace::{self, Tracer},
+        Resource,
+    },
+    trace::{Span, SpanKind, Tracer as _},
+    KeyValue,
+};
+use parking_lot::RwLock;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+use tower::{Layer, Service};
+
+pub const SPAN_NAME: &str = "eth inbound message";
+
+/// Start exporting spans in batches to the collector at `endpoint`.
+pub fn install(endpoint: &str) -> anyhow::Result<Tracer> {
+    Ok(opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)?)
+}
+
+/// Flush spans that have not been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+fn result(res: &Result<Option<Message>, DisconnectReason>, forwarded: bool) -> &'static str {
+    match res {
+        Ok(Some(_)) => "responded",
+        Ok(None) if forwarded => "forwarded",
+        _ => "dropped",
+    }
+}
+
+/// Emits a span per eth message if a tracer is set, passes events through otherwise.
+/// The tracer may be set after the layer is built.
+#[derive(Clone)]
+pub struct TelemetryLayer {
+    tracer: Arc<RwLock<Option<Tracer>>>,
+}
+
+impl TelemetryLayer {
+    pub fn new(tracer: Arc<RwLock<Option<Tracer>>>) -> Self {
+        Self { tracer }
+    }
+}
+
+impl<S> Layer<S> for TelemetryLayer {
+    type Service = Telemetry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Telemetry {
+            tracer: self.tracer.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Telemetry<S> {
+    tracer: Arc<RwLock<Option<Tracer>>>,
+    inner: S,
+}
+
+impl<S> Service<PeerEvent> for Telemetry<S>
+where
+    S: Service<
+        PeerEvent,
+        Response = Option<Message>,
+        Error = DisconnectReason,
+        Future = PeerEventFuture,
+    >,
+{
+    type Response = Option<Message>;
+    type Error = DisconnectReason;
+    type Future = PeerEventFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PeerEvent) -> Self::Future {
+        let tracer = self.tracer.read().clone();
+        let (tracer, message) = match (tracer, &req.event) {
+            (Some(tracer), InboundEvent::Message { message, .. }) => (tracer, message),
+            _ => return self.inner.call(req),
+        };
+
+        let start_time = SystemTime::now();
+        let started = Instant::now();
+        let attributes = vec![
+            KeyValue::new(
+                "eth.message_type",
+                EthMessageId::from_usize(message.id)
+                    .map(|id| format!("{:?}", id))
+                    .unwrap_or_else(|| "Unknown".into()),
+            ),
+            KeyValue::new("eth.peer_id", req.peer.to_string()),
+            KeyValue::new("eth.payload_bytes", message.data.len() as i64),
+        ];
+        let forwarded = req.forwarded.clone();
+        let fut = self.inner.call(req);
+
+        async move {
+            let res = fut.await;
+            let span = tracer.build(
+                tracer
+                    .span_builder(SPAN_NAME)
+                    .with_kind(SpanKind::Server)
+                    .with_start_time(start_time)
+                    .with_attributes(attributes),
+            );
+            span.set_attribute(KeyValue::new(
+                "eth.processing_latency_ms",
+                started.elapsed().as_secs_f64() * 1000.0,
+            ));
+            span.set_attribute(KeyValue::new(
+                "eth.result",
+                result(&res, forwarded.load(Ordering::Relaxed)),
+            ));
+            span.end();
+            res
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use devp2p::PeerId;
+    use num_traits::ToPrimitive;
+    use opentelemetry::{
+        sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+        Key, Value,
+    };
+    use parking_lot::Mutex;
+    use tower::{service_fn, ServiceExt};
+
+    #[derive(Debug, Default)]
+    struct Collect(Arc<Mutex<Vec<SpanData>>>);
+
+    #[async_trait]
+    impl SpanExporter for Collect {
+        async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+            self.0.lock().extend(batch);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn span_per_message() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let provider = trace::TracerProvider::builder()
+            .with_simple_exporter(Collect(spans.clone()))
+            .build();
+        let tracer = Arc::new(RwLock::new(Some(provider.get_tracer("test", None))));
+        let telemetry = TelemetryLayer::new(tracer).layer(service_fn(|req: PeerEvent| {
+            let forwarded = match &req.event {
+                InboundEvent::Message { message, .. } => message.id != 0,
+                _ => false,
+            };
+            req.forwarded.store(forwarded, Ordering::Relaxed);
+            futures::future::ready(Ok(None)).boxed()
+        }));
+
+        for &(id, expected) in &[
+            (EthMessageId::NewBlockHashes, "forwarded"),
+            (EthMessageId::Status, "dropped"),
+        ] {
+            let event = PeerEvent::new(
+                PeerId::from_low_u64_be(1),
+                InboundEvent::Message {
+                    capability_name: crate::eth::capability_name(),
+                    message: Message {
+                        id: id.to_usize().unwrap(),
+                        data: vec![0xc0].into(),
+                    },
+                },
+            );
+            telemetry.clone().oneshot(event).await.unwrap();
+
+            let span = spans.lock().pop().unwrap();
+            assert_eq!(span.name, SPAN_NAME);
+            assert_eq!(span.span_kind, SpanKind::Server);
+            let attribute = |key: &'static str| span.attributes.get(&Key::new(key)).cloned();
+            assert_eq!(
+                attribute("eth.message_type"),
+                Some(Value::from(format!("{:?}", id)))
+            );
+            assert_eq!(attribute("eth.payload_bytes"), Some(Value::I64(1)));
+            assert_eq!(attribute("eth.result"), Some(Value::from(expected)));
+            assert!(attribute("eth.processing_latency_ms").is_some());
+        }
+    }
+}
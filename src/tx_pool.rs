@@ -0,0 +1,128 @@
+use crate::types::H256Map;
+use bytes::Bytes;
+use devp2p::util::keccak256;
+use ethereum_types::H256;
+use rlp::{DecoderError, Rlp};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Hash of a transaction as it appears in a Transactions or PooledTransactions list:
+/// legacy ones are lists, typed ones are strings holding the type byte and the payload.
+pub fn tx_hash(tx: &Rlp) -> Result<H256, DecoderError> {
+    Ok(if tx.is_list() {
+        keccak256(tx.as_raw())
+    } else {
+        keccak256(tx.data()?)
+    })
+}
+
+/// Transactions recently sent to peers, kept to answer GetPooledTransactions
+/// without asking the control. Entries expire after `ttl`, oldest are evicted
+/// first once `capacity` is reached.
+#[derive(Debug)]
+pub struct TxPool {
+    ttl: Duration,
+    capacity: usize,
+    txs: H256Map<Bytes>,
+    order: VecDeque<(Instant, H256)>,
+}
+
+impl TxPool {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            txs: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    /// Remember transactions of a Transactions or PooledTransactions payload without request ID.
+    pub fn insert_list(&mut self, data: &[u8], now: Instant) -> Result<(), DecoderError> {
+        self.expire(now);
+        for tx in Rlp::new(data).iter() {
+            let hash = tx_hash(&tx)?;
+            if self.txs.contains_key(&hash) {
+                continue;
+            }
+
+            self.txs.insert(hash, Bytes::copy_from_slice(tx.as_raw()));
+            self.order.push_back((now, hash));
+            while self.order.len() > self.capacity {
+                if let Some((_, oldest)) = self.order.pop_front() {
+                    self.txs.remove(&oldest);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Encoded transactions in request order, `None` for unknown or expired ones.
+    pub fn get(&mut self, hashes: &[H256], now: Instant) -> Vec<Option<Bytes>> {
+        self.expire(now);
+        hashes
+            .iter()
+            .map(|hash| self.txs.get(hash).cloned())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(inserted, hash)) = self.order.front() {
+            if now.saturating_duration_since(inserted) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.txs.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::RlpStream;
+
+    #[test]
+    fn typed_and_legacy_transactions_expire() {
+        let legacy = rlp::encode_list(&[1_u64, 2, 3]).freeze();
+        let typed_payload = [2_u8, 0xc0];
+        let typed = rlp::encode(&typed_payload.to_vec()).freeze();
+        let mut list = RlpStream::new_list(2);
+        list.append_raw(&legacy, 1).append_raw(&typed, 1);
+        let list = list.out().freeze();
+
+        let ttl = Duration::from_secs(300);
+        let mut pool = TxPool::new(ttl, 2);
+        let now = Instant::now();
+        pool.insert_list(&list, now).unwrap();
+
+        let hashes = [keccak256(&legacy), keccak256(&typed_payload), H256::zero()];
+        assert_eq!(
+            pool.get(&hashes, now + ttl / 2),
+            vec![Some(legacy.clone()), Some(typed.clone()), None]
+        );
+
+        // Capacity evicts the oldest, TTL the rest.
+        let other = rlp::encode_list(&[4_u64]).freeze();
+        let mut list = RlpStream::new_list(1);
+        list.append_raw(&other, 1);
+        pool.insert_list(&list.out(), now + ttl / 2).unwrap();
+        assert_eq!(
+            pool.get(&hashes, now + ttl / 2),
+            vec![None, Some(typed), None]
+        );
+        assert_eq!(pool.get(&[keccak256(&other)], now + ttl), vec![Some(other)]);
+        assert!(pool.get(&hashes, now + ttl).iter().all(Option::is_none));
+        assert_eq!(pool.len(), 1);
+    }
+}
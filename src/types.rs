@@ -1,6 +1,9 @@
 use ethereum_types::H256;
 use plain_hasher::PlainHasher;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::BuildHasherDefault,
+};
 
-pub type H256Map<T> = HashMap<H256, T, PlainHasher>;
-pub type H256Set = HashSet<H256, PlainHasher>;
+pub type H256Map<T> = HashMap<H256, T, BuildHasherDefault<PlainHasher>>;
+pub type H256Set = HashSet<H256, BuildHasherDefault<PlainHasher>>;
@@ -0,0 +1,55 @@
+use parking_lot::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Wall clock for timestamps that are shown or written to disk, durations are measured
+/// with `Instant`. Readings are strictly increasing at millisecond resolution, so a
+/// backwards step of the system clock holds it until the system clock catches up.
+#[derive(Debug, Default)]
+pub struct WallClock {
+    last: Mutex<Option<SystemTime>>,
+}
+
+impl WallClock {
+    pub fn now(&self) -> SystemTime {
+        self.observe(SystemTime::now())
+    }
+
+    fn observe(&self, system: SystemTime) -> SystemTime {
+        let mut last = self.last.lock();
+        let now = match *last {
+            Some(last) if system < last + Duration::from_millis(1) => {
+                last + Duration::from_millis(1)
+            }
+            _ => system,
+        };
+        *last = Some(now);
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn backwards_step_is_held() {
+        let clock = WallClock::default();
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let millis = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+        assert_eq!(clock.observe(start), start);
+        // NTP steps the clock back by five minutes.
+        let held = clock.observe(start - Duration::from_secs(300));
+        assert_eq!(millis(held), millis(start) + 1);
+        assert_eq!(
+            millis(clock.observe(start - Duration::from_secs(299))),
+            millis(start) + 2
+        );
+        // Same millisecond twice still gives distinct readings.
+        assert!(clock.observe(start) > held);
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(clock.observe(later), later);
+    }
+}
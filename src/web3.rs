@@ -0,0 +1,266 @@
+//! Status taken from a node's JSON-RPC endpoint, so that the sentry can find peers before
+//! the control has connected and set its status.
+
+use crate::eth::{Forks, StatusData};
+use anyhow::{anyhow, bail, Context};
+use educe::Educe;
+use ethereum_types::{H256, U256, U64};
+use futures::SinkExt;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::Mutex as AsyncMutex, time::timeout};
+use tokio_stream::StreamExt;
+use tokio_tungstenite::{
+    connect_async, tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
+
+pub const WEB3_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const WEB3_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Block {
+    number: U64,
+    hash: H256,
+    total_difficulty: Option<U256>,
+}
+
+type WsConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How JSON-RPC calls reach the node, chosen by the scheme of its URL.
+#[derive(Educe)]
+#[educe(Debug)]
+pub enum Web3Transport {
+    Http {
+        client: reqwest::Client,
+        url: Url,
+    },
+    /// Calls take turns over the connections, which are opened on first use and again
+    /// after an error.
+    WebSocket {
+        url: Url,
+        #[educe(Debug(ignore))]
+        pool: Vec<AsyncMutex<Option<WsConnection>>>,
+        next: AtomicUsize,
+    },
+}
+
+impl Web3Transport {
+    /// Up to `pool_size` connections are kept open to the node.
+    pub fn new(url: Url, pool_size: usize) -> anyhow::Result<Self> {
+        let pool_size = pool_size.max(1);
+        Ok(match url.scheme() {
+            "http" | "https" => Self::Http {
+                client: reqwest::Client::builder()
+                    .timeout(WEB3_TIMEOUT)
+                    .pool_max_idle_per_host(pool_size)
+                    .build()?,
+                url,
+            },
+            "ws" | "wss" => Self::WebSocket {
+                url,
+                pool: (0..pool_size).map(|_| AsyncMutex::new(None)).collect(),
+                next: AtomicUsize::new(0),
+            },
+            other => bail!("Unsupported web3 endpoint scheme: {}", other),
+        })
+    }
+
+    async fn send(&self, request: String) -> anyhow::Result<String> {
+        match self {
+            Self::Http { client, url } => Ok(client
+                .post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(request)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?),
+            Self::WebSocket { url, pool, next } => {
+                let mut connection = pool[next.fetch_add(1, Ordering::Relaxed) % pool.len()]
+                    .lock()
+                    .await;
+                let res = timeout(WEB3_TIMEOUT, async {
+                    if connection.is_none() {
+                        *connection = Some(connect_async(url.as_str()).await?.0);
+                    }
+                    let ws = connection.as_mut().unwrap();
+                    ws.send(WsMessage::Text(request)).await?;
+                    // One call at a time per connection, so the next text frame is the reply.
+                    loop {
+                        match ws.next().await {
+                            Some(Ok(WsMessage::Text(response))) => return Ok(response),
+                            Some(Ok(WsMessage::Close(_))) | None => {
+                                bail!("Connection closed by the node")
+                            }
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => return Err(e.into()),
+                        }
+                    }
+                })
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+                if res.is_err() {
+                    *connection = None;
+                }
+                res
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Web3StatusProvider {
+    transport: Web3Transport,
+    network_id: u64,
+    fork_data: Forks,
+}
+
+impl Web3StatusProvider {
+    pub fn new(transport: Web3Transport, network_id: u64, fork_data: Forks) -> Self {
+        Self {
+            transport,
+            network_id,
+            fork_data,
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> anyhow::Result<T> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let response = self.transport.send(request.to_string()).await?;
+        let response = serde_json::from_str::<RpcResponse<T>>(&response)
+            .with_context(|| format!("Invalid {} response", method))?;
+
+        if let Some(e) = response.error {
+            return Err(anyhow!("{} failed: {} ({})", method, e.message, e.code));
+        }
+        response
+            .result
+            .ok_or_else(|| anyhow!("{} returned nothing", method))
+    }
+
+    /// Status of the node's current head.
+    pub async fn get_status_data(&self) -> anyhow::Result<StatusData> {
+        let best_block = self.call::<U64>("eth_blockNumber", json!([])).await?;
+        let block = self
+            .call::<Block>(
+                "eth_getBlockByNumber",
+                json!([format!("{:#x}", best_block), false]),
+            )
+            .await?;
+        self.status_data(block)
+    }
+
+    fn status_data(&self, block: Block) -> anyhow::Result<StatusData> {
+        Ok(StatusData {
+            network_id: self.network_id,
+            total_difficulty: block
+                .total_difficulty
+                .ok_or_else(|| anyhow!("Node does not report total difficulty"))?,
+            best_hash: block.hash,
+            best_block: block.number.as_u64(),
+            fork_data: self.fork_data.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_from_block() {
+        let provider = Web3StatusProvider::new(
+            Web3Transport::new("http://127.0.0.1:8545".parse().unwrap(), 1).unwrap(),
+            5,
+            Forks {
+                genesis: H256::repeat_byte(1),
+                forks: [10, 20].iter().copied().collect(),
+            },
+        );
+
+        let response = serde_json::from_str::<RpcResponse<Block>>(
+            r#"{
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {
+                    "number": "0x1b4",
+                    "hash": "0x0202020202020202020202020202020202020202020202020202020202020202",
+                    "totalDifficulty": "0x3e8",
+                    "transactions": []
+                }
+            }"#,
+        )
+        .unwrap();
+        let status = provider.status_data(response.result.unwrap()).unwrap();
+        assert_eq!(status.network_id, 5);
+        assert_eq!(status.best_block, 436);
+        assert_eq!(status.best_hash, H256::repeat_byte(2));
+        assert_eq!(status.total_difficulty, 1000.into());
+        assert_eq!(status.fork_data, provider.fork_data);
+    }
+
+    #[tokio::test]
+    async fn status_over_websocket() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(WsMessage::Text(request))) = ws.next().await {
+                let request = serde_json::from_str::<Value>(&request).unwrap();
+                let result = match request["method"].as_str().unwrap() {
+                    "eth_blockNumber" => json!("0x1b4"),
+                    "eth_getBlockByNumber" => {
+                        assert_eq!(request["params"][0], "0x1b4");
+                        json!({
+                            "number": "0x1b4",
+                            "hash": format!("{:?}", H256::repeat_byte(2)),
+                            "totalDifficulty": "0x3e8",
+                        })
+                    }
+                    other => panic!("Unexpected call {}", other),
+                };
+                let response = json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+                ws.send(WsMessage::Text(response.to_string()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let provider = Web3StatusProvider::new(
+            Web3Transport::new(format!("ws://{}", addr).parse().unwrap(), 1).unwrap(),
+            1,
+            Forks {
+                genesis: H256::repeat_byte(1),
+                forks: Default::default(),
+            },
+        );
+        let status = provider.get_status_data().await.unwrap();
+        assert_eq!(status.best_block, 436);
+        assert_eq!(status.best_hash, H256::repeat_byte(2));
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::bail;
+use devp2p::PeerId;
+use educe::Educe;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, str::FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerWhitelistMode {
+    /// Peers off the whitelist are disconnected as soon as they connect.
+    Strict,
+    /// Whitelisted peers are kept over others, anyone may connect.
+    #[educe(Default)]
+    Permissive,
+}
+
+impl FromStr for PeerWhitelistMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "strict" => Self::Strict,
+            "permissive" => Self::Permissive,
+            other => bail!(
+                "Unknown whitelist mode {}, expected strict or permissive",
+                other
+            ),
+        })
+    }
+}
+
+/// Peers explicitly allowed to connect, updatable at runtime.
+#[derive(Debug)]
+pub struct PeerWhitelist {
+    mode: PeerWhitelistMode,
+    peers: HashSet<PeerId>,
+}
+
+impl PeerWhitelist {
+    pub fn new(mode: PeerWhitelistMode, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            mode,
+            peers: peers.into_iter().collect(),
+        }
+    }
+
+    pub fn mode(&self) -> PeerWhitelistMode {
+        self.mode
+    }
+
+    pub fn contains(&self, peer: PeerId) -> bool {
+        self.peers.contains(&peer)
+    }
+
+    /// Whether the peer may stay connected.
+    pub fn allows(&self, peer: PeerId) -> bool {
+        self.mode == PeerWhitelistMode::Permissive || self.contains(peer)
+    }
+
+    /// Returns `false` if the peer is already whitelisted.
+    pub fn add(&mut self, peer: PeerId) -> bool {
+        self.peers.insert(peer)
+    }
+
+    /// Returns `false` if the peer was not whitelisted.
+    pub fn remove(&mut self, peer: PeerId) -> bool {
+        self.peers.remove(&peer)
+    }
+
+    /// Whitelisted peers, sorted.
+    pub fn peers(&self) -> Vec<PeerId> {
+        let mut peers = self.peers.iter().copied().collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_and_permissive() {
+        let listed = PeerId::from_low_u64_be(1);
+        let other = PeerId::from_low_u64_be(2);
+
+        let mut whitelist = PeerWhitelist::new("strict".parse().unwrap(), vec![listed]);
+        assert!(whitelist.allows(listed));
+        assert!(!whitelist.allows(other));
+
+        assert!(whitelist.add(other));
+        assert!(!whitelist.add(other));
+        assert!(whitelist.allows(other));
+        assert_eq!(whitelist.peers(), vec![listed, other]);
+        assert!(whitelist.remove(listed));
+        assert!(!whitelist.allows(listed));
+
+        let whitelist = PeerWhitelist::new(PeerWhitelistMode::default(), vec![listed]);
+        assert!(whitelist.allows(other));
+        assert!(whitelist.contains(listed) && !whitelist.contains(other));
+
+        assert!("lenient".parse::<PeerWhitelistMode>().is_err());
+    }
+}